@@ -0,0 +1,259 @@
+//! API-key authentication.
+//!
+//! Keys are never stored. An [ApiKeyStore] holds a salted SHA-256 hash
+//! of each key, along with the identity (the [Principal]) it belongs
+//! to. Requests carry their key in an `authorization` metadata header,
+//! which [ApiKeyInterceptor] checks before a request reaches a server.
+
+use anyhow::{anyhow, Context, Result};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use std::sync::Arc;
+use tonic::service::Interceptor;
+use tonic::{Request, Status};
+
+/// The metadata header requests send their API key in.
+pub const AUTHORIZATION_HEADER: &str = "authorization";
+
+/// The (optional) scheme before the key in the [AUTHORIZATION_HEADER].
+pub const BEARER_PREFIX: &str = "Bearer ";
+
+/// The length of a key's salt, in bytes.
+const SALT_LEN: usize = 16;
+
+/// What a [Principal] is allowed to do.
+///
+/// Each role can do everything the roles before it can.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Serialize, Deserialize,
+)]
+pub enum Role {
+    /// Can read documents.
+    #[default]
+    ReadOnly,
+
+    /// Can also write and delete documents.
+    ReadWrite,
+
+    /// Can also create and drop collections.
+    Admin,
+}
+
+/// The identity a request was authenticated as.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Principal {
+    /// The id of the key's owner.
+    pub id: String,
+
+    /// What the key's owner is allowed to do.
+    pub role: Role,
+}
+
+impl Principal {
+    /// Checks if the principal's role includes `role`.
+    pub fn has_role(&self, role: Role) -> bool {
+        self.role >= role
+    }
+}
+
+/// A stored API key, as it's written in an [ApiKeyStore]'s file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ApiKeyEntry {
+    /// The id of the key's owner (see [Principal]).
+    pub id: String,
+
+    /// The key's salt, hex-encoded.
+    pub salt: String,
+
+    /// The SHA-256 hash of the salt followed by the key, hex-encoded.
+    pub hash: String,
+
+    /// What the key is allowed to do. Entries without a role are
+    /// read-only.
+    #[serde(default)]
+    pub role: Role,
+}
+
+impl ApiKeyEntry {
+    /// Creates an entry for `key`, owned by `id`, with a random salt.
+    pub fn new(id: &str, key: &str, role: Role) -> Self {
+        let mut salt = [0u8; SALT_LEN];
+        rand::thread_rng().fill_bytes(&mut salt);
+        ApiKeyEntry {
+            id: id.to_string(),
+            salt: hex::encode(salt),
+            hash: hex::encode(hash_key(&salt, key)),
+            role,
+        }
+    }
+
+    /// Checks if `key` is this entry's key.
+    fn matches(&self, key: &str) -> bool {
+        let (Ok(salt), Ok(hash)) = (hex::decode(&self.salt), hex::decode(&self.hash)) else {
+            return false;
+        };
+        constant_time_eq(&hash_key(&salt, key), &hash)
+    }
+}
+
+/// Hashes an API key with its salt.
+fn hash_key(salt: &[u8], key: &str) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(salt);
+    hasher.update(key.as_bytes());
+    hasher.finalize().to_vec()
+}
+
+/// Compares two byte strings in time that only depends on their
+/// lengths, so a comparison doesn't leak how much of a hash matched.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// The API keys that are allowed to use the database.
+#[derive(Debug, Clone, Default)]
+pub struct ApiKeyStore {
+    entries: Vec<ApiKeyEntry>,
+}
+
+impl ApiKeyStore {
+    /// Creates a store holding the given entries.
+    pub fn new(entries: Vec<ApiKeyEntry>) -> Self {
+        ApiKeyStore { entries }
+    }
+
+    /// Loads a store from a JSON file holding a list of [ApiKeyEntry]s.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let b = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read API keys from {:?}", path))?;
+        let entries = serde_json::from_str(&b)
+            .with_context(|| format!("Failed to parse API keys in {:?}", path))?;
+        Ok(ApiKeyStore { entries })
+    }
+
+    /// Writes the store to a JSON file (see [ApiKeyStore::load]).
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let b = serde_json::to_string_pretty(&self.entries)?;
+        std::fs::write(path, b).with_context(|| format!("Failed to write API keys to {:?}", path))
+    }
+
+    /// Adds a key, owned by `id`, with the given `role`.
+    pub fn add(&mut self, id: &str, key: &str, role: Role) {
+        self.entries.push(ApiKeyEntry::new(id, key, role));
+    }
+
+    /// Checks an API key.
+    ///
+    /// # Returns
+    ///
+    /// The identity the key belongs to, or an error if it's unknown.
+    pub fn verify(&self, key: &str) -> Result<Principal> {
+        self.entries
+            .iter()
+            .find(|e| e.matches(key))
+            .map(|e| Principal {
+                id: e.id.clone(),
+                role: e.role,
+            })
+            .ok_or(anyhow!("Unknown API key"))
+    }
+}
+
+/// A tonic interceptor that rejects requests without a valid API key.
+///
+/// Authenticated requests get their [Principal] added to their
+/// extensions, for handlers to check.
+#[derive(Debug, Clone)]
+pub struct ApiKeyInterceptor {
+    store: Arc<ApiKeyStore>,
+}
+
+impl ApiKeyInterceptor {
+    /// Creates an interceptor checking keys against `store`.
+    pub fn new(store: Arc<ApiKeyStore>) -> Self {
+        ApiKeyInterceptor { store }
+    }
+}
+
+impl Interceptor for ApiKeyInterceptor {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        let header = request
+            .metadata()
+            .get(AUTHORIZATION_HEADER)
+            .ok_or_else(|| Status::unauthenticated("Missing API key"))?
+            .to_str()
+            .map_err(|_| Status::unauthenticated("Invalid API key"))?;
+        let key = header.strip_prefix(BEARER_PREFIX).unwrap_or(header);
+        let principal = self
+            .store
+            .verify(key)
+            .map_err(|_| Status::unauthenticated("Invalid API key"))?;
+        request.extensions_mut().insert(principal);
+        Ok(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bson::oid::ObjectId;
+    use tonic::Code;
+
+    /// Runs a request with the given `authorization` header (if any)
+    /// through an interceptor, returning the error's code if it fails.
+    fn intercept(store: &Arc<ApiKeyStore>, header: Option<&str>) -> Result<Principal, Code> {
+        let mut request = Request::new(());
+        if let Some(header) = header {
+            request
+                .metadata_mut()
+                .insert(AUTHORIZATION_HEADER, header.parse().unwrap());
+        }
+        let request = ApiKeyInterceptor::new(store.clone())
+            .call(request)
+            .map_err(|s| s.code())?;
+        Ok(request.extensions().get::<Principal>().unwrap().clone())
+    }
+
+    #[test]
+    fn api_keys_authenticate_requests() -> Result<()> {
+        // Save a store with a couple of keys, and load it back...
+        let path = format!("/tmp/{}.json", ObjectId::new());
+        let mut store = ApiKeyStore::default();
+        store.add("alice", "alice-secret", Role::Admin);
+        store.add("bob", "bob-secret", Role::ReadOnly);
+        store.save(&path)?;
+        let store = Arc::new(ApiKeyStore::load(&path)?);
+
+        // The keys themselves aren't in the file...
+        let b = std::fs::read_to_string(&path)?;
+        assert!(!b.contains("alice-secret"));
+
+        // A valid key authenticates as its owner...
+        let alice = Principal {
+            id: "alice".to_string(),
+            role: Role::Admin,
+        };
+        assert_eq!(store.verify("alice-secret")?, alice);
+        assert!(alice.has_role(Role::ReadWrite));
+        assert_eq!(intercept(&store, Some("alice-secret")), Ok(alice));
+        let bob = intercept(&store, Some("Bearer bob-secret")).map(|p| (p.id, p.role));
+        assert_eq!(bob, Ok(("bob".to_string(), Role::ReadOnly)));
+
+        // An unknown key doesn't...
+        assert!(store.verify("eve-secret").is_err());
+        let err = intercept(&store, Some("Bearer eve-secret")).unwrap_err();
+        assert_eq!(err, Code::Unauthenticated);
+
+        // And neither does a missing one...
+        let err = intercept(&store, None).unwrap_err();
+        assert_eq!(err, Code::Unauthenticated);
+
+        // (Clean up) Remove the file...
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
@@ -1 +1,3 @@
 //! This module handles authentication and authorization for users of the database.
+
+pub mod apikey;
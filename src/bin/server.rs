@@ -1,54 +1,75 @@
-use bson::{doc, Document};
-use serde::{Deserialize, Serialize};
+use anyhow::{anyhow, Context, Result};
+use brickdb_lib::db::database::{Database, DB_META_FILE};
+use brickdb_lib::logging::{self, LogConfig};
+use brickdb_lib::server::prometheus::spawn_metrics_endpoint;
+use brickdb_lib::server::server::{create_service, BDBDatabaseServer, ServerConfig};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::Mutex;
 
-#[derive(Debug, Serialize, Deserialize)]
-enum Value<T> {
-    Tombstone,
-    Value(T),
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct Record {
-    id: String,
-    value: Value<Document>,
-}
-
-fn main() {
-    // Define two records...
-    let record_a = Record {
-        id: "a".to_string(),
-        value: Value::Value(doc! { "name": "Alice" }),
-    };
-    let record_b = Record {
-        id: "c".to_string(),
-        value: Value::Tombstone,
-    };
+/// The directory data is stored in, if one isn't given.
+const DEFAULT_DATA_DIR: &str = "data";
 
-    // Convert them to BSON...
-    let doc_a = bson::to_document(&record_a).unwrap();
-    let doc_b = bson::to_document(&record_b).unwrap();
+/// The address the gRPC server listens on, if one isn't given.
+const DEFAULT_ADDR: &str = "127.0.0.1:50051";
 
-    // Create two files...
-    let mut file_a = std::fs::File::create("a.bson").unwrap();
-    let mut file_b = std::fs::File::create("b.bson").unwrap();
+const USAGE: &str = "Usage: brickdb_server [DATA_DIR] [--addr ADDR] [--metrics-addr ADDR]";
 
-    // Write the BSON to the files...
-    doc_a.to_writer(&mut file_a).unwrap();
-    doc_b.to_writer(&mut file_b).unwrap();
+/// Reads the server's settings (and the gRPC address) from the
+/// command line arguments.
+fn parse_args() -> Result<(ServerConfig, SocketAddr)> {
+    let mut config = ServerConfig::new(DEFAULT_DATA_DIR);
+    let mut addr = DEFAULT_ADDR.parse()?;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = |flag: &str| -> Result<SocketAddr> {
+            let value = args.next().ok_or(anyhow!("Missing value for {}", flag))?;
+            value
+                .parse()
+                .with_context(|| format!("Invalid address for {}: {:?}", flag, value))
+        };
+        match arg.as_str() {
+            "--addr" => addr = value("--addr")?,
+            "--metrics-addr" => config.metrics_addr = Some(value("--metrics-addr")?),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            }
+            _ if !arg.starts_with('-') => config.data_dir = arg,
+            _ => return Err(anyhow!("Unknown argument {:?}\n{}", arg, USAGE)),
+        }
+    }
+    Ok((config, addr))
+}
 
-    // Read the BSON from the files...
-    let doc_a = bson::Document::from_reader(&mut std::fs::File::open("a.bson").unwrap()).unwrap();
-    let doc_b = bson::Document::from_reader(&mut std::fs::File::open("b.bson").unwrap()).unwrap();
+#[tokio::main]
+async fn main() -> Result<()> {
+    logging::init(&LogConfig::default())?;
+    let (config, addr) = parse_args()?;
 
-    // Convert the BSON to records...
-    let record_a: Record = bson::from_document(doc_a).unwrap();
-    let record_b: Record = bson::from_document(doc_b).unwrap();
+    // Check the data directory, then open (or create) the database...
+    let server = BDBDatabaseServer::start(&config).await?;
+    let db = if Path::new(&config.data_dir).join(DB_META_FILE).exists() {
+        Database::load(&config.data_dir).await?
+    } else {
+        Database::new("brickdb", &config.data_dir)
+    };
+    let db = Arc::new(Mutex::new(db));
 
-    // Print the records...
-    println!("record_a: {:?}", record_a);
-    println!("record_b: {:?}", record_b);
+    // Serve the metrics on their own task, if asked to...
+    if let Some(metrics_addr) = config.metrics_addr {
+        let listener = std::net::TcpListener::bind(metrics_addr)
+            .with_context(|| format!("Couldn't bind the metrics endpoint to {}", metrics_addr))?;
+        spawn_metrics_endpoint(listener, db.clone())?;
+        tracing::info!(%metrics_addr, "Serving metrics");
+    }
 
-    // Delete the files...
-    std::fs::remove_file("a.bson").unwrap();
-    std::fs::remove_file("b.bson").unwrap();
+    // Then serve the database...
+    tracing::info!(%addr, data_dir = %config.data_dir, "Serving the database");
+    tonic::transport::Server::builder()
+        .add_service(create_service(server.with_database(db)))
+        .serve(addr)
+        .await?;
+    Ok(())
 }
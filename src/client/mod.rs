@@ -1 +1,448 @@
+//! A typed async client for the database server.
+//!
+//! [BrickClient] wraps the generated gRPC client, sending documents
+//! as BSON and keys as `ObjectId`s, and turns error statuses into
+//! `anyhow` errors (with the `Status` as their source).
 
+use anyhow::{Context, Result};
+use bson::oid::ObjectId;
+use bson::Document;
+use rand::Rng;
+use std::future::Future;
+use std::time::Duration;
+use tonic::transport::Channel;
+use tonic::{Code, Status, Streaming};
+
+use crate::server::gen::database_server_client::DatabaseServerClient;
+use crate::server::gen::{
+    DeleteRequest, GetRequest, ReadConsistency, ScanRequest, ScanResponse, SetRequest,
+};
+
+/// The default number of times a failed call is retried.
+pub const DEFAULT_MAX_RETRIES: u32 = 3;
+
+/// The default wait before the first retry.
+pub const DEFAULT_INITIAL_BACKOFF: Duration = Duration::from_millis(50);
+
+/// The default longest wait between retries.
+pub const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(2);
+
+/// The default fraction of each wait that's randomized.
+pub const DEFAULT_JITTER: f64 = 0.5;
+
+/// How a [BrickClient] retries calls that fail with a transient error
+/// (see [is_retryable]).
+///
+/// The wait doubles after each attempt, from `initial_backoff` up to
+/// `max_backoff`, and then up to `jitter` of it is taken off at random,
+/// so clients that failed together don't all retry together.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    /// The most times a call is retried (so it's tried at most
+    /// `max_retries + 1` times). Zero turns retries off.
+    pub max_retries: u32,
+
+    /// The wait before the first retry.
+    pub initial_backoff: Duration,
+
+    /// The longest wait between retries.
+    pub max_backoff: Duration,
+
+    /// The fraction (from 0 to 1) of each wait that's randomized.
+    pub jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: DEFAULT_MAX_RETRIES,
+            initial_backoff: DEFAULT_INITIAL_BACKOFF,
+            max_backoff: DEFAULT_MAX_BACKOFF,
+            jitter: DEFAULT_JITTER,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Returns how long to wait before retry number `attempt` (from 0).
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_backoff);
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        if jitter == 0.0 {
+            return backoff;
+        }
+        backoff.mul_f64(1.0 - jitter * rand::thread_rng().gen::<f64>())
+    }
+}
+
+/// Settings for a [BrickClient].
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ClientConfig {
+    /// How failed calls are retried.
+    ///
+    /// Connecting, `get`, and `scan` are always retried.
+    pub retry: RetryPolicy,
+
+    /// If `true`, `set` and `delete` are retried too. This is off by
+    /// default, since a write that failed may still have been applied,
+    /// so the caller has to say that repeating its writes is safe.
+    pub idempotent_writes: bool,
+}
+
+/// Checks if a call that failed with `code` may succeed if retried.
+///
+/// Only transient failures are retried, never errors in the request
+/// itself (like `InvalidArgument` or `NotFound`).
+pub fn is_retryable(code: Code) -> bool {
+    matches!(code, Code::Unavailable | Code::DeadlineExceeded)
+}
+
+/// A client for a database server.
+#[derive(Debug, Clone)]
+pub struct BrickClient {
+    inner: DatabaseServerClient<Channel>,
+
+    /// The client's settings.
+    pub config: ClientConfig,
+}
+
+impl BrickClient {
+    /// Connects to the server at `addr` (e.g. `"http://127.0.0.1:50051"`),
+    /// retrying failed attempts according to the config's retry policy.
+    pub async fn connect(addr: &str, config: ClientConfig) -> Result<Self> {
+        let mut attempt = 0;
+        let inner = loop {
+            match DatabaseServerClient::connect(addr.to_string()).await {
+                Ok(inner) => break inner,
+                Err(_) if attempt < config.retry.max_retries => {
+                    tokio::time::sleep(config.retry.backoff(attempt)).await;
+                    attempt += 1;
+                }
+                Err(err) => {
+                    return Err(err)
+                        .with_context(|| format!("Couldn't connect to the server at {:?}", addr))
+                }
+            }
+        };
+        Ok(BrickClient { inner, config })
+    }
+
+    /// Gets the document with the given `key` from a collection.
+    ///
+    /// # Returns
+    ///
+    /// The document, or `None` if it (or the collection) doesn't exist.
+    pub async fn get(&mut self, collection: &str, key: &ObjectId) -> Result<Option<Document>> {
+        let req = GetRequest {
+            collection: collection.to_string(),
+            key: key.to_hex(),
+            consistency: ReadConsistency::Strong.into(),
+        };
+        let res = with_retries(&self.config.retry, true, || {
+            let mut inner = self.inner.clone();
+            let req = req.clone();
+            async move { inner.get(req).await }
+        })
+        .await;
+        let res = match res {
+            Ok(res) => res.into_inner(),
+            Err(status) if status.code() == Code::NotFound => return Ok(None),
+            Err(status) => {
+                return Err(status_error(
+                    *status,
+                    format!("get {} from {:?}", key, collection),
+                ))
+            }
+        };
+        let doc = bson::from_slice(&res.document)
+            .with_context(|| format!("Couldn't decode the document {}", key))?;
+        Ok(Some(doc))
+    }
+
+    /// Sets the document with the given `key` in a collection, creating
+    /// the collection if it doesn't exist.
+    ///
+    /// Only retried if the config has `idempotent_writes` set.
+    pub async fn set(&mut self, collection: &str, key: &ObjectId, doc: &Document) -> Result<()> {
+        let req = SetRequest {
+            collection: collection.to_string(),
+            key: key.to_hex(),
+            document: bson::to_vec(doc)?,
+            durable: false,
+        };
+        with_retries(&self.config.retry, self.config.idempotent_writes, || {
+            let mut inner = self.inner.clone();
+            let req = req.clone();
+            async move { inner.set(req).await }
+        })
+        .await
+        .map_err(|status| status_error(*status, format!("set {} in {:?}", key, collection)))?;
+        Ok(())
+    }
+
+    /// Deletes the document with the given `key` from a collection.
+    ///
+    /// Only retried if the config has `idempotent_writes` set.
+    pub async fn delete(&mut self, collection: &str, key: &ObjectId) -> Result<()> {
+        let req = DeleteRequest {
+            collection: collection.to_string(),
+            key: key.to_hex(),
+        };
+        with_retries(&self.config.retry, self.config.idempotent_writes, || {
+            let mut inner = self.inner.clone();
+            let req = req.clone();
+            async move { inner.delete(req).await }
+        })
+        .await
+        .map_err(|status| status_error(*status, format!("delete {} from {:?}", key, collection)))?;
+        Ok(())
+    }
+
+    /// Scans the documents in a collection with keys from `start` to
+    /// `end` (inclusive), sorted by key.
+    ///
+    /// Starting the scan is retried, but once documents are streaming
+    /// an error ends the stream.
+    ///
+    /// # Arguments
+    ///
+    /// * `collection` - The collection to scan
+    /// * `start` - The first key in the range, or `None` to start at the beginning
+    /// * `end` - The last key in the range, or `None` to go to the end
+    /// * `limit` - The most documents to return, if any
+    ///
+    /// # Returns
+    ///
+    /// A stream of the documents, which are sent by the server as
+    /// they're read.
+    pub async fn scan(
+        &mut self,
+        collection: &str,
+        start: Option<ObjectId>,
+        end: Option<ObjectId>,
+        limit: Option<u64>,
+    ) -> Result<ScanStream> {
+        let req = ScanRequest {
+            collection: collection.to_string(),
+            start_key: start.map(|k| k.to_hex()).unwrap_or_default(),
+            end_key: end.map(|k| k.to_hex()).unwrap_or_default(),
+            limit: limit.unwrap_or(0),
+            consistency: ReadConsistency::Strong.into(),
+        };
+        let stream = with_retries(&self.config.retry, true, || {
+            let mut inner = self.inner.clone();
+            let req = req.clone();
+            async move { inner.scan(req).await }
+        })
+        .await
+        .map_err(|status| status_error(*status, format!("scan {:?}", collection)))?
+        .into_inner();
+        Ok(ScanStream { stream })
+    }
+}
+
+/// Makes a call, retrying it according to `policy` while it fails with
+/// a retryable status (see [is_retryable]) if `retry` is set.
+///
+/// The status is boxed, since it's large.
+async fn with_retries<T, F, Fut>(
+    policy: &RetryPolicy,
+    retry: bool,
+    mut call: F,
+) -> Result<T, Box<Status>>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, Status>>,
+{
+    let mut attempt = 0;
+    loop {
+        match call().await {
+            Ok(res) => return Ok(res),
+            Err(status) if retry && attempt < policy.max_retries && is_retryable(status.code()) => {
+                tokio::time::sleep(policy.backoff(attempt)).await;
+                attempt += 1;
+            }
+            Err(status) => return Err(Box::new(status)),
+        }
+    }
+}
+
+/// The documents streamed back from a [BrickClient::scan].
+pub struct ScanStream {
+    stream: Streaming<ScanResponse>,
+}
+
+impl ScanStream {
+    /// Returns the next document (and its key), or `None` once the
+    /// scan is done.
+    pub async fn next(&mut self) -> Option<Result<(ObjectId, Document)>> {
+        let msg = match self.stream.message().await {
+            Ok(Some(msg)) => msg,
+            Ok(None) => return None,
+            Err(status) => return Some(Err(status_error(status, "read a scan".to_string()))),
+        };
+        Some(decode_scan_response(msg))
+    }
+
+    /// Reads the rest of the scan's documents.
+    pub async fn collect(mut self) -> Result<Vec<(ObjectId, Document)>> {
+        let mut docs = vec![];
+        while let Some(res) = self.next().await {
+            docs.push(res?);
+        }
+        Ok(docs)
+    }
+}
+
+/// Decodes a document (and its key) from a scan response.
+fn decode_scan_response(msg: ScanResponse) -> Result<(ObjectId, Document)> {
+    let key = ObjectId::parse_str(&msg.key)
+        .with_context(|| format!("The server sent an invalid key {:?}", msg.key))?;
+    let doc = bson::from_slice(&msg.document)
+        .with_context(|| format!("Couldn't decode the document {}", key))?;
+    Ok((key, doc))
+}
+
+/// Converts an error status into an error, saying what the client
+/// was trying to do.
+fn status_error(status: Status, action: String) -> anyhow::Error {
+    let msg = format!(
+        "Couldn't {} ({:?}: {})",
+        action,
+        status.code(),
+        status.message()
+    );
+    anyhow::Error::new(status).context(msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::database::Database;
+    use crate::server::server::{create_service, BDBDatabaseServer};
+    use bson::doc;
+    use std::sync::Arc;
+    use tokio::sync::Mutex;
+    use tokio_stream::wrappers::TcpListenerStream;
+
+    #[tokio::test]
+    async fn round_trip_through_server() -> Result<()> {
+        // Start a server (on any free port)...
+        let path = format!("/tmp/{}", ObjectId::new());
+        let db = Arc::new(Mutex::new(Database::new("test", &path)));
+        let server = BDBDatabaseServer::new().with_database(db);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(create_service(server))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+        let mut client =
+            BrickClient::connect(&format!("http://{}", addr), ClientConfig::default()).await?;
+
+        // Set some documents, and get one back...
+        let mut keys: Vec<_> = (0..5).map(|_| ObjectId::new()).collect();
+        keys.sort();
+        for (i, key) in keys.iter().enumerate() {
+            client.set("things", key, &doc! { "i": i as i32 }).await?;
+        }
+        assert_eq!(client.get("things", &keys[2]).await?, Some(doc! { "i": 2 }));
+
+        // Scan them...
+        let docs = client
+            .scan("things", Some(keys[1]), None, Some(3))
+            .await?
+            .collect()
+            .await?;
+        let exp: Vec<_> = (1..4).map(|i| (keys[i], doc! { "i": i as i32 })).collect();
+        assert_eq!(docs, exp);
+
+        // Delete one...
+        client.delete("things", &keys[2]).await?;
+        assert_eq!(client.get("things", &keys[2]).await?, None);
+
+        // Errors say what failed, and keep the status...
+        let err = client
+            .scan("other", None, None, None)
+            .await
+            .err()
+            .expect("scanning a missing collection should fail");
+        assert!(format!("{:#}", err).contains("Couldn't scan \"other\""));
+        let status = err
+            .downcast_ref::<Status>()
+            .expect("should keep the status");
+        assert_eq!(status.code(), Code::NotFound);
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_max() {
+        let policy = RetryPolicy {
+            max_retries: 10,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(100),
+            jitter: 0.0,
+        };
+        let waits: Vec<_> = (0..6).map(|i| policy.backoff(i).as_millis()).collect();
+        assert_eq!(waits, vec![10, 20, 40, 80, 100, 100]);
+
+        // Jitter only ever shortens the wait...
+        let policy = RetryPolicy {
+            jitter: 0.5,
+            ..policy
+        };
+        for _ in 0..100 {
+            let wait = policy.backoff(2);
+            assert!(wait >= Duration::from_millis(20) && wait <= Duration::from_millis(40));
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_only_transient_errors() {
+        let policy = RetryPolicy {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(1),
+            jitter: 0.0,
+        };
+
+        // Fails with each of `codes` in turn, then succeeds...
+        let attempts = |codes: Vec<Code>, retry: bool| async move {
+            let mut calls = 0;
+            let res = with_retries(&policy, retry, || {
+                calls += 1;
+                let res = match codes.get(calls - 1) {
+                    Some(code) => Err(Status::new(*code, "oops")),
+                    None => Ok(()),
+                };
+                async move { res }
+            })
+            .await;
+            (res.map_err(|s| s.code()), calls)
+        };
+
+        // Transient errors are retried...
+        let codes = vec![Code::Unavailable, Code::DeadlineExceeded];
+        assert_eq!(attempts(codes, true).await, (Ok(()), 3));
+
+        // But not more than the policy allows...
+        let codes = vec![Code::Unavailable; 5];
+        assert_eq!(attempts(codes, true).await, (Err(Code::Unavailable), 4));
+
+        // Nor errors with the request...
+        for code in [Code::InvalidArgument, Code::NotFound] {
+            assert_eq!(attempts(vec![code], true).await, (Err(code), 1));
+        }
+
+        // Nor anything, for calls that can't be retried...
+        let codes = vec![Code::Unavailable];
+        assert_eq!(attempts(codes, false).await, (Err(Code::Unavailable), 1));
+    }
+}
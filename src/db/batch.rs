@@ -0,0 +1,51 @@
+//! Batches of writes to a collection.
+
+use bson::oid::ObjectId;
+use bson::Document;
+use serde::{Deserialize, Serialize};
+
+/// The default maximum number of operations in a batch.
+pub const DEFAULT_MAX_BATCH_SIZE: usize = 1000;
+
+/// A single write in a batch.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BatchOp {
+    /// Sets the document with the given key.
+    Set(ObjectId, Document),
+
+    /// Deletes the document with the given key.
+    Del(ObjectId),
+}
+
+/// What to do with a batch that's larger than the maximum size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum OversizedBatch {
+    /// Split the batch into chunks of at most the maximum size.
+    ///
+    /// Each chunk is applied atomically but the batch as a whole
+    /// isn't -- if a chunk fails, the chunks before it stay applied.
+    #[default]
+    Chunk,
+
+    /// Reject the batch with an error, without applying any of it.
+    Reject,
+}
+
+/// Limits on the batches a collection accepts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BatchConfig {
+    /// The maximum number of operations applied atomically.
+    pub max_batch_size: usize,
+
+    /// What to do with batches larger than `max_batch_size`.
+    pub oversized: OversizedBatch,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        BatchConfig {
+            max_batch_size: DEFAULT_MAX_BATCH_SIZE,
+            oversized: OversizedBatch::default(),
+        }
+    }
+}
@@ -1,15 +1,153 @@
 use std::collections::HashMap;
-use anyhow::Result;
+use std::sync::Arc;
+use anyhow::{anyhow, Result};
 use bson::oid::ObjectId;
-use bson::Document;
+use bson::{Bson, DateTime, Document};
 use serde::{Deserialize, Serialize};
-use crate::storage::lsm::LSMTree;
-use crate::index::bptree::BPTree;
+use crate::storage::conf::StorageConfig;
+use crate::storage::describe::LevelStat;
+use crate::storage::iter::TreeIter;
+use crate::storage::lru::EvictionCap;
+use crate::storage::lsm::{Durability, LSMTree};
+use crate::storage::record::{Record, Value};
+use crate::index::bptree::{BPTree, IndexLoading};
+use crate::db::ratelimit::RateLimiter;
+use crate::db::batch::{BatchConfig, BatchOp, OversizedBatch};
+use crate::db::key::{self, InsertMode, Key, KeyKind, KeyMinter};
+use crate::db::ttl;
+use crate::index::order::cmp_bson;
+use crate::query::planner::{self, Plan, Query};
+use crate::storage::util::{dir_size, read_bson, write_bson_atomic};
+
+/// The name of the directory (in a collection's directory) that
+/// its indexes are stored in.
+pub const INDEX_DIR: &str = "indexes";
+
+/// The name of the file (in a collection's directory) that its
+/// metadata (see [CollectionMeta]) is stored in.
+pub const COLLECTION_META_FILE: &str = "_collection_meta.bson";
 
 /// Metadata about a collection.
+///
+/// This holds the collection's settings and the ids of its indexes, so
+/// they can be restored by [Collection::load].
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CollectionMeta {
     pub name: String,
+
+    /// The kind of primary key the collection uses.
+    pub key_kind: KeyKind,
+
+    /// Whether the collection's data is persisted to disk.
+    pub durability: Durability,
+
+    /// How the collection chooses the keys of inserted documents.
+    pub insert_mode: InsertMode,
+
+    /// Limits on the collection's batches.
+    pub batch_config: BatchConfig,
+
+    /// The collection's eviction cap, if it's an in-memory cache.
+    pub eviction: Option<EvictionCap>,
+
+    /// The ids of the collection's indexes (see [BPTree::load]).
+    ///
+    /// These are stored as strings, since a [uuid::Uuid] doesn't
+    /// round-trip through BSON.
+    pub index_ids: Vec<String>,
+}
+
+/// A change to a document's value in one of a collection's indexes
+/// (see [Collection::write_records]).
+#[derive(Debug, Clone)]
+struct IndexChange {
+    /// The name of the index.
+    index: String,
+
+    /// The document's key.
+    key: ObjectId,
+
+    /// The document's old value in the index, if it had one.
+    old: Option<Bson>,
+
+    /// The document's new value in the index, if it has one.
+    new: Option<Bson>,
+}
+
+/// The result of vacuuming a collection (see [Collection::vacuum]).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VacuumReport {
+    /// The collection's on-disk size before vacuuming, in bytes.
+    pub bytes_before: u64,
+
+    /// The collection's on-disk size after vacuuming, in bytes.
+    pub bytes_after: u64,
+}
+
+/// A disagreement between a secondary index and a collection's documents
+/// (see [Collection::verify_integrity]).
+#[derive(Debug, Clone, PartialEq)]
+pub enum IndexMismatch {
+    /// A live document's indexed value isn't in the index.
+    Missing { key: ObjectId, value: Bson },
+
+    /// An index entry points to a document that doesn't exist or
+    /// doesn't have that value.
+    Stale { key: ObjectId, value: Bson },
+}
+
+/// The result of checking a collection's integrity (see
+/// [Collection::verify_integrity]).
+#[derive(Debug, Clone, Default)]
+pub struct IntegrityReport {
+    /// The SSTables that couldn't be read, with the reason.
+    pub bad_tables: Vec<(ObjectId, String)>,
+
+    /// The mismatches found in each index, by index name.
+    ///
+    /// Only indexes with mismatches are included, and only if indexes
+    /// were checked.
+    pub index_mismatches: HashMap<String, Vec<IndexMismatch>>,
+}
+
+impl IntegrityReport {
+    /// Checks if no problems were found.
+    pub fn is_ok(&self) -> bool {
+        self.bad_tables.is_empty() && self.index_mismatches.is_empty()
+    }
+}
+
+/// An iterator over a collection's live documents in a key range, in
+/// key order (see [Collection::iter_range]).
+///
+/// Expired documents (see [ttl]) are skipped.
+pub struct RangeIter {
+    /// The tree's records in the range.
+    inner: TreeIter,
+
+    /// When the iterator was created, which documents expire against.
+    now: DateTime,
+}
+
+impl RangeIter {
+    /// Returns the next document and its key, or `None` once the range
+    /// is done.
+    ///
+    /// After an error, the iterator ends.
+    pub async fn next(&mut self) -> Option<Result<(ObjectId, Document)>> {
+        loop {
+            match self.inner.next().await? {
+                Ok((_, doc)) if ttl::is_expired(&doc, self.now) => continue,
+                res => return Some(res),
+            }
+        }
+    }
+
+    /// Returns the number of records read from the collection's tree
+    /// so far (see [TreeIter::records_read]).
+    pub fn records_read(&self) -> usize {
+        self.inner.records_read()
+    }
 }
 
 /// A collection of documents. Equivalent to a table in a relational database.
@@ -25,36 +163,2130 @@ pub struct Collection {
 
     /// A map from index id to the fields in the collection.
     pub indexes: HashMap<String, BPTree>,
+
+    /// The database's write rate limiter, if it has one.
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+
+    /// The kind of primary key used by the `*_keyed` methods (see
+    /// [Collection::set_key_kind]).
+    key_kind: KeyKind,
+
+    /// Limits on the batches passed to [Collection::apply_batch].
+    pub batch_config: BatchConfig,
+
+    /// How [Collection::insert] chooses the keys of new documents.
+    pub insert_mode: InsertMode,
+
+    /// Mints keys for [InsertMode::ServerKeys] inserts.
+    key_minter: KeyMinter,
 }
 
 impl Collection {
     pub fn new(name: &str, path: &str) -> Self {
         Collection {
-            tree: LSMTree::new(name, path),
+            tree: LSMTree::new(name, path, StorageConfig::default()),
             indexes: HashMap::new(),
+            rate_limiter: None,
+            key_kind: KeyKind::default(),
+            batch_config: BatchConfig::default(),
+            insert_mode: InsertMode::default(),
+            key_minter: KeyMinter::new(),
+        }
+    }
+
+    /// Sets whether the collection's data is persisted to disk.
+    ///
+    /// Note that an in-memory collection ([Durability::InMemory]) never
+    /// writes to disk, so its data is lost on restart.
+    pub fn with_durability(mut self, durability: Durability) -> Self {
+        self.tree.durability = durability;
+        self
+    }
+
+    /// Makes the collection an in-memory cache, evicting the least
+    /// recently used documents once it grows past `cap`.
+    ///
+    /// This also makes the collection in-memory only (see
+    /// [Collection::with_durability]), since evicted documents are
+    /// dropped rather than written to disk.
+    pub fn with_eviction(mut self, cap: EvictionCap) -> Self {
+        self.tree.durability = Durability::InMemory;
+        self.tree.memtable.eviction = Some(cap);
+        self
+    }
+
+    /// Sets the kind of primary key used by the collection (see
+    /// [Collection::set_key_kind]).
+    pub fn with_key_kind(mut self, key_kind: KeyKind) -> Self {
+        self.set_key_kind(key_kind);
+        self
+    }
+
+    /// Gets the kind of primary key used by the collection.
+    pub fn key_kind(&self) -> KeyKind {
+        self.key_kind
+    }
+
+    /// Sets the kind of primary key used by the collection.
+    ///
+    /// Unless the keys are `ObjectId`s, this also stops the tree from
+    /// splitting tables by the timestamps in their keys (see
+    /// [KeyKind::is_timestamped]).
+    ///
+    /// Note: This doesn't re-encode existing documents, so it should be
+    /// set before the collection is written to.
+    pub fn set_key_kind(&mut self, key_kind: KeyKind) {
+        self.key_kind = key_kind;
+        self.tree.timestamped_keys = key_kind.is_timestamped();
+    }
+
+    /// Sets how the collection chooses the keys of inserted documents.
+    pub fn with_insert_mode(mut self, insert_mode: InsertMode) -> Self {
+        self.insert_mode = insert_mode;
+        self
+    }
+
+    /// Loads an existing collection from its directory (see
+    /// [LSMTree::load]).
+    ///
+    /// The collection's settings and indexes are restored from its
+    /// [COLLECTION_META_FILE], if it has one. Otherwise the defaults are
+    /// used and the collection has no indexes.
+    pub async fn load(name: &str, path: &str) -> Result<Self> {
+        Collection::load_with(name, path, IndexLoading::default()).await
+    }
+
+    /// Loads an existing collection from its directory (see
+    /// [Collection::load]), with `index_loading` controlling whether its
+    /// indexes are loaded now or when they're first used.
+    pub async fn load_with(name: &str, path: &str, index_loading: IndexLoading) -> Result<Self> {
+        let mut coll = Collection::new(name, path);
+        coll.tree = LSMTree::load(name, path, StorageConfig::default()).await?;
+
+        // Restore the collection's settings and indexes...
+        if let Some(meta) = Collection::load_meta(path).await? {
+            coll.set_key_kind(meta.key_kind);
+            coll.insert_mode = meta.insert_mode;
+            coll.batch_config = meta.batch_config;
+            coll.tree.durability = meta.durability;
+            coll.tree.memtable.eviction = meta.eviction;
+            let index_dir = std::path::Path::new(path).join(INDEX_DIR);
+            for id in meta.index_ids {
+                let id = uuid::Uuid::parse_str(&id)?;
+                let index_dir = index_dir.to_string_lossy().into();
+                let index = match index_loading {
+                    IndexLoading::Eager => BPTree::load(index_dir, id)?,
+                    IndexLoading::Lazy => BPTree::load_lazy(index_dir, id)?,
+                };
+                coll.indexes.insert(index.meta.name.clone(), index);
+            }
         }
+
+        if coll.tree.memtable.is_full() {
+            coll.tree.compaction_cycle().await?;
+        }
+        Ok(coll)
+    }
+
+    /// Gets the collection's metadata (see [CollectionMeta]).
+    pub fn meta(&self) -> CollectionMeta {
+        let mut index_ids: Vec<_> = self
+            .indexes
+            .values()
+            .map(|idx| idx.meta.id.to_string())
+            .collect();
+        index_ids.sort();
+        CollectionMeta {
+            name: self.tree.name.clone(),
+            key_kind: self.key_kind,
+            durability: self.tree.durability,
+            insert_mode: self.insert_mode,
+            batch_config: self.batch_config,
+            eviction: self.tree.memtable.eviction,
+            index_ids,
+        }
+    }
+
+    /// Writes the collection's metadata to its [COLLECTION_META_FILE],
+    /// creating its directory if needed.
+    ///
+    /// This is done when the collection is created and when an index is
+    /// added, so it only needs to be called after changing the
+    /// collection's settings.
+    pub async fn write_meta(&self) -> Result<()> {
+        tokio::fs::create_dir_all(&self.tree.path).await?;
+        let path = std::path::Path::new(&self.tree.path).join(COLLECTION_META_FILE);
+        write_bson_atomic(path, &bson::to_document(&self.meta())?).await
     }
 
-    pub fn load() -> Result<Self> {
-        todo!();
+    /// Reads the metadata for the collection stored at `path`.
+    ///
+    /// # Returns
+    ///
+    /// The collection's metadata, or `None` if it hasn't been written.
+    pub async fn load_meta(path: &str) -> Result<Option<CollectionMeta>> {
+        let path = std::path::Path::new(path).join(COLLECTION_META_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let bytes = read_bson(&path).await?;
+        Ok(Some(bson::from_slice(&bytes)?))
     }
 
+    /// Gets a document by its key.
+    ///
+    /// Documents whose TTL has passed (see [Collection::set_with_ttl])
+    /// are treated as missing.
     pub async fn get(&self, key: &ObjectId) -> Result<Option<Document>> {
-        self.tree.get(key).await
+        let doc = self.tree.get(key).await?;
+        Ok(doc.filter(|doc| !ttl::is_expired(doc, DateTime::now())))
+    }
+
+    /// Gets the documents with keys in the given range (inclusive),
+    /// sorted by key, skipping any that have been deleted or expired.
+    ///
+    /// # Arguments
+    ///
+    /// * `start` - The first key in the range.
+    /// * `end` - The last key in the range.
+    /// * `limit` - The most documents to return, if any. To page through
+    ///   a large range, start the next call just after the last key seen.
+    pub async fn get_range(
+        &self,
+        start: &ObjectId,
+        end: &ObjectId,
+        limit: Option<usize>,
+    ) -> Result<Vec<Document>> {
+        let docs = self.tree.get_range(start, end).await?;
+        let now = DateTime::now();
+        Ok(docs
+            .into_iter()
+            .filter(|doc| !ttl::is_expired(doc, now))
+            .take(limit.unwrap_or(usize::MAX))
+            .collect())
+    }
+
+    /// Like [Collection::get_range], but returns each document with its
+    /// key, so a large range can be paged through (see [key::next_key]).
+    ///
+    /// The documents are read from a snapshot, and reading stops once
+    /// `limit` documents have been found (see [Collection::iter_range]).
+    pub async fn get_range_with_keys(
+        &self,
+        start: &ObjectId,
+        end: &ObjectId,
+        limit: Option<usize>,
+    ) -> Result<Vec<(ObjectId, Document)>> {
+        let mut iter = self.iter_range(start, end).await?;
+        let mut docs = vec![];
+        while docs.len() < limit.unwrap_or(usize::MAX) {
+            match iter.next().await {
+                Some(res) => docs.push(res?),
+                None => break,
+            }
+        }
+        Ok(docs)
     }
 
-    pub async fn get_range(&self, _start: &ObjectId, _end: &ObjectId) -> Result<Vec<Document>> {
-        // self.tree.get_range(start, end).await
-        todo!();
+    /// Iterates over the documents with keys in the given range
+    /// (inclusive), in key order.
+    ///
+    /// The documents are read from a snapshot, merging the collection's
+    /// memtables and tables as they're read (see [LSMTree::iter_range]),
+    /// so only what's read is held in memory.
+    pub async fn iter_range(&self, start: &ObjectId, end: &ObjectId) -> Result<RangeIter> {
+        Ok(RangeIter {
+            inner: self.tree.iter_range(start, end).await?,
+            now: DateTime::now(),
+        })
     }
 
+    /// Sets a document, updating the collection's indexes.
+    ///
+    /// If the document already existed, its old values are removed
+    /// from the indexes. Indexes on fields the document doesn't have
+    /// are skipped.
     pub async fn set(&mut self, key: &ObjectId, doc: Document) -> Result<()> {
-        self.tree.set(key, doc);
+        if let Some(rl) = &self.rate_limiter {
+            rl.check_write(bson::to_vec(&doc)?.len())?;
+        }
+        self.write_records(vec![Record {
+            key: *key,
+            value: Value::Data(doc),
+        }])
+        .await
+    }
+
+    /// Writes records to the collection's tree and then updates its
+    /// indexes to match.
+    ///
+    /// Every write to the collection goes through here, so the indexes
+    /// can't drift from the documents:
+    ///
+    /// * The index changes are worked out (and checked against the
+    ///   distinct indexes) up front, so a write that a distinct index
+    ///   would reject isn't applied at all.
+    /// * The indexes are only updated once the tree write succeeds.
+    /// * If updating an index fails part way, the index changes made so
+    ///   far are undone and the documents' old versions are written
+    ///   back, before the error is returned.
+    async fn write_records(&mut self, records: Vec<Record>) -> Result<()> {
+        if self.indexes.is_empty() {
+            return self.write_tree(records).await;
+        }
+
+        // Work out how the indexes need to change...
+        let (old, changes) = self.index_changes(&records).await?;
+        self.check_distinct(&changes)?;
+
+        // Write the documents, then update the indexes to match...
+        self.write_tree(records).await?;
+        if let Err(err) = self.apply_index_changes(&changes) {
+            self.write_tree(old).await?;
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /// Writes records to the collection's tree, as a single write if
+    /// there's more than one.
+    async fn write_tree(&mut self, mut records: Vec<Record>) -> Result<()> {
+        match records.len() {
+            0 => Ok(()),
+            1 => self.tree.write(records.remove(0)).await,
+            _ => self.tree.write_batch(records).await,
+        }
+    }
+
+    /// Works out how the collection's indexes need to change for
+    /// `records` to be written (later records for a key winning).
+    ///
+    /// # Returns
+    ///
+    /// The records' keys' current values (as records that would put them
+    /// back) and the index changes.
+    async fn index_changes(&self, records: &[Record]) -> Result<(Vec<Record>, Vec<IndexChange>)> {
+        // Find the final value of each key, in the order they're written...
+        let mut finals: Vec<(ObjectId, Option<&Document>)> = vec![];
+        let mut positions = HashMap::new();
+        for record in records {
+            let doc = match &record.value {
+                Value::Data(doc) => Some(doc),
+                Value::Tombstone => None,
+            };
+            match positions.get(&record.key) {
+                Some(&i) => finals[i] = (record.key, doc),
+                None => {
+                    positions.insert(record.key, finals.len());
+                    finals.push((record.key, doc));
+                }
+            }
+        }
+
+        // Compare them with the current documents...
+        let mut old = vec![];
+        let mut changes = vec![];
+        for (key, new_doc) in finals {
+            let old_doc = self.tree.get(&key).await?;
+            for (name, index) in self.indexes.iter() {
+                let field = &index.meta.key;
+                let old_value = old_doc.as_ref().and_then(|d| d.get(field)).cloned();
+                let new_value = new_doc.and_then(|d| d.get(field)).cloned();
+                if old_value != new_value {
+                    changes.push(IndexChange {
+                        index: name.clone(),
+                        key,
+                        old: old_value,
+                        new: new_value,
+                    });
+                }
+            }
+            old.push(Record {
+                key,
+                value: old_doc.map_or(Value::Tombstone, Value::Data),
+            });
+        }
+        Ok((old, changes))
+    }
+
+    /// Checks that the index changes wouldn't give two documents the
+    /// same value in a distinct index.
+    fn check_distinct(&self, changes: &[IndexChange]) -> Result<()> {
+        for (i, change) in changes.iter().enumerate() {
+            let (Some(index), Some(value)) = (self.indexes.get(&change.index), &change.new) else {
+                continue;
+            };
+            if !index.meta.distinct {
+                continue;
+            }
+            let is_value =
+                |v: &Option<Bson>| v.as_ref().is_some_and(|v| cmp_bson(v, value).is_eq());
+
+            // Another document in the same write can't take the value...
+            let taken_here = changes[..i]
+                .iter()
+                .any(|c| c.index == change.index && is_value(&c.new));
+
+            // ...and neither can a document that already has it, unless
+            // it's being changed to something else...
+            let mut taken = false;
+            for id in index.get_all(value.clone())? {
+                let moving = changes
+                    .iter()
+                    .any(|c| c.index == change.index && c.key == id && is_value(&c.old));
+                if id != change.key && !moving {
+                    taken = true;
+                }
+            }
+            if taken || taken_here {
+                return Err(anyhow!(
+                    "The value {} already exists in the distinct index {:?}",
+                    value,
+                    change.index
+                ));
+            }
+        }
         Ok(())
     }
 
+    /// Applies index changes (see [Collection::index_changes]).
+    ///
+    /// The old values are removed before the new ones are added, so
+    /// documents can swap values in a distinct index. If a change fails,
+    /// the ones already made are undone.
+    fn apply_index_changes(&mut self, changes: &[IndexChange]) -> Result<()> {
+        let mut done: Vec<(&IndexChange, bool)> = vec![];
+        let removes = changes.iter().map(|c| (c, false));
+        let inserts = changes.iter().map(|c| (c, true));
+        for (change, insert) in removes.chain(inserts) {
+            let index = self
+                .indexes
+                .get_mut(&change.index)
+                .ok_or(anyhow!("Index {:?} not found", change.index))?;
+            let res = match (insert, &change.old, &change.new) {
+                (false, Some(value), _) => index.remove(value.clone(), change.key),
+                (true, _, Some(value)) => index.insert(value.clone(), change.key).map(|_| true),
+                _ => Ok(false),
+            };
+            match res {
+                Ok(true) => done.push((change, insert)),
+                Ok(false) => {}
+                Err(err) => {
+                    self.undo_index_changes(done);
+                    return Err(err);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Undoes index changes made by [Collection::apply_index_changes],
+    /// in reverse order.
+    ///
+    /// This is best-effort, since it's already handling an error. If it
+    /// fails, the index can be fixed with [Collection::rebuild_index].
+    fn undo_index_changes(&mut self, done: Vec<(&IndexChange, bool)>) {
+        for (change, inserted) in done.into_iter().rev() {
+            let Some(index) = self.indexes.get_mut(&change.index) else {
+                continue;
+            };
+            let res = match (inserted, &change.old, &change.new) {
+                (true, _, Some(value)) => index.remove(value.clone(), change.key).map(|_| ()),
+                (false, Some(value), _) => index.insert(value.clone(), change.key),
+                _ => Ok(()),
+            };
+            if let Err(err) = res {
+                tracing::warn!(index = %change.index, %err, "Failed to undo an index change");
+            }
+        }
+    }
+
+    /// Inserts a new document, with a key chosen by the collection's
+    /// [InsertMode].
+    ///
+    /// With [InsertMode::ClientKeys] the caller must supply the key and
+    /// with [InsertMode::ServerKeys] it must not. Server keys are minted
+    /// `ObjectId`s, so they can only be used with [KeyKind::ObjectId].
+    ///
+    /// # Returns
+    ///
+    /// The document's key.
+    pub async fn insert(&mut self, key: Option<ObjectId>, doc: Document) -> Result<ObjectId> {
+        let key = match (self.insert_mode, key) {
+            (InsertMode::ClientKeys, Some(key)) => key,
+            (InsertMode::ServerKeys, None) if self.key_kind == KeyKind::ObjectId => {
+                self.key_minter.mint()
+            }
+            (InsertMode::ServerKeys, None) => {
+                return Err(anyhow!(
+                    "Server keys can't be minted for {:?} keys",
+                    self.key_kind
+                ))
+            }
+            (InsertMode::ClientKeys, None) => {
+                return Err(anyhow!("A key is required to insert into this collection"))
+            }
+            (InsertMode::ServerKeys, Some(_)) => {
+                return Err(anyhow!(
+                    "Keys in this collection are assigned by the server"
+                ))
+            }
+        };
+        self.set(&key, doc).await?;
+        Ok(key)
+    }
+
+    /// Sets a document that expires after `ttl`.
+    ///
+    /// The expiry time is stored in the document's reserved
+    /// [ttl::EXPIRES_AT_FIELD], overwriting any value already there.
+    pub async fn set_with_ttl(
+        &mut self,
+        key: &ObjectId,
+        mut doc: Document,
+        ttl: std::time::Duration,
+    ) -> Result<()> {
+        ttl::set_expiry(&mut doc, ttl::expiry_after(DateTime::now(), ttl));
+        self.set(key, doc).await
+    }
+
+    /// Refreshes a document's TTL, so it expires `ttl` from now,
+    /// without changing the rest of the document.
+    ///
+    /// Documents that are missing or have already expired are left
+    /// as they are, rather than being brought back.
+    ///
+    /// # Returns
+    ///
+    /// Whether the document existed (and hadn't expired).
+    pub async fn touch(&mut self, key: &ObjectId, ttl: std::time::Duration) -> Result<bool> {
+        let Some(mut doc) = self.get(key).await? else {
+            return Ok(false);
+        };
+        ttl::set_expiry(&mut doc, ttl::expiry_after(DateTime::now(), ttl));
+        self.set(key, doc).await?;
+        Ok(true)
+    }
+
+    /// Updates a document, setting each of the fields in `changes` and
+    /// leaving its other fields as they are.
+    ///
+    /// Like [Collection::set], the document's old values are removed
+    /// from the indexes (once the update is written).
+    ///
+    /// # Returns
+    ///
+    /// Whether the document existed (and hadn't expired). Missing
+    /// documents aren't created.
+    pub async fn update(&mut self, key: &ObjectId, changes: Document) -> Result<bool> {
+        let Some(mut doc) = self.get(key).await? else {
+            return Ok(false);
+        };
+        doc.extend(changes);
+        self.set(key, doc).await?;
+        Ok(true)
+    }
+
+    /// Sets a document only if the current one matches `expected`
+    /// (or, if `expected` is `None`, only if there isn't one).
+    ///
+    /// Returns whether the document was swapped. A failed comparison
+    /// isn't an error, so callers can retry a read-modify-write loop,
+    /// and doesn't count against the rate limit.
+    pub async fn compare_and_swap(
+        &mut self,
+        key: &ObjectId,
+        expected: Option<&Document>,
+        doc: Document,
+    ) -> Result<bool> {
+        if self.tree.get(key).await?.as_ref() != expected {
+            return Ok(false);
+        }
+
+        // Only a swap that writes counts against the rate limit...
+        if let Some(rl) = &self.rate_limiter {
+            rl.check_write(bson::to_vec(&doc)?.len())?;
+        }
+        self.write_records(vec![Record {
+            key: *key,
+            value: Value::Data(doc),
+        }])
+        .await?;
+        Ok(true)
+    }
+
+    /// Applies a batch of writes.
+    ///
+    /// Batches larger than the collection's `max_batch_size` are either
+    /// rejected or split into chunks, depending on its [BatchConfig].
+    /// Each chunk is atomic -- it's checked against the rate limiter (and
+    /// the distinct indexes) as a whole, logged to the WAL as a single
+    /// entry, and never split across memtable flushes -- but if a later
+    /// chunk fails, the earlier chunks stay applied.
+    ///
+    /// # Returns
+    ///
+    /// The number of chunks the batch was applied in.
+    pub async fn apply_batch(&mut self, ops: Vec<BatchOp>) -> Result<usize> {
+        let max = self.batch_config.max_batch_size.max(1);
+        if ops.len() > max && self.batch_config.oversized == OversizedBatch::Reject {
+            return Err(anyhow!(
+                "Batch of {} operations is over the limit of {}",
+                ops.len(),
+                max
+            ));
+        }
+
+        let mut n_chunks = 0;
+        for chunk in ops.chunks(max) {
+            // Check the whole chunk against the rate limiter first...
+            if let Some(rl) = &self.rate_limiter {
+                let mut n_bytes = 0;
+                for op in chunk {
+                    if let BatchOp::Set(_, doc) = op {
+                        n_bytes += bson::to_vec(doc)?.len();
+                    }
+                }
+                rl.check_batch(chunk.len(), n_bytes)?;
+            }
+
+            // Apply it...
+            let records = chunk
+                .iter()
+                .map(|op| match op {
+                    BatchOp::Set(key, doc) => Record {
+                        key: *key,
+                        value: Value::Data(doc.clone()),
+                    },
+                    BatchOp::Del(key) => Record {
+                        key: *key,
+                        value: Value::Tombstone,
+                    },
+                })
+                .collect();
+            self.write_records(records).await?;
+
+            // Then flush, if needed, between chunks...
+            if self.tree.memtable.is_full() {
+                self.tree.compaction_cycle().await?;
+            }
+            n_chunks += 1;
+        }
+        Ok(n_chunks)
+    }
+
+    /// Sets a document and flushes the memtable to disk before returning.
+    ///
+    /// This is slower than [Collection::set] but, once it returns,
+    /// the document is guaranteed to be in an on-disk SSTable.
+    pub async fn set_durable(&mut self, key: &ObjectId, doc: Document) -> Result<()> {
+        self.set(key, doc).await?;
+        self.tree.compact_memtable(true).await
+    }
+
+    /// Applies a batch of writes (see [Collection::apply_batch]) and
+    /// flushes the memtable to disk before returning, like
+    /// [Collection::set_durable].
+    pub async fn apply_batch_durable(&mut self, ops: Vec<BatchOp>) -> Result<usize> {
+        let n_chunks = self.apply_batch(ops).await?;
+        self.tree.compact_memtable(true).await?;
+        Ok(n_chunks)
+    }
+
+    /// Returns all of the collection's documents, sorted by key.
+    ///
+    /// The documents are read from a snapshot, so the backup is
+    /// consistent even if the collection is written to meanwhile.
+    pub async fn backup(&self) -> Result<Vec<(ObjectId, Document)>> {
+        let snap = self.tree.snapshot();
+        let start = ObjectId::from_bytes([0; 12]);
+        let end = ObjectId::from_bytes([0xff; 12]);
+        self.tree.snapshot_range(&snap, &start, &end).await
+    }
+
+    /// Writes a batch of documents, flushing the memtable to disk
+    /// whenever it fills up.
+    ///
+    /// The documents are loaded a memtable's worth at a time (see
+    /// [LSMTree::write_batch]), rather than set one at a time.
+    pub async fn bulk_load(&mut self, docs: Vec<(ObjectId, Document)>) -> Result<()> {
+        let mut docs = docs.into_iter().peekable();
+        while docs.peek().is_some() {
+            // Fill whatever room is left in the memtable...
+            let room = self
+                .tree
+                .memtable
+                .max_records
+                .saturating_sub(self.tree.memtable.size());
+            let mut records = vec![];
+            for (key, doc) in docs.by_ref().take(room.max(1)) {
+                if let Some(rl) = &self.rate_limiter {
+                    rl.check_write(bson::to_vec(&doc)?.len())?;
+                }
+                records.push(Record {
+                    key,
+                    value: Value::Data(doc),
+                });
+            }
+            self.write_records(records).await?;
+            if self.tree.memtable.is_full() {
+                self.tree.compaction_cycle().await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Creates an index on the documents' `key_field`, backfilling it
+    /// from the collection's current documents.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the index
+    /// * `key_field` - The document field to index
+    /// * `distinct` - Whether the field's values must be unique
+    ///
+    /// Returns an error if an index named `name` already exists, or if
+    /// the index is distinct and the documents have duplicate values.
+    pub async fn create_index(
+        &mut self,
+        name: &str,
+        key_field: &str,
+        distinct: bool,
+    ) -> Result<()> {
+        if self.indexes.contains_key(name) {
+            return Err(anyhow!("Index {:?} already exists", name));
+        }
+
+        // Create the index...
+        let dir = std::path::Path::new(&self.tree.path).join(INDEX_DIR);
+        tokio::fs::create_dir_all(&dir).await?;
+        let index = BPTree::new(&dir.to_string_lossy(), name, key_field, distinct)?;
+        let index_dir = index.dir_path.clone();
+        self.indexes.insert(name.to_string(), index);
+
+        // Fill it in, removing it again if that fails...
+        if let Err(err) = self.rebuild_index(name).await {
+            self.indexes.remove(name);
+            tokio::fs::remove_dir_all(&index_dir).await?;
+            return Err(err);
+        }
+
+        // Then record it, so it's reloaded with the collection...
+        self.write_meta().await
+    }
+
+    /// Gets the documents whose value in the named index is `value`.
+    ///
+    /// Returns an error if the index doesn't exist.
+    pub async fn find_by(&self, index_name: &str, value: Bson) -> Result<Vec<Document>> {
+        let index = self
+            .indexes
+            .get(index_name)
+            .ok_or(anyhow!("Index {:?} not found", index_name))?;
+        let ids = index.get_all(value)?;
+        Ok(self.get_many(&ids).await?.into_iter().flatten().collect())
+    }
+
+    /// Rebuilds the named index from the collection's current documents.
+    pub async fn rebuild_index(&mut self, name: &str) -> Result<()> {
+        let docs = self.backup().await?;
+        let index = self
+            .indexes
+            .get_mut(name)
+            .ok_or(anyhow!("Index {:?} not found", name))?;
+        index.clear()?;
+        for (key, doc) in docs {
+            if let Some(value) = doc.get(&index.meta.key) {
+                index.insert(value.clone(), key)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the total size of the collection's files on disk.
+    async fn disk_size(&self) -> Result<u64> {
+        let mut size = dir_size(&self.tree.path).await?;
+        for index in self.indexes.values() {
+            if !std::path::Path::new(&index.dir_path).starts_with(&self.tree.path) {
+                size += dir_size(&index.dir_path).await?;
+            }
+        }
+        Ok(size)
+    }
+
+    /// Rewrites the collection to its minimal on-disk footprint.
+    ///
+    /// This fully compacts the collection's data (dropping tombstones
+    /// and shadowed versions), rebuilds its indexes from the result,
+    /// and removes any orphaned files.
+    pub async fn vacuum(&mut self) -> Result<VacuumReport> {
+        let bytes_before = self.disk_size().await?;
+        self.tree.major_compact().await?;
+        let names: Vec<_> = self.indexes.keys().cloned().collect();
+        for name in names {
+            self.rebuild_index(&name).await?;
+        }
+        self.tree.gc_orphans().await?;
+        Ok(VacuumReport {
+            bytes_before,
+            bytes_after: self.disk_size().await?,
+        })
+    }
+
+    /// Checks the collection for corruption, without changing anything.
+    ///
+    /// Every SSTable is read back from disk. If `check_indexes` is set,
+    /// each secondary index is also cross-checked against the documents:
+    /// every live document's indexed value should be in the index (under
+    /// the document's key) and every index entry should point to a live
+    /// document with that value.
+    pub async fn verify_integrity(&self, check_indexes: bool) -> Result<IntegrityReport> {
+        let mut report = IntegrityReport::default();
+
+        // Check that the tables can be read...
+        for level in self.tree.levels.iter() {
+            for table in level.tables.iter() {
+                if let Err(err) = table.read().await {
+                    report
+                        .bad_tables
+                        .push((table.meta.table_id, err.to_string()));
+                }
+            }
+        }
+        if !check_indexes || self.indexes.is_empty() {
+            return Ok(report);
+        }
+
+        // Cross-check the indexes against the documents...
+        let docs: HashMap<_, _> = self.backup().await?.into_iter().collect();
+        for (name, index) in self.indexes.iter() {
+            let field = &index.meta.key;
+            let entries = index.entries()?;
+            let mut mismatches = vec![];
+
+            // Every document with the field should be indexed...
+            for (key, doc) in docs.iter() {
+                let Some(value) = doc.get(field) else {
+                    continue;
+                };
+                let indexed = entries
+                    .iter()
+                    .any(|(v, k)| k == key && cmp_bson(v, value).is_eq());
+                if !indexed {
+                    mismatches.push(IndexMismatch::Missing {
+                        key: *key,
+                        value: value.clone(),
+                    });
+                }
+            }
+
+            // ...and every entry should point to a matching document...
+            for (value, key) in entries {
+                let matches = docs
+                    .get(&key)
+                    .and_then(|doc| doc.get(field))
+                    .is_some_and(|v| cmp_bson(v, &value).is_eq());
+                if !matches {
+                    mismatches.push(IndexMismatch::Stale { key, value });
+                }
+            }
+
+            if !mismatches.is_empty() {
+                report.index_mismatches.insert(name.clone(), mismatches);
+            }
+        }
+        Ok(report)
+    }
+
+    /// Finds the documents whose `field` is in the range from `from`
+    /// to `to` (inclusive), using an index on the field.
+    ///
+    /// Values are compared with the index's total ordering over `Bson`
+    /// (see [crate::index::order::cmp_bson]) and the documents are
+    /// returned in that order. Returns an error if the field isn't
+    /// indexed, rather than falling back to a full scan.
+    pub async fn find_range_by(&self, field: &str, from: Bson, to: Bson) -> Result<Vec<Document>> {
+        let index = self
+            .indexes
+            .values()
+            .find(|index| index.meta.key == field)
+            .ok_or(anyhow!("No index on field {:?}", field))?;
+        let mut docs = vec![];
+        for id in index.scan(from, to)? {
+            if let Some(doc) = self.get(&id).await? {
+                docs.push(doc);
+            }
+        }
+        Ok(docs)
+    }
+
+    /// Gets the documents with each of the given keys, aligned with
+    /// `keys` (`None` for missing keys).
+    ///
+    /// Each distinct key is fetched once, in key order, so neighboring
+    /// keys are likely to be read from the same table.
+    pub async fn get_many(&self, keys: &[ObjectId]) -> Result<Vec<Option<Document>>> {
+        let mut sorted = keys.to_vec();
+        sorted.sort();
+        sorted.dedup();
+        let mut found = HashMap::new();
+        for key in sorted {
+            if let Some(doc) = self.get(&key).await? {
+                found.insert(key, doc);
+            }
+        }
+        Ok(keys.iter().map(|key| found.get(key).cloned()).collect())
+    }
+
+    /// Gets the documents whose indexed `field` equals each of `values`,
+    /// aligned with `values`.
+    ///
+    /// The index lookups are batched (see [BPTree::get_many]), as are
+    /// the document fetches (see [Collection::get_many]). Returns an
+    /// error if the field isn't indexed.
+    pub async fn multi_get_by(&self, field: &str, values: &[Bson]) -> Result<Vec<Vec<Document>>> {
+        let index = self
+            .indexes
+            .values()
+            .find(|index| index.meta.key == field)
+            .ok_or(anyhow!("No index on field {:?}", field))?;
+        let ids = index.get_many(values)?;
+        let keys: Vec<_> = ids.iter().flatten().copied().collect();
+        let mut docs = self.get_many(&keys).await?.into_iter();
+        Ok(ids
+            .iter()
+            .map(|value_ids| {
+                docs.by_ref()
+                    .take(value_ids.len())
+                    .flatten()
+                    .collect::<Vec<_>>()
+            })
+            .collect())
+    }
+
+    /// Returns how a query would be executed (see [planner::plan]).
+    pub fn explain(&self, query: &Query) -> Result<Plan> {
+        planner::plan(query, &self.indexes, self.approx_count())
+    }
+
+    /// Runs a query, returning the matching documents.
+    ///
+    /// Index scans return documents in the index's value order, while
+    /// full scans return them in key order.
+    pub async fn query(&self, query: &Query) -> Result<Vec<Document>> {
+        match self.explain(query)? {
+            Plan::IndexScan { index } => {
+                let index = self
+                    .indexes
+                    .get(&index)
+                    .ok_or(anyhow!("Index {:?} not found", index))?;
+                let (from, to) = query.filter.bounds();
+                let mut docs = vec![];
+                for id in index.scan(from, to)? {
+                    if let Some(doc) = self.get(&id).await? {
+                        docs.push(doc);
+                    }
+                }
+                Ok(docs)
+            }
+            Plan::FullScan => Ok(self
+                .backup()
+                .await?
+                .into_iter()
+                .map(|(_, doc)| doc)
+                .filter(|doc| query.filter.matches(doc))
+                .collect()),
+        }
+    }
+
+    /// Estimates the number of documents in the collection, from the
+    /// number of records in its memtable and tables.
+    ///
+    /// This over-counts keys written more than once (or deleted), but
+    /// doesn't need to read anything from disk. See
+    /// [LSMTree::approx_count].
+    pub fn approx_count(&self) -> usize {
+        self.tree.approx_count()
+    }
+
+    /// Returns the size of each of the collection's levels (see
+    /// [LSMTree::level_stats]).
+    pub async fn level_stats(&self) -> Result<Vec<LevelStat>> {
+        self.tree.level_stats().await
+    }
+
+    /// Gets a document by its (non-`ObjectId`) primary key.
+    ///
+    /// Returns an error if the key can't be encoded for the collection's
+    /// [KeyKind] (see [KeyKind::encode]).
+    pub async fn get_keyed(&self, key: &Key) -> Result<Option<Document>> {
+        let doc = self.get(&self.key_kind.encode(key)?).await?;
+        Ok(match (self.key_kind, key) {
+            // Skip a document under another string with the same hash...
+            (KeyKind::Hashed, Key::String(s)) => {
+                doc.filter(|doc| doc.get_str(key::KEY_FIELD) == Ok(s.as_str()))
+            }
+            _ => doc,
+        })
+    }
+
+    /// Gets all documents with primary keys in the given range
+    /// (inclusive), sorted by key.
+    ///
+    /// Note: [KeyKind::Hashed] keys are sorted by their hash, so the
+    /// range is of hashes rather than strings.
+    pub async fn get_range_keyed(&self, start: &Key, end: &Key) -> Result<Vec<(Key, Document)>> {
+        let start = self.key_kind.encode(start)?;
+        let end = self.key_kind.encode(end)?;
+        let snap = self.tree.snapshot();
+        self.tree
+            .snapshot_range(&snap, &start, &end)
+            .await?
+            .into_iter()
+            .map(|(k, doc)| match self.key_kind {
+                KeyKind::Hashed => Ok((Key::String(doc.get_str(key::KEY_FIELD)?.into()), doc)),
+                kind => Ok((kind.decode(&k)?, doc)),
+            })
+            .collect()
+    }
+
+    /// Sets a document by its (non-`ObjectId`) primary key.
+    ///
+    /// Returns an error if the key can't be encoded for the collection's
+    /// [KeyKind] (e.g. a [KeyKind::String] key longer than 12 bytes).
+    ///
+    /// With [KeyKind::Hashed] keys, the string is stored in the
+    /// document's reserved [key::KEY_FIELD] (overwriting any value
+    /// already there), and it's an error if a different string's
+    /// document is already stored under the same hashed key.
+    pub async fn set_keyed(&mut self, key: &Key, mut doc: Document) -> Result<()> {
+        let oid = self.key_kind.encode(key)?;
+        if let (KeyKind::Hashed, Key::String(s)) = (self.key_kind, key) {
+            self.check_hash_collision(&oid, s).await?;
+            doc.insert(key::KEY_FIELD, s.as_str());
+        }
+        self.set(&oid, doc).await
+    }
+
+    /// Deletes a document by its (non-`ObjectId`) primary key.
+    ///
+    /// Like [Collection::set_keyed], it's an error if a [KeyKind::Hashed]
+    /// key's hash is taken by a different string's document.
+    pub async fn del_keyed(&mut self, key: &Key) -> Result<()> {
+        let oid = self.key_kind.encode(key)?;
+        if let (KeyKind::Hashed, Key::String(s)) = (self.key_kind, key) {
+            self.check_hash_collision(&oid, s).await?;
+        }
+        self.del(&oid).await
+    }
+
+    /// Returns an error if the document under the hashed key `oid` was
+    /// set by a string other than `key_str`.
+    async fn check_hash_collision(&self, oid: &ObjectId, key_str: &str) -> Result<()> {
+        if let Some(existing) = self.tree.get(oid).await? {
+            if let Ok(other) = existing.get_str(key::KEY_FIELD) {
+                if other != key_str {
+                    return Err(anyhow!(
+                        "Key {:?} collides with existing key {:?}",
+                        key_str,
+                        other
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets a document under a string key of any length, in a
+    /// [KeyKind::Hashed] collection (see [Collection::set_keyed]).
+    ///
+    /// Returns an error if the collection uses another kind of key, so
+    /// hashed keys are never mixed in with other keys.
+    pub async fn set_with_key(&mut self, key_str: &str, doc: Document) -> Result<()> {
+        self.expect_hashed_keys()?;
+        self.set_keyed(&Key::String(key_str.into()), doc).await
+    }
+
+    /// Gets a document set with [Collection::set_with_key].
+    ///
+    /// Returns an error if the collection doesn't use [KeyKind::Hashed]
+    /// keys.
+    pub async fn get_by_key(&self, key_str: &str) -> Result<Option<Document>> {
+        self.expect_hashed_keys()?;
+        self.get_keyed(&Key::String(key_str.into())).await
+    }
+
+    /// Returns an error if the collection doesn't use [KeyKind::Hashed]
+    /// keys.
+    fn expect_hashed_keys(&self) -> Result<()> {
+        if self.key_kind != KeyKind::Hashed {
+            return Err(anyhow!(
+                "Collection uses {:?} keys, not hashed string keys",
+                self.key_kind
+            ));
+        }
+        Ok(())
+    }
+
+    /// Gets the documents whose keys start with `prefix` (e.g. a
+    /// tenant's documents, see [key::prefixed_key]), sorted by key.
+    ///
+    /// Like [Collection::get_range], deleted and expired documents are
+    /// skipped.
+    pub async fn scan_prefix(&self, prefix: &[u8], limit: Option<usize>) -> Result<Vec<Document>> {
+        let (start, end) = key::prefix_range(prefix)?;
+        self.get_range(&start, &end, limit).await
+    }
+
+    /// Returns the documents whose keys start with `prefix`, with their
+    /// keys, sorted by key.
+    ///
+    /// Like [Collection::backup], the documents are read from a snapshot.
+    pub async fn export_prefix(&self, prefix: &[u8]) -> Result<Vec<(ObjectId, Document)>> {
+        let (start, end) = key::prefix_range(prefix)?;
+        let snap = self.tree.snapshot();
+        self.tree.snapshot_range(&snap, &start, &end).await
+    }
+
+    /// Deletes all of the documents whose keys start with `prefix`,
+    /// removing them from the collection's indexes.
+    ///
+    /// # Returns
+    ///
+    /// The number of documents deleted.
+    pub async fn delete_prefix(&mut self, prefix: &[u8]) -> Result<usize> {
+        let docs = self.export_prefix(prefix).await?;
+        for (key, _) in docs.iter() {
+            self.del(key).await?;
+        }
+        Ok(docs.len())
+    }
+
+    /// Deletes a document, removing it from the collection's indexes.
     pub async fn del(&mut self, key: &ObjectId) -> Result<()> {
-        self.tree.del(key);
+        self.write_records(vec![Record {
+            key: *key,
+            value: Value::Tombstone,
+        }])
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::planner::Filter;
+    use bson::doc;
+
+    #[tokio::test]
+    async fn set_durable_is_on_disk() -> Result<()> {
+        // Create a collection in a fresh directory...
+        let path = format!("/tmp/{}", ObjectId::new());
+        let mut coll = Collection::new("test", &path);
+
+        // Set a document durably...
+        let key = ObjectId::new();
+        coll.set_durable(&key, doc! { "msg": "hello" }).await?;
+
+        // Check that it's readable from disk alone...
+        let res = coll.tree.get_from_disk_only(&key).await?;
+        assert_eq!(res, Some(doc! { "msg": "hello" }));
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn server_keys_strictly_increase() -> Result<()> {
+        let mut coll = Collection::new("test", "/tmp")
+            .with_durability(Durability::InMemory)
+            .with_insert_mode(InsertMode::ServerKeys);
+
+        // Rapid inserts (many in the same second) get increasing keys...
+        let mut keys = vec![];
+        for i in 0..1000 {
+            keys.push(coll.insert(None, doc! { "i": i }).await?);
+        }
+        for w in keys.windows(2) {
+            assert!(w[0] < w[1], "Expected keys to strictly increase");
+        }
+        assert_eq!(coll.get(&keys[42]).await?, Some(doc! { "i": 42 }));
+
+        // Clients can't pick their own keys in this mode...
+        assert!(coll.insert(Some(ObjectId::new()), doc! {}).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn touch_extends_ttl() -> Result<()> {
+        let mut coll = Collection::new("test", "/tmp").with_durability(Durability::InMemory);
+        let key = ObjectId::new();
+        let ms = std::time::Duration::from_millis;
+        let hours = |n: u64| std::time::Duration::from_secs(n * 60 * 60);
+
+        // Set a document with a TTL, then touch it with a longer one...
+        coll.set_with_ttl(&key, doc! { "msg": "hello" }, hours(1))
+            .await?;
+        let doc = coll.get(&key).await?.unwrap();
+        let original = ttl::expires_at(&doc).unwrap();
+        assert!(coll.touch(&key, hours(10)).await?);
+
+        // It should outlive its original expiry (checked against the
+        // stored expiry, rather than by waiting for it)...
+        let doc = coll.get(&key).await?.unwrap();
+        assert_eq!(doc.get_str("msg")?, "hello");
+        assert!(!ttl::is_expired(&doc, original));
+        let later = ttl::expiry_after(original, hours(8));
+        assert!(!ttl::is_expired(&doc, later));
+
+        // Expired documents can't be touched back to life...
+        let other = ObjectId::new();
+        coll.set_with_ttl(&other, doc! { "msg": "bye" }, ms(0))
+            .await?;
+        assert!(!coll.touch(&other, ms(1000)).await?);
+        assert_eq!(coll.get(&other).await?, None);
+        assert!(!coll.touch(&ObjectId::new(), ms(1000)).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_range_skips_deleted_and_limits() -> Result<()> {
+        let mut coll = Collection::new("test", "/tmp").with_durability(Durability::InMemory);
+        let keys: Vec<_> = (0..5).map(|_| ObjectId::new()).collect();
+        for (i, key) in keys.iter().enumerate() {
+            coll.set(key, doc! { "n": i as i32 }).await?;
+        }
+        coll.del(&keys[2]).await?;
+        let ns = |docs: Vec<Document>| -> Vec<i32> {
+            docs.iter().filter_map(|d| d.get_i32("n").ok()).collect()
+        };
+
+        // The deleted document should be left out, in key order...
+        let docs = coll.get_range(&keys[0], &keys[4], None).await?;
+        assert_eq!(ns(docs), vec![0, 1, 3, 4]);
+
+        // And the limit should cap the page...
+        let docs = coll.get_range(&keys[1], &keys[4], Some(2)).await?;
+        assert_eq!(ns(docs), vec![1, 3]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn compare_and_swap_race() -> Result<()> {
+        // Create a shared collection with a counter...
+        let path = format!("/tmp/{}", ObjectId::new());
+        let coll = Arc::new(tokio::sync::Mutex::new(Collection::new("test", &path)));
+        let key = ObjectId::new();
+        coll.lock().await.set(&key, doc! { "n": 0 }).await?;
+
+        // Two clients read the counter, then race to increment it...
+        let seen = coll.lock().await.get(&key).await?.unwrap();
+        let mut tasks = vec![];
+        for _ in 0..2 {
+            let coll = coll.clone();
+            let seen = seen.clone();
+            tasks.push(tokio::spawn(async move {
+                let next = doc! { "n": seen.get_i32("n")? + 1 };
+                coll.lock()
+                    .await
+                    .compare_and_swap(&key, Some(&seen), next)
+                    .await
+            }));
+        }
+        let mut swapped = 0;
+        for t in tasks {
+            if t.await?? {
+                swapped += 1;
+            }
+        }
+
+        // Exactly one of them should win...
+        assert_eq!(swapped, 1);
+        assert_eq!(coll.lock().await.get(&key).await?, Some(doc! { "n": 1 }));
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failed_compare_and_swaps_are_not_rate_limited() -> Result<()> {
+        use crate::db::ratelimit::{RateLimitError, RateUnit, WriteRateLimit};
+
+        // Create a collection that allows two writes...
+        let path = format!("/tmp/{}", ObjectId::new());
+        let mut coll = Collection::new("test", &path);
+        coll.rate_limiter = Some(Arc::new(RateLimiter::new(WriteRateLimit {
+            unit: RateUnit::Ops,
+            rate: 0.01,
+            burst: 2.0,
+        })));
+        let key = ObjectId::new();
+        coll.set(&key, doc! { "n": 0 }).await?;
+
+        // Failed swaps shouldn't use up the rest of the limit...
+        for _ in 0..5 {
+            let stale = doc! { "n": -1 };
+            assert!(!coll.compare_and_swap(&key, Some(&stale), doc! { "n": 1 }).await?);
+        }
+        let cur = doc! { "n": 0 };
+        assert!(coll.compare_and_swap(&key, Some(&cur), doc! { "n": 1 }).await?);
+
+        // ...but successful ones should
+        let cur = doc! { "n": 1 };
+        let err = coll
+            .compare_and_swap(&key, Some(&cur), doc! { "n": 2 })
+            .await
+            .unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&RateLimitError::Exceeded));
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn query_with_index_hint() -> Result<()> {
+        // Create a collection with an index on "n"...
+        let path = format!("/tmp/{}", ObjectId::new());
+        tokio::fs::create_dir_all(&path).await?;
+        let mut coll = Collection::new("test", &path);
+        let mut index = BPTree::with_order(&path, "by_n", "n", false, 4)?;
+        for i in 0..20 {
+            let key = ObjectId::new();
+            coll.set(&key, doc! { "n": i }).await?;
+            index.insert(Bson::Int32(i), key)?;
+        }
+        coll.indexes.insert("by_n".to_string(), index);
+
+        // A wide range query is planned as a full scan...
+        let query = Query::new(Filter::Range {
+            field: "n".to_string(),
+            from: Bson::Int32(5),
+            to: Bson::Int32(9),
+        });
+        assert_eq!(coll.explain(&query)?, Plan::FullScan);
+        let scanned = coll.query(&query).await?;
+
+        // But a hint forces the index...
+        let hinted = query.clone().with_index_hint("by_n");
+        assert_eq!(
+            coll.explain(&hinted)?,
+            Plan::IndexScan {
+                index: "by_n".to_string()
+            }
+        );
+        let indexed = coll.query(&hinted).await?;
+
+        // Either way, the results are the same...
+        let exp: Vec<_> = (5..=9).map(|n| doc! { "n": n }).collect();
+        assert_eq!(scanned, exp);
+        assert_eq!(indexed, exp);
+
+        // Hints for missing indexes, or indexes on other fields, error...
+        assert!(coll
+            .explain(&query.clone().with_index_hint("nope"))
+            .is_err());
+        let other = Query::new(Filter::Eq {
+            field: "m".to_string(),
+            value: Bson::Int32(1),
+        })
+        .with_index_hint("by_n");
+        assert!(coll.query(&other).await.is_err());
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn find_range_by_index() -> Result<()> {
+        // Create a collection with an index on "n"...
+        let path = format!("/tmp/{}", ObjectId::new());
+        tokio::fs::create_dir_all(&path).await?;
+        let mut coll = Collection::new("test", &path);
+        let mut index = BPTree::with_order(&path, "by_n", "n", false, 4)?;
+        for i in 0..20 {
+            let key = ObjectId::new();
+            coll.set(&key, doc! { "n": i }).await?;
+            index.insert(Bson::Int32(i), key)?;
+        }
+        coll.indexes.insert("by_n".to_string(), index);
+
+        // Find a sub-range, including both boundaries...
+        let docs = coll
+            .find_range_by("n", Bson::Int32(5), Bson::Int32(9))
+            .await?;
+        let ns: Vec<_> = docs
+            .iter()
+            .map(|d| d.get_i32("n"))
+            .collect::<std::result::Result<_, _>>()?;
+        assert_eq!(ns, vec![5, 6, 7, 8, 9]);
+
+        // Mixed numeric types compare by value...
+        let docs = coll
+            .find_range_by("n", Bson::Double(17.5), Bson::Int64(100))
+            .await?;
+        assert_eq!(docs, vec![doc! { "n": 18 }, doc! { "n": 19 }]);
+
+        // Un-indexed fields are an error...
+        assert!(coll
+            .find_range_by("m", Bson::Int32(0), Bson::Int32(1))
+            .await
+            .is_err());
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn in_memory_writes_no_files() -> Result<()> {
+        let path = format!("/tmp/{}", ObjectId::new());
+        let mut coll = Collection::new("test", &path).with_durability(Durability::InMemory);
+
+        // Write well past the memtable's size, even asking for durability...
+        let keys: Vec<_> = (0..250).map(|_| ObjectId::new()).collect();
+        for (i, key) in keys.iter().enumerate() {
+            coll.set(key, doc! { "i": i as i32 }).await?;
+            coll.tree.compaction_cycle().await?;
+        }
+        coll.set_durable(&keys[0], doc! { "i": -1 }).await?;
+
+        // Nothing should have been written to disk...
+        assert!(!std::path::Path::new(&path).exists());
+        assert!(coll.tree.levels.is_empty());
+
+        // But everything is served from memory...
+        assert_eq!(coll.get(&keys[0]).await?, Some(doc! { "i": -1 }));
+        assert_eq!(coll.get(&keys[249]).await?, Some(doc! { "i": 249 }));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn in_memory_cache_evicts() -> Result<()> {
+        let mut coll = Collection::new("cache", "/tmp").with_eviction(EvictionCap::Entries(100));
+
+        // Fill the collection past its cap...
+        let keys: Vec<_> = (0..150).map(|_| ObjectId::new()).collect();
+        for (i, key) in keys.iter().enumerate() {
+            coll.set(key, doc! { "i": i as i32 }).await?;
+        }
+
+        // The oldest keys should have been evicted...
+        assert_eq!(coll.tree.memtable.size(), 100);
+        assert_eq!(coll.get(&keys[0]).await?, None);
+        assert_eq!(coll.get(&keys[49]).await?, None);
+        assert_eq!(coll.get(&keys[50]).await?, Some(doc! { "i": 50 }));
+        assert_eq!(coll.get(&keys[149]).await?, Some(doc! { "i": 149 }));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn apply_batch_limits() -> Result<()> {
+        let path = format!("/tmp/{}", ObjectId::new());
+        let mut coll = Collection::new("test", &path);
+        coll.batch_config = BatchConfig {
+            max_batch_size: 10,
+            oversized: OversizedBatch::Reject,
+        };
+        let keys: Vec<_> = (0..25).map(|_| ObjectId::new()).collect();
+        let sets = |ks: &[ObjectId]| -> Vec<_> {
+            ks.iter()
+                .map(|k| BatchOp::Set(*k, doc! { "k": k.to_hex() }))
+                .collect()
+        };
+
+        // A batch at the limit is fine...
+        assert_eq!(coll.apply_batch(sets(&keys[..10])).await?, 1);
+        assert!(coll.get(&keys[9]).await?.is_some());
+
+        // A bigger one is rejected, without applying any of it...
+        assert!(coll.apply_batch(sets(&keys[10..])).await.is_err());
+        assert!(coll.get(&keys[10]).await?.is_none());
+
+        // Unless it's configured to be chunked...
+        coll.batch_config.oversized = OversizedBatch::Chunk;
+        let mut ops = sets(&keys[10..]);
+        ops.push(BatchOp::Del(keys[0]));
+        assert_eq!(coll.apply_batch(ops).await?, 2);
+        assert!(coll.get(&keys[24]).await?.is_some());
+        assert!(coll.get(&keys[0]).await?.is_none());
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn apply_batch_chunks_are_atomic() -> Result<()> {
+        // Create a collection with a distinct index, split into chunks of 3...
+        let path = format!("/tmp/{}", ObjectId::new());
+        let mut coll = Collection::new("test", &path);
+        coll.batch_config.max_batch_size = 3;
+        coll.create_index("by_n", "n", true).await?;
+        let taken = ObjectId::new();
+        coll.set(&taken, doc! { "n": 100 }).await?;
+
+        // Fail part way through the second chunk...
+        let keys: Vec<_> = (0..6).map(|_| ObjectId::new()).collect();
+        let mut ops: Vec<_> = keys
+            .iter()
+            .enumerate()
+            .map(|(i, k)| BatchOp::Set(*k, doc! { "n": i as i32 }))
+            .collect();
+        ops[4] = BatchOp::Set(keys[4], doc! { "n": 100 });
+        assert!(coll.apply_batch(ops).await.is_err());
+
+        // The first chunk is applied, but none of the second...
+        for (i, key) in keys.iter().enumerate() {
+            let exp = (i < 3).then(|| doc! { "n": i as i32 });
+            assert_eq!(coll.get(key).await?, exp);
+        }
+        assert_eq!(coll.indexes["by_n"].get_all(Bson::Int32(3))?, vec![]);
+
+        // Even after reloading the collection from its WAL...
+        let loaded = Collection::load("test", &path).await?;
+        assert_eq!(loaded.get(&keys[2]).await?, Some(doc! { "n": 2 }));
+        assert_eq!(loaded.get(&keys[3]).await?, None);
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn string_keyed_range() -> Result<()> {
+        let path = format!("/tmp/{}", ObjectId::new());
+        let mut coll = Collection::new("fruit", &path).with_key_kind(KeyKind::String);
+
+        // Write some string-keyed documents...
+        for name in ["cherry", "apple", "banana", "apricot", "date"] {
+            coll.set_keyed(&Key::String(name.into()), doc! { "name": name })
+                .await?;
+        }
+        coll.del_keyed(&Key::String("date".into())).await?;
+
+        // Check a point read...
+        let res = coll.get_keyed(&Key::String("banana".into())).await?;
+        assert_eq!(res, Some(doc! { "name": "banana" }));
+
+        // Range scan and check the (lexicographic) order...
+        let res = coll
+            .get_range_keyed(&Key::String("ap".into()), &Key::String("c".into()))
+            .await?;
+        let names: Vec<_> = res.iter().map(|(k, _)| k.clone()).collect();
+        assert_eq!(
+            names,
+            vec![
+                Key::String("apple".into()),
+                Key::String("apricot".into()),
+                Key::String("banana".into()),
+            ]
+        );
+
+        // An ObjectId key shouldn't work for a string-keyed collection...
+        assert!(coll
+            .get_keyed(&Key::ObjectId(ObjectId::new()))
+            .await
+            .is_err());
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn hashed_string_keys() -> Result<()> {
+        let mut coll = Collection::new("users", "/tmp")
+            .with_durability(Durability::InMemory)
+            .with_key_kind(KeyKind::Hashed);
+
+        // Write some documents under natural keys...
+        let names = ["alice@example.com", "bob@example.com", "carol@example.com"];
+        for name in names {
+            coll.set_with_key(name, doc! { "email": name }).await?;
+        }
+        coll.set_with_key("bob@example.com", doc! { "email": "bob", "v": 2 })
+            .await?;
+
+        // Point reads find them (with their key stored alongside)...
+        let res = coll.get_by_key("bob@example.com").await?;
+        assert_eq!(
+            res,
+            Some(doc! { "email": "bob", "v": 2, "_key": "bob@example.com" })
+        );
+        assert!(coll.get_by_key("dave@example.com").await?.is_none());
+
+        // A range read returns them in hash order, not string order...
+        let start = ObjectId::from_bytes([0; 12]);
+        let end = ObjectId::from_bytes([0xff; 12]);
+        let docs = coll.get_range(&start, &end, None).await?;
+        let got: Vec<_> = docs.iter().map(|d| d.get_str("_key").unwrap()).collect();
+        let mut want = names.to_vec();
+        want.sort_by_key(|name| key::key_from_str(name));
+        assert_eq!(got, want);
+
+        // A document under another string's hash isn't returned...
+        let key = key::key_from_str("erin@example.com");
+        coll.set(&key, doc! { "_key": "someone else" }).await?;
+        assert!(coll.get_by_key("erin@example.com").await?.is_none());
+        assert!(coll
+            .set_with_key("erin@example.com", doc! {})
+            .await
+            .is_err());
+        assert!(coll
+            .del_keyed(&Key::String("erin@example.com".into()))
+            .await
+            .is_err());
+
+        // Keyed range reads get the strings back from the documents...
+        let res = coll
+            .get_range_keyed(
+                &Key::String("bob@example.com".into()),
+                &Key::String("bob@example.com".into()),
+            )
+            .await?;
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].0, Key::String("bob@example.com".into()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn key_kind_limits() -> Result<()> {
+        let path = format!("/tmp/{}", ObjectId::new());
+        let mut coll = Collection::new("fruit", &path).with_key_kind(KeyKind::String);
+
+        // Hashed keys only work in a hashed-key collection...
+        assert!(coll.set_with_key("apple", doc! {}).await.is_err());
+        assert!(coll.get_by_key("apple").await.is_err());
+
+        // String keys are limited to 12 bytes...
+        let long = Key::String("much too long of a key".into());
+        let err = coll.set_keyed(&long, doc! {}).await.unwrap_err();
+        assert!(err.to_string().contains("longer than 12 bytes"));
+
+        // And the server can't mint ObjectIds for them...
+        coll.insert_mode = InsertMode::ServerKeys;
+        assert!(coll.insert(None, doc! {}).await.is_err());
+
+        // Their (meaningless) timestamps don't split tables...
+        assert!(!coll.tree.timestamped_keys);
+        coll.tree.max_table_span = Some(std::time::Duration::from_secs(1));
+        for names in [["apple", "banana"], ["cherry", "zucchini"]] {
+            for name in names {
+                coll.set_keyed(&Key::String(name.into()), doc! { "name": name })
+                    .await?;
+            }
+            coll.tree.compact_memtable(true).await?;
+        }
+        let ids: Vec<_> = coll.tree.levels[0]
+            .tables
+            .iter()
+            .map(|t| t.meta.table_id)
+            .collect();
+        assert_eq!(ids.len(), 2);
+        coll.tree.compact_tables(1, &ids, false).await?;
+        assert_eq!(coll.tree.levels[0].tables.len(), 1);
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn prefix_ops_scoped_to_tenant() -> Result<()> {
+        let mut coll = Collection::new("tenants", "/tmp").with_durability(Durability::InMemory);
+
+        // Write documents for two tenants...
+        for tenant in ["acme", "globex"] {
+            for i in 0..5 {
+                let key = key::prefixed_key(tenant.as_bytes(), &ObjectId::new())?;
+                coll.set(&key, doc! { "tenant": tenant, "i": i }).await?;
+            }
+        }
+        let tenants = |docs: &[Document]| -> Vec<String> {
+            docs.iter()
+                .map(|d| d.get_str("tenant").unwrap().to_string())
+                .collect()
+        };
+
+        // Scans and exports should only see the tenant's documents...
+        let docs = coll.scan_prefix(b"acme", None).await?;
+        assert_eq!(tenants(&docs), vec!["acme"; 5]);
+        assert_eq!(coll.scan_prefix(b"acme", Some(2)).await?.len(), 2);
+        let exported = coll.export_prefix(b"globex").await?;
+        assert!(exported
+            .iter()
+            .all(|(k, _)| k.bytes().starts_with(b"globex")));
+        let docs: Vec<_> = exported.into_iter().map(|(_, d)| d).collect();
+        assert_eq!(tenants(&docs), vec!["globex"; 5]);
+
+        // Deleting a tenant's data should leave the other's alone...
+        assert_eq!(coll.delete_prefix(b"acme").await?, 5);
+        assert!(coll.scan_prefix(b"acme", None).await?.is_empty());
+        assert_eq!(coll.scan_prefix(b"globex", None).await?.len(), 5);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn multi_get_by_aligns_with_values() -> Result<()> {
+        // Create a collection with a (small order) index on "name"...
+        let path = format!("/tmp/{}", ObjectId::new());
+        tokio::fs::create_dir_all(&path).await?;
+        let mut coll = Collection::new("test", &path);
+        let mut index = BPTree::with_order(&path, "by_name", "name", false, 4)?;
+
+        // Add some documents, a few per name...
+        let names = ["alice", "bob", "carol", "dave", "eve"];
+        for i in 0..20 {
+            let key = ObjectId::new();
+            let name = names[i % names.len()];
+            coll.set(&key, doc! { "name": name, "i": i as i32 }).await?;
+            index.insert(Bson::String(name.into()), key)?;
+        }
+        coll.indexes.insert("by_name".to_string(), index);
+
+        // Look up several names (out of order, with a repeat and a miss)...
+        let values: Vec<_> = ["eve", "alice", "nobody", "alice"]
+            .iter()
+            .map(|n| Bson::String(n.to_string()))
+            .collect();
+        let res = coll.multi_get_by("name", &values).await?;
+        assert_eq!(res.len(), values.len());
+        for (docs, value) in res.iter().zip(values.iter()) {
+            let expected = if value.as_str() == Some("nobody") {
+                0
+            } else {
+                4
+            };
+            assert_eq!(docs.len(), expected);
+            assert!(docs.iter().all(|d| d.get("name") == Some(value)));
+        }
+
+        // Unindexed fields are an error...
+        assert!(coll.multi_get_by("i", &values).await.is_err());
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn del_removes_from_indexes() -> Result<()> {
+        // Create a collection with a (small order) index on "num"...
+        let path = format!("/tmp/{}", ObjectId::new());
+        tokio::fs::create_dir_all(&path).await?;
+        let mut coll = Collection::new("test", &path);
+        let mut index = BPTree::with_order(&path, "by_num", "num", true, 4)?;
+
+        // Add some documents (and index them)...
+        let keys: Vec<_> = (0..30).map(|_| ObjectId::new()).collect();
+        for (i, key) in keys.iter().enumerate() {
+            coll.set(key, doc! { "num": i as i32 }).await?;
+            index.insert(Bson::Int32(i as i32), *key)?;
+        }
+        let n_nodes = index.meta.node_ids.len();
+        coll.indexes.insert("by_num".to_string(), index);
+
+        // Delete most of them...
+        for key in keys.iter().skip(5) {
+            coll.del(key).await?;
+        }
+
+        // Check the index shrank and only has the remaining documents...
+        let index = &coll.indexes["by_num"];
+        assert!(
+            index.meta.node_ids.len() < n_nodes,
+            "Expected nodes to merge"
+        );
+        for (i, key) in keys.iter().enumerate() {
+            let exp = if i < 5 { Some(*key) } else { None };
+            assert_eq!(index.get_one(Bson::Int32(i as i32))?, exp);
+        }
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_moves_index_entries() -> Result<()> {
+        // Create a collection with a (small order) index on "num"...
+        let path = format!("/tmp/{}", ObjectId::new());
+        tokio::fs::create_dir_all(&path).await?;
+        let mut coll = Collection::new("test", &path);
+        let index = BPTree::with_order(&path, "by_num", "num", true, 4)?;
+        coll.indexes.insert("by_num".to_string(), index);
+        let keys: Vec<_> = (0..30).map(|_| ObjectId::new()).collect();
+        for (i, key) in keys.iter().enumerate() {
+            coll.set(key, doc! { "num": i as i32, "name": "x" }).await?;
+        }
+
+        // Move most of them past the end of the index, emptying (and so
+        // merging) the nodes they were in...
+        for (i, key) in keys.iter().enumerate().skip(5) {
+            assert!(coll.update(key, doc! { "num": 100 + i as i32 }).await?);
+        }
+        assert!(!coll.update(&ObjectId::new(), doc! { "num": 1 }).await?);
+
+        // Each document's old value is gone and its new one is there...
+        let index = &coll.indexes["by_num"];
+        for (i, key) in keys.iter().enumerate() {
+            let (old, new) = (Bson::Int32(i as i32), Bson::Int32(100 + i as i32));
+            if i < 5 {
+                assert_eq!(index.get_one(old)?, Some(*key));
+            } else {
+                assert_eq!(index.get_one(old)?, None);
+                assert_eq!(index.get_one(new)?, Some(*key));
+            }
+        }
+        assert_eq!(index.entries()?.len(), keys.len());
+
+        // And the other fields are kept...
+        let doc = coll.get(&keys[10]).await?;
+        assert_eq!(doc, Some(doc! { "num": 110, "name": "x" }));
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn set_and_del_maintain_indexes() -> Result<()> {
+        // Create a collection with an (empty) index on "n"...
+        let path = format!("/tmp/{}", ObjectId::new());
+        tokio::fs::create_dir_all(&path).await?;
+        let mut coll = Collection::new("test", &path);
+        let index = BPTree::with_order(&path, "by_n", "n", false, 4)?;
+        coll.indexes.insert("by_n".to_string(), index);
+
+        // Setting documents indexes them (skipping ones without "n")...
+        let keys: Vec<_> = (0..10).map(|_| ObjectId::new()).collect();
+        for (i, key) in keys.iter().enumerate() {
+            coll.set(key, doc! { "n": i as i32 }).await?;
+        }
+        let other = ObjectId::new();
+        coll.set(&other, doc! { "m": 1 }).await?;
+        let found = coll
+            .find_range_by("n", Bson::Int32(0), Bson::Int32(9))
+            .await?;
+        assert_eq!(found.len(), 10);
+
+        // Updating a document moves it in the index...
+        coll.set(&keys[3], doc! { "n": 30 }).await?;
+        let index = &coll.indexes["by_n"];
+        assert!(index.get_all(Bson::Int32(3))?.is_empty());
+        assert_eq!(index.get_all(Bson::Int32(30))?, vec![keys[3]]);
+        let found = coll
+            .find_range_by("n", Bson::Int32(20), Bson::Int32(40))
+            .await?;
+        assert_eq!(found, vec![doc! { "n": 30 }]);
+
+        // Dropping the field removes it...
+        coll.set(&keys[4], doc! { "m": 4 }).await?;
+        assert!(coll.indexes["by_n"].get_all(Bson::Int32(4))?.is_empty());
+
+        // And so does deleting the document...
+        coll.del(&keys[5]).await?;
+        let found = coll
+            .find_range_by("n", Bson::Int32(0), Bson::Int32(9))
+            .await?;
+        assert_eq!(found.len(), 7);
+        assert!(coll.indexes["by_n"].get_all(Bson::Int32(5))?.is_empty());
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn every_write_maintains_indexes() -> Result<()> {
+        // Create a collection with an index on "n"...
+        let path = format!("/tmp/{}", ObjectId::new());
+        let mut coll = Collection::new("test", &path);
+        coll.create_index("by_n", "n", false).await?;
+        let keys: Vec<_> = (0..6).map(|_| ObjectId::new()).collect();
+        let ids = |coll: &Collection, n: i32| coll.indexes["by_n"].get_all(Bson::Int32(n));
+
+        // Bulk loads are indexed...
+        let docs = keys.iter().map(|k| (*k, doc! { "n": 1 })).collect();
+        coll.bulk_load(docs).await?;
+        assert_eq!(ids(&coll, 1)?, keys);
+
+        // As are batches...
+        let ops = vec![
+            BatchOp::Set(keys[0], doc! { "n": 2 }),
+            BatchOp::Del(keys[1]),
+            BatchOp::Set(keys[2], doc! { "n": 3 }),
+            BatchOp::Set(keys[2], doc! { "n": 2 }),
+        ];
+        coll.apply_batch(ops).await?;
+        assert_eq!(ids(&coll, 1)?, keys[3..]);
+        assert_eq!(ids(&coll, 2)?, vec![keys[0], keys[2]]);
+        assert!(ids(&coll, 3)?.is_empty());
+
+        // And compare-and-swaps (but only if they swap)...
+        let cur = doc! { "n": 1 };
+        assert!(
+            coll.compare_and_swap(&keys[3], Some(&cur), doc! { "n": 4 })
+                .await?
+        );
+        assert!(
+            !coll
+                .compare_and_swap(&keys[4], None, doc! { "n": 4 })
+                .await?
+        );
+        assert_eq!(ids(&coll, 4)?, vec![keys[3]]);
+        assert_eq!(ids(&coll, 1)?, keys[4..]);
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failed_index_updates_write_nothing() -> Result<()> {
+        // Create a collection with a distinct index and a regular one...
+        let path = format!("/tmp/{}", ObjectId::new());
+        let mut coll = Collection::new("test", &path);
+        coll.create_index("by_email", "email", true).await?;
+        coll.create_index("by_team", "team", false).await?;
+        let (a, b) = (ObjectId::new(), ObjectId::new());
+        coll.set(&a, doc! { "email": "a@example.com", "team": "red" })
+            .await?;
+
+        // A write a distinct index rejects isn't applied...
+        let taken = doc! { "email": "a@example.com", "team": "blue" };
+        assert!(coll.set(&b, taken).await.is_err());
+        assert_eq!(coll.get(&b).await?, None);
+        assert!(coll.indexes["by_team"].get_all("blue".into())?.is_empty());
+
+        // But documents can swap distinct values in one batch...
+        coll.set(&b, doc! { "email": "b@example.com" }).await?;
+        let ops = vec![
+            BatchOp::Set(a, doc! { "email": "b@example.com", "team": "red" }),
+            BatchOp::Set(b, doc! { "email": "a@example.com" }),
+        ];
+        coll.apply_batch(ops).await?;
+        let by_email = |coll: &Collection, e: &str| coll.indexes["by_email"].get_all(e.into());
+        assert_eq!(by_email(&coll, "a@example.com")?, vec![b]);
+        assert_eq!(by_email(&coll, "b@example.com")?, vec![a]);
+
+        // If an index can't be updated, the other index's changes are
+        // undone and the old document is put back...
+        let before = coll.get(&a).await?;
+        std::fs::remove_dir_all(&coll.indexes["by_team"].dir_path)?;
+        let moved = doc! { "email": "c@example.com", "team": "blue" };
+        assert!(coll.set(&a, moved).await.is_err());
+        assert_eq!(coll.get(&a).await?, before);
+        assert_eq!(by_email(&coll, "b@example.com")?, vec![a]);
+        assert!(by_email(&coll, "c@example.com")?.is_empty());
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn create_index_and_find_by() -> Result<()> {
+        // Add some documents to a collection...
+        let path = format!("/tmp/{}", ObjectId::new());
+        let mut coll = Collection::new("test", &path);
+        let keys: Vec<_> = (0..12).map(|_| ObjectId::new()).collect();
+        for (i, key) in keys.iter().enumerate() {
+            let color = ["red", "green", "blue"][i % 3];
+            coll.set(key, doc! { "color": color, "i": i as i32 })
+                .await?;
+        }
+
+        // Index them by color...
+        coll.create_index("by_color", "color", false).await?;
+        let index = &coll.indexes["by_color"];
+        assert!(std::path::Path::new(&index.dir_path).starts_with(&path));
+
+        // And look them up through the index...
+        let reds = coll.find_by("by_color", Bson::String("red".into())).await?;
+        assert_eq!(reds.len(), 4);
+        assert!(reds.iter().all(|d| d.get_str("color") == Ok("red")));
+        assert!(coll
+            .find_by("by_color", Bson::String("pink".into()))
+            .await?
+            .is_empty());
+        assert!(coll.find_by("by_size", Bson::Int32(1)).await.is_err());
+
+        // Names can't be reused...
+        assert!(coll.create_index("by_color", "i", true).await.is_err());
+
+        // And a distinct index on duplicate values isn't created...
+        assert!(coll
+            .create_index("by_color_2", "color", true)
+            .await
+            .is_err());
+        assert!(!coll.indexes.contains_key("by_color_2"));
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn lazy_indexes_load_on_first_use() -> Result<()> {
+        // Add some indexed documents to a collection...
+        let path = format!("/tmp/{}", ObjectId::new());
+        let mut coll = Collection::new("test", &path);
+        coll.create_index("by_color", "color", false).await?;
+        for i in 0..12 {
+            let color = ["red", "green", "blue"][i % 3];
+            coll.set(&ObjectId::new(), doc! { "color": color, "i": i as i32 })
+                .await?;
+        }
+
+        // Loading it eagerly reads the index's nodes...
+        let eager = Collection::load_with("test", &path, IndexLoading::Eager).await?;
+        assert!(eager.indexes["by_color"].is_loaded());
+        assert!(eager.indexes["by_color"].nodes_read() > 0);
+
+        // While loading it lazily doesn't touch them...
+        let lazy = Collection::load_with("test", &path, IndexLoading::Lazy).await?;
+        let index = &lazy.indexes["by_color"];
+        assert!(!index.is_loaded());
+        assert_eq!(index.nodes_read(), 0);
+
+        // Until the index is first queried...
+        let reds = lazy.find_by("by_color", Bson::String("red".into())).await?;
+        assert_eq!(reds.len(), 4);
+        assert!(lazy.indexes["by_color"].is_loaded());
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn verify_integrity_finds_index_drift() -> Result<()> {
+        // Create a collection with an index on "num"...
+        let path = format!("/tmp/{}", ObjectId::new());
+        tokio::fs::create_dir_all(&path).await?;
+        let mut coll = Collection::new("test", &path);
+        let mut index = BPTree::with_order(&path, "by_num", "num", false, 4)?;
+        let keys: Vec<_> = (0..10).map(|_| ObjectId::new()).collect();
+        for (i, key) in keys.iter().enumerate() {
+            coll.set(key, doc! { "num": i as i32 }).await?;
+            index.insert(Bson::Int32(i as i32), *key)?;
+        }
+        coll.tree.compact_memtable(true).await?;
+        coll.indexes.insert("by_num".to_string(), index);
+        assert!(coll.verify_integrity(true).await?.is_ok());
+
+        // Desync the index: drop one entry, point one at the wrong
+        // value, and add one for a document that doesn't exist...
+        let index = coll.indexes.get_mut("by_num").unwrap();
+        index.remove(Bson::Int32(3), keys[3])?;
+        index.remove(Bson::Int32(5), keys[5])?;
+        index.insert(Bson::Int32(50), keys[5])?;
+        let ghost = ObjectId::new();
+        index.insert(Bson::Int32(99), ghost)?;
+
+        // The mismatches are reported (only when asked for)...
+        assert!(coll.verify_integrity(false).await?.is_ok());
+        let report = coll.verify_integrity(true).await?;
+        assert!(report.bad_tables.is_empty());
+        let mut mismatches = report.index_mismatches["by_num"].clone();
+        mismatches.sort_by_key(|m| format!("{:?}", m));
+        let mut expected = vec![
+            IndexMismatch::Missing {
+                key: keys[3],
+                value: Bson::Int32(3),
+            },
+            IndexMismatch::Missing {
+                key: keys[5],
+                value: Bson::Int32(5),
+            },
+            IndexMismatch::Stale {
+                key: keys[5],
+                value: Bson::Int32(50),
+            },
+            IndexMismatch::Stale {
+                key: ghost,
+                value: Bson::Int32(99),
+            },
+        ];
+        expected.sort_by_key(|m| format!("{:?}", m));
+        assert_eq!(mismatches, expected);
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn vacuum_shrinks_collection() -> Result<()> {
+        // Create a collection with an index on "n"...
+        let path = format!("/tmp/{}", ObjectId::new());
+        tokio::fs::create_dir_all(&path).await?;
+        let mut coll = Collection::new("test", &path);
+        let mut index = BPTree::with_order(&path, "by_n", "n", false, 8)?;
+
+        // Churn through a few rounds of overwrites...
+        let keys: Vec<_> = (0..100).map(|_| ObjectId::new()).collect();
+        for round in 0..3 {
+            let docs = keys
+                .iter()
+                .enumerate()
+                .map(|(i, k)| {
+                    (
+                        *k,
+                        doc! { "n": round * 1000 + i as i32, "pad": "x".repeat(100) },
+                    )
+                })
+                .collect();
+            coll.bulk_load(docs).await?;
+        }
+        for (i, k) in keys.iter().enumerate() {
+            index.insert(Bson::Int32(i as i32), *k)?;
+        }
+        coll.indexes.insert("by_n".to_string(), index);
+
+        // Then delete half of the documents...
+        for k in keys.iter().skip(50) {
+            coll.del(k).await?;
+        }
+        coll.tree.compact_memtable(true).await?;
+
+        // Vacuum and check the size dropped to near the live data's...
+        let report = coll.vacuum().await?;
+        let live: u64 = coll
+            .backup()
+            .await?
+            .iter()
+            .map(|(_, doc)| bson::to_vec(doc).map(|b| b.len() as u64))
+            .sum::<std::result::Result<u64, _>>()?;
+        assert!(report.bytes_after < report.bytes_before / 2);
+        assert!(
+            report.bytes_after < live * 2 + 8192,
+            "Expected {} bytes on disk to be near the {} bytes of live data",
+            report.bytes_after,
+            live
+        );
+
+        // The live documents (and the index) should still be right...
+        let index = &coll.indexes["by_n"];
+        for (i, k) in keys.iter().enumerate() {
+            let n = 2000 + i as i32;
+            if i < 50 {
+                assert_eq!(coll.get(k).await?.unwrap().get_i32("n")?, n);
+                assert_eq!(index.get_one(Bson::Int32(n))?, Some(*k));
+            } else {
+                assert_eq!(coll.get(k).await?, None);
+                assert_eq!(index.get_one(Bson::Int32(n))?, None);
+            }
+            assert_eq!(index.get_one(Bson::Int32(i as i32))?, None);
+        }
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
         Ok(())
     }
 }
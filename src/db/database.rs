@@ -1,7 +1,18 @@
 use crate::db::collection::Collection;
-use anyhow::Result;
+use crate::db::ratelimit::{RateLimiter, WriteRateLimit};
+use crate::index::bptree::IndexLoading;
+use crate::storage::util::{read_bson, write_bson_atomic};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
 
+/// The name of a database's metadata file, stored in the database's
+/// directory alongside a directory for each of its collections.
+pub const DB_META_FILE: &str = "_db_meta.bson";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DBMeta {
     /// The name of the database.
     pub name: String,
@@ -18,6 +29,10 @@ pub struct Database {
 
     /// The collections in this database.
     pub collections: HashMap<String, Collection>,
+
+    /// An optional write rate limiter shared by all of the
+    /// database's collections.
+    pub rate_limiter: Option<Arc<RateLimiter>>,
 }
 
 impl Database {
@@ -29,11 +44,254 @@ impl Database {
                 path: path.to_string(),
             },
             collections: HashMap::new(),
+            rate_limiter: None,
+        }
+    }
+
+    /// Sets (or removes, if `None`) the database's write rate limit.
+    ///
+    /// The limit is global to the database, so a single limiter is
+    /// shared by all of its collections.
+    pub fn set_write_limit(&mut self, limit: Option<WriteRateLimit>) {
+        self.rate_limiter = limit.map(|l| Arc::new(RateLimiter::new(l)));
+        for coll in self.collections.values_mut() {
+            coll.rate_limiter = self.rate_limiter.clone();
         }
     }
 
     /// Load an existing database from disk.
-    pub fn load() -> Result<Self> {
-        todo!();
+    ///
+    /// The database's metadata is read from its [DB_META_FILE] and each
+    /// of the subdirectories of `path` is loaded as a collection (see
+    /// [Collection::load]).
+    pub async fn load(path: &str) -> Result<Self> {
+        Database::load_with(path, IndexLoading::default()).await
+    }
+
+    /// Load an existing database from disk (see [Database::load]), with
+    /// `index_loading` controlling whether its collections' indexes are
+    /// loaded on startup or when they're first used.
+    pub async fn load_with(path: &str, index_loading: IndexLoading) -> Result<Self> {
+        // Read the database's metadata...
+        let meta_path = Path::new(path).join(DB_META_FILE);
+        if !meta_path.exists() {
+            return Err(anyhow!("No database found at {:?}", path));
+        }
+        let meta: DBMeta = bson::from_slice(&read_bson(&meta_path).await?)?;
+        let mut db = Database::new(&meta.name, path);
+
+        // Load the collections (whose directories are named after them)...
+        let mut entries = tokio::fs::read_dir(path).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if !entry.metadata().await?.is_dir() {
+                continue;
+            }
+            let name = entry.file_name().to_string_lossy().to_string();
+            let coll_path = entry.path().to_string_lossy().to_string();
+            let coll = Collection::load_with(&name, &coll_path, index_loading).await?;
+            db.collections.insert(name, coll);
+        }
+        Ok(db)
+    }
+
+    /// Writes the database's metadata to its directory, creating the
+    /// directory if needed.
+    async fn write_meta(&self) -> Result<()> {
+        tokio::fs::create_dir_all(&self.meta.path).await?;
+        let path = Path::new(&self.meta.path).join(DB_META_FILE);
+        write_bson_atomic(path, &bson::to_document(&self.meta)?).await
+    }
+
+    /// Creates a new, empty collection (and its directory).
+    ///
+    /// Returns an error if a collection with the same name already
+    /// exists, or if the name can't be used as a directory name.
+    pub async fn create_collection(&mut self, name: &str) -> Result<&mut Collection> {
+        if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+            return Err(anyhow!("Invalid collection name {:?}", name));
+        }
+        if self.collections.contains_key(name) {
+            return Err(anyhow!("Collection {:?} already exists", name));
+        }
+
+        // Create the collection's directory...
+        self.write_meta().await?;
+        let path = Path::new(&self.meta.path).join(name);
+        tokio::fs::create_dir_all(&path).await?;
+
+        // Then add the collection...
+        let mut coll = Collection::new(name, &path.to_string_lossy());
+        coll.rate_limiter = self.rate_limiter.clone();
+        coll.write_meta().await?;
+        Ok(self.collections.entry(name.to_string()).or_insert(coll))
+    }
+
+    /// Drops a collection, deleting its files.
+    ///
+    /// Returns an error if there's no collection with that name.
+    pub async fn drop_collection(&mut self, name: &str) -> Result<()> {
+        let coll = self
+            .collections
+            .remove(name)
+            .ok_or(anyhow!("Collection {:?} doesn't exist", name))?;
+        match tokio::fs::remove_dir_all(&coll.tree.path).await {
+            Ok(()) => Ok(()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Returns the names of the database's collections, sorted.
+    pub fn list_collections(&self) -> Vec<String> {
+        let mut names: Vec<_> = self.collections.keys().cloned().collect();
+        names.sort();
+        names
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bson::doc;
+    use bson::oid::ObjectId;
+
+    #[tokio::test]
+    async fn create_drop_and_load_collections() -> Result<()> {
+        let path = format!("/tmp/{}", ObjectId::new());
+        let mut db = Database::new("test", &path);
+
+        // Create a couple of collections and write to them...
+        let key = ObjectId::new();
+        for name in ["users", "orders"] {
+            let coll = db.create_collection(name).await?;
+            coll.set(&key, doc! { "coll": name }).await?;
+            coll.tree.compact_memtable(true).await?;
+        }
+        assert!(db.create_collection("users").await.is_err());
+        assert_eq!(db.list_collections(), vec!["orders", "users"]);
+
+        // Drop one, which should delete its files...
+        db.drop_collection("orders").await?;
+        assert!(!Path::new(&path).join("orders").exists());
+        let err = db.drop_collection("orders").await.unwrap_err();
+        assert!(err.to_string().contains("doesn't exist"));
+
+        // Loading the database should find the remaining collection...
+        let loaded = Database::load(&path).await?;
+        assert_eq!(loaded.meta.name, "test");
+        assert_eq!(loaded.list_collections(), vec!["users"]);
+        let doc = loaded.collections["users"].get(&key).await?;
+        assert_eq!(doc, Some(doc! { "coll": "users" }));
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    /// Returns the keys in a collection's WAL, in the order they were
+    /// written.
+    async fn logged_keys(db: &Database, name: &str) -> Result<Vec<ObjectId>> {
+        let records = db.collections[name].tree.wal.read().await?;
+        Ok(records.into_iter().map(|r| r.key).collect())
+    }
+
+    #[tokio::test]
+    async fn collections_log_and_checkpoint_independently() -> Result<()> {
+        let path = format!("/tmp/{}", ObjectId::new());
+        let mut db = Database::new("test", &path);
+        db.create_collection("users").await?;
+        db.create_collection("orders").await?;
+
+        // Write to both collections...
+        let user_keys: Vec<_> = (0..5).map(|_| ObjectId::new()).collect();
+        let order_keys: Vec<_> = (0..3).map(|_| ObjectId::new()).collect();
+        for (name, keys) in [("users", &user_keys), ("orders", &order_keys)] {
+            let coll = db.collections.get_mut(name).unwrap();
+            for key in keys.iter() {
+                coll.set(key, doc! { "coll": name }).await?;
+            }
+        }
+
+        // Each collection's writes are only in its own WAL...
+        assert_eq!(logged_keys(&db, "users").await?, user_keys);
+        assert_eq!(logged_keys(&db, "orders").await?, order_keys);
+
+        // Flushing one collection only checkpoints its own WAL...
+        let users = db.collections.get_mut("users").unwrap();
+        users.tree.compact_memtable(true).await?;
+        assert!(logged_keys(&db, "users").await?.is_empty());
+        assert_eq!(logged_keys(&db, "orders").await?, order_keys);
+
+        // Then write to it again, without flushing...
+        let late = ObjectId::new();
+        let users = db.collections.get_mut("users").unwrap();
+        users.set(&late, doc! { "coll": "users" }).await?;
+        assert_eq!(logged_keys(&db, "users").await?, vec![late]);
+
+        // Loading the database (as after a crash) replays each
+        // collection's own WAL...
+        let loaded = Database::load(&path).await?;
+        let users = &loaded.collections["users"];
+        let orders = &loaded.collections["orders"];
+        assert_eq!(users.tree.memtable.size(), 1);
+        assert_eq!(orders.tree.memtable.size(), order_keys.len());
+        for key in user_keys.iter().chain([&late]) {
+            assert_eq!(users.get(key).await?, Some(doc! { "coll": "users" }));
+            assert_eq!(orders.get(key).await?, None);
+        }
+        for key in order_keys.iter() {
+            assert_eq!(orders.get(key).await?, Some(doc! { "coll": "orders" }));
+            assert_eq!(users.get(key).await?, None);
+        }
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn load_restores_collection_settings_and_indexes() -> Result<()> {
+        use crate::db::batch::{BatchConfig, OversizedBatch};
+        use crate::db::key::KeyKind;
+        use bson::Bson;
+
+        let path = format!("/tmp/{}", ObjectId::new());
+        let mut db = Database::new("test", &path);
+
+        // Create a collection with non-default settings...
+        let coll = db.create_collection("users").await?;
+        coll.set_key_kind(KeyKind::Int);
+        coll.batch_config = BatchConfig {
+            max_batch_size: 7,
+            oversized: OversizedBatch::Reject,
+        };
+        coll.write_meta().await?;
+
+        // Index it and add some documents...
+        coll.create_index("by_color", "color", false).await?;
+        for i in 0..6 {
+            let color = ["red", "blue"][i % 2];
+            coll.set(&ObjectId::new(), doc! { "color": color, "i": i as i32 })
+                .await?;
+        }
+
+        // Loading the database should restore the settings...
+        let loaded = Database::load(&path).await?;
+        let users = &loaded.collections["users"];
+        assert_eq!(users.key_kind(), KeyKind::Int);
+        assert!(!users.tree.timestamped_keys);
+        assert_eq!(users.batch_config.max_batch_size, 7);
+        assert_eq!(users.batch_config.oversized, OversizedBatch::Reject);
+
+        // And the index, with its entries...
+        let reds = users
+            .find_by("by_color", Bson::String("red".into()))
+            .await?;
+        assert_eq!(reds.len(), 3);
+        assert!(reds.iter().all(|d| d.get_str("color") == Ok("red")));
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
     }
 }
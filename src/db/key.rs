@@ -0,0 +1,360 @@
+//! Encoding of a collection's primary keys.
+//!
+//! The storage layer is keyed on `ObjectId`s, so other key types are
+//! encoded into the same 12 bytes in a way that preserves their sort
+//! order. This keeps `Record`, `MemTable`, `SSTable`, and `Level` (which
+//! rely on `Ord` and binary search) unchanged.
+//!
+//! Keys can also be scoped to a tenant with a key prefix (see
+//! [prefixed_key]): the first bytes of each of a tenant's keys are the
+//! tenant's prefix. Since tables are sorted by key, a tenant's data is
+//! stored together and can be read (or deleted) as a single key range
+//! (see [prefix_range]).
+//!
+//! Since every key has to fit in 12 bytes, [KeyKind::String] keys are
+//! limited to 12 bytes (with no NUL bytes) and encoding a longer one is
+//! an error. Arbitrary string keys (of any length) can be hashed into a
+//! key instead, with [KeyKind::Hashed] (see [key_from_str]). Unlike
+//! [KeyKind::String], this *doesn't* preserve their order: hashed keys
+//! are spread evenly over the key space, so they sort by their hash,
+//! not their string.
+//!
+//! Only generated `ObjectId` keys start with a real timestamp, so the
+//! storage features that go by the time in a key (like
+//! [crate::storage::lsm::LSMTree::max_table_span]) are turned off for
+//! the other kinds (see [KeyKind::is_timestamped]).
+
+use anyhow::{anyhow, Result};
+use bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The number of bytes in an `ObjectId`.
+const KEY_LEN: usize = 12;
+
+/// The reserved field a document's string key is stored in, when it's
+/// set by a hashed string key (see [key_from_str]).
+pub const KEY_FIELD: &str = "_key";
+
+/// A primary key for a document in a collection.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Key {
+    /// An `ObjectId` key (the default).
+    ObjectId(ObjectId),
+
+    /// A string key.
+    String(String),
+
+    /// An integer key.
+    Int(i64),
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Key::ObjectId(oid) => write!(f, "{}", oid),
+            Key::String(s) => write!(f, "{:?}", s),
+            Key::Int(n) => write!(f, "{}", n),
+        }
+    }
+}
+
+/// The kind of primary key a collection uses, which determines
+/// how keys are encoded for storage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum KeyKind {
+    /// Keys are `ObjectId`s and are stored as-is.
+    #[default]
+    ObjectId,
+
+    /// Keys are strings of up to 12 bytes (UTF-8), not containing
+    /// any NUL bytes. They're stored zero-padded, so they sort
+    /// lexicographically by their bytes.
+    String,
+
+    /// Keys are signed 64-bit integers. They're stored big-endian
+    /// with the sign bit flipped, so they sort numerically.
+    Int,
+
+    /// Keys are strings of any length, hashed into a key (see
+    /// [key_from_str]). They're stored in hash order, and the string is
+    /// kept in each document's [KEY_FIELD] since the hash can't be
+    /// decoded.
+    Hashed,
+}
+
+impl KeyKind {
+    /// Encodes a key as an `ObjectId` for storage.
+    ///
+    /// Returns an error if the key doesn't match this kind, or if a
+    /// string key can't be encoded (see [KeyKind::String]).
+    pub fn encode(&self, key: &Key) -> Result<ObjectId> {
+        let mut bytes = [0u8; KEY_LEN];
+        match (self, key) {
+            (KeyKind::ObjectId, Key::ObjectId(oid)) => return Ok(*oid),
+            (KeyKind::String, Key::String(s)) => {
+                let b = s.as_bytes();
+                if b.len() > KEY_LEN {
+                    return Err(anyhow!(
+                        "String key {:?} is longer than {} bytes",
+                        s,
+                        KEY_LEN
+                    ));
+                }
+                if b.contains(&0) {
+                    return Err(anyhow!("String key {:?} contains a NUL byte", s));
+                }
+                bytes[..b.len()].copy_from_slice(b);
+            }
+            (KeyKind::Int, Key::Int(n)) => {
+                let b = ((*n as u64) ^ (1 << 63)).to_be_bytes();
+                bytes[..b.len()].copy_from_slice(&b);
+            }
+            (KeyKind::Hashed, Key::String(s)) => return Ok(key_from_str(s)),
+            _ => return Err(anyhow!("Key {} doesn't match key kind {:?}", key, self)),
+        }
+        Ok(ObjectId::from_bytes(bytes))
+    }
+
+    /// Decodes a stored `ObjectId` back into a key.
+    ///
+    /// Returns an error for [KeyKind::Hashed] keys, which can't be
+    /// decoded (their string is in the document's [KEY_FIELD] instead).
+    pub fn decode(&self, oid: &ObjectId) -> Result<Key> {
+        let bytes = oid.bytes();
+        match self {
+            KeyKind::ObjectId => Ok(Key::ObjectId(*oid)),
+            KeyKind::String => {
+                let end = bytes.iter().position(|b| *b == 0).unwrap_or(KEY_LEN);
+                Ok(Key::String(String::from_utf8(bytes[..end].to_vec())?))
+            }
+            KeyKind::Int => {
+                let mut b = [0u8; 8];
+                b.copy_from_slice(&bytes[..8]);
+                Ok(Key::Int((u64::from_be_bytes(b) ^ (1 << 63)) as i64))
+            }
+            KeyKind::Hashed => Err(anyhow!("Hashed key {} can't be decoded", oid)),
+        }
+    }
+
+    /// Checks if keys of this kind start with a real timestamp.
+    ///
+    /// Only [KeyKind::ObjectId] keys do. The first bytes of the other
+    /// kinds' keys are part of the encoded value, so reading them as a
+    /// time gives a meaningless one.
+    pub fn is_timestamped(&self) -> bool {
+        *self == KeyKind::ObjectId
+    }
+}
+
+/// Derives a key from a string of any length, by hashing it.
+///
+/// The key is the first 12 bytes of the string's SHA-256 hash, so the
+/// same string always gives the same key. Since the bytes are a hash:
+///
+/// * Keys don't sort in the strings' order, so a range of hashed keys
+///   is a random sample of the strings (not, e.g., all strings starting
+///   with `"a"`). Use [KeyKind::String] if short keys need ordered scans.
+/// * Keys written in any order land all over the key space, so each
+///   flushed table overlaps every other one and compactions can't skip
+///   levels by key range.
+/// * Two strings *can* share a key, though with 96 bits it's very
+///   unlikely. [KeyKind::Hashed] collections store the string in the
+///   document's [KEY_FIELD] to catch that.
+pub fn key_from_str(s: &str) -> ObjectId {
+    let hash = Sha256::digest(s.as_bytes());
+    let mut bytes = [0u8; KEY_LEN];
+    bytes.copy_from_slice(&hash[..KEY_LEN]);
+    ObjectId::from_bytes(bytes)
+}
+
+/// Builds a key for a tenant, starting with the tenant's `prefix`.
+///
+/// The rest of the key is the *last* bytes of `id` (its counter and
+/// part of its unique value), so keys built from fresh `ObjectId`s in
+/// one process stay unique. Returns an error if the prefix is longer
+/// than a key.
+pub fn prefixed_key(prefix: &[u8], id: &ObjectId) -> Result<ObjectId> {
+    if prefix.len() > KEY_LEN {
+        return Err(anyhow!("Key prefix is longer than {} bytes", KEY_LEN));
+    }
+    let mut bytes = id.bytes();
+    bytes[..prefix.len()].copy_from_slice(prefix);
+    Ok(ObjectId::from_bytes(bytes))
+}
+
+/// Returns the first and last keys (inclusive) starting with `prefix`.
+///
+/// Returns an error if the prefix is longer than a key.
+pub fn prefix_range(prefix: &[u8]) -> Result<(ObjectId, ObjectId)> {
+    if prefix.len() > KEY_LEN {
+        return Err(anyhow!("Key prefix is longer than {} bytes", KEY_LEN));
+    }
+    let mut start = [0u8; KEY_LEN];
+    let mut end = [0xffu8; KEY_LEN];
+    start[..prefix.len()].copy_from_slice(prefix);
+    end[..prefix.len()].copy_from_slice(prefix);
+    Ok((ObjectId::from_bytes(start), ObjectId::from_bytes(end)))
+}
+
+/// Returns the key right after `id` (in byte order), or `None` if
+/// `id` is the last possible key.
+///
+/// Useful for paging through a range, starting each page just after
+/// the last key seen.
+pub fn next_key(id: &ObjectId) -> Option<ObjectId> {
+    let mut bytes = id.bytes();
+    for b in bytes.iter_mut().rev() {
+        let (next, overflow) = b.overflowing_add(1);
+        *b = next;
+        if !overflow {
+            return Some(ObjectId::from_bytes(bytes));
+        }
+    }
+    None
+}
+
+/// How the keys of inserted documents are chosen (see
+/// [crate::db::collection::Collection::insert]).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum InsertMode {
+    /// The client supplies each document's key.
+    ///
+    /// Keys minted on different clients are only as ordered as the
+    /// clients' clocks are in sync.
+    #[default]
+    ClientKeys,
+
+    /// The server mints each document's key (see [KeyMinter]), so keys
+    /// are strictly increasing on that node regardless of client clocks.
+    ServerKeys,
+}
+
+/// Mints strictly increasing `ObjectId`s.
+///
+/// Keys are laid out like regular `ObjectId`s -- the current time (in
+/// seconds), a value unique to the minter, and a counter -- but a key is
+/// always bumped past the previous one, so keys minted in the same second
+/// (or after the clock moves backwards) still increase.
+#[derive(Debug, Clone)]
+pub struct KeyMinter {
+    /// The minter's unique value (the middle 5 bytes of its keys).
+    unique: [u8; 5],
+
+    /// The last key minted, as a big-endian integer.
+    last: Option<u128>,
+}
+
+impl KeyMinter {
+    /// Creates a new key minter.
+    pub fn new() -> Self {
+        let mut unique = [0u8; 5];
+        unique.copy_from_slice(&ObjectId::new().bytes()[4..9]);
+        KeyMinter { unique, last: None }
+    }
+
+    /// Mints the next key.
+    pub fn mint(&mut self) -> ObjectId {
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as u32)
+            .unwrap_or(0);
+
+        // Start from the current time (with a zeroed counter)...
+        let mut bytes = [0u8; 16];
+        bytes[4..8].copy_from_slice(&secs.to_be_bytes());
+        bytes[8..13].copy_from_slice(&self.unique);
+        let floor = u128::from_be_bytes(bytes);
+
+        // ...but always move past the last key...
+        let next = match self.last {
+            Some(last) => floor.max(last + 1),
+            None => floor,
+        };
+        self.last = Some(next);
+        let mut key = [0u8; KEY_LEN];
+        key.copy_from_slice(&next.to_be_bytes()[4..]);
+        ObjectId::from_bytes(key)
+    }
+}
+
+impl Default for KeyMinter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn string_keys_keep_order() -> Result<()> {
+        let kind = KeyKind::String;
+        let mut keys = vec!["banana", "apple", "", "apricot", "b", "ab"];
+        let mut encoded = vec![];
+        for k in keys.iter() {
+            encoded.push(kind.encode(&Key::String(k.to_string()))?);
+        }
+
+        // Sorting the encoded keys should sort the strings...
+        encoded.sort();
+        keys.sort();
+        for (oid, k) in encoded.iter().zip(keys) {
+            assert_eq!(kind.decode(oid)?, Key::String(k.to_string()));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn int_keys_keep_order() -> Result<()> {
+        let kind = KeyKind::Int;
+        let nums = [i64::MIN, -100, -1, 0, 1, 42, i64::MAX];
+        let encoded = nums
+            .iter()
+            .map(|n| kind.encode(&Key::Int(*n)))
+            .collect::<Result<Vec<_>>>()?;
+        for w in encoded.windows(2) {
+            assert!(w[0] < w[1], "Expected encoded ints to stay ordered");
+        }
+        for (oid, n) in encoded.iter().zip(nums) {
+            assert_eq!(kind.decode(oid)?, Key::Int(n));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn bad_keys_error() {
+        assert!(KeyKind::String
+            .encode(&Key::String("much too long of a key".into()))
+            .is_err());
+        assert!(KeyKind::String.encode(&Key::String("a\0b".into())).is_err());
+        assert!(KeyKind::Int.encode(&Key::String("a".into())).is_err());
+        assert!(KeyKind::Hashed.encode(&Key::Int(1)).is_err());
+    }
+
+    #[test]
+    fn hashed_keys_take_any_string() -> Result<()> {
+        let kind = KeyKind::Hashed;
+        let long = "a string key much longer than twelve bytes";
+        let key = kind.encode(&Key::String(long.into()))?;
+        assert_eq!(key, key_from_str(long));
+        assert!(kind.decode(&key).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn next_key_increments() {
+        let mut bytes = [0u8; KEY_LEN];
+        bytes[KEY_LEN - 1] = 0xff;
+        let next = next_key(&ObjectId::from_bytes(bytes)).unwrap();
+        let mut exp = [0u8; KEY_LEN];
+        exp[KEY_LEN - 2] = 1;
+        assert_eq!(next.bytes(), exp);
+
+        // The last key has no next key
+        assert!(next_key(&ObjectId::from_bytes([0xff; KEY_LEN])).is_none());
+    }
+}
@@ -1,4 +1,9 @@
 //! Contains code for the database as an abstraction on top of the storage layer.
 
+pub mod batch;
 pub mod collection;
 pub mod database;
+pub mod key;
+pub mod ratelimit;
+pub mod restore;
+pub mod ttl;
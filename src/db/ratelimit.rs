@@ -0,0 +1,245 @@
+//! Write rate limiting for a database, using a token bucket.
+
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// The unit a [WriteRateLimit] is measured in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RateUnit {
+    /// Limit the number of write operations per second.
+    Ops,
+
+    /// Limit the number of (BSON-encoded) bytes written per second.
+    Bytes,
+}
+
+/// Configuration for a database's write rate limit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WriteRateLimit {
+    /// What the rate is measured in.
+    pub unit: RateUnit,
+
+    /// The sustained rate, in `unit`s per second.
+    pub rate: f64,
+
+    /// The maximum burst size, in `unit`s.
+    pub burst: f64,
+}
+
+/// The error returned when a write is rejected by the rate limiter.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RateLimitError {
+    /// There aren't enough tokens for the write right now. Retrying
+    /// later may succeed.
+    Exceeded,
+
+    /// The write costs more than the limit's burst, so the bucket can
+    /// never hold enough tokens for it and retrying won't help.
+    TooLarge { cost: f64, burst: f64 },
+}
+
+impl fmt::Display for RateLimitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RateLimitError::Exceeded => write!(f, "Write rate limit exceeded"),
+            RateLimitError::TooLarge { cost, burst } => write!(
+                f,
+                "Write is too large for the rate limit (costs {} but the burst is {})",
+                cost, burst
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RateLimitError {}
+
+/// A token bucket shared by all of a database's collections.
+#[derive(Debug)]
+pub struct RateLimiter {
+    /// The limiter's configuration.
+    pub conf: WriteRateLimit,
+
+    /// The number of tokens available and when they were last refilled.
+    state: Mutex<(f64, Instant)>,
+}
+
+impl RateLimiter {
+    /// Creates a new rate limiter with a full bucket.
+    pub fn new(conf: WriteRateLimit) -> Self {
+        RateLimiter {
+            conf,
+            state: Mutex::new((conf.burst, Instant::now())),
+        }
+    }
+
+    /// Tries to take `cost` tokens from the bucket.
+    ///
+    /// Returns `false` (and takes nothing) if there aren't enough
+    /// tokens available. A `cost` over the burst never succeeds (see
+    /// [RateLimitError::TooLarge]).
+    pub fn try_acquire(&self, cost: f64) -> bool {
+        let mut state = match self.state.lock() {
+            Ok(s) => s,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let (tokens, last) = *state;
+
+        // Refill the bucket based on the time elapsed...
+        let now = Instant::now();
+        let elapsed = now.duration_since(last).as_secs_f64();
+        let tokens = (tokens + elapsed * self.conf.rate).min(self.conf.burst);
+
+        // Take the tokens, if there are enough...
+        if tokens < cost {
+            *state = (tokens, now);
+            return false;
+        }
+        *state = (tokens - cost, now);
+        true
+    }
+
+    /// Takes `cost` tokens from the bucket, or returns the reason it
+    /// can't.
+    fn acquire(&self, cost: f64) -> Result<(), RateLimitError> {
+        if cost > self.conf.burst {
+            return Err(RateLimitError::TooLarge {
+                cost,
+                burst: self.conf.burst,
+            });
+        }
+        if self.try_acquire(cost) {
+            Ok(())
+        } else {
+            Err(RateLimitError::Exceeded)
+        }
+    }
+
+    /// Checks a batch of `n_ops` writes, totalling `n_bytes`, against
+    /// the limit. The whole batch is either allowed or rejected.
+    ///
+    /// Returns a [RateLimitError] if the batch should be rejected.
+    pub fn check_batch(&self, n_ops: usize, n_bytes: usize) -> Result<(), RateLimitError> {
+        let cost = match self.conf.unit {
+            RateUnit::Ops => n_ops as f64,
+            RateUnit::Bytes => n_bytes as f64,
+        };
+        self.acquire(cost)
+    }
+
+    /// Checks a write of `n_bytes` against the limit.
+    ///
+    /// Returns a [RateLimitError] if the write should be rejected.
+    pub fn check_write(&self, n_bytes: usize) -> Result<(), RateLimitError> {
+        let cost = match self.conf.unit {
+            RateUnit::Ops => 1.0,
+            RateUnit::Bytes => n_bytes as f64,
+        };
+        self.acquire(cost)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::collection::Collection;
+    use crate::db::database::Database;
+    use anyhow::Result;
+    use bson::doc;
+    use bson::oid::ObjectId;
+
+    #[test]
+    fn bucket_limits_ops() {
+        let rl = RateLimiter::new(WriteRateLimit {
+            unit: RateUnit::Ops,
+            rate: 0.01,
+            burst: 3.0,
+        });
+
+        // The first few writes fit in the burst...
+        assert!(rl.check_write(10).is_ok());
+        assert!(rl.check_write(10).is_ok());
+        assert!(rl.check_write(10).is_ok());
+
+        // But then the bucket is empty...
+        assert_eq!(rl.check_write(10), Err(RateLimitError::Exceeded));
+    }
+
+    #[test]
+    fn bucket_limits_bytes() {
+        let rl = RateLimiter::new(WriteRateLimit {
+            unit: RateUnit::Bytes,
+            rate: 0.01,
+            burst: 100.0,
+        });
+        assert!(rl.check_write(60).is_ok());
+        assert!(rl.check_write(60).is_err(), "Expected bytes to be limited");
+        assert!(
+            rl.check_write(30).is_ok(),
+            "Expected a smaller write to fit"
+        );
+    }
+
+    #[test]
+    fn writes_over_the_burst_are_too_large() {
+        let rl = RateLimiter::new(WriteRateLimit {
+            unit: RateUnit::Bytes,
+            rate: 1000.0,
+            burst: 100.0,
+        });
+
+        // A write bigger than the bucket can ever hold is rejected as
+        // too large, rather than as rate limited, even with a full bucket...
+        let too_large = RateLimitError::TooLarge {
+            cost: 150.0,
+            burst: 100.0,
+        };
+        assert_eq!(rl.check_write(150), Err(too_large.clone()));
+        assert_eq!(rl.check_batch(2, 150), Err(too_large));
+
+        // ...and it doesn't take any tokens
+        assert!(rl.check_write(100).is_ok());
+    }
+
+    #[tokio::test]
+    async fn collection_writes_throttled() -> Result<()> {
+        // Create a database with a collection and a limit...
+        let path = format!("/tmp/{}", ObjectId::new());
+        let mut db = Database::new("test", &path);
+        db.collections
+            .insert("things".to_string(), Collection::new("things", &path));
+        db.set_write_limit(Some(WriteRateLimit {
+            unit: RateUnit::Ops,
+            rate: 0.01,
+            burst: 5.0,
+        }));
+        let coll = db
+            .collections
+            .get_mut("things")
+            .ok_or(anyhow::anyhow!("Collection not found"))?;
+
+        // Write faster than the limit allows...
+        let mut n_ok = 0;
+        let mut n_limited = 0;
+        for i in 0..20 {
+            match coll.set(&ObjectId::new(), doc! { "i": i }).await {
+                Ok(()) => n_ok += 1,
+                Err(e) => {
+                    assert_eq!(
+                        e.downcast_ref::<RateLimitError>(),
+                        Some(&RateLimitError::Exceeded),
+                        "Unexpected error: {}",
+                        e
+                    );
+                    n_limited += 1;
+                }
+            }
+        }
+        assert_eq!(n_ok, 5, "Expected only the burst to be allowed");
+        assert_eq!(n_limited, 15, "Expected the rest to be throttled");
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,148 @@
+//! Restoring a collection from a stream of backed-up documents.
+//!
+//! Documents arrive in batches (e.g. from [Collection::backup] on
+//! another database) and are bulk-loaded into the target collection.
+//! After each batch, the restore acknowledges its progress so an
+//! interrupted restore can be resumed from where it left off.
+
+use anyhow::{anyhow, Result};
+use bson::oid::ObjectId;
+use bson::Document;
+
+use crate::db::collection::Collection;
+
+/// How far a restore has gotten.
+///
+/// Backups are sorted by key, so everything up to (and including)
+/// `last_key` has been durably written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RestoreProgress {
+    /// The number of documents written so far.
+    pub loaded: u64,
+
+    /// The key of the last document written, if any.
+    pub last_key: Option<ObjectId>,
+}
+
+/// An in-progress restore into a collection.
+pub struct Restore<'a> {
+    coll: &'a mut Collection,
+    progress: RestoreProgress,
+}
+
+impl<'a> Restore<'a> {
+    /// Starts a new restore into the given collection.
+    ///
+    /// Returns an error if the collection isn't empty, unless
+    /// `overwrite` is set.
+    pub fn start(coll: &'a mut Collection, overwrite: bool) -> Result<Self> {
+        if !overwrite && !coll.tree.is_empty() {
+            return Err(anyhow!(
+                "Can't restore into non-empty collection {:?}",
+                coll.tree.name
+            ));
+        }
+        Ok(Restore {
+            coll,
+            progress: RestoreProgress::default(),
+        })
+    }
+
+    /// Resumes an interrupted restore from its last acknowledged progress.
+    ///
+    /// Documents at or before `progress.last_key` are skipped, so the
+    /// stream can be replayed from the start (or from any earlier batch).
+    pub fn resume(coll: &'a mut Collection, progress: RestoreProgress) -> Self {
+        Restore { coll, progress }
+    }
+
+    /// Returns the restore's current progress.
+    pub fn progress(&self) -> RestoreProgress {
+        self.progress
+    }
+
+    /// Writes a batch of documents and acknowledges the progress.
+    ///
+    /// The batch must be sorted by key, following any previous
+    /// batches. Once this returns, the batch is on disk.
+    pub async fn push(&mut self, batch: Vec<(ObjectId, Document)>) -> Result<RestoreProgress> {
+        // Skip anything that was already restored...
+        let batch: Vec<_> = match self.progress.last_key {
+            Some(last) => batch.into_iter().filter(|(k, _)| *k > last).collect(),
+            None => batch,
+        };
+
+        // Check that the keys are in order...
+        let mut prev = self.progress.last_key;
+        for (key, _) in batch.iter() {
+            if prev.is_some_and(|p| *key <= p) {
+                return Err(anyhow!("Restore batch out of order at key {}", key));
+            }
+            prev = Some(*key);
+        }
+
+        // Load the batch and make sure it's durable...
+        let n = batch.len() as u64;
+        self.coll.bulk_load(batch).await?;
+        if self.coll.tree.memtable.size() > 0 {
+            self.coll.tree.compact_memtable(true).await?;
+        }
+
+        // Acknowledge the progress...
+        self.progress.loaded += n;
+        self.progress.last_key = prev;
+        Ok(self.progress)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::database::Database;
+    use bson::doc;
+
+    #[tokio::test]
+    async fn backup_and_restore() -> Result<()> {
+        // Create a collection with some documents...
+        let src_path = format!("/tmp/{}", ObjectId::new());
+        let mut src = Collection::new("src", &src_path);
+        let keys: Vec<_> = (0..250).map(|_| ObjectId::new()).collect();
+        for (i, key) in keys.iter().enumerate() {
+            src.set(key, doc! { "i": i as i32 }).await?;
+        }
+        for key in keys.iter().step_by(10) {
+            src.del(key).await?;
+        }
+        let backup = src.backup().await?;
+        assert_eq!(backup.len(), 225);
+
+        // Restore it into a fresh database, getting interrupted partway...
+        let dst_path = format!("/tmp/{}", ObjectId::new());
+        let mut db = Database::new("restored", &dst_path);
+        db.collections
+            .insert("dst".to_string(), Collection::new("dst", &dst_path));
+        let dst = db.collections.get_mut("dst").unwrap();
+        let progress = {
+            let mut restore = Restore::start(dst, false)?;
+            restore.push(backup[..100].to_vec()).await?
+        };
+        assert_eq!(progress.loaded, 100);
+
+        // Restoring from scratch should now fail...
+        assert!(Restore::start(dst, false).is_err());
+
+        // Resume, replaying some of the stream...
+        let mut restore = Restore::resume(dst, progress);
+        restore.push(backup[50..150].to_vec()).await?;
+        restore.push(backup[150..].to_vec()).await?;
+        assert_eq!(restore.progress().loaded, 225);
+
+        // The restored collection should match the original...
+        assert_eq!(dst.backup().await?, backup);
+
+        // (Clean up) Remove the directories...
+        tokio::fs::remove_dir_all(&src_path).await.ok();
+        tokio::fs::remove_dir_all(&dst_path).await?;
+        Ok(())
+    }
+}
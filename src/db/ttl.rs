@@ -0,0 +1,34 @@
+//! Time-to-live (TTL) for a collection's documents.
+//!
+//! A document's expiry time is stored in the document itself, under
+//! the reserved [EXPIRES_AT_FIELD]. Expired documents aren't removed
+//! from storage; they're just hidden from reads.
+
+use bson::{DateTime, Document};
+use std::time::Duration;
+
+/// The field a document's expiry time is stored in.
+pub const EXPIRES_AT_FIELD: &str = "_expires_at";
+
+/// Returns the time `ttl` after `now`.
+pub fn expiry_after(now: DateTime, ttl: Duration) -> DateTime {
+    let ttl = i64::try_from(ttl.as_millis()).unwrap_or(i64::MAX);
+    DateTime::from_millis(now.timestamp_millis().saturating_add(ttl))
+}
+
+/// Returns the document's expiry time, if it has one.
+pub fn expires_at(doc: &Document) -> Option<DateTime> {
+    doc.get_datetime(EXPIRES_AT_FIELD).ok().copied()
+}
+
+/// Checks if the document has expired as of `now`.
+///
+/// Documents without an expiry time never expire.
+pub fn is_expired(doc: &Document, now: DateTime) -> bool {
+    expires_at(doc).is_some_and(|t| t <= now)
+}
+
+/// Sets the document's expiry time, replacing any existing one.
+pub fn set_expiry(doc: &mut Document, at: DateTime) {
+    doc.insert(EXPIRES_AT_FIELD, at);
+}
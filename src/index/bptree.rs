@@ -2,12 +2,30 @@ use anyhow::{anyhow, Context, Result};
 use bson::oid::ObjectId;
 use bson::Bson;
 use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::Mutex;
 use uuid::Uuid;
 
+use crate::index::order::cmp_bson;
+
 /// The name of the metadata file for a B+ tree
 /// index in the index directory.
 const BPTREE_META_NAME: &str = "_meta.json";
 
+/// The file extension for a B+ tree index's node files.
+///
+/// Nodes are stored as JSON, like the metadata, with their keys in
+/// canonical extended JSON so they keep their exact BSON types (e.g.
+/// `Int32` vs `Int64`).
+const BPTREE_NODE_EXT: &str = "json";
+
+/// The default order (max keys per node) for a new B+ tree index.
+pub const DEFAULT_BPTREE_ORDER: usize = 64;
+
+/// The smallest order allowed for a B+ tree index.
+const MIN_BPTREE_ORDER: usize = 3;
+
 /// BPTree represents a handle to a B+ tree index.
 ///
 /// On disk, a BPTree has the following structure:
@@ -20,22 +38,87 @@ pub struct BPTree {
 
     /// The path to the index directory.
     pub dir_path: String,
+
+    /// Whether the index's nodes have been loaded (see [BPTree::load]).
+    ///
+    /// This is locked while they're loaded, so concurrent first queries
+    /// on a lazily loaded index only load it once.
+    loaded: Mutex<bool>,
+
+    /// The number of node files read from disk.
+    nodes_read: AtomicUsize,
+}
+
+/// When a collection's indexes are loaded (see
+/// [crate::db::collection::Collection::load_with]).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IndexLoading {
+    /// Load every index's nodes on startup (see [BPTree::load]).
+    #[default]
+    Eager,
+
+    /// Only read each index's metadata on startup, loading its nodes
+    /// when it's first used (see [BPTree::load_lazy]).
+    Lazy,
 }
 
 impl BPTree {
-    /// Creates a new B+ tree index.
-    pub fn new(dir_path: &str, name: &str, key: &str, distinct: bool) -> Result<Self> {
+    /// Creates a new, empty B+ tree index.
+    ///
+    /// The index gets a new id and its directory is created under
+    /// `parent_dir_path` (see [BPTree::load]).
+    ///
+    /// # Arguments
+    ///
+    /// * `parent_dir_path` - The directory the index's directory is created in
+    /// * `name` - The name of the index
+    /// * `key` - The document key the index is on
+    /// * `distinct` - Whether the index's values must be unique
+    pub fn new(parent_dir_path: &str, name: &str, key: &str, distinct: bool) -> Result<Self> {
+        Self::with_order(parent_dir_path, name, key, distinct, DEFAULT_BPTREE_ORDER)
+    }
+
+    /// Creates a new B+ tree index with the given `order` (the max
+    /// number of keys per node).
+    ///
+    /// The order is stored in the index's metadata, so it stays the
+    /// same for the life of the index.
+    pub fn with_order(
+        parent_dir_path: &str,
+        name: &str,
+        key: &str,
+        distinct: bool,
+        order: usize,
+    ) -> Result<Self> {
+        // Validate the order
+        if order < MIN_BPTREE_ORDER {
+            return Err(anyhow!(
+                "The index order must be at least {} (got {})",
+                MIN_BPTREE_ORDER,
+                order
+            ));
+        }
+
+        // Create the index directory
+        let id = Uuid::new_v4();
+        let dir_path = std::path::Path::new(parent_dir_path).join(id.to_string());
+        std::fs::create_dir_all(&dir_path)
+            .context(format!("Failed to create index ({}) directory", id))?;
+
         // Create the tree object
         let tree = Self {
             meta: BPTreeMeta {
-                id: Uuid::new_v4(),
+                id,
                 name: name.to_string(),
                 key: key.to_string(),
                 distinct,
+                order,
                 root_node_id: None,
                 node_ids: Vec::new(),
             },
-            dir_path: dir_path.to_string(),
+            dir_path: dir_path.to_string_lossy().into(),
+            loaded: Mutex::new(true),
+            nodes_read: AtomicUsize::new(0),
         };
 
         // Write the meta to disk
@@ -46,7 +129,23 @@ impl BPTree {
     }
 
     /// Loads a B+ tree index from disk.
+    ///
+    /// The index's metadata is read and each of its nodes is loaded,
+    /// checking that they're all there and readable. Startup time grows
+    /// with the index's size, so see [BPTree::load_lazy] for a cheaper
+    /// alternative.
     pub fn load(parent_dir_path: String, id: Uuid) -> Result<Self> {
+        let tree = Self::load_lazy(parent_dir_path, id)?;
+        tree.ensure_loaded()?;
+        Ok(tree)
+    }
+
+    /// Loads a B+ tree index from disk, deferring loading its nodes.
+    ///
+    /// Only the index's metadata is read. The nodes are loaded (as by
+    /// [BPTree::load]) when the index is first used, so that use pays
+    /// the cost instead.
+    pub fn load_lazy(parent_dir_path: String, id: Uuid) -> Result<Self> {
         // Get the path to the index directory
         let sid = id.to_string();
         let idx_dir_path = std::path::Path::new(&parent_dir_path).join(&sid);
@@ -63,11 +162,61 @@ impl BPTree {
             &sid
         ))?;
 
-        // TODO - Validate the metadata file and confirm nodes exist?
-        // ...
-
         // Create the b+ tree and return
-        Ok(Self { dir_path, meta })
+        Ok(Self {
+            dir_path,
+            meta,
+            loaded: Mutex::new(false),
+            nodes_read: AtomicUsize::new(0),
+        })
+    }
+
+    /// Checks if the index's nodes have been loaded.
+    pub fn is_loaded(&self) -> bool {
+        *self.loaded.lock().unwrap()
+    }
+
+    /// Returns the number of node files read from disk by this handle.
+    pub fn nodes_read(&self) -> usize {
+        self.nodes_read.load(AtomicOrdering::Relaxed)
+    }
+
+    /// Loads the index's nodes, if they haven't been loaded yet.
+    ///
+    /// Each node is read to check it's there and readable, along with
+    /// the root being one of the index's nodes. If that fails the index
+    /// stays unloaded, so the next use tries again.
+    fn ensure_loaded(&self) -> Result<()> {
+        let mut loaded = self.loaded.lock().unwrap();
+        if *loaded {
+            return Ok(());
+        }
+
+        // Check the root is one of the nodes...
+        if let Some(root) = self.meta.root_node_id {
+            if self.meta.node_ids.binary_search(&root).is_err() {
+                return Err(anyhow!(
+                    "The root node={} isn't in the index={}",
+                    &root,
+                    &self.meta.id
+                ));
+            }
+        }
+
+        // Then read each of them...
+        for id in self.meta.node_ids.iter() {
+            let node = self.read_node(*id)?;
+            if node.id != *id {
+                return Err(anyhow!(
+                    "The node file for node={} has id={} in the index={}",
+                    id,
+                    &node.id,
+                    &self.meta.id
+                ));
+            }
+        }
+        *loaded = true;
+        Ok(())
     }
 
     /// Checks if the `value` is in the index.
@@ -77,27 +226,504 @@ impl BPTree {
 
     /// Gets the ID of the first record in the index with the
     /// given `value`.
-    pub fn get_one(&self, _value: Bson) -> Result<Option<ObjectId>> {
-        todo!();
+    pub fn get_one(&self, value: Bson) -> Result<Option<ObjectId>> {
+        // Find the leaf that would contain the value
+        let leaf = match self.find_path(&value)?.pop() {
+            Some(node) => node,
+            None => return Ok(None),
+        };
+
+        // Look for the value in the leaf
+        let leaf = leaf.node.as_leaf()?;
+        Ok(match leaf.find(&value) {
+            Ok(i) => leaf.entries[i].1.first().copied(),
+            Err(_) => None,
+        })
     }
 
     /// Gets the IDs of all records in the index with the
-    /// given `value`.
-    pub fn get_all(&self, _value: Bson) -> Result<Vec<ObjectId>> {
-        todo!();
+    /// given `value`, in the order they were added.
+    pub fn get_all(&self, value: Bson) -> Result<Vec<ObjectId>> {
+        // Find the leaf that would contain the value
+        let leaf = match self.find_path(&value)?.pop() {
+            Some(node) => node,
+            None => return Ok(vec![]),
+        };
+
+        // Look for the value in the leaf
+        let leaf = leaf.node.as_leaf()?;
+        Ok(match leaf.find(&value) {
+            Ok(i) => leaf.entries[i].1.clone(),
+            Err(_) => vec![],
+        })
     }
 
     /// Returns all IDs for records where the index key's value
     /// is in the range from `from_value` to `to_value`, inclusive.
-    pub fn scan(&self, _from_val: Bson, _to_val: Bson) -> Result<Vec<ObjectId>> {
-        todo!();
+    ///
+    /// Values are compared using [cmp_bson]. The IDs are returned in
+    /// index order.
+    pub fn scan(&self, from_val: Bson, to_val: Bson) -> Result<Vec<ObjectId>> {
+        let mut ids = vec![];
+        if cmp_bson(&from_val, &to_val) == Ordering::Greater {
+            return Ok(ids);
+        }
+
+        // Find the leaf where the range starts
+        let mut leaf = match self.find_path(&from_val)?.pop() {
+            Some(node) => node,
+            None => return Ok(ids),
+        };
+        let mut start = match leaf.node.as_leaf()?.find(&from_val) {
+            Ok(i) | Err(i) => i,
+        };
+
+        // Walk the leaves until passing the end of the range
+        loop {
+            let l = leaf.node.as_leaf()?;
+            for (value, value_ids) in l.entries.iter().skip(start) {
+                if cmp_bson(value, &to_val) == Ordering::Greater {
+                    return Ok(ids);
+                }
+                ids.extend(value_ids.iter().copied());
+            }
+            leaf = match l.next {
+                Some(id) => self.get_node(id)?,
+                None => return Ok(ids),
+            };
+            start = 0;
+        }
+    }
+
+    /// Gets the IDs of the records with each of the given `values`,
+    /// aligned with `values`.
+    ///
+    /// The values are looked up in index order, so a value in the same
+    /// leaf as the one before it is found without re-walking the tree.
+    pub fn get_many(&self, values: &[Bson]) -> Result<Vec<Vec<ObjectId>>> {
+        let mut order: Vec<_> = (0..values.len()).collect();
+        order.sort_by(|&a, &b| cmp_bson(&values[a], &values[b]));
+
+        let mut results = vec![vec![]; values.len()];
+        let mut leaf: Option<DiskNode> = None;
+        for i in order {
+            let value = &values[i];
+
+            // Walk down from the root, unless the value is in the current leaf...
+            let in_leaf = match &leaf {
+                Some(node) => match node.node.as_leaf()?.entries.last() {
+                    Some((last, _)) => cmp_bson(value, last) != Ordering::Greater,
+                    None => false,
+                },
+                None => false,
+            };
+            if !in_leaf {
+                leaf = self.find_path(value)?.pop();
+            }
+
+            // Look for the value in the leaf
+            if let Some(node) = &leaf {
+                let l = node.node.as_leaf()?;
+                if let Ok(j) = l.find(value) {
+                    results[i] = l.entries[j].1.clone();
+                }
+            }
+        }
+        Ok(results)
+    }
+
+    /// Returns every `(value, id)` pair in the index, in index order.
+    pub fn entries(&self) -> Result<Vec<(Bson, ObjectId)>> {
+        // Find the leftmost leaf
+        let mut entries = vec![];
+        let mut next = self.meta.root_node_id;
+        let mut leaf = loop {
+            let node = match next {
+                Some(id) => self.get_node(id)?,
+                None => return Ok(entries),
+            };
+            match &node.node {
+                Node::Internal(int) => next = int.children.first().copied(),
+                Node::Leaf(_) => break node,
+            }
+        };
+
+        // Walk the leaves
+        loop {
+            let l = leaf.node.as_leaf()?;
+            for (value, ids) in l.entries.iter() {
+                entries.extend(ids.iter().map(|id| (value.clone(), *id)));
+            }
+            leaf = match l.next {
+                Some(id) => self.get_node(id)?,
+                None => return Ok(entries),
+            };
+        }
+    }
+
+    /// Adds the record `id` to the index under `value`.
+    ///
+    /// If the leaf the value belongs in overflows the tree's order,
+    /// it's split and the split propagates up the tree as needed
+    /// (creating a new root if the root splits).
+    ///
+    /// Returns an error if the index is distinct and `value` is
+    /// already in the index.
+    pub fn insert(&mut self, value: Bson, id: ObjectId) -> Result<()> {
+        // Find the path to the leaf that should hold the value
+        let mut path = self.find_path(&value)?;
+
+        // If the tree is empty, the value goes in a new root leaf
+        let mut leaf = match path.pop() {
+            Some(node) => node,
+            None => {
+                let node = Node::Leaf(LeafNode {
+                    entries: vec![(value, vec![id])],
+                    next: None,
+                });
+                self.create_node(None, node)?;
+                return Ok(());
+            }
+        };
+
+        // Add the value to the leaf
+        let l = leaf.node.as_leaf_mut()?;
+        match l.find(&value) {
+            Ok(i) => {
+                if self.meta.distinct {
+                    return Err(anyhow!(
+                        "The value {} already exists in the distinct index={}",
+                        value,
+                        &self.meta.id
+                    ));
+                }
+                let ids = &mut l.entries[i].1;
+                if !ids.contains(&id) {
+                    ids.push(id);
+                }
+            }
+            Err(i) => l.entries.insert(i, (value, vec![id])),
+        }
+
+        // Write it, splitting if needed
+        self.split_up(leaf, path)
+    }
+
+    /// Removes the record `id` from the index under `value`.
+    ///
+    /// If it was the last id for the value, the value is removed. A
+    /// node left with too few keys borrows from a sibling or merges
+    /// with one, which can propagate up the tree (and shrink the root).
+    ///
+    /// Returns whether the id was found in the index.
+    pub fn remove(&mut self, value: Bson, id: ObjectId) -> Result<bool> {
+        // Find the path to the leaf that would hold the value
+        let mut path = self.find_path(&value)?;
+        let mut leaf = match path.pop() {
+            Some(node) => node,
+            None => return Ok(false),
+        };
+
+        // Find the value and the id in the leaf
+        let l = leaf.node.as_leaf_mut()?;
+        let i = match l.find(&value) {
+            Ok(i) => i,
+            Err(_) => return Ok(false),
+        };
+        let ids = &mut l.entries[i].1;
+        match ids.iter().position(|x| *x == id) {
+            Some(pos) => ids.remove(pos),
+            None => return Ok(false),
+        };
+
+        // If there are still other ids, just write the leaf
+        if !ids.is_empty() {
+            self.write_node(&leaf)?;
+            return Ok(true);
+        }
+
+        // Otherwise remove the value and rebalance
+        l.entries.remove(i);
+        self.rebalance(leaf, path)?;
+        Ok(true)
+    }
+
+    /// Returns the minimum number of keys for a non-root node.
+    fn min_keys(&self) -> usize {
+        self.meta.order / 2
+    }
+
+    /// Walks from the root to the leaf that would contain `value`,
+    /// returning the nodes along the way (root first).
+    ///
+    /// Returns an empty vec if the tree is empty.
+    fn find_path(&self, value: &Bson) -> Result<Vec<DiskNode>> {
+        let mut path = vec![];
+        let mut next = self.meta.root_node_id;
+        while let Some(id) = next {
+            let node = self.get_node(id)?;
+            next = match &node.node {
+                Node::Internal(int) => Some(int.children[int.child_index(value)]),
+                Node::Leaf(_) => None,
+            };
+            path.push(node);
+        }
+        Ok(path)
+    }
+
+    /// Writes `node` and, if it overflows, splits it -- repeating
+    /// with its parent (from `path`) until a node doesn't overflow.
+    fn split_up(&mut self, mut node: DiskNode, mut path: Vec<DiskNode>) -> Result<()> {
+        loop {
+            // Does the node need to be split?
+            if node.node.len() <= self.meta.order {
+                return self.write_node(&node);
+            }
+
+            // Split off the right half of the node
+            let (sep, right) = match &mut node.node {
+                Node::Leaf(leaf) => {
+                    let mid = leaf.entries.len() / 2;
+                    let entries = leaf.entries.split_off(mid);
+                    let sep = entries[0].0.clone();
+                    (
+                        sep,
+                        Node::Leaf(LeafNode {
+                            entries,
+                            next: leaf.next,
+                        }),
+                    )
+                }
+                Node::Internal(int) => {
+                    let mid = int.keys.len() / 2;
+                    let mut keys = int.keys.split_off(mid);
+                    let sep = keys.remove(0);
+                    let children = int.children.split_off(mid + 1);
+                    (sep, Node::Internal(InternalNode { keys, children }))
+                }
+            };
+
+            // Get the parent (creating a new root if this was the root)
+            let mut parent = match path.pop() {
+                Some(parent) => parent,
+                None => {
+                    let root = Node::Internal(InternalNode {
+                        keys: vec![],
+                        children: vec![node.id],
+                    });
+                    let root = self.create_node(None, root)?;
+                    node.parent = Some(root.id);
+                    root
+                }
+            };
+
+            // Create the new right node
+            let right = self.create_node(Some(parent.id), right)?;
+            match &mut node.node {
+                Node::Leaf(leaf) => leaf.next = Some(right.id),
+                Node::Internal(_) => {
+                    for child in right.node.as_internal()?.children.iter() {
+                        self.set_parent(*child, Some(right.id))?;
+                    }
+                }
+            }
+            self.write_node(&node)?;
+
+            // Add the separator to the parent
+            let p = parent.node.as_internal_mut()?;
+            let i = p
+                .children
+                .iter()
+                .position(|c| *c == node.id)
+                .ok_or(anyhow!("Node={} not found in its parent", &node.id))?;
+            p.keys.insert(i, sep);
+            p.children.insert(i + 1, right.id);
+
+            // Continue with the parent
+            node = parent;
+        }
+    }
+
+    /// Writes `node` and, if it has too few keys, borrows from or
+    /// merges it with a sibling -- repeating with its parent (from
+    /// `path`) until a node doesn't underflow.
+    fn rebalance(&mut self, mut node: DiskNode, mut path: Vec<DiskNode>) -> Result<()> {
+        let min = self.min_keys();
+        loop {
+            // Get the parent (unless this is the root)
+            let mut parent = match path.pop() {
+                Some(parent) => parent,
+                None => return self.shrink_root(node),
+            };
+
+            // Is the node still full enough?
+            if node.node.len() >= min {
+                return self.write_node(&node);
+            }
+
+            // Load the node's siblings
+            let p = parent.node.as_internal_mut()?;
+            let i = p
+                .children
+                .iter()
+                .position(|c| *c == node.id)
+                .ok_or(anyhow!("Node={} not found in its parent", &node.id))?;
+            let mut left = match i {
+                0 => None,
+                _ => Some(self.get_node(p.children[i - 1])?),
+            };
+            let mut right = match p.children.get(i + 1) {
+                Some(id) => Some(self.get_node(*id)?),
+                None => None,
+            };
+
+            // Try to borrow a key from the left sibling
+            if let Some(l) = left.as_mut() {
+                if l.node.len() > min {
+                    self.rotate_right(l, &mut node, &mut p.keys[i - 1])?;
+                    self.write_node(l)?;
+                    self.write_node(&node)?;
+                    return self.write_node(&parent);
+                }
+            }
+
+            // Then try to borrow a key from the right sibling
+            if let Some(r) = right.as_mut() {
+                if r.node.len() > min {
+                    self.rotate_left(&mut node, r, &mut p.keys[i])?;
+                    self.write_node(r)?;
+                    self.write_node(&node)?;
+                    return self.write_node(&parent);
+                }
+            }
+
+            // Otherwise merge with a sibling
+            if let Some(mut l) = left {
+                let sep = p.keys.remove(i - 1);
+                p.children.remove(i);
+                self.merge_nodes(&mut l, node, sep)?;
+                self.write_node(&l)?;
+            } else if let Some(r) = right {
+                let sep = p.keys.remove(i);
+                p.children.remove(i + 1);
+                self.merge_nodes(&mut node, r, sep)?;
+                self.write_node(&node)?;
+            } else {
+                return Err(anyhow!("Node={} has no siblings", &node.id));
+            }
+
+            // Continue with the parent
+            node = parent;
+        }
+    }
+
+    /// Writes the root `node` or, if it's an internal node with
+    /// no keys left, replaces it with its only child.
+    fn shrink_root(&mut self, node: DiskNode) -> Result<()> {
+        match &node.node {
+            Node::Internal(int) if int.keys.is_empty() => {
+                let child = int.children[0];
+                self.set_parent(child, None)?;
+                self.meta.root_node_id = Some(child);
+                self.delete_node(node.id)
+            }
+            _ => self.write_node(&node),
+        }
+    }
+
+    /// Moves the last key from `left` to the front of its sibling
+    /// `right`, updating their separator in the parent (`sep`).
+    fn rotate_right(
+        &self,
+        left: &mut DiskNode,
+        right: &mut DiskNode,
+        sep: &mut Bson,
+    ) -> Result<()> {
+        match (&mut left.node, &mut right.node) {
+            (Node::Leaf(l), Node::Leaf(r)) => {
+                let entry = l
+                    .entries
+                    .pop()
+                    .ok_or(anyhow!("Can't borrow from an empty node"))?;
+                r.entries.insert(0, entry);
+                *sep = r.entries[0].0.clone();
+            }
+            (Node::Internal(l), Node::Internal(r)) => {
+                let key = l
+                    .keys
+                    .pop()
+                    .ok_or(anyhow!("Can't borrow from an empty node"))?;
+                let child = l
+                    .children
+                    .pop()
+                    .ok_or(anyhow!("Can't borrow from an empty node"))?;
+                r.keys.insert(0, std::mem::replace(sep, key));
+                r.children.insert(0, child);
+                self.set_parent(child, Some(right.id))?;
+            }
+            _ => return Err(anyhow!("Sibling nodes have mismatched types")),
+        }
+        Ok(())
+    }
+
+    /// Moves the first key from `right` to the end of its sibling
+    /// `left`, updating their separator in the parent (`sep`).
+    fn rotate_left(&self, left: &mut DiskNode, right: &mut DiskNode, sep: &mut Bson) -> Result<()> {
+        match (&mut left.node, &mut right.node) {
+            (Node::Leaf(l), Node::Leaf(r)) => {
+                if r.entries.len() < 2 {
+                    return Err(anyhow!("Can't borrow from a node with fewer than two keys"));
+                }
+                l.entries.push(r.entries.remove(0));
+                *sep = r.entries[0].0.clone();
+            }
+            (Node::Internal(l), Node::Internal(r)) => {
+                if r.keys.is_empty() {
+                    return Err(anyhow!("Can't borrow from an empty node"));
+                }
+                let key = r.keys.remove(0);
+                let child = r.children.remove(0);
+                l.keys.push(std::mem::replace(sep, key));
+                l.children.push(child);
+                self.set_parent(child, Some(left.id))?;
+            }
+            _ => return Err(anyhow!("Sibling nodes have mismatched types")),
+        }
+        Ok(())
+    }
+
+    /// Merges `right` into its sibling `left` (pulling down their
+    /// separator `sep` for internal nodes) and deletes `right`.
+    fn merge_nodes(&mut self, left: &mut DiskNode, right: DiskNode, sep: Bson) -> Result<()> {
+        match (&mut left.node, right.node) {
+            (Node::Leaf(l), Node::Leaf(r)) => {
+                l.entries.extend(r.entries);
+                l.next = r.next;
+            }
+            (Node::Internal(l), Node::Internal(r)) => {
+                for child in r.children.iter() {
+                    self.set_parent(*child, Some(left.id))?;
+                }
+                l.keys.push(sep);
+                l.keys.extend(r.keys);
+                l.children.extend(r.children);
+            }
+            _ => return Err(anyhow!("Sibling nodes have mismatched types")),
+        }
+        self.delete_node(right.id)
+    }
+
+    /// Updates the parent of the node with the given `id`.
+    fn set_parent(&self, id: Uuid, parent: Option<Uuid>) -> Result<()> {
+        let mut node = self.get_node(id)?;
+        node.parent = parent;
+        self.write_node(&node)
     }
 
     /// Writes the tree's metadata to disk.
     fn write_meta(&self) -> Result<()> {
         // Get the path to the meta file
-        let p = std::path::Path::new(&self.dir_path)
-            .join(BPTREE_META_NAME);
+        let p = std::path::Path::new(&self.dir_path).join(BPTREE_META_NAME);
 
         // Encode the metadata
         let b = serde_json::to_string(&self.meta).context(format!(
@@ -138,39 +764,60 @@ impl BPTree {
         Ok(node)
     }
 
-    /// Gets a node with the given `id` from disk.
-    fn get_node(&self, id: Uuid) -> Result<DiskNode> {
-        // Check that a node with the given id exists
-        if (&self).meta.node_ids.binary_search(&id).is_ok() {
+    /// Removes every entry from the index, deleting all of its nodes.
+    pub fn clear(&mut self) -> Result<()> {
+        for id in std::mem::take(&mut self.meta.node_ids) {
+            std::fs::remove_file(node_path(&self.dir_path, id))
+                .context(format!("Failed to delete node={} from disk", &id))?;
+        }
+        self.meta.root_node_id = None;
+        self.write_meta()
+    }
+
+    /// Checks that the node with the given `id` is one of the index's
+    /// nodes, returning an error if it isn't.
+    ///
+    /// Note: This relies on `node_ids` being kept sorted (see
+    /// [BPTree::create_node]).
+    fn check_node_exists(&self, id: Uuid) -> Result<()> {
+        if self.meta.node_ids.binary_search(&id).is_err() {
             // TODO - Create custom error for this
             return Err(anyhow!(
                 "The node={} doesn't exist in the index={}",
-                &self.meta.id,
-                &id
+                &id,
+                &self.meta.id
             ));
         }
+        Ok(())
+    }
 
+    /// Gets a node with the given `id` from disk.
+    fn get_node(&self, id: Uuid) -> Result<DiskNode> {
+        self.check_node_exists(id)?;
+        self.ensure_loaded()?;
+        self.read_node(id)
+    }
+
+    /// Reads the node with the given `id` from disk.
+    fn read_node(&self, id: Uuid) -> Result<DiskNode> {
+        self.nodes_read.fetch_add(1, AtomicOrdering::Relaxed);
         DiskNode::load(&self.dir_path, id)
     }
 
-    fn update_node_content(&mut self, id: Uuid, node: Node) -> Result<()> {
-        // Does the node exist?
-        if (&self).meta.node_ids.binary_search(&id).is_err() {
-            // TODO - Create custom error for this
-            return Err(anyhow!(
-                "The node={} doesn't exist in the index={}",
-                &self.meta.id,
-                &id
-            ));
-        }
-        todo!();
+    /// Writes an existing node back to disk.
+    ///
+    /// Returns an error (without writing anything) if the node isn't one
+    /// of the index's nodes, since its file would never be cleaned up.
+    fn write_node(&self, node: &DiskNode) -> Result<()> {
+        self.check_node_exists(node.id)?;
+        node.write(&self.dir_path)
     }
 
     /// Deletes a node with the given `id` from disk.
-    /// 
+    ///
     /// Note: This may need to be replaced with something or some things
     /// more case-specific for cases like moving/merging/splitting nodes.
-    /// And those things may need to perform multiple operations before 
+    /// And those things may need to perform multiple operations before
     /// the disk-updates get flushed (e.g. re-write metadata).
     fn delete_node(&mut self, id: Uuid) -> Result<()> {
         // Delete it from the metadata and write
@@ -181,9 +828,13 @@ impl BPTree {
 
                 // Re-write
                 self.write_meta()?;
-            },
-            Err(_pos) => {},
+            }
+            Err(_pos) => {}
         };
+
+        // Delete the node's file
+        std::fs::remove_file(node_path(&self.dir_path, id))
+            .context(format!("Failed to delete node={} from disk", &id))?;
         Ok(())
     }
 }
@@ -203,6 +854,9 @@ pub struct BPTreeMeta {
     /// Does the index contain unique values?
     pub distinct: bool,
 
+    /// The order of the tree (the max number of keys per node).
+    pub order: usize,
+
     /// The ID of the starting node.
     pub root_node_id: Option<Uuid>,
 
@@ -235,54 +889,806 @@ impl DiskNode {
 
     /// Loads a `DiskNode` from disk.
     pub fn load(dir_name: &str, id: Uuid) -> Result<Self> {
-        let p = std::path::Path::new(&dir_name)
-            .join(id.to_string());
-        let b = std::fs::read(&p)
-            .context(format!("Failed to read node={} from disk", &id))?;
-        let node: DiskNode = bson::from_slice(&b)
+        let p = node_path(dir_name, id);
+        let b = std::fs::read(&p).context(format!("Failed to read node={} from disk", &id))?;
+        let node: DiskNode = serde_json::from_slice(&b)
             .context(format!("Failed to parse node={} from json", &id))?;
         Ok(node)
     }
 
     /// Writes a `DiskNode` to disk.
     pub fn write(&self, dir_name: &str) -> Result<()> {
-        let p = self.file_path(&dir_name);
-        let b = bson::to_vec(&self)
+        let p = self.file_path(dir_name);
+        let b = serde_json::to_vec(&self)
             .context(format!("Failed to encode node={} as json", &self.id))?;
-        std::fs::write(p, b)
-            .context(format!("Failed to write node={} to disk", &self.id))?;
+        std::fs::write(p, b).context(format!("Failed to write node={} to disk", &self.id))?;
         Ok(())
     }
 
     /// Deletes a `DiskNode` from disk.
     pub fn delete(&self, dir_name: &str) -> Result<()> {
-        let p = self.file_path(&dir_name);
+        let p = self.file_path(dir_name);
         std::fs::remove_file(p).context(format!("Failed to delete node={} from disk", &self.id))?;
         Ok(())
     }
 
     fn file_path(&self, dir_name: &str) -> String {
-        std::path::Path::new(&dir_name)
-            .join(self.id.to_string())
-            .to_string_lossy()
-            .into()
+        node_path(dir_name, self.id)
     }
 }
 
+/// Formats the path to the file for the node with the given `id`.
+fn node_path(dir_name: &str, id: Uuid) -> String {
+    std::path::Path::new(&dir_name)
+        .join(format!("{}.{}", id, BPTREE_NODE_EXT))
+        .to_string_lossy()
+        .into()
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Node {
     Internal(InternalNode),
     Leaf(LeafNode),
 }
 
-/// Internal nodes contain pointers from key ranges 
+impl Node {
+    /// Returns the number of keys in the node.
+    pub fn len(&self) -> usize {
+        match self {
+            Node::Internal(int) => int.keys.len(),
+            Node::Leaf(leaf) => leaf.entries.len(),
+        }
+    }
+
+    /// Returns true if the node has no keys.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn as_leaf(&self) -> Result<&LeafNode> {
+        match self {
+            Node::Leaf(leaf) => Ok(leaf),
+            Node::Internal(_) => Err(anyhow!("Expected a leaf node")),
+        }
+    }
+
+    fn as_leaf_mut(&mut self) -> Result<&mut LeafNode> {
+        match self {
+            Node::Leaf(leaf) => Ok(leaf),
+            Node::Internal(_) => Err(anyhow!("Expected a leaf node")),
+        }
+    }
+
+    fn as_internal(&self) -> Result<&InternalNode> {
+        match self {
+            Node::Internal(int) => Ok(int),
+            Node::Leaf(_) => Err(anyhow!("Expected an internal node")),
+        }
+    }
+
+    fn as_internal_mut(&mut self) -> Result<&mut InternalNode> {
+        match self {
+            Node::Internal(int) => Ok(int),
+            Node::Leaf(_) => Err(anyhow!("Expected an internal node")),
+        }
+    }
+}
+
+/// Internal nodes contain pointers from key ranges
 /// to other nodes -- either internal or leaf.
+///
+/// There's always one more child than there are keys. The
+/// key at `keys[i]` is the smallest key under `children[i + 1]`.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct InternalNode;
+pub struct InternalNode {
+    /// The separator keys, sorted.
+    #[serde(with = "ext_json_keys")]
+    pub keys: Vec<Bson>,
+
+    /// The IDs of the child nodes.
+    pub children: Vec<Uuid>,
+}
+
+impl InternalNode {
+    /// Returns the index of the child that would contain `value`.
+    pub fn child_index(&self, value: &Bson) -> usize {
+        self.keys
+            .partition_point(|k| cmp_bson(k, value) != Ordering::Greater)
+    }
+}
 
 /// Leaf nodes contain pointers from keys to record IDs.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct LeafNode;
+pub struct LeafNode {
+    /// The keys and the IDs of the records with that key, sorted by key.
+    #[serde(with = "ext_json_entries")]
+    pub entries: Vec<(Bson, Vec<ObjectId>)>,
+
+    /// The ID of the next leaf node (in key order).
+    pub next: Option<Uuid>,
+}
+
+impl LeafNode {
+    /// Searches the leaf's entries for `value`.
+    ///
+    /// Like `binary_search`, returns `Ok` with the index of the
+    /// entry if found or `Err` with where it would be inserted.
+    pub fn find(&self, value: &Bson) -> std::result::Result<usize, usize> {
+        self.entries.binary_search_by(|(k, _)| cmp_bson(k, value))
+    }
+}
+
+/// Parses a node key from canonical extended JSON.
+fn key_from_ext_json<E: serde::de::Error>(value: serde_json::Value) -> Result<Bson, E> {
+    Bson::try_from(value).map_err(E::custom)
+}
+
+/// (De)serializes an internal node's keys as canonical extended JSON
+/// (see [BPTREE_NODE_EXT]).
+mod ext_json_keys {
+    use super::*;
+    use serde::{Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(keys: &[Bson], s: S) -> Result<S::Ok, S::Error> {
+        let keys: Vec<_> = keys
+            .iter()
+            .map(|k| k.clone().into_canonical_extjson())
+            .collect();
+        keys.serialize(s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<Bson>, D::Error> {
+        let keys = Vec::<serde_json::Value>::deserialize(d)?;
+        keys.into_iter().map(key_from_ext_json).collect()
+    }
+}
+
+/// (De)serializes a leaf node's entries with their keys as canonical
+/// extended JSON (see [BPTREE_NODE_EXT]).
+mod ext_json_entries {
+    use super::*;
+    use serde::{Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        entries: &[(Bson, Vec<ObjectId>)],
+        s: S,
+    ) -> Result<S::Ok, S::Error> {
+        let entries: Vec<_> = entries
+            .iter()
+            .map(|(k, ids)| (k.clone().into_canonical_extjson(), ids))
+            .collect();
+        entries.serialize(s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        d: D,
+    ) -> Result<Vec<(Bson, Vec<ObjectId>)>, D::Error> {
+        let entries = Vec::<(serde_json::Value, Vec<ObjectId>)>::deserialize(d)?;
+        entries
+            .into_iter()
+            .map(|(k, ids)| Ok((key_from_ext_json(k)?, ids)))
+            .collect()
+    }
+}
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    /// Creates a new, empty directory for an index.
+    fn temp_dir() -> Result<String> {
+        let p = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&p)?;
+        Ok(p.to_string_lossy().into())
+    }
+
+    /// Walks the whole tree, checking that it's balanced, that the
+    /// nodes are within the tree's order, that there are no leaked
+    /// nodes, and that the keys are sorted. Returns the keys in order.
+    fn check_tree(tree: &BPTree) -> Result<Vec<Bson>> {
+        let mut keys = vec![];
+        let mut leaf_depths = HashSet::new();
+        let mut n_nodes = 0;
+        let mut stack: Vec<(Uuid, usize)> =
+            tree.meta.root_node_id.iter().map(|id| (*id, 0)).collect();
+        while let Some((id, depth)) = stack.pop() {
+            let node = tree.get_node(id)?;
+            n_nodes += 1;
+            assert!(
+                node.node.len() <= tree.meta.order,
+                "Node over the tree's order"
+            );
+            if depth > 0 {
+                assert!(
+                    node.node.len() >= tree.min_keys(),
+                    "Non-root node under min keys"
+                );
+            }
+            match node.node {
+                Node::Internal(int) => {
+                    assert_eq!(int.children.len(), int.keys.len() + 1);
+                    for child in int.children.iter().rev() {
+                        assert_eq!(
+                            tree.get_node(*child)?.parent,
+                            Some(id),
+                            "Bad parent pointer"
+                        );
+                        stack.push((*child, depth + 1));
+                    }
+                }
+                Node::Leaf(leaf) => {
+                    leaf_depths.insert(depth);
+                    keys.extend(leaf.entries.into_iter().map(|(k, _)| k));
+                }
+            }
+        }
+        assert!(
+            leaf_depths.len() <= 1,
+            "Expected all leaves at the same depth"
+        );
+        assert_eq!(
+            n_nodes,
+            tree.meta.node_ids.len(),
+            "Expected no leaked nodes"
+        );
+        for w in keys.windows(2) {
+            assert_eq!(
+                cmp_bson(&w[0], &w[1]),
+                Ordering::Less,
+                "Expected sorted keys"
+            );
+        }
+        Ok(keys)
+    }
+
+    #[test]
+    fn order_is_stored() -> Result<()> {
+        let dir = temp_dir()?;
+        let tree = BPTree::with_order(&dir, "test", "num", false, 4)?;
+        assert_eq!(tree.meta.order, 4);
+
+        // Read the metadata back in
+        let b =
+            std::fs::read_to_string(std::path::Path::new(&tree.dir_path).join(BPTREE_META_NAME))?;
+        let meta: BPTreeMeta = serde_json::from_str(&b)?;
+        assert_eq!(meta.order, 4);
+
+        // A too-small order should error
+        assert!(BPTree::with_order(&dir, "test", "num", false, 2).is_err());
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn new_creates_and_persists_meta() -> Result<()> {
+        let dir = temp_dir()?;
+        let tree = BPTree::new(&dir, "test", "num", true)?;
+
+        // The index gets its own directory, with a metadata file
+        let idx_dir = std::path::Path::new(&dir).join(tree.meta.id.to_string());
+        assert_eq!(tree.dir_path, idx_dir.to_string_lossy());
+        assert!(idx_dir.is_dir());
+        assert!(idx_dir.join(BPTREE_META_NAME).is_file());
+
+        // Loading it gets the same (empty) metadata back
+        let loaded = BPTree::load(dir.clone(), tree.meta.id)?;
+        assert_eq!(loaded.dir_path, tree.dir_path);
+        assert_eq!(loaded.meta.id, tree.meta.id);
+        assert_eq!(loaded.meta.name, "test");
+        assert_eq!(loaded.meta.key, "num");
+        assert!(loaded.meta.distinct);
+        assert_eq!(loaded.meta.order, DEFAULT_BPTREE_ORDER);
+        assert!(loaded.meta.root_node_id.is_none());
+        assert!(loaded.meta.node_ids.is_empty());
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn get_node_loads_known_nodes() -> Result<()> {
+        let dir = temp_dir()?;
+        let mut tree = BPTree::new(&dir, "test", "num", false)?;
+
+        // Write a node and record it in the metadata
+        let leaf = Node::Leaf(LeafNode {
+            entries: vec![(Bson::Int32(1), vec![ObjectId::new()])],
+            next: None,
+        });
+        let node = DiskNode::new(&tree.dir_path, None, leaf)?;
+        tree.meta.node_ids.push(node.id);
+
+        // A known node loads...
+        let loaded = tree.get_node(node.id)?;
+        assert_eq!(loaded.id, node.id);
+        assert_eq!(loaded.node.len(), 1);
+
+        // ...while an unknown one errors
+        assert!(tree.get_node(Uuid::new_v4()).is_err());
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn write_node_only_writes_known_nodes() -> Result<()> {
+        let dir = temp_dir()?;
+        let mut tree = BPTree::new(&dir, "test", "num", false)?;
+        let id = ObjectId::new();
+        tree.insert(Bson::Int32(1), id)?;
+
+        // A known node is written back...
+        let root_id = tree.meta.root_node_id.unwrap();
+        let mut root = tree.get_node(root_id)?;
+        if let Node::Leaf(leaf) = &mut root.node {
+            leaf.entries.push((Bson::Int32(2), vec![id]));
+        }
+        tree.write_node(&root)?;
+        assert_eq!(tree.get_one(Bson::Int32(2))?, Some(id));
+
+        // ...while an unknown one errors, without leaving a file behind
+        let stray = DiskNode {
+            id: Uuid::new_v4(),
+            parent: None,
+            node: root.node.clone(),
+        };
+        let err = tree.write_node(&stray).unwrap_err();
+        assert!(err.to_string().contains(&stray.id.to_string()));
+        assert!(!std::path::Path::new(&node_path(&tree.dir_path, stray.id)).exists());
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn disk_node_round_trip() -> Result<()> {
+        let dir = temp_dir()?;
+        let ids = vec![ObjectId::new(), ObjectId::new()];
+        let leaf = Node::Leaf(LeafNode {
+            entries: vec![
+                (Bson::Int32(1), ids.clone()),
+                (Bson::Int64(2), vec![ids[0]]),
+                (Bson::String("three".into()), vec![ids[1]]),
+            ],
+            next: Some(Uuid::new_v4()),
+        });
+        let parent = Some(Uuid::new_v4());
+
+        // Writing puts the node in `<id>.json`
+        let node = DiskNode::new(&dir, parent, leaf.clone())?;
+        let p = std::path::Path::new(&dir).join(format!("{}.json", node.id));
+        assert!(p.is_file());
+        let json: serde_json::Value = serde_json::from_slice(&std::fs::read(&p)?)?;
+        assert_eq!(json["id"], serde_json::json!(node.id.to_string()));
+
+        // And loading reads the same node back, types and all
+        let loaded = DiskNode::load(&dir, node.id)?;
+        assert_eq!(loaded.id, node.id);
+        assert_eq!(loaded.parent, parent);
+        let (got, want) = (loaded.node.as_leaf()?, leaf.as_leaf()?);
+        assert_eq!(got.entries, want.entries);
+        assert_eq!(got.next, want.next);
+
+        // Deleting removes the same file
+        loaded.delete(&dir)?;
+        assert!(!p.exists());
+
+        // Internal nodes' keys keep their types too
+        let keys = vec![Bson::Int64(5), Bson::Double(6.5)];
+        let internal = Node::Internal(InternalNode {
+            keys: keys.clone(),
+            children: vec![Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4()],
+        });
+        let node = DiskNode::new(&dir, None, internal)?;
+        match DiskNode::load(&dir, node.id)?.node {
+            Node::Internal(int) => assert_eq!(int.keys, keys),
+            Node::Leaf(_) => panic!("expected an internal node"),
+        }
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn get_one_walks_two_levels() -> Result<()> {
+        let dir = temp_dir()?;
+        let mut tree = BPTree::new(&dir, "test", "val", false)?;
+        let ids: Vec<_> = (0..4).map(|_| ObjectId::new()).collect();
+
+        // Build a root with two leaves, split at "m"
+        let root = tree.create_node(
+            None,
+            Node::Internal(InternalNode {
+                keys: vec![],
+                children: vec![],
+            }),
+        )?;
+        let right = tree.create_node(
+            Some(root.id),
+            Node::Leaf(LeafNode {
+                entries: vec![
+                    (Bson::String("m".into()), vec![ids[2]]),
+                    (Bson::Boolean(true), vec![ids[3]]),
+                ],
+                next: None,
+            }),
+        )?;
+        let left = tree.create_node(
+            Some(root.id),
+            Node::Leaf(LeafNode {
+                entries: vec![
+                    (Bson::Int32(1), vec![ids[0]]),
+                    (Bson::Double(2.5), vec![ids[1]]),
+                ],
+                next: Some(right.id),
+            }),
+        )?;
+        tree.write_node(&DiskNode {
+            node: Node::Internal(InternalNode {
+                keys: vec![Bson::String("m".into())],
+                children: vec![left.id, right.id],
+            }),
+            ..root
+        })?;
+
+        // Existing values are found in either leaf
+        assert_eq!(tree.get_one(Bson::Int64(1))?, Some(ids[0]));
+        assert_eq!(tree.get_one(Bson::Double(2.5))?, Some(ids[1]));
+        assert_eq!(tree.get_one(Bson::String("m".into()))?, Some(ids[2]));
+        assert_eq!(tree.get_one(Bson::Boolean(true))?, Some(ids[3]));
+
+        // While missing ones aren't
+        assert_eq!(tree.get_one(Bson::Int32(2))?, None);
+        assert_eq!(tree.get_one(Bson::String("a".into()))?, None);
+        assert_eq!(tree.get_one(Bson::Boolean(false))?, None);
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn insert_splits() -> Result<()> {
+        let dir = temp_dir()?;
+        let mut tree = BPTree::with_order(&dir, "test", "num", true, 4)?;
+
+        // Insert enough values to split the leaves and the root
+        let ids: Vec<_> = (0..50).map(|_| ObjectId::new()).collect();
+        for (i, id) in ids.iter().enumerate() {
+            tree.insert(Bson::Int32(i as i32), *id)?;
+        }
+        assert!(
+            tree.meta.node_ids.len() > 5,
+            "Expected the tree to have split"
+        );
+
+        // Check that it's balanced and everything can be found
+        let keys = check_tree(&tree)?;
+        assert_eq!(keys.len(), 50);
+        for (i, id) in ids.iter().enumerate() {
+            assert_eq!(tree.get_one(Bson::Int32(i as i32))?, Some(*id));
+        }
+        assert_eq!(tree.get_one(Bson::Int32(100))?, None);
+
+        // A duplicate in a distinct index should error
+        assert!(tree.insert(Bson::Int32(1), ObjectId::new()).is_err());
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn insert_splits_leaf_then_root() -> Result<()> {
+        let dir = temp_dir()?;
+        let mut tree = BPTree::with_order(&dir, "test", "num", true, 3)?;
+
+        // Returns the number of levels in the tree
+        let depth = |tree: &BPTree| -> Result<usize> {
+            let mut depth = 0;
+            let mut next = tree.meta.root_node_id;
+            while let Some(id) = next {
+                depth += 1;
+                next = match tree.get_node(id)?.node {
+                    Node::Internal(int) => int.children.first().copied(),
+                    Node::Leaf(_) => None,
+                };
+            }
+            Ok(depth)
+        };
+
+        // A full leaf is still just the root
+        for i in 0..3 {
+            tree.insert(Bson::Int32(i), ObjectId::new())?;
+        }
+        assert_eq!(depth(&tree)?, 1);
+
+        // One more splits the leaf, adding a new root above it
+        tree.insert(Bson::Int32(3), ObjectId::new())?;
+        assert_eq!(depth(&tree)?, 2);
+        let root = tree.meta.root_node_id;
+
+        // Enough more splits the root itself
+        let mut i = 4;
+        while depth(&tree)? == 2 {
+            tree.insert(Bson::Int32(i), ObjectId::new())?;
+            i += 1;
+        }
+        assert_eq!(depth(&tree)?, 3);
+        assert_ne!(tree.meta.root_node_id, root);
+        assert_eq!(check_tree(&tree)?.len(), i as usize);
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn remove_keeps_other_ids() -> Result<()> {
+        let dir = temp_dir()?;
+        let mut tree = BPTree::with_order(&dir, "test", "name", false, 4)?;
+
+        // Add two ids under the same value
+        let (a, b) = (ObjectId::new(), ObjectId::new());
+        let v = Bson::String("brick".into());
+        tree.insert(v.clone(), a)?;
+        tree.insert(v.clone(), b)?;
+
+        // Removing one leaves the other
+        assert!(tree.remove(v.clone(), a)?);
+        assert_eq!(tree.get_one(v.clone())?, Some(b));
+
+        // Removing the last one removes the value
+        assert!(tree.remove(v.clone(), b)?);
+        assert!(!tree.has(v)?);
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn get_all_returns_every_id() -> Result<()> {
+        let dir = temp_dir()?;
+        let mut tree = BPTree::with_order(&dir, "test", "num", false, 4)?;
+
+        // Add several ids under each value (across several leaves)
+        let ids: Vec<Vec<_>> = (0..10)
+            .map(|_| (0..3).map(|_| ObjectId::new()).collect())
+            .collect();
+        for j in 0..3 {
+            for (i, value_ids) in ids.iter().enumerate() {
+                tree.insert(Bson::Int32(i as i32), value_ids[j])?;
+            }
+        }
+        check_tree(&tree)?;
+
+        // Each value gets all of its ids back
+        for (i, value_ids) in ids.iter().enumerate() {
+            assert_eq!(tree.get_all(Bson::Int32(i as i32))?, *value_ids);
+        }
+        assert!(tree.get_all(Bson::Int32(100))?.is_empty());
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn load_reads_every_node() -> Result<()> {
+        let dir = temp_dir()?;
+        let mut tree = BPTree::with_order(&dir, "test", "num", false, 4)?;
+        let id = ObjectId::new();
+        for i in 0..20 {
+            tree.insert(Bson::Int32(i), id)?;
+        }
+
+        // Loading reads each node up front...
+        let loaded = BPTree::load(dir.clone(), tree.meta.id)?;
+        assert!(loaded.is_loaded());
+        assert_eq!(loaded.nodes_read(), tree.meta.node_ids.len());
+
+        // ...so it fails if one is missing
+        let missing = tree.meta.node_ids[0];
+        std::fs::remove_file(node_path(&tree.dir_path, missing))?;
+        let err = BPTree::load(dir.clone(), tree.meta.id).err().unwrap();
+        assert!(err.to_string().contains(&missing.to_string()));
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn load_lazy_defers_nodes() -> Result<()> {
+        let dir = temp_dir()?;
+        let mut tree = BPTree::with_order(&dir, "test", "num", false, 4)?;
+        let id = ObjectId::new();
+        for i in 0..20 {
+            tree.insert(Bson::Int32(i), id)?;
+        }
+
+        // Hide the node files, so any read of them fails
+        let hidden = format!("{}-hidden", dir);
+        std::fs::create_dir_all(&hidden)?;
+        for node_id in tree.meta.node_ids.iter() {
+            std::fs::rename(
+                node_path(&tree.dir_path, *node_id),
+                node_path(&hidden, *node_id),
+            )?;
+        }
+
+        // Loading lazily only needs the metadata
+        let loaded = BPTree::load_lazy(dir.clone(), tree.meta.id)?;
+        assert_eq!(loaded.meta.node_ids, tree.meta.node_ids);
+        assert!(!loaded.is_loaded());
+
+        // While the first query loads the nodes, retrying if that fails
+        assert!(loaded.get_one(Bson::Int32(3)).is_err());
+        assert!(!loaded.is_loaded());
+        for node_id in tree.meta.node_ids.iter() {
+            std::fs::rename(
+                node_path(&hidden, *node_id),
+                node_path(&tree.dir_path, *node_id),
+            )?;
+        }
+        assert_eq!(loaded.get_one(Bson::Int32(3))?, Some(id));
+        assert!(loaded.is_loaded());
+
+        std::fs::remove_dir_all(&dir)?;
+        std::fs::remove_dir_all(&hidden)?;
+        Ok(())
+    }
+
+    #[test]
+    fn concurrent_first_queries_load_once() -> Result<()> {
+        let dir = temp_dir()?;
+        let mut tree = BPTree::with_order(&dir, "test", "num", false, 4)?;
+        let id = ObjectId::new();
+        for i in 0..50 {
+            tree.insert(Bson::Int32(i), id)?;
+        }
+        let loaded = BPTree::load_lazy(dir.clone(), tree.meta.id)?;
+
+        // Query the unloaded index from several threads at once
+        let n_threads = 8;
+        std::thread::scope(|s| {
+            let handles: Vec<_> = (0..n_threads)
+                .map(|i| {
+                    let loaded = &loaded;
+                    s.spawn(move || loaded.get_one(Bson::Int32(i * 5)))
+                })
+                .collect();
+            for h in handles {
+                assert_eq!(h.join().unwrap().unwrap(), Some(id));
+            }
+        });
+
+        // The nodes were only loaded once, plus each query's walk
+        // from the root to its leaf
+        let before = loaded.nodes_read();
+        loaded.get_one(Bson::Int32(0))?;
+        let depth = loaded.nodes_read() - before;
+        assert_eq!(
+            before,
+            tree.meta.node_ids.len() + n_threads as usize * depth
+        );
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn scan_range() -> Result<()> {
+        let dir = temp_dir()?;
+        let mut tree = BPTree::with_order(&dir, "test", "num", false, 4)?;
+
+        // Fill up the tree (across several leaves)
+        let ids: Vec<_> = (0..30).map(|_| ObjectId::new()).collect();
+        for (i, id) in ids.iter().enumerate() {
+            tree.insert(Bson::Int32(i as i32), *id)?;
+        }
+
+        // Scan a range, including both ends
+        let res = tree.scan(Bson::Int32(5), Bson::Int32(17))?;
+        assert_eq!(res, ids[5..=17].to_vec());
+
+        // Bounds don't need to be in the index (or the same type)
+        let res = tree.scan(Bson::Double(4.5), Bson::Int64(7))?;
+        assert_eq!(res, ids[5..=7].to_vec());
+
+        // Empty and backwards ranges find nothing
+        assert!(tree.scan(Bson::Int32(100), Bson::Int32(200))?.is_empty());
+        assert!(tree.scan(Bson::Int32(9), Bson::Int32(3))?.is_empty());
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn scan_follows_leaf_pointers() -> Result<()> {
+        let dir = temp_dir()?;
+        let mut tree = BPTree::with_order(&dir, "test", "num", false, 4)?;
+
+        // Add two ids under each value, so the values span many leaves
+        let mut want = vec![];
+        for i in 0..40 {
+            let pair = [ObjectId::new(), ObjectId::new()];
+            for id in pair {
+                tree.insert(Bson::Int32(i), id)?;
+            }
+            want.push(pair);
+        }
+
+        // Pick a range that starts and ends mid-way through different leaves
+        let (from, to) = (3, 33);
+        let first = tree.find_path(&Bson::Int32(from))?.pop().unwrap();
+        let last = tree.find_path(&Bson::Int32(to))?.pop().unwrap();
+        assert_ne!(first.id, last.id);
+        let mut n_leaves = 1;
+        let mut leaf = first.clone();
+        while leaf.id != last.id {
+            leaf = tree.get_node(leaf.node.as_leaf()?.next.unwrap())?;
+            n_leaves += 1;
+        }
+        assert!(n_leaves > 2, "the range only spans {} leaves", n_leaves);
+
+        // Scanning it returns every id in between, in key order
+        let res = tree.scan(Bson::Int32(from), Bson::Int32(to))?;
+        let expected: Vec<_> = want[from as usize..=to as usize]
+            .iter()
+            .flatten()
+            .copied()
+            .collect();
+        assert_eq!(res, expected);
+
+        // As does a scan of the whole index
+        let res = tree.scan(Bson::MinKey, Bson::MaxKey)?;
+        assert_eq!(res.len(), 80);
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn remove_merges() -> Result<()> {
+        let dir = temp_dir()?;
+        let mut tree = BPTree::with_order(&dir, "test", "num", false, 4)?;
+
+        // Fill up the tree
+        let ids: Vec<_> = (0..50).map(|_| ObjectId::new()).collect();
+        for (i, id) in ids.iter().enumerate() {
+            tree.insert(Bson::Int32(i as i32), *id)?;
+        }
+        let n_nodes = tree.meta.node_ids.len();
+
+        // Remove most of the values (out of order)
+        for i in (0..50).filter(|i| i % 5 != 0) {
+            assert!(tree.remove(Bson::Int32(i), ids[i as usize])?);
+            check_tree(&tree)?;
+        }
+        assert!(
+            tree.meta.node_ids.len() < n_nodes,
+            "Expected nodes to merge"
+        );
+
+        // The remaining values should still be found
+        for i in 0..50 {
+            let exp = if i % 5 == 0 {
+                Some(ids[i as usize])
+            } else {
+                None
+            };
+            assert_eq!(tree.get_one(Bson::Int32(i))?, exp);
+        }
+
+        // Removing a missing id should do nothing
+        assert!(!tree.remove(Bson::Int32(1), ids[1])?);
+
+        // Remove the rest, which should shrink back to a single leaf
+        for i in (0..50).filter(|i| i % 5 == 0) {
+            assert!(tree.remove(Bson::Int32(i), ids[i as usize])?);
+        }
+        assert_eq!(check_tree(&tree)?.len(), 0);
+        assert_eq!(tree.meta.node_ids.len(), 1);
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
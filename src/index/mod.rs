@@ -1,3 +1,4 @@
 //! Code for managing table indexes.
 
 pub mod bptree;
+pub mod order;
@@ -0,0 +1,104 @@
+//! A total ordering over `Bson` values, used for index keys.
+
+use bson::Bson;
+use std::cmp::Ordering;
+
+/// Returns the sort rank of a `Bson` value's type.
+///
+/// Values of different types are ordered by their type's rank,
+/// (roughly) following MongoDB's comparison order. All numeric
+/// types share a rank so they compare by value.
+fn type_rank(value: &Bson) -> u8 {
+    match value {
+        Bson::MinKey => 0,
+        Bson::Null | Bson::Undefined => 1,
+        Bson::Int32(_) | Bson::Int64(_) | Bson::Double(_) | Bson::Decimal128(_) => 2,
+        Bson::String(_) | Bson::Symbol(_) => 3,
+        Bson::Document(_) => 4,
+        Bson::Array(_) => 5,
+        Bson::Binary(_) => 6,
+        Bson::ObjectId(_) => 7,
+        Bson::Boolean(_) => 8,
+        Bson::DateTime(_) => 9,
+        Bson::Timestamp(_) => 10,
+        Bson::RegularExpression(_) => 11,
+        Bson::JavaScriptCode(_) | Bson::JavaScriptCodeWithScope(_) => 12,
+        Bson::DbPointer(_) => 13,
+        Bson::MaxKey => 14,
+    }
+}
+
+/// Converts a numeric `Bson` value to an `f64` for comparison.
+fn as_f64(value: &Bson) -> Option<f64> {
+    match value {
+        Bson::Int32(n) => Some(*n as f64),
+        Bson::Int64(n) => Some(*n as f64),
+        Bson::Double(n) => Some(*n),
+        _ => None,
+    }
+}
+
+/// Compares two `Bson` values using a well-defined total order.
+///
+/// Values are first ordered by type (see [type_rank]) and then by
+/// value. Numbers compare by numeric value across `Int32`, `Int64`,
+/// and `Double`; strings compare lexicographically; `false < true`.
+/// Types without a natural ordering fall back to comparing their
+/// string representations, so the order is still total.
+pub fn cmp_bson(a: &Bson, b: &Bson) -> Ordering {
+    // Compare by type first...
+    let (ra, rb) = (type_rank(a), type_rank(b));
+    if ra != rb {
+        return ra.cmp(&rb);
+    }
+
+    // Then by value...
+    match (a, b) {
+        (Bson::Int32(x), Bson::Int32(y)) => x.cmp(y),
+        (Bson::Int64(x), Bson::Int64(y)) => x.cmp(y),
+        (Bson::String(x), Bson::String(y)) => x.cmp(y),
+        (Bson::Boolean(x), Bson::Boolean(y)) => x.cmp(y),
+        (Bson::ObjectId(x), Bson::ObjectId(y)) => x.cmp(y),
+        (Bson::DateTime(x), Bson::DateTime(y)) => x.cmp(y),
+        (Bson::Timestamp(x), Bson::Timestamp(y)) => {
+            (x.time, x.increment).cmp(&(y.time, y.increment))
+        }
+        (Bson::Null, Bson::Null) | (Bson::MinKey, Bson::MinKey) | (Bson::MaxKey, Bson::MaxKey) => {
+            Ordering::Equal
+        }
+        _ => match (as_f64(a), as_f64(b)) {
+            (Some(x), Some(y)) => x.total_cmp(&y),
+            _ => a.to_string().cmp(&b.to_string()),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbers_compare_by_value() {
+        assert_eq!(cmp_bson(&Bson::Int32(1), &Bson::Int64(2)), Ordering::Less);
+        assert_eq!(
+            cmp_bson(&Bson::Double(2.5), &Bson::Int32(2)),
+            Ordering::Greater
+        );
+        assert_eq!(
+            cmp_bson(&Bson::Int64(3), &Bson::Double(3.0)),
+            Ordering::Equal
+        );
+    }
+
+    #[test]
+    fn types_compare_by_rank() {
+        let s = Bson::String("a".into());
+        assert_eq!(cmp_bson(&Bson::Null, &Bson::Int32(0)), Ordering::Less);
+        assert_eq!(cmp_bson(&Bson::Int32(100), &s), Ordering::Less);
+        assert_eq!(cmp_bson(&s, &Bson::Boolean(false)), Ordering::Less);
+        assert_eq!(
+            cmp_bson(&Bson::Boolean(false), &Bson::Boolean(true)),
+            Ordering::Less
+        );
+    }
+}
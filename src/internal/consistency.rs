@@ -0,0 +1,192 @@
+//! Read consistency levels for a replicated collection.
+//!
+//! Given the primary's copy of a collection, its replicas' copies, and
+//! a [LagMonitor] tracking how far each replica has caught up, this
+//! picks which copies to read for a given [ReadConsistency]. The `Get`
+//! and `Scan` RPCs route their reads through it.
+//!
+//! Note: Replication itself doesn't exist yet, so keeping the replicas
+//! (and the [LagMonitor]) up to date is left to the caller.
+
+use anyhow::Result;
+use bson::oid::ObjectId;
+use bson::Document;
+
+use crate::db::collection::Collection;
+use crate::internal::lag::{LagMonitor, Lsn};
+
+/// How up to date a read must be.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReadConsistency {
+    /// Read from the primary, so the latest write is always seen.
+    #[default]
+    Strong,
+
+    /// Read from the nearest replica. This is faster but may return
+    /// stale data if the replica is behind.
+    Eventual,
+
+    /// Read from a majority of the nodes and return the result from
+    /// whichever is furthest along (by LSN).
+    Quorum,
+}
+
+/// A copy of a collection that reads can be served from.
+pub struct ReadNode<'a> {
+    /// The node's name (as reported to the [LagMonitor]).
+    pub name: &'a str,
+
+    /// The node's copy of the collection.
+    pub coll: &'a Collection,
+}
+
+/// Routes reads across a collection's primary and replicas.
+pub struct ReplicaReader<'a> {
+    /// The primary's copy of the collection.
+    pub primary: &'a Collection,
+
+    /// The replicas' copies, nearest first.
+    pub replicas: Vec<ReadNode<'a>>,
+
+    /// Tracks how far each replica has caught up to the primary.
+    pub lag: &'a LagMonitor,
+}
+
+impl<'a> ReplicaReader<'a> {
+    /// Picks the copies to read for the given consistency level, along
+    /// with the LSN each has caught up to.
+    fn targets(&self, consistency: ReadConsistency) -> Vec<(&'a Collection, Lsn)> {
+        let primary = (self.primary, self.lag.primary_lsn());
+        let mut replicas = self
+            .replicas
+            .iter()
+            .map(|r| (r.coll, self.lag.acked(r.name).unwrap_or(0)));
+        match consistency {
+            ReadConsistency::Strong => vec![primary],
+            ReadConsistency::Eventual => match replicas.next() {
+                Some(replica) => vec![replica],
+                None => vec![primary],
+            },
+            ReadConsistency::Quorum => {
+                // Prefer the (nearer) replicas, filling in with the primary...
+                let nodes = self.replicas.len() + 1;
+                let majority = nodes / 2 + 1;
+                let mut targets: Vec<_> = replicas.take(majority).collect();
+                if targets.len() < majority {
+                    targets.push(primary);
+                }
+                targets
+            }
+        }
+    }
+
+    /// Picks the copy to read for the given consistency level: the one
+    /// furthest along (by LSN) of its targets, or the first of them if
+    /// they're tied.
+    pub fn pick(&self, consistency: ReadConsistency) -> &'a Collection {
+        let mut newest: Option<(&'a Collection, Lsn)> = None;
+        for (coll, lsn) in self.targets(consistency) {
+            match newest {
+                Some((_, n)) if n >= lsn => {}
+                _ => newest = Some((coll, lsn)),
+            }
+        }
+        newest.map(|(coll, _)| coll).unwrap_or(self.primary)
+    }
+
+    /// Gets a document with the given consistency level.
+    pub async fn get(
+        &self,
+        key: &ObjectId,
+        consistency: ReadConsistency,
+    ) -> Result<Option<Document>> {
+        self.pick(consistency).get(key).await
+    }
+
+    /// Gets all documents with keys in the given range (inclusive),
+    /// sorted by key, with the given consistency level.
+    pub async fn scan(
+        &self,
+        start: &ObjectId,
+        end: &ObjectId,
+        consistency: ReadConsistency,
+    ) -> Result<Vec<Document>> {
+        let docs = self.scan_with_keys(start, end, None, consistency).await?;
+        Ok(docs.into_iter().map(|(_, doc)| doc).collect())
+    }
+
+    /// Like [ReplicaReader::scan], but returns each document with its
+    /// key and stops after `limit` documents, so a large range can be
+    /// paged through (see [Collection::get_range_with_keys]).
+    pub async fn scan_with_keys(
+        &self,
+        start: &ObjectId,
+        end: &ObjectId,
+        limit: Option<usize>,
+        consistency: ReadConsistency,
+    ) -> Result<Vec<(ObjectId, Document)>> {
+        let coll = self.pick(consistency);
+        coll.get_range_with_keys(start, end, limit).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::lsm::Durability;
+    use bson::doc;
+
+    #[tokio::test]
+    async fn strong_and_eventual_reads_with_lagging_replica() -> Result<()> {
+        let mut primary = Collection::new("test", "/tmp").with_durability(Durability::InMemory);
+        let mut replica = Collection::new("test", "/tmp").with_durability(Durability::InMemory);
+        let lag = LagMonitor::new(0);
+        let key = ObjectId::new();
+
+        // Write a value to both, then update only the primary...
+        primary.set(&key, doc! { "v": 1 }).await?;
+        replica.set(&key, doc! { "v": 1 }).await?;
+        lag.advance(1);
+        lag.ack("replica", 1);
+        primary.set(&key, doc! { "v": 2 }).await?;
+        lag.advance(2);
+
+        let reader = ReplicaReader {
+            primary: &primary,
+            replicas: vec![ReadNode {
+                name: "replica",
+                coll: &replica,
+            }],
+            lag: &lag,
+        };
+
+        // Strong reads go to the primary...
+        let doc = reader.get(&key, ReadConsistency::Strong).await?;
+        assert_eq!(doc, Some(doc! { "v": 2 }));
+
+        // Eventual reads go to the lagging replica...
+        let doc = reader.get(&key, ReadConsistency::Eventual).await?;
+        assert_eq!(doc, Some(doc! { "v": 1 }));
+
+        // Quorum reads (both nodes, out of two) pick the newest...
+        let doc = reader.get(&key, ReadConsistency::Quorum).await?;
+        assert_eq!(doc, Some(doc! { "v": 2 }));
+        let docs = reader.scan(&key, &key, ReadConsistency::Quorum).await?;
+        assert_eq!(docs, vec![doc! { "v": 2 }]);
+
+        // Once the replica catches up, eventual reads see the update...
+        replica.set(&key, doc! { "v": 2 }).await?;
+        lag.ack("replica", 2);
+        let reader = ReplicaReader {
+            primary: &primary,
+            replicas: vec![ReadNode {
+                name: "replica",
+                coll: &replica,
+            }],
+            lag: &lag,
+        };
+        let docs = reader.scan(&key, &key, ReadConsistency::Eventual).await?;
+        assert_eq!(docs, vec![doc! { "v": 2 }]);
+        Ok(())
+    }
+}
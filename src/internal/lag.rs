@@ -0,0 +1,154 @@
+//! Tracking of how far replicas are behind the primary.
+//!
+//! Note: Replication itself doesn't exist yet. This is the bookkeeping
+//! the primary will need once it does -- the replication stream should
+//! call [LagMonitor::advance] as the primary writes and
+//! [LagMonitor::ack] as each replica acknowledges.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+/// A log sequence number, identifying a position in the primary's log.
+pub type Lsn = u64;
+
+#[derive(Debug, Default)]
+struct LagState {
+    /// The primary's latest LSN.
+    primary: Lsn,
+
+    /// The last LSN acknowledged by each replica.
+    replicas: HashMap<String, Lsn>,
+}
+
+/// Tracks each replica's last-acknowledged LSN against the primary's.
+#[derive(Debug, Clone)]
+pub struct LagMonitor {
+    /// The lag (in LSNs) above which a replica is reported.
+    pub threshold: Lsn,
+
+    state: Arc<Mutex<LagState>>,
+}
+
+impl LagMonitor {
+    /// Creates a new monitor that reports replicas more than
+    /// `threshold` LSNs behind.
+    pub fn new(threshold: Lsn) -> Self {
+        LagMonitor {
+            threshold,
+            state: Arc::new(Mutex::new(LagState::default())),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, LagState> {
+        match self.state.lock() {
+            Ok(s) => s,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    /// Records the primary's latest LSN.
+    pub fn advance(&self, lsn: Lsn) {
+        let mut state = self.lock();
+        state.primary = state.primary.max(lsn);
+    }
+
+    /// Records the latest LSN acknowledged by the given replica.
+    pub fn ack(&self, replica: &str, lsn: Lsn) {
+        let mut state = self.lock();
+        let acked = state.replicas.entry(replica.to_string()).or_default();
+        *acked = (*acked).max(lsn);
+    }
+
+    /// Stops tracking the given replica.
+    pub fn remove(&self, replica: &str) {
+        self.lock().replicas.remove(replica);
+    }
+
+    /// Returns the primary's latest LSN.
+    pub fn primary_lsn(&self) -> Lsn {
+        self.lock().primary
+    }
+
+    /// Returns the last LSN acknowledged by the given replica, if
+    /// it's being tracked.
+    pub fn acked(&self, replica: &str) -> Option<Lsn> {
+        self.lock().replicas.get(replica).copied()
+    }
+
+    /// Returns how far behind each replica is, in LSNs.
+    pub fn lags(&self) -> HashMap<String, Lsn> {
+        let state = self.lock();
+        state
+            .replicas
+            .iter()
+            .map(|(name, acked)| (name.clone(), state.primary.saturating_sub(*acked)))
+            .collect()
+    }
+
+    /// Returns the replicas that are further behind than the threshold.
+    pub fn lagging(&self) -> Vec<(String, Lsn)> {
+        let mut lagging: Vec<_> = self
+            .lags()
+            .into_iter()
+            .filter(|(_, lag)| *lag > self.threshold)
+            .collect();
+        lagging.sort();
+        lagging
+    }
+
+    /// Spawns a background task that checks the replicas' lag every
+    /// `interval` and logs any that are over the threshold.
+    pub fn spawn_reporter(&self, interval: Duration) -> JoinHandle<()> {
+        let monitor = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                for (replica, lag) in monitor.lagging() {
+                    tracing::warn!(
+                        %replica,
+                        lag,
+                        threshold = monitor.threshold,
+                        "Replica is behind the primary"
+                    );
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lag_grows_and_shrinks() {
+        let monitor = LagMonitor::new(10);
+
+        // Both replicas keep up at first...
+        monitor.advance(5);
+        monitor.ack("fast", 5);
+        monitor.ack("slow", 5);
+        assert_eq!(monitor.lags()["slow"], 0);
+        assert!(monitor.lagging().is_empty());
+
+        // The slow replica falls behind...
+        monitor.advance(50);
+        monitor.ack("fast", 50);
+        monitor.ack("slow", 20);
+        assert_eq!(monitor.lags()["fast"], 0);
+        assert_eq!(monitor.lags()["slow"], 30);
+        assert_eq!(monitor.lagging(), vec![("slow".to_string(), 30)]);
+
+        // Then catches up...
+        monitor.ack("slow", 45);
+        assert_eq!(monitor.lags()["slow"], 5);
+        assert!(monitor.lagging().is_empty());
+
+        // Out-of-order acks don't move it backwards...
+        monitor.ack("slow", 1);
+        assert_eq!(monitor.lags()["slow"], 5);
+    }
+}
@@ -1,6 +1,8 @@
 //! Internal gRPC API for communications between databse nodes.
 
 pub mod client;
+pub mod consistency;
+pub mod lag;
 pub mod server;
 
 pub mod gen {
@@ -1 +1,84 @@
 //! This module handles logging for the database.
+//!
+//! Logs are written with [tracing]. The storage layer's hot paths
+//! (reads, memtable flushes, compactions, and table I/O) run in spans,
+//! which record how many keys they touched, and [init] logs each span
+//! as it closes, along with how long it took.
+
+use anyhow::{anyhow, Result};
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::EnvFilter;
+
+/// The default log level (see [LogConfig::level]).
+pub const DEFAULT_LOG_LEVEL: &str = "info";
+
+/// How log lines are formatted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    /// Multi-line, human-readable output.
+    #[default]
+    Pretty,
+
+    /// One JSON object per line, for log collectors.
+    Json,
+}
+
+/// Settings for the database's logging.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogConfig {
+    /// Which logs to write, as an `EnvFilter` directive (e.g. `"info"`
+    /// or `"brickdb_lib::storage=debug"`).
+    pub level: String,
+
+    /// How log lines are formatted.
+    pub format: LogFormat,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            level: DEFAULT_LOG_LEVEL.to_string(),
+            format: LogFormat::default(),
+        }
+    }
+}
+
+/// Sets up logging for the process.
+///
+/// Returns an error if the level isn't a valid filter, or if logging
+/// has already been set up.
+pub fn init(config: &LogConfig) -> Result<()> {
+    let filter = EnvFilter::try_new(&config.level)
+        .map_err(|err| anyhow!("Invalid log level {:?}: {}", config.level, err))?;
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_span_events(FmtSpan::CLOSE);
+    let res = match config.format {
+        LogFormat::Pretty => builder.pretty().try_init(),
+        LogFormat::Json => builder.json().try_init(),
+    };
+    res.map_err(|err| anyhow!("Couldn't set up logging: {}", err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn init_checks_the_level() {
+        let config = LogConfig {
+            level: "not a [level".to_string(),
+            format: LogFormat::Json,
+        };
+        assert!(init(&config).is_err());
+
+        // Logging can only be set up once...
+        // (Only warnings, to keep the other tests' output quiet.)
+        let config = LogConfig {
+            level: "warn".to_string(),
+            format: LogFormat::Json,
+        };
+        assert!(init(&config).is_ok());
+        assert!(init(&config).is_err());
+    }
+}
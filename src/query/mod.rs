@@ -1 +1,3 @@
 //! This module handles query planning and execution.
+
+pub mod planner;
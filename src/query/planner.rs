@@ -0,0 +1,155 @@
+//! A simple cost-based planner for single-field queries.
+
+use anyhow::{anyhow, Result};
+use bson::{Bson, Document};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use crate::index::bptree::BPTree;
+use crate::index::order::cmp_bson;
+
+/// The estimated fraction of documents matching an equality filter
+/// on a non-distinct field.
+pub const EQ_SELECTIVITY: f64 = 0.1;
+
+/// The estimated fraction of documents matching a range filter.
+pub const RANGE_SELECTIVITY: f64 = 1.0 / 3.0;
+
+/// The cost of fetching a single document by id (after an index
+/// lookup), relative to reading a document during a full scan.
+///
+/// Full scans read tables sequentially, while index lookups fetch
+/// documents one at a time, so each fetch costs more.
+pub const INDEX_FETCH_COST: f64 = 4.0;
+
+/// A filter on a single field of a collection's documents.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+    /// Matches documents where `field` equals `value`.
+    Eq { field: String, value: Bson },
+
+    /// Matches documents where `field` is between `from` and `to`
+    /// (inclusive), using the `Bson` total ordering.
+    Range { field: String, from: Bson, to: Bson },
+}
+
+impl Filter {
+    /// Returns the field being filtered on.
+    pub fn field(&self) -> &str {
+        match self {
+            Filter::Eq { field, .. } | Filter::Range { field, .. } => field,
+        }
+    }
+
+    /// Returns the (inclusive) bounds of the values the filter matches.
+    pub fn bounds(&self) -> (Bson, Bson) {
+        match self {
+            Filter::Eq { value, .. } => (value.clone(), value.clone()),
+            Filter::Range { from, to, .. } => (from.clone(), to.clone()),
+        }
+    }
+
+    /// Checks if a document matches the filter.
+    pub fn matches(&self, doc: &Document) -> bool {
+        let value = match doc.get(self.field()) {
+            Some(value) => value,
+            None => return false,
+        };
+        let (from, to) = self.bounds();
+        cmp_bson(value, &from) != Ordering::Less && cmp_bson(value, &to) != Ordering::Greater
+    }
+}
+
+/// A query against a collection.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Query {
+    /// Which documents to return.
+    pub filter: Filter,
+
+    /// If set, the name of the index to use, overriding the planner.
+    ///
+    /// Planning errors if the index doesn't exist or isn't on the
+    /// filtered field.
+    pub index_hint: Option<String>,
+}
+
+impl Query {
+    /// Creates a new query, with no index hint.
+    pub fn new(filter: Filter) -> Self {
+        Query {
+            filter,
+            index_hint: None,
+        }
+    }
+
+    /// Forces the query to use the named index.
+    pub fn with_index_hint(mut self, index: &str) -> Self {
+        self.index_hint = Some(index.to_string());
+        self
+    }
+}
+
+/// How a query will be executed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Plan {
+    /// Read every document and filter them.
+    FullScan,
+
+    /// Look up matching ids in the named index, then fetch them.
+    IndexScan { index: String },
+}
+
+/// Picks how to execute a query.
+///
+/// If the query has an index hint, that index is used. Otherwise, an
+/// index on the filtered field is used only if its estimated cost (the
+/// estimated matches times [INDEX_FETCH_COST]) is lower than scanning
+/// all `num_docs` documents.
+///
+/// # Arguments
+///
+/// * `query` - The query to plan.
+/// * `indexes` - The collection's indexes, by name.
+/// * `num_docs` - The (estimated) number of documents in the collection.
+pub fn plan(query: &Query, indexes: &HashMap<String, BPTree>, num_docs: usize) -> Result<Plan> {
+    let field = query.filter.field();
+
+    // Use the hinted index, if there is one...
+    if let Some(name) = &query.index_hint {
+        let index = indexes
+            .get(name)
+            .ok_or(anyhow!("Hinted index {:?} not found", name))?;
+        if index.meta.key != field {
+            return Err(anyhow!(
+                "Hinted index {:?} is on {:?}, not {:?}",
+                name,
+                index.meta.key,
+                field
+            ));
+        }
+        return Ok(Plan::IndexScan {
+            index: name.clone(),
+        });
+    }
+
+    // Otherwise, find the cheapest index on the field (if any)...
+    let scan_cost = num_docs as f64;
+    let best = indexes
+        .iter()
+        .filter(|(_, index)| index.meta.key == field)
+        .map(|(name, index)| {
+            let matches = match (&query.filter, index.meta.distinct) {
+                (Filter::Eq { .. }, true) => 1.0,
+                (Filter::Eq { .. }, false) => num_docs as f64 * EQ_SELECTIVITY,
+                (Filter::Range { .. }, _) => num_docs as f64 * RANGE_SELECTIVITY,
+            };
+            (name, matches * INDEX_FETCH_COST)
+        })
+        .min_by(|a, b| a.1.total_cmp(&b.1));
+    match best {
+        Some((name, cost)) if cost < scan_cost => Ok(Plan::IndexScan {
+            index: name.clone(),
+        }),
+        _ => Ok(Plan::FullScan),
+    }
+}
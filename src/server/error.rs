@@ -0,0 +1,147 @@
+//! Maps storage errors to gRPC statuses.
+//!
+//! Handlers should convert errors with `Status::from` (or `?`) so
+//! every RPC reports the same error the same way.
+
+use prost::Message;
+use tonic::codegen::Bytes;
+use tonic::{Code, Status};
+
+use super::gen::{ErrorCode, ErrorDetail};
+use crate::db::ratelimit::RateLimitError;
+use crate::storage::error::StorageError;
+
+impl From<StorageError> for Status {
+    fn from(err: StorageError) -> Self {
+        let (code, error_code) = match &err {
+            StorageError::NotFound(_) => (Code::NotFound, ErrorCode::NotFound),
+            StorageError::Corruption(_) => (Code::DataLoss, ErrorCode::Corruption),
+            StorageError::Full(_) => (Code::ResourceExhausted, ErrorCode::Full),
+            StorageError::Io(_) => (Code::Unavailable, ErrorCode::Io),
+            StorageError::Serialization(_) => (Code::Internal, ErrorCode::Serialization),
+        };
+        with_detail(code, error_code, err.message())
+    }
+}
+
+impl From<RateLimitError> for Status {
+    fn from(err: RateLimitError) -> Self {
+        let (code, error_code) = match &err {
+            RateLimitError::Exceeded => (Code::ResourceExhausted, ErrorCode::RateLimited),
+            RateLimitError::TooLarge { .. } => (Code::InvalidArgument, ErrorCode::WriteTooLarge),
+        };
+        with_detail(code, error_code, &err.to_string())
+    }
+}
+
+/// Creates a status with an [ErrorDetail] attached.
+fn with_detail(code: Code, error_code: ErrorCode, message: &str) -> Status {
+    let detail = ErrorDetail {
+        code: error_code as i32,
+        message: message.to_string(),
+    };
+    Status::with_details(code, message, Bytes::from(detail.encode_to_vec()))
+}
+
+/// Converts an error from the database to a status.
+///
+/// Storage and rate limit errors keep their mappings (see
+/// `From<StorageError>` and `From<RateLimitError>`), while anything
+/// else is reported as an internal error.
+pub fn status_from_anyhow(err: anyhow::Error) -> Status {
+    let err = match err.downcast::<StorageError>() {
+        Ok(err) => return err.into(),
+        Err(err) => err,
+    };
+    match err.downcast::<RateLimitError>() {
+        Ok(err) => err.into(),
+        Err(err) => Status::internal(format!("{:#}", err)),
+    }
+}
+
+/// Decodes the [ErrorDetail] attached to a status, if it has one.
+pub fn error_detail(status: &Status) -> Option<ErrorDetail> {
+    if status.details().is_empty() {
+        return None;
+    }
+    ErrorDetail::decode(status.details()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn storage_errors_map_to_statuses() {
+        let cases = [
+            (
+                StorageError::NotFound("no table".to_string()),
+                Code::NotFound,
+                ErrorCode::NotFound,
+            ),
+            (
+                StorageError::Corruption("bad checksum".to_string()),
+                Code::DataLoss,
+                ErrorCode::Corruption,
+            ),
+            (
+                StorageError::Full("memtable full".to_string()),
+                Code::ResourceExhausted,
+                ErrorCode::Full,
+            ),
+            (
+                StorageError::Io("disk gone".to_string()),
+                Code::Unavailable,
+                ErrorCode::Io,
+            ),
+            (
+                StorageError::Serialization("bad bson".to_string()),
+                Code::Internal,
+                ErrorCode::Serialization,
+            ),
+        ];
+        for (err, code, error_code) in cases {
+            let message = err.message().to_string();
+            let status = Status::from(err);
+            assert_eq!(status.code(), code);
+            assert_eq!(status.message(), message);
+
+            // The detail should carry the same error...
+            let detail = error_detail(&status).expect("status should have a detail");
+            assert_eq!(detail.code(), error_code);
+            assert_eq!(detail.message, message);
+        }
+
+        // Statuses from elsewhere don't have a detail...
+        assert!(error_detail(&Status::internal("oops")).is_none());
+
+        // Storage errors keep their mapping through anyhow...
+        let err = anyhow::Error::from(StorageError::Full("memtable full".to_string()));
+        assert_eq!(status_from_anyhow(err).code(), Code::ResourceExhausted);
+        let status = status_from_anyhow(anyhow::anyhow!("oops"));
+        assert_eq!(status.code(), Code::Internal);
+        assert!(error_detail(&status).is_none());
+    }
+
+    #[test]
+    fn rate_limited_writes_are_resource_exhausted() {
+        let status = status_from_anyhow(anyhow::Error::from(RateLimitError::Exceeded));
+        assert_eq!(status.code(), Code::ResourceExhausted);
+        let detail = error_detail(&status).expect("status should have a detail");
+        assert_eq!(detail.code(), ErrorCode::RateLimited);
+        assert_eq!(detail.message, RateLimitError::Exceeded.to_string());
+    }
+
+    #[test]
+    fn writes_too_large_for_the_limit_are_invalid() {
+        let err = RateLimitError::TooLarge {
+            cost: 150.0,
+            burst: 100.0,
+        };
+        let status = status_from_anyhow(anyhow::Error::from(err.clone()));
+        assert_eq!(status.code(), Code::InvalidArgument);
+        let detail = error_detail(&status).expect("status should have a detail");
+        assert_eq!(detail.code(), ErrorCode::WriteTooLarge);
+        assert_eq!(detail.message, err.to_string());
+    }
+}
@@ -1,4 +1,7 @@
 pub mod gen {
     tonic::include_proto!("brickdb.v0");
 }
+pub mod error;
+pub mod prometheus;
+pub mod selftest;
 pub mod server;
@@ -0,0 +1,260 @@
+//! An HTTP endpoint exposing the database's storage metrics to
+//! Prometheus.
+//!
+//! The endpoint serves each collection's [Metrics] (see
+//! [crate::storage::lsm::LSMTree::metrics]) at [METRICS_PATH], in
+//! Prometheus' text format, labelled by collection. It runs on its own
+//! task, alongside the gRPC server, and only holds the database's lock
+//! long enough to copy the counters.
+
+use anyhow::Result;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+
+use crate::db::database::Database;
+use crate::storage::metrics::{LevelSize, Metrics, MetricsSnapshot};
+
+/// The path the metrics are served from.
+pub const METRICS_PATH: &str = "/metrics";
+
+/// The content type of Prometheus' text format.
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// The prefix of each metric's name.
+pub const METRIC_PREFIX: &str = "brickdb";
+
+/// A counter, and how to read it from a snapshot.
+type Counter = (&'static str, &'static str, fn(&MetricsSnapshot) -> u64);
+
+/// The counters reported for each collection.
+const COUNTERS: &[Counter] = &[
+    ("gets_total", "Gets from the collection.", |s| s.gets),
+    (
+        "memtable_hits_total",
+        "Gets answered by the memtable.",
+        |s| s.memtable_hits,
+    ),
+    (
+        "memtable_flushes_total",
+        "Memtables flushed to disk.",
+        |s| s.flushes,
+    ),
+    ("compactions_total", "Level compactions.", |s| s.compactions),
+    ("bytes_read_total", "Bytes of tables read from disk.", |s| {
+        s.bytes_read
+    }),
+    (
+        "bytes_written_total",
+        "Bytes of tables written to disk.",
+        |s| s.bytes_written,
+    ),
+    ("bloom_hits_total", "Reads a bloom filter ruled out.", |s| {
+        s.bloom_hits
+    }),
+    (
+        "bloom_misses_total",
+        "Reads a bloom filter couldn't rule out.",
+        |s| s.bloom_misses,
+    ),
+    ("cache_hits_total", "Reads served from memory.", |s| {
+        s.cache_hits
+    }),
+    ("cache_misses_total", "Tables read from disk.", |s| {
+        s.cache_misses
+    }),
+];
+
+/// A per-level value, and how to read it from a level's size and hits.
+type LevelMetric = (
+    &'static str,
+    &'static str,
+    &'static str,
+    fn(&LevelSize, u64) -> u64,
+);
+
+/// The values reported for each level of each collection.
+const LEVEL_METRICS: &[LevelMetric] = &[
+    ("level_tables", "gauge", "Tables in the level.", |l, _| {
+        l.tables
+    }),
+    (
+        "level_records",
+        "gauge",
+        "Records in the level's tables.",
+        |l, _| l.records,
+    ),
+    (
+        "level_hits_total",
+        "counter",
+        "Gets answered by the level.",
+        |_, hits| hits,
+    ),
+];
+
+/// Copies the metrics of each of the database's collections, sorted by
+/// collection name.
+pub async fn collect(db: &Mutex<Database>) -> Vec<(String, MetricsSnapshot)> {
+    let metrics: Vec<(String, Arc<Metrics>)> = {
+        let db = db.lock().await;
+        db.collections
+            .iter()
+            .map(|(name, coll)| (name.clone(), coll.tree.metrics.clone()))
+            .collect()
+    };
+    let mut snaps: Vec<_> = metrics
+        .into_iter()
+        .map(|(name, m)| (name, m.snapshot()))
+        .collect();
+    snaps.sort_by(|a, b| a.0.cmp(&b.0));
+    snaps
+}
+
+/// Formats the collections' metrics in Prometheus' text format.
+///
+/// # Arguments
+///
+/// * `collections` - Each collection's name and metrics.
+pub fn render(collections: &[(String, MetricsSnapshot)]) -> String {
+    let mut out = String::new();
+
+    // Write the collection-wide counters...
+    for (name, help, value) in COUNTERS {
+        let _ = writeln!(out, "# HELP {METRIC_PREFIX}_{name} {help}");
+        let _ = writeln!(out, "# TYPE {METRIC_PREFIX}_{name} counter");
+        for (coll, snap) in collections {
+            let _ = writeln!(
+                out,
+                "{METRIC_PREFIX}_{name}{{collection={coll:?}}} {}",
+                value(snap)
+            );
+        }
+    }
+
+    // Then the per-level values...
+    for (name, kind, help, value) in LEVEL_METRICS {
+        let _ = writeln!(out, "# HELP {METRIC_PREFIX}_{name} {help}");
+        let _ = writeln!(out, "# TYPE {METRIC_PREFIX}_{name} {kind}");
+        for (coll, snap) in collections {
+            let n = snap.levels.len().max(snap.level_hits.len());
+            for i in 0..n {
+                let size = snap.levels.get(i).copied().unwrap_or_default();
+                let hits = snap.level_hits.get(i).copied().unwrap_or_default();
+                let _ = writeln!(
+                    out,
+                    "{METRIC_PREFIX}_{name}{{collection={coll:?},level=\"{}\"}} {}",
+                    i + 1,
+                    value(&size, hits)
+                );
+            }
+        }
+    }
+    out
+}
+
+/// Handles a request to the metrics endpoint.
+async fn handle(
+    db: Arc<Mutex<Database>>,
+    req: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    let res = if req.method() == Method::GET && req.uri().path() == METRICS_PATH {
+        Response::builder()
+            .header(hyper::header::CONTENT_TYPE, CONTENT_TYPE)
+            .body(Body::from(render(&collect(&db).await)))
+    } else {
+        Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())
+    };
+    Ok(res.unwrap_or_default())
+}
+
+/// Starts serving the database's metrics from `listener`, on a new
+/// task.
+///
+/// # Arguments
+///
+/// * `listener` - The (already bound) listener to accept scrapes on.
+/// * `db` - The database whose collections' metrics are served.
+///
+/// # Returns
+///
+/// The task running the endpoint, which runs until it's aborted (or
+/// the listener fails).
+pub fn spawn_metrics_endpoint(
+    listener: std::net::TcpListener,
+    db: Arc<Mutex<Database>>,
+) -> Result<JoinHandle<Result<()>>> {
+    listener.set_nonblocking(true)?;
+    let make_svc = make_service_fn(move |_conn| {
+        let db = db.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |req| handle(db.clone(), req))) }
+    });
+    let server = hyper::Server::from_tcp(listener)?.serve(make_svc);
+    Ok(tokio::spawn(async move {
+        server.await?;
+        Ok(())
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bson::doc;
+    use bson::oid::ObjectId;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Sends a GET request for `path` and returns the raw response.
+    async fn http_get(addr: std::net::SocketAddr, path: &str) -> Result<String> {
+        let mut stream = tokio::net::TcpStream::connect(addr).await?;
+        let req = format!("GET {path} HTTP/1.1\r\nHost: {addr}\r\nConnection: close\r\n\r\n");
+        stream.write_all(req.as_bytes()).await?;
+        let mut res = String::new();
+        stream.read_to_string(&mut res).await?;
+        Ok(res)
+    }
+
+    #[tokio::test]
+    async fn serves_collection_metrics() -> Result<()> {
+        let path = format!("/tmp/{}", ObjectId::new());
+        let db = Arc::new(Mutex::new(Database::new("test", &path)));
+
+        // Write, flush, and read back a document...
+        {
+            let mut db = db.lock().await;
+            let coll = db.create_collection("things").await?;
+            let key = ObjectId::new();
+            coll.set(&key, doc! { "v": 1 }).await?;
+            coll.tree.compact_memtable(true).await?;
+            assert!(coll.get(&key).await?.is_some());
+        }
+
+        // Start the endpoint (on any free port)...
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let task = spawn_metrics_endpoint(listener, db.clone())?;
+
+        // The metrics should be labelled by collection (and level)...
+        let res = http_get(addr, METRICS_PATH).await?;
+        assert!(res.starts_with("HTTP/1.1 200"));
+        assert!(res.contains(CONTENT_TYPE));
+        assert!(res.contains("# TYPE brickdb_compactions_total counter"));
+        assert!(res.contains("brickdb_memtable_flushes_total{collection=\"things\"} 1"));
+        assert!(res.contains("brickdb_compactions_total{collection=\"things\"} 0"));
+        assert!(res.contains("brickdb_level_tables{collection=\"things\",level=\"1\"} 1"));
+        assert!(res.contains("brickdb_level_hits_total{collection=\"things\",level=\"1\"} 1"));
+
+        // Other paths aren't found...
+        let res = http_get(addr, "/other").await?;
+        assert!(res.starts_with("HTTP/1.1 404"));
+
+        // (Clean up) Stop the endpoint and remove the directory...
+        task.abort();
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,80 @@
+//! A startup self-test of the storage path.
+//!
+//! Before the server starts accepting traffic, it can check that its
+//! data directory actually works, by writing a sentinel record to a
+//! temporary internal collection, flushing it to disk, reading it
+//! back, and deleting it. That way a misconfigured or damaged data
+//! directory fails startup, rather than the first write.
+
+use anyhow::{anyhow, Context, Result};
+use bson::doc;
+use bson::oid::ObjectId;
+use std::path::Path;
+
+use crate::db::collection::Collection;
+
+/// The name of the temporary collection the self-test writes to.
+///
+/// Its directory is created inside the data directory and removed
+/// once the test is done.
+pub const SELF_TEST_COLLECTION: &str = "_selftest";
+
+/// Runs the startup self-test against the data directory at `data_dir`.
+///
+/// # Returns
+///
+/// An error describing which step failed, if any did.
+pub async fn run_self_test(data_dir: &str) -> Result<()> {
+    // Make sure the data directory exists and is writable...
+    tokio::fs::create_dir_all(data_dir)
+        .await
+        .with_context(|| format!("Couldn't create the data directory {:?}", data_dir))?;
+    let perms = tokio::fs::metadata(data_dir).await?.permissions();
+    if perms.readonly() {
+        return Err(anyhow!(
+            "The data directory {:?} is read-only (it needs to be writable)",
+            data_dir
+        ));
+    }
+
+    // Write, flush, read back, and delete a sentinel record...
+    let path = Path::new(data_dir).join(SELF_TEST_COLLECTION);
+    let path = path.to_string_lossy();
+    let res = exercise(&path).await;
+
+    // Clean up, even if the test failed part way through...
+    match tokio::fs::remove_dir_all(path.as_ref()).await {
+        Ok(()) => {}
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) if res.is_ok() => {
+            return Err(err).context("Couldn't remove the self-test collection");
+        }
+        Err(_) => {}
+    }
+    res
+}
+
+/// Puts a sentinel record through the storage path of a new collection
+/// at `path`.
+async fn exercise(path: &str) -> Result<()> {
+    let mut coll = Collection::new(SELF_TEST_COLLECTION, path);
+    let key = ObjectId::new();
+    let sentinel = doc! { "sentinel": key };
+
+    coll.set(&key, sentinel.clone())
+        .await
+        .context("Couldn't write the sentinel record")?;
+    coll.tree
+        .compact_memtable(true)
+        .await
+        .context("Couldn't flush the sentinel record to disk")?;
+    match coll.tree.get_from_disk_only(&key).await {
+        Ok(Some(doc)) if doc == sentinel => {}
+        Ok(_) => return Err(anyhow!("The sentinel record didn't read back from disk")),
+        Err(err) => return Err(err.context("Couldn't read the sentinel record back")),
+    }
+    coll.del(&key)
+        .await
+        .context("Couldn't delete the sentinel record")?;
+    Ok(())
+}
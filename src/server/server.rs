@@ -1,28 +1,441 @@
+use super::error::status_from_anyhow;
 use super::gen::database_server_server::{DatabaseServer, DatabaseServerServer};
-use super::gen::{PingRequest, PingResponse};
-use tonic::{Request, Response, Status};
+use super::gen::{
+    BatchSetRequest, BatchSetResponse, CompareAndSwapRequest, CompareAndSwapResponse,
+    CreateCollectionRequest, CreateCollectionResponse, DeleteRequest, DeleteResponse,
+    DescribeRequest, DescribeResponse, DropCollectionRequest, DropCollectionResponse, GetRequest,
+    GetResponse, LevelSize, MetricsRequest, MetricsResponse, PingRequest, PingResponse,
+    RestoreProgress, RestoreRequest, ScanRequest, ScanResponse, SetRequest, SetResponse,
+};
+use super::selftest::run_self_test;
+use crate::auth::apikey::{ApiKeyInterceptor, ApiKeyStore, Principal, Role};
+use crate::db::batch::BatchOp;
+use crate::db::collection::Collection;
+use crate::db::database::Database;
+use crate::db::restore::{self, Restore};
+use crate::internal::consistency::{ReadConsistency, ReadNode, ReplicaReader};
+use crate::internal::lag::LagMonitor;
+use crate::storage::metrics::Metrics;
+use anyhow::{Context, Result};
+use bson::oid::ObjectId;
+use bson::Document;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::{mpsc, Mutex, MutexGuard};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::codegen::InterceptedService;
+use tonic::{Request, Response, Status, Streaming};
+
+/// The number of documents a `Scan` reads ahead of its client.
+///
+/// The documents are read from a snapshot (see
+/// [Collection::iter_range]), so the database is only locked while the
+/// scan starts, and at most this many are held in memory at once.
+pub const SCAN_PAGE_SIZE: usize = 256;
 
 pub fn create_service(server: BDBDatabaseServer) -> DatabaseServerServer<BDBDatabaseServer> {
     DatabaseServerServer::new(server)
 }
 
-#[derive(Debug, Default)]
-pub struct BDBDatabaseServer;
+/// Creates the service, rejecting requests without a valid API key
+/// (see [crate::auth::apikey]).
+pub fn create_authenticated_service(
+    server: BDBDatabaseServer,
+    keys: Arc<ApiKeyStore>,
+) -> InterceptedService<DatabaseServerServer<BDBDatabaseServer>, ApiKeyInterceptor> {
+    DatabaseServerServer::with_interceptor(server, ApiKeyInterceptor::new(keys))
+}
+
+/// Settings for starting the server.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerConfig {
+    /// The directory the server's data is stored in.
+    pub data_dir: String,
+
+    /// If `true` (the default), startup runs a self-test of the storage
+    /// path in `data_dir` and fails if it doesn't pass.
+    ///
+    /// See also: [crate::server::selftest]
+    pub self_test: bool,
+
+    /// The address to serve Prometheus metrics from, if any.
+    ///
+    /// See also: [crate::server::prometheus]
+    pub metrics_addr: Option<SocketAddr>,
+}
+
+impl ServerConfig {
+    /// Creates a config for a server storing its data in `data_dir`.
+    pub fn new(data_dir: &str) -> Self {
+        ServerConfig {
+            data_dir: data_dir.to_string(),
+            self_test: true,
+            metrics_addr: None,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct BDBDatabaseServer {
+    /// The storage metrics reported by the `GetMetrics` RPC.
+    metrics: Arc<Metrics>,
+
+    /// The database the `Get`, `Set`, and `Delete` RPCs operate on.
+    db: Option<Arc<Mutex<Database>>>,
+
+    /// The replicas `Get` and `Scan` can read from, depending on their
+    /// read consistency.
+    replicas: Replicas,
+}
+
+/// The replicas of the server's database that reads can be served
+/// from (see [crate::internal::consistency]).
+#[derive(Clone)]
+struct Replicas {
+    /// Each replica's name and database, nearest first.
+    nodes: Vec<(String, Arc<Mutex<Database>>)>,
+
+    /// Tracks how far each replica has caught up to the primary.
+    lag: LagMonitor,
+}
+
+impl Default for Replicas {
+    fn default() -> Self {
+        Replicas {
+            nodes: vec![],
+            lag: LagMonitor::new(0),
+        }
+    }
+}
+
+impl Replicas {
+    /// Locks the replicas' databases, unless `consistency` only reads
+    /// from the primary.
+    async fn lock(&self, consistency: ReadConsistency) -> Vec<(&str, MutexGuard<'_, Database>)> {
+        let mut locked = vec![];
+        if consistency != ReadConsistency::Strong {
+            for (name, db) in self.nodes.iter() {
+                locked.push((name.as_str(), db.lock().await));
+            }
+        }
+        locked
+    }
+
+    /// Returns a reader for the collection `name`, given the primary's
+    /// copy and the `locked` replicas. Replicas without the collection
+    /// are skipped.
+    fn reader<'a>(
+        &'a self,
+        primary: &'a Collection,
+        locked: &'a [(&'a str, MutexGuard<'_, Database>)],
+        name: &str,
+    ) -> ReplicaReader<'a> {
+        let replicas = locked
+            .iter()
+            .filter_map(|(node, db)| {
+                let coll = db.collections.get(name)?;
+                Some(ReadNode { name: node, coll })
+            })
+            .collect();
+        ReplicaReader {
+            primary,
+            replicas,
+            lag: &self.lag,
+        }
+    }
+}
+
+impl std::fmt::Debug for BDBDatabaseServer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BDBDatabaseServer")
+            .field("metrics", &self.metrics)
+            .finish_non_exhaustive()
+    }
+}
 
 impl BDBDatabaseServer {
     pub fn new() -> Self {
-        BDBDatabaseServer {}
+        BDBDatabaseServer::default()
+    }
+
+    /// Prepares the server to start accepting traffic.
+    ///
+    /// If the config's `self_test` is set, the storage path is checked
+    /// first and an error is returned if it doesn't work.
+    pub async fn start(config: &ServerConfig) -> Result<Self> {
+        if config.self_test {
+            run_self_test(&config.data_dir).await.with_context(|| {
+                format!(
+                    "Startup self-test failed for data directory {:?} \
+                     (check that it exists, is writable, and isn't damaged)",
+                    config.data_dir
+                )
+            })?;
+        }
+        Ok(BDBDatabaseServer::new())
+    }
+
+    /// Reports the given metrics (e.g. an LSM Tree's
+    /// [crate::storage::lsm::LSMTree::metrics]) from `GetMetrics`.
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Serves the given database from the document RPCs.
+    pub fn with_database(mut self, db: Arc<Mutex<Database>>) -> Self {
+        self.db = Some(db);
+        self
+    }
+
+    /// Serves `Get` and `Scan` requests that don't need strong
+    /// consistency from the given replicas (by name, nearest first),
+    /// using `lag` to tell how far each has caught up.
+    ///
+    /// Note: Replication doesn't exist yet, so keeping the replicas'
+    /// databases (and `lag`) up to date is left to the caller.
+    pub fn with_replicas(
+        mut self,
+        replicas: Vec<(String, Arc<Mutex<Database>>)>,
+        lag: LagMonitor,
+    ) -> Self {
+        self.replicas = Replicas {
+            nodes: replicas,
+            lag,
+        };
+        self
+    }
+}
+
+/// Returns a "no database" status, for when the server isn't serving
+/// a database.
+fn no_database() -> Status {
+    Status::unavailable("The server has no database")
+}
+
+/// Returns an "invalid key" status, for a key that isn't an ObjectId
+/// hex string.
+fn invalid_key(key: &str, err: bson::oid::Error) -> Status {
+    Status::invalid_argument(format!("Invalid key {:?}: {}", key, err))
+}
+
+/// Returns a "permission denied" status if the request's principal
+/// doesn't have `role`.
+///
+/// Requests without a principal are allowed, since they only reach the
+/// server when authentication is off (see [create_service]).
+fn permission_denied<T>(request: &Request<T>, role: Role) -> Option<Status> {
+    let principal = request.extensions().get::<Principal>()?;
+    if principal.has_role(role) {
+        return None;
+    }
+    Some(Status::permission_denied(format!(
+        "{:?} needs the {:?} role",
+        principal.id, role
+    )))
+}
+
+/// Converts a request's read consistency, returning `None` if it
+/// isn't a known level.
+fn read_consistency(value: i32) -> Option<ReadConsistency> {
+    use super::gen::ReadConsistency as Proto;
+    Some(match Proto::from_i32(value)? {
+        Proto::Strong => ReadConsistency::Strong,
+        Proto::Eventual => ReadConsistency::Eventual,
+        Proto::Quorum => ReadConsistency::Quorum,
+    })
+}
+
+/// Returns an "invalid read consistency" status.
+fn invalid_consistency(value: i32) -> Status {
+    Status::invalid_argument(format!("Invalid read consistency {}", value))
+}
+
+/// Returns a "collection not found" status.
+fn collection_not_found(name: &str) -> Status {
+    Status::not_found(format!("Collection {:?} doesn't exist", name))
+}
+
+/// Returns an "invalid document" status, for a document that isn't
+/// valid BSON.
+fn invalid_document(err: bson::de::Error) -> Status {
+    Status::invalid_argument(format!("Invalid document: {}", err))
+}
+
+/// Gets a collection for a write, creating it if it doesn't exist and
+/// `can_create` is set.
+async fn collection_for_write<'a>(
+    db: &'a mut Database,
+    name: &str,
+    can_create: bool,
+) -> Result<&'a mut Collection, Status> {
+    if !db.collections.contains_key(name) {
+        if !can_create {
+            return Err(collection_not_found(name));
+        }
+        db.create_collection(name)
+            .await
+            .map_err(|err| Status::invalid_argument(format!("{:#}", err)))?;
+    }
+    db.collections
+        .get_mut(name)
+        .ok_or_else(|| collection_not_found(name))
+}
+
+/// Parses an optional range bound for `Scan`, using `default` if
+/// it's empty.
+fn parse_bound(key: &str, default: [u8; 12]) -> Result<ObjectId, bson::oid::Error> {
+    if key.is_empty() {
+        return Ok(ObjectId::from_bytes(default));
+    }
+    ObjectId::parse_str(key)
+}
+
+/// Streams the documents in a collection's key range to `tx`, until
+/// the range (or the request's limit) is done or the receiver is
+/// dropped.
+async fn scan_range(
+    db: Arc<Mutex<Database>>,
+    replicas: Replicas,
+    req: ScanRequest,
+    consistency: ReadConsistency,
+    start: ObjectId,
+    end: ObjectId,
+    tx: mpsc::Sender<Result<ScanResponse, Status>>,
+) {
+    // Start reading from a snapshot (only holding the locks while it's
+    // taken)...
+    let iter = {
+        let db = db.lock().await;
+        match db.collections.get(&req.collection) {
+            Some(coll) => {
+                let locked = replicas.lock(consistency).await;
+                let coll = replicas
+                    .reader(coll, &locked, &req.collection)
+                    .pick(consistency);
+                coll.iter_range(&start, &end)
+                    .await
+                    .map_err(status_from_anyhow)
+            }
+            None => Err(collection_not_found(&req.collection)),
+        }
+    };
+    let mut iter = match iter {
+        Ok(iter) => iter,
+        Err(status) => {
+            let _ = tx.send(Err(status)).await;
+            return;
+        }
+    };
+
+    // Send the documents as they're read, stopping if the client has
+    // gone away...
+    let limit = match req.limit {
+        0 => usize::MAX,
+        n => usize::try_from(n).unwrap_or(usize::MAX),
+    };
+    for _ in 0..limit {
+        let res = match iter.next().await {
+            Some(Ok((key, doc))) => bson::to_vec(&doc)
+                .map(|document| ScanResponse {
+                    key: key.to_hex(),
+                    document,
+                })
+                .map_err(|err| Status::internal(err.to_string())),
+            Some(Err(err)) => Err(status_from_anyhow(err)),
+            None => return,
+        };
+        let failed = res.is_err();
+        if tx.send(res).await.is_err() || failed {
+            return;
+        }
+    }
+}
+
+/// Restores the batches from a `Restore` stream, sending the progress
+/// to `tx` after each one, until the stream ends or fails.
+async fn restore_batches(
+    db: Arc<Mutex<Database>>,
+    mut stream: Streaming<RestoreRequest>,
+    tx: mpsc::Sender<Result<RestoreProgress, Status>>,
+) {
+    let mut name = None;
+    let mut progress = restore::RestoreProgress::default();
+    loop {
+        let req = match stream.message().await {
+            Ok(Some(req)) => req,
+            Ok(None) => return,
+            Err(status) => {
+                let _ = tx.send(Err(status)).await;
+                return;
+            }
+        };
+        let res = restore_batch(&db, &mut name, &mut progress, req).await;
+        let failed = res.is_err();
+        if tx.send(res).await.is_err() || failed {
+            return;
+        }
     }
 }
 
+/// Restores one batch from a `Restore` stream. The first batch sets the
+/// collection (`name`) and starts (or resumes) the restore.
+async fn restore_batch(
+    db: &Mutex<Database>,
+    name: &mut Option<String>,
+    progress: &mut restore::RestoreProgress,
+    req: RestoreRequest,
+) -> Result<RestoreProgress, Status> {
+    let mut batch = Vec::with_capacity(req.entries.len());
+    for e in req.entries.iter() {
+        let key = ObjectId::parse_str(&e.key).map_err(|err| invalid_key(&e.key, err))?;
+        let doc = bson::from_slice(&e.document).map_err(invalid_document)?;
+        batch.push((key, doc));
+    }
+
+    // Only hold the lock for one batch at a time...
+    let mut db = db.lock().await;
+    let mut restore = match name {
+        Some(name) => {
+            let coll = collection_for_write(&mut db, name, true).await?;
+            Restore::resume(coll, *progress)
+        }
+        None if !req.resume_after.is_empty() => {
+            let last_key = ObjectId::parse_str(&req.resume_after)
+                .map_err(|err| invalid_key(&req.resume_after, err))?;
+            let coll = collection_for_write(&mut db, &req.collection, true).await?;
+            *name = Some(req.collection.clone());
+            let progress = restore::RestoreProgress {
+                loaded: req.resume_loaded,
+                last_key: Some(last_key),
+            };
+            Restore::resume(coll, progress)
+        }
+        None => {
+            let coll = collection_for_write(&mut db, &req.collection, true).await?;
+            *name = Some(req.collection.clone());
+            Restore::start(coll, req.overwrite)
+                .map_err(|err| Status::failed_precondition(format!("{:#}", err)))?
+        }
+    };
+    *progress = restore.push(batch).await.map_err(status_from_anyhow)?;
+    Ok(RestoreProgress {
+        loaded: progress.loaded,
+        last_key: progress.last_key.map(|k| k.to_hex()).unwrap_or_default(),
+    })
+}
+
 #[tonic::async_trait]
 impl DatabaseServer for BDBDatabaseServer {
+    type ScanStream = ReceiverStream<Result<ScanResponse, Status>>;
+    type RestoreStream = ReceiverStream<Result<RestoreProgress, Status>>;
+
     async fn ping(
         &self,
         request: Request<PingRequest>, // Accept request of type HelloRequest
     ) -> Result<Response<PingResponse>, Status> {
         // Return an instance of type HelloReply
-        println!("Got a request: {:?}", request);
+        tracing::debug!(?request, "Got a ping");
 
         let reply = PingResponse {
             message: format!("Hello {}!", request.into_inner().name).into(), // We must use .into_inner() as the fields of gRPC requests and responses are private
@@ -30,4 +443,970 @@ impl DatabaseServer for BDBDatabaseServer {
 
         Ok(Response::new(reply)) // Send back our formatted greeting
     }
+
+    async fn get_metrics(
+        &self,
+        _request: Request<MetricsRequest>,
+    ) -> Result<Response<MetricsResponse>, Status> {
+        // Only reads the counters, so this is cheap enough to scrape often...
+        let snap = self.metrics.snapshot();
+        Ok(Response::new(MetricsResponse {
+            gets: snap.gets,
+            memtable_hits: snap.memtable_hits,
+            flushes: snap.flushes,
+            compactions: snap.compactions,
+            bytes_read: snap.bytes_read,
+            bytes_written: snap.bytes_written,
+            bloom_hits: snap.bloom_hits,
+            bloom_misses: snap.bloom_misses,
+            cache_hits: snap.cache_hits,
+            cache_misses: snap.cache_misses,
+            cache_hit_rate: snap.cache_hit_rate(),
+            levels: snap
+                .levels
+                .iter()
+                .map(|l| LevelSize {
+                    tables: l.tables,
+                    records: l.records,
+                })
+                .collect(),
+            level_hits: snap.level_hits,
+        }))
+    }
+
+    async fn get(&self, request: Request<GetRequest>) -> Result<Response<GetResponse>, Status> {
+        let req = request.into_inner();
+        let key = ObjectId::parse_str(&req.key).map_err(|err| invalid_key(&req.key, err))?;
+        let consistency = read_consistency(req.consistency)
+            .ok_or_else(|| invalid_consistency(req.consistency))?;
+        let db = self.db.as_ref().ok_or_else(no_database)?.lock().await;
+        let coll = db
+            .collections
+            .get(&req.collection)
+            .ok_or_else(|| collection_not_found(&req.collection))?;
+        let locked = self.replicas.lock(consistency).await;
+        let doc = self
+            .replicas
+            .reader(coll, &locked, &req.collection)
+            .get(&key, consistency)
+            .await
+            .map_err(status_from_anyhow)?
+            .ok_or_else(|| Status::not_found(format!("Key {} not found", key)))?;
+        let document = bson::to_vec(&doc).map_err(|err| Status::internal(err.to_string()))?;
+        Ok(Response::new(GetResponse { document }))
+    }
+
+    async fn set(&self, request: Request<SetRequest>) -> Result<Response<SetResponse>, Status> {
+        if let Some(denied) = permission_denied(&request, Role::ReadWrite) {
+            return Err(denied);
+        }
+        let can_create = permission_denied(&request, Role::Admin).is_none();
+        let req = request.into_inner();
+        let key = ObjectId::parse_str(&req.key).map_err(|err| invalid_key(&req.key, err))?;
+        let doc: Document = bson::from_slice(&req.document).map_err(invalid_document)?;
+
+        // Create the collection if this is its first document...
+        let mut db = self.db.as_ref().ok_or_else(no_database)?.lock().await;
+        let coll = collection_for_write(&mut db, &req.collection, can_create).await?;
+        let res = match req.durable {
+            true => coll.set_durable(&key, doc).await,
+            false => coll.set(&key, doc).await,
+        };
+        res.map_err(status_from_anyhow)?;
+        Ok(Response::new(SetResponse {}))
+    }
+
+    async fn batch_set(
+        &self,
+        request: Request<BatchSetRequest>,
+    ) -> Result<Response<BatchSetResponse>, Status> {
+        if let Some(denied) = permission_denied(&request, Role::ReadWrite) {
+            return Err(denied);
+        }
+        let can_create = permission_denied(&request, Role::Admin).is_none();
+        let req = request.into_inner();
+        let mut ops = Vec::with_capacity(req.entries.len());
+        for e in req.entries.iter() {
+            let key = ObjectId::parse_str(&e.key).map_err(|err| invalid_key(&e.key, err))?;
+            let doc = bson::from_slice(&e.document).map_err(invalid_document)?;
+            ops.push(BatchOp::Set(key, doc));
+        }
+
+        // Create the collection if these are its first documents...
+        let mut db = self.db.as_ref().ok_or_else(no_database)?.lock().await;
+        let coll = collection_for_write(&mut db, &req.collection, can_create).await?;
+        let res = match req.durable {
+            true => coll.apply_batch_durable(ops).await,
+            false => coll.apply_batch(ops).await,
+        };
+        res.map_err(status_from_anyhow)?;
+        Ok(Response::new(BatchSetResponse {}))
+    }
+
+    async fn compare_and_swap(
+        &self,
+        request: Request<CompareAndSwapRequest>,
+    ) -> Result<Response<CompareAndSwapResponse>, Status> {
+        if let Some(denied) = permission_denied(&request, Role::ReadWrite) {
+            return Err(denied);
+        }
+        let can_create = permission_denied(&request, Role::Admin).is_none();
+        let req = request.into_inner();
+        let key = ObjectId::parse_str(&req.key).map_err(|err| invalid_key(&req.key, err))?;
+        let expected: Option<Document> = match req.expect_absent {
+            true => None,
+            false => Some(bson::from_slice(&req.expected).map_err(invalid_document)?),
+        };
+        let doc: Document = bson::from_slice(&req.document).map_err(invalid_document)?;
+
+        // The database stays locked from the comparison to the write, so
+        // concurrent swaps can't both see the same document...
+        let mut db = self.db.as_ref().ok_or_else(no_database)?.lock().await;
+        let coll = collection_for_write(&mut db, &req.collection, can_create).await?;
+        let swapped = coll
+            .compare_and_swap(&key, expected.as_ref(), doc)
+            .await
+            .map_err(status_from_anyhow)?;
+        Ok(Response::new(CompareAndSwapResponse { swapped }))
+    }
+
+    async fn delete(
+        &self,
+        request: Request<DeleteRequest>,
+    ) -> Result<Response<DeleteResponse>, Status> {
+        if let Some(denied) = permission_denied(&request, Role::ReadWrite) {
+            return Err(denied);
+        }
+        let req = request.into_inner();
+        let key = ObjectId::parse_str(&req.key).map_err(|err| invalid_key(&req.key, err))?;
+        let mut db = self.db.as_ref().ok_or_else(no_database)?.lock().await;
+        let coll = db
+            .collections
+            .get_mut(&req.collection)
+            .ok_or_else(|| collection_not_found(&req.collection))?;
+        coll.del(&key).await.map_err(status_from_anyhow)?;
+        Ok(Response::new(DeleteResponse {}))
+    }
+
+    async fn scan(
+        &self,
+        request: Request<ScanRequest>,
+    ) -> Result<Response<Self::ScanStream>, Status> {
+        let req = request.into_inner();
+        let start =
+            parse_bound(&req.start_key, [0; 12]).map_err(|err| invalid_key(&req.start_key, err))?;
+        let end =
+            parse_bound(&req.end_key, [0xff; 12]).map_err(|err| invalid_key(&req.end_key, err))?;
+        let consistency = read_consistency(req.consistency)
+            .ok_or_else(|| invalid_consistency(req.consistency))?;
+        let db = self.db.as_ref().ok_or_else(no_database)?.clone();
+        if !db.lock().await.collections.contains_key(&req.collection) {
+            return Err(collection_not_found(&req.collection));
+        }
+
+        // Stream the documents from a task, which waits whenever the
+        // channel is full, so only a page's worth is in memory at once...
+        let (tx, rx) = mpsc::channel(SCAN_PAGE_SIZE);
+        let replicas = self.replicas.clone();
+        tokio::spawn(scan_range(db, replicas, req, consistency, start, end, tx));
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn restore(
+        &self,
+        request: Request<Streaming<RestoreRequest>>,
+    ) -> Result<Response<Self::RestoreStream>, Status> {
+        if let Some(denied) = permission_denied(&request, Role::Admin) {
+            return Err(denied);
+        }
+        let db = self.db.as_ref().ok_or_else(no_database)?.clone();
+        let (tx, rx) = mpsc::channel(1);
+        tokio::spawn(restore_batches(db, request.into_inner(), tx));
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn create_collection(
+        &self,
+        request: Request<CreateCollectionRequest>,
+    ) -> Result<Response<CreateCollectionResponse>, Status> {
+        if let Some(denied) = permission_denied(&request, Role::Admin) {
+            return Err(denied);
+        }
+        let req = request.into_inner();
+        let mut db = self.db.as_ref().ok_or_else(no_database)?.lock().await;
+        if db.collections.contains_key(&req.name) {
+            return Err(Status::already_exists(format!(
+                "Collection {:?} already exists",
+                req.name
+            )));
+        }
+        db.create_collection(&req.name)
+            .await
+            .map_err(|err| Status::invalid_argument(format!("{:#}", err)))?;
+        Ok(Response::new(CreateCollectionResponse {}))
+    }
+
+    async fn drop_collection(
+        &self,
+        request: Request<DropCollectionRequest>,
+    ) -> Result<Response<DropCollectionResponse>, Status> {
+        if let Some(denied) = permission_denied(&request, Role::Admin) {
+            return Err(denied);
+        }
+        let req = request.into_inner();
+        let mut db = self.db.as_ref().ok_or_else(no_database)?.lock().await;
+        if !db.collections.contains_key(&req.name) {
+            return Err(collection_not_found(&req.name));
+        }
+        db.drop_collection(&req.name)
+            .await
+            .map_err(status_from_anyhow)?;
+        Ok(Response::new(DropCollectionResponse {}))
+    }
+
+    async fn describe(
+        &self,
+        request: Request<DescribeRequest>,
+    ) -> Result<Response<DescribeResponse>, Status> {
+        if let Some(denied) = permission_denied(&request, Role::Admin) {
+            return Err(denied);
+        }
+        let req = request.into_inner();
+        let db = self.db.as_ref().ok_or_else(no_database)?.lock().await;
+        let coll = db
+            .collections
+            .get(&req.collection)
+            .ok_or_else(|| collection_not_found(&req.collection))?;
+        let description = serde_json::to_string_pretty(&coll.tree.describe())
+            .map_err(|err| Status::internal(err.to_string()))?;
+        Ok(Response::new(DescribeResponse { description }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::gen::database_server_client::DatabaseServerClient;
+    use crate::server::gen::{self, BatchSetEntry};
+    use crate::storage::conf::StorageConfig;
+    use crate::storage::describe::TreeDescription;
+    use crate::storage::lsm::LSMTree;
+    use bson::doc;
+    use bson::oid::ObjectId;
+    use std::os::unix::fs::PermissionsExt;
+    use tokio_stream::wrappers::TcpListenerStream;
+
+    #[tokio::test]
+    async fn startup_self_test_passes() -> Result<()> {
+        let path = format!("/tmp/{}", ObjectId::new());
+        BDBDatabaseServer::start(&ServerConfig::new(&path)).await?;
+
+        // The self-test shouldn't leave anything behind...
+        let mut entries = tokio::fs::read_dir(&path).await?;
+        assert!(entries.next_entry().await?.is_none());
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn startup_self_test_fails_on_read_only_dir() -> Result<()> {
+        let path = format!("/tmp/{}", ObjectId::new());
+        tokio::fs::create_dir_all(&path).await?;
+        let mut perms = tokio::fs::metadata(&path).await?.permissions();
+        perms.set_readonly(true);
+        tokio::fs::set_permissions(&path, perms.clone()).await?;
+
+        // Startup should fail, saying what's wrong...
+        let err = BDBDatabaseServer::start(&ServerConfig::new(&path))
+            .await
+            .unwrap_err();
+        let msg = format!("{:#}", err);
+        assert!(msg.contains("Startup self-test failed"), "{}", msg);
+        assert!(msg.contains("read-only"), "{}", msg);
+
+        // Unless the self-test is turned off...
+        let mut config = ServerConfig::new(&path);
+        config.self_test = false;
+        BDBDatabaseServer::start(&config).await?;
+
+        // (Clean up) Remove the directory...
+        perms.set_mode(0o755);
+        tokio::fs::set_permissions(&path, perms).await?;
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn metrics_reflect_operations() -> Result<()> {
+        let path = format!("/tmp/{}", ObjectId::new());
+        let mut tree = LSMTree::new("test", &path, StorageConfig::default());
+        let server = BDBDatabaseServer::new().with_metrics(tree.metrics.clone());
+
+        // Write and flush some records, then read them back...
+        let keys: Vec<_> = (0..10).map(|_| ObjectId::new()).collect();
+        for k in keys.iter() {
+            tree.set(k, doc! { "v": 1 }).await?;
+        }
+        tree.compact_memtable(true).await?;
+        for k in keys.iter() {
+            assert!(tree.get(k).await?.is_some());
+        }
+        assert!(tree.get(&ObjectId::new()).await?.is_none());
+
+        // The metrics should reflect it...
+        let res = server
+            .get_metrics(Request::new(MetricsRequest {}))
+            .await?
+            .into_inner();
+        assert_eq!(res.gets, 11);
+        assert_eq!(res.memtable_hits, 0);
+        assert_eq!(res.level_hits, vec![10]);
+        assert_eq!(res.flushes, 1);
+        assert_eq!(res.compactions, 0);
+        assert!(res.bytes_written > 0);
+        assert!(res.bytes_read > 0);
+        assert_eq!(res.cache_misses, 1);
+        assert_eq!(res.cache_hits, 9);
+        assert_eq!(res.bloom_misses, 10);
+        assert!(res.bloom_hits <= 1);
+        assert_eq!(
+            res.levels,
+            vec![LevelSize {
+                tables: 1,
+                records: 10
+            }]
+        );
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_set_and_delete_documents() -> Result<()> {
+        let path = format!("/tmp/{}", ObjectId::new());
+        let db = Arc::new(Mutex::new(Database::new("test", &path)));
+        let server = BDBDatabaseServer::new().with_database(db.clone());
+        let key = ObjectId::new().to_hex();
+        let get = |key: &str| {
+            Request::new(GetRequest {
+                collection: "things".to_string(),
+                key: key.to_string(),
+                ..Default::default()
+            })
+        };
+
+        // Setting a document creates its collection...
+        let doc = doc! { "name": "brick", "n": 1 };
+        server
+            .set(Request::new(SetRequest {
+                collection: "things".to_string(),
+                key: key.clone(),
+                document: bson::to_vec(&doc)?,
+                durable: false,
+            }))
+            .await?;
+        assert_eq!(db.lock().await.list_collections(), vec!["things"]);
+
+        // And it can be read back...
+        let res = server.get(get(&key)).await?.into_inner();
+        assert_eq!(bson::from_slice::<Document>(&res.document)?, doc);
+
+        // Until it's deleted...
+        server
+            .delete(Request::new(DeleteRequest {
+                collection: "things".to_string(),
+                key: key.clone(),
+            }))
+            .await?;
+        let err = server.get(get(&key)).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::NotFound);
+
+        // Bad keys and documents are invalid arguments...
+        let err = server.get(get("not-a-key")).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+        let err = server
+            .set(Request::new(SetRequest {
+                collection: "things".to_string(),
+                key: key.clone(),
+                document: vec![1, 2, 3],
+                durable: false,
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+
+        // And missing collections aren't found...
+        let err = server
+            .get(Request::new(GetRequest {
+                collection: "other".to_string(),
+                key,
+                ..Default::default()
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::NotFound);
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    /// Serves `db` (on any free port) and connects a client to it.
+    async fn serve(
+        db: Arc<Mutex<Database>>,
+    ) -> Result<DatabaseServerClient<tonic::transport::Channel>> {
+        serve_server(BDBDatabaseServer::new().with_database(db)).await
+    }
+
+    /// Serves the given server on a local port, returning a client.
+    async fn serve_server(
+        server: BDBDatabaseServer,
+    ) -> Result<DatabaseServerClient<tonic::transport::Channel>> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(create_service(server))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+        Ok(DatabaseServerClient::connect(format!("http://{}", addr)).await?)
+    }
+
+    #[tokio::test]
+    async fn durable_sets_are_on_disk() -> Result<()> {
+        let path = format!("/tmp/{}", ObjectId::new());
+        let db = Arc::new(Mutex::new(Database::new("test", &path)));
+        let mut client = serve(db.clone()).await?;
+        let on_disk = |key: ObjectId| {
+            let db = db.clone();
+            async move {
+                let db = db.lock().await;
+                db.collections["things"].tree.get_from_disk_only(&key).await
+            }
+        };
+
+        // A regular set is only in the memtable...
+        let key = ObjectId::new();
+        client
+            .set(SetRequest {
+                collection: "things".to_string(),
+                key: key.to_hex(),
+                document: bson::to_vec(&doc! { "n": 1 })?,
+                durable: false,
+            })
+            .await?;
+        assert_eq!(on_disk(key).await?, None);
+
+        // While a durable one is on disk as soon as it returns...
+        let key = ObjectId::new();
+        client
+            .set(SetRequest {
+                collection: "things".to_string(),
+                key: key.to_hex(),
+                document: bson::to_vec(&doc! { "n": 2 })?,
+                durable: true,
+            })
+            .await?;
+        assert_eq!(on_disk(key).await?, Some(doc! { "n": 2 }));
+
+        // As are the documents in a durable batch...
+        let keys: Vec<_> = (0..3).map(|_| ObjectId::new()).collect();
+        client
+            .batch_set(BatchSetRequest {
+                collection: "things".to_string(),
+                entries: keys
+                    .iter()
+                    .enumerate()
+                    .map(|(i, key)| BatchSetEntry {
+                        key: key.to_hex(),
+                        document: bson::to_vec(&doc! { "i": i as i32 }).unwrap(),
+                    })
+                    .collect(),
+                durable: true,
+            })
+            .await?;
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(on_disk(*key).await?, Some(doc! { "i": i as i32 }));
+        }
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn racing_compare_and_swaps() -> Result<()> {
+        let path = format!("/tmp/{}", ObjectId::new());
+        let db = Arc::new(Mutex::new(Database::new("test", &path)));
+        let mut a = serve(db.clone()).await?;
+        let mut b = a.clone();
+        let key = ObjectId::new().to_hex();
+        let cas = |expected: Option<&Document>, doc: &Document| CompareAndSwapRequest {
+            collection: "counters".to_string(),
+            key: key.clone(),
+            expected: expected
+                .map(|d| bson::to_vec(d).unwrap())
+                .unwrap_or_default(),
+            expect_absent: expected.is_none(),
+            document: bson::to_vec(doc).unwrap(),
+        };
+
+        // Expecting no document creates it, but only once...
+        let v0 = doc! { "n": 0 };
+        assert!(
+            a.compare_and_swap(cas(None, &v0))
+                .await?
+                .into_inner()
+                .swapped
+        );
+        assert!(
+            !b.compare_and_swap(cas(None, &v0))
+                .await?
+                .into_inner()
+                .swapped
+        );
+
+        // Two clients racing to update the same version...
+        let (ra, rb) = tokio::join!(
+            a.compare_and_swap(cas(Some(&v0), &doc! { "n": 1, "by": "a" })),
+            b.compare_and_swap(cas(Some(&v0), &doc! { "n": 1, "by": "b" })),
+        );
+        let (ra, rb) = (ra?.into_inner().swapped, rb?.into_inner().swapped);
+
+        // ...exactly one wins, and its document is stored
+        assert!(ra ^ rb, "a swapped: {}, b swapped: {}", ra, rb);
+        let winner = if ra { "a" } else { "b" };
+        let res = a
+            .get(GetRequest {
+                collection: "counters".to_string(),
+                key: key.clone(),
+                ..Default::default()
+            })
+            .await?
+            .into_inner();
+        let doc: Document = bson::from_slice(&res.document)?;
+        assert_eq!(doc, doc! { "n": 1, "by": winner });
+
+        // A bad expected document is an error, not a failed swap...
+        let mut req = cas(Some(&doc), &v0);
+        req.expected = vec![1, 2, 3];
+        let err = a.compare_and_swap(req).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn restore_from_a_scan() -> Result<()> {
+        // Back up a collection by scanning it...
+        let src_path = format!("/tmp/{}", ObjectId::new());
+        let src = Arc::new(Mutex::new(Database::new("src", &src_path)));
+        let mut src_client = serve(src.clone()).await?;
+        let entries: Vec<_> = (0..120)
+            .map(|i| BatchSetEntry {
+                key: ObjectId::new().to_hex(),
+                document: bson::to_vec(&doc! { "i": i }).unwrap(),
+            })
+            .collect();
+        src_client
+            .batch_set(BatchSetRequest {
+                collection: "things".to_string(),
+                entries,
+                durable: false,
+            })
+            .await?;
+        let mut stream = src_client
+            .scan(ScanRequest {
+                collection: "things".to_string(),
+                ..Default::default()
+            })
+            .await?
+            .into_inner();
+        let mut backup = vec![];
+        while let Some(res) = stream.message().await? {
+            backup.push(BatchSetEntry {
+                key: res.key,
+                document: res.document,
+            });
+        }
+        assert_eq!(backup.len(), 120);
+
+        // Restore it into a fresh database, in batches...
+        let dst_path = format!("/tmp/{}", ObjectId::new());
+        let dst = Arc::new(Mutex::new(Database::new("dst", &dst_path)));
+        let mut dst_client = serve(dst.clone()).await?;
+        let reqs: Vec<_> = backup
+            .chunks(50)
+            .map(|chunk| RestoreRequest {
+                collection: "things".to_string(),
+                entries: chunk.to_vec(),
+                ..Default::default()
+            })
+            .collect();
+        let mut acks = dst_client
+            .restore(tokio_stream::iter(reqs.clone()))
+            .await?
+            .into_inner();
+        let mut loaded = vec![];
+        while let Some(ack) = acks.message().await? {
+            loaded.push(ack.loaded);
+        }
+        assert_eq!(loaded, vec![50, 100, 120]);
+
+        // ...and it should match the original
+        let expected = src.lock().await.collections["things"].backup().await?;
+        let restored = dst.lock().await.collections["things"].backup().await?;
+        assert_eq!(restored, expected);
+
+        // Restoring again needs the overwrite flag...
+        let mut acks = dst_client
+            .restore(tokio_stream::iter(reqs.clone()))
+            .await?
+            .into_inner();
+        let err = acks.message().await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::FailedPrecondition);
+
+        // ...unless it's resuming, which skips what's already restored
+        let mut resumed = reqs.clone();
+        resumed[0].resume_after = backup[99].key.clone();
+        resumed[0].resume_loaded = 100;
+        let mut acks = dst_client
+            .restore(tokio_stream::iter(resumed))
+            .await?
+            .into_inner();
+        let mut last = None;
+        while let Some(ack) = acks.message().await? {
+            last = Some(ack);
+        }
+        let last = last.unwrap();
+        assert_eq!(last.loaded, 120);
+        assert_eq!(last.last_key, backup[119].key);
+        let restored = dst.lock().await.collections["things"].backup().await?;
+        assert_eq!(restored, expected);
+
+        // (Clean up) Remove the directories...
+        tokio::fs::remove_dir_all(&src_path).await?;
+        tokio::fs::remove_dir_all(&dst_path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn describe_returns_the_tree_as_json() -> Result<()> {
+        let path = format!("/tmp/{}", ObjectId::new());
+        let db = Arc::new(Mutex::new(Database::new("test", &path)));
+        let mut client = serve(db.clone()).await?;
+        client
+            .set(SetRequest {
+                collection: "things".to_string(),
+                key: ObjectId::new().to_hex(),
+                document: bson::to_vec(&doc! { "a": 1 })?,
+                durable: false,
+            })
+            .await?;
+
+        // The description should match the tree's...
+        let res = client
+            .describe(DescribeRequest {
+                collection: "things".to_string(),
+            })
+            .await?
+            .into_inner();
+        let desc: TreeDescription = serde_json::from_str(&res.description)?;
+        assert_eq!(desc, db.lock().await.collections["things"].tree.describe());
+        assert_eq!(desc.memtable_records, 1);
+
+        // ...and unknown collections aren't found
+        let err = client
+            .describe(DescribeRequest {
+                collection: "nope".to_string(),
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::NotFound);
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn read_consistency_with_lagging_replica() -> Result<()> {
+        let primary_path = format!("/tmp/{}", ObjectId::new());
+        let replica_path = format!("/tmp/{}", ObjectId::new());
+        let primary = Arc::new(Mutex::new(Database::new("primary", &primary_path)));
+        let replica = Arc::new(Mutex::new(Database::new("replica", &replica_path)));
+        let lag = LagMonitor::new(0);
+        let server = BDBDatabaseServer::new()
+            .with_database(primary.clone())
+            .with_replicas(vec![("replica".to_string(), replica.clone())], lag.clone());
+        let mut client = serve_server(server).await?;
+
+        // Write a value to both, then update only the primary...
+        let key = ObjectId::new();
+        for db in [&primary, &replica] {
+            let mut db = db.lock().await;
+            db.create_collection("things").await?;
+            let coll = db.collections.get_mut("things").unwrap();
+            coll.set(&key, doc! { "v": 1 }).await?;
+        }
+        lag.advance(1);
+        lag.ack("replica", 1);
+        let set = SetRequest {
+            collection: "things".to_string(),
+            key: key.to_hex(),
+            document: bson::to_vec(&doc! { "v": 2 })?,
+            durable: false,
+        };
+        client.set(set).await?;
+        lag.advance(2);
+
+        let get = |consistency: gen::ReadConsistency| GetRequest {
+            collection: "things".to_string(),
+            key: key.to_hex(),
+            consistency: consistency.into(),
+        };
+        let scan = |consistency: gen::ReadConsistency| ScanRequest {
+            collection: "things".to_string(),
+            consistency: consistency.into(),
+            ..Default::default()
+        };
+        let mut cases = [
+            (gen::ReadConsistency::Strong, doc! { "v": 2 }),
+            (gen::ReadConsistency::Eventual, doc! { "v": 1 }),
+            (gen::ReadConsistency::Quorum, doc! { "v": 2 }),
+        ];
+
+        // Strong (and quorum) reads see the latest value, eventual reads
+        // see the lagging replica's...
+        for (consistency, expected) in cases.iter() {
+            let res = client.get(get(*consistency)).await?.into_inner();
+            let doc: Document = bson::from_slice(&res.document)?;
+            assert_eq!(&doc, expected, "get {:?}", consistency);
+
+            let mut stream = client.scan(scan(*consistency)).await?.into_inner();
+            let res = stream.message().await?.unwrap();
+            let doc: Document = bson::from_slice(&res.document)?;
+            assert_eq!(&doc, expected, "scan {:?}", consistency);
+            assert!(stream.message().await?.is_none());
+        }
+
+        // Once the replica catches up, every level sees the update...
+        let mut db = replica.lock().await;
+        let coll = db.collections.get_mut("things").unwrap();
+        coll.set(&key, doc! { "v": 2 }).await?;
+        drop(db);
+        lag.ack("replica", 2);
+        cases[1].1 = doc! { "v": 2 };
+        for (consistency, expected) in cases.iter() {
+            let res = client.get(get(*consistency)).await?.into_inner();
+            let doc: Document = bson::from_slice(&res.document)?;
+            assert_eq!(&doc, expected, "get {:?}", consistency);
+        }
+
+        // Unknown levels are rejected...
+        let mut req = get(gen::ReadConsistency::Strong);
+        req.consistency = 42;
+        let err = client.get(req).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+
+        // (Clean up) Remove the directories...
+        tokio::fs::remove_dir_all(&primary_path).await?;
+        tokio::fs::remove_dir_all(&replica_path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rate_limited_sets_are_resource_exhausted() -> Result<()> {
+        use crate::db::ratelimit::{RateUnit, WriteRateLimit};
+        use crate::server::error::error_detail;
+        use crate::server::gen::ErrorCode;
+
+        // Serve a database that allows a burst of two writes...
+        let path = format!("/tmp/{}", ObjectId::new());
+        let mut db = Database::new("test", &path);
+        db.set_write_limit(Some(WriteRateLimit {
+            unit: RateUnit::Ops,
+            rate: 0.001,
+            burst: 2.0,
+        }));
+        let server = BDBDatabaseServer::new().with_database(Arc::new(Mutex::new(db)));
+        let set = || {
+            Request::new(SetRequest {
+                collection: "things".to_string(),
+                key: ObjectId::new().to_hex(),
+                document: bson::to_vec(&doc! { "n": 1 }).unwrap(),
+                durable: false,
+            })
+        };
+
+        // The burst goes through...
+        server.set(set()).await?;
+        server.set(set()).await?;
+
+        // But the next write is rejected as out of resources...
+        let err = server.set(set()).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::ResourceExhausted);
+        let detail = error_detail(&err).expect("status should have a detail");
+        assert_eq!(detail.code(), ErrorCode::RateLimited);
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn scan_streams_documents() -> Result<()> {
+        // Start a server (on any free port)...
+        let path = format!("/tmp/{}", ObjectId::new());
+        let db = Arc::new(Mutex::new(Database::new("test", &path)));
+        let mut client = serve(db.clone()).await?;
+
+        // Add more documents than fit in a page...
+        let n = SCAN_PAGE_SIZE + 10;
+        let mut keys: Vec<_> = (0..n).map(|_| ObjectId::new()).collect();
+        keys.sort();
+        for (i, key) in keys.iter().enumerate() {
+            client
+                .set(SetRequest {
+                    collection: "things".to_string(),
+                    key: key.to_hex(),
+                    document: bson::to_vec(&doc! { "i": i as i32 })?,
+                    durable: false,
+                })
+                .await?;
+        }
+
+        // Collects a scan's results...
+        let scan = |start: &str, end: &str, limit: u64| ScanRequest {
+            collection: "things".to_string(),
+            start_key: start.to_string(),
+            end_key: end.to_string(),
+            limit,
+            ..Default::default()
+        };
+        async fn collect(
+            client: &mut DatabaseServerClient<tonic::transport::Channel>,
+            req: ScanRequest,
+        ) -> Result<Vec<(String, Document)>> {
+            let mut stream = client.scan(req).await?.into_inner();
+            let mut res = vec![];
+            while let Some(msg) = stream.message().await? {
+                res.push((msg.key, bson::from_slice(&msg.document)?));
+            }
+            Ok(res)
+        }
+
+        // The whole collection comes back in order...
+        let all = collect(&mut client, scan("", "", 0)).await?;
+        assert_eq!(all.len(), n);
+        for (i, (key, doc)) in all.iter().enumerate() {
+            assert_eq!(*key, keys[i].to_hex());
+            assert_eq!(doc.get_i32("i")?, i as i32);
+        }
+
+        // As does a range, or the start of one...
+        let some = collect(&mut client, scan(&keys[5].to_hex(), &keys[9].to_hex(), 0)).await?;
+        assert_eq!(some.len(), 5);
+        assert_eq!(some[0].0, keys[5].to_hex());
+        let limited = collect(&mut client, scan(&keys[5].to_hex(), "", 3)).await?;
+        assert_eq!(limited.len(), 3);
+
+        // Missing collections aren't found...
+        let mut req = scan("", "", 0);
+        req.collection = "other".to_string();
+        let err = client.scan(req).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::NotFound);
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn roles_limit_what_principals_can_do() -> Result<()> {
+        let path = format!("/tmp/{}", ObjectId::new());
+        let db = Arc::new(Mutex::new(Database::new("test", &path)));
+        let server = BDBDatabaseServer::new().with_database(db.clone());
+        let key = ObjectId::new();
+
+        // Wraps a message in a request from a principal with `role`...
+        fn as_role<T>(role: Role, msg: T) -> Request<T> {
+            let mut req = Request::new(msg);
+            req.extensions_mut().insert(Principal {
+                id: format!("{:?}", role),
+                role,
+            });
+            req
+        }
+        let set = || SetRequest {
+            collection: "things".to_string(),
+            key: key.to_hex(),
+            document: bson::to_vec(&doc! { "n": 1 }).unwrap(),
+            durable: false,
+        };
+        let get = || GetRequest {
+            collection: "things".to_string(),
+            key: key.to_hex(),
+            ..Default::default()
+        };
+        let create = || CreateCollectionRequest {
+            name: "things".to_string(),
+        };
+
+        // Only admins can create collections...
+        let err = server
+            .create_collection(as_role(Role::ReadWrite, create()))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::PermissionDenied);
+        let err = server
+            .set(as_role(Role::ReadWrite, set()))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::NotFound);
+        server
+            .create_collection(as_role(Role::Admin, create()))
+            .await?;
+
+        // A read-only principal can get, but not set or delete...
+        server.set(as_role(Role::ReadWrite, set())).await?;
+        server.get(as_role(Role::ReadOnly, get())).await?;
+        let err = server
+            .set(as_role(Role::ReadOnly, set()))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::PermissionDenied);
+        let err = server
+            .delete(as_role(
+                Role::ReadOnly,
+                DeleteRequest {
+                    collection: "things".to_string(),
+                    key: key.to_hex(),
+                },
+            ))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::PermissionDenied);
+
+        // Only admins can drop collections...
+        let drop = || DropCollectionRequest {
+            name: "things".to_string(),
+        };
+        let err = server
+            .drop_collection(as_role(Role::ReadWrite, drop()))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::PermissionDenied);
+        server.drop_collection(as_role(Role::Admin, drop())).await?;
+        assert!(db.lock().await.list_collections().is_empty());
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
 }
@@ -0,0 +1,200 @@
+//! A bloom filter with a stable, portable layout.
+//!
+//! The `bloom` crate's filter can't be persisted -- its bits are private
+//! and it hashes with randomly-seeded hashers, so the same key hashes
+//! differently in each process. This filter hashes deterministically,
+//! so its bits can be written to disk (see [crate::storage::util::serialize_bloom])
+//! and read back by another process.
+
+use anyhow::{anyhow, Result};
+use std::hash::{Hash, Hasher};
+
+/// The 64-bit FNV-1a offset basis.
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+
+/// The 64-bit FNV-1a prime.
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// A 64-bit FNV-1a hasher, seeded so that two independent hashes can
+/// be taken of the same value.
+struct FnvHasher(u64);
+
+impl FnvHasher {
+    fn with_seed(seed: u64) -> Self {
+        FnvHasher(FNV_OFFSET ^ seed.wrapping_mul(FNV_PRIME))
+    }
+}
+
+impl Hasher for FnvHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 ^= *b as u64;
+            self.0 = self.0.wrapping_mul(FNV_PRIME);
+        }
+    }
+}
+
+/// Hashes a value with the given seed.
+fn hash_with<T: Hash + ?Sized>(item: &T, seed: u64) -> u64 {
+    let mut hasher = FnvHasher::with_seed(seed);
+    item.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// A bloom filter.
+///
+/// Bit positions are chosen with double hashing over two FNV-1a hashes
+/// of the item, so the filter's bits are reproducible across processes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BloomFilter {
+    /// The filter's bits, packed into words (least significant bit first).
+    bits: Vec<u64>,
+
+    /// The number of bits in the filter.
+    num_bits: u64,
+
+    /// The number of hash functions (bits set per item).
+    num_hashes: u32,
+}
+
+impl BloomFilter {
+    /// Creates an empty bloom filter with the given number of bits
+    /// and hash functions.
+    ///
+    /// Both must be non-zero.
+    pub fn new(num_bits: u64, num_hashes: u32) -> Result<Self> {
+        let words = vec![0; words_for(num_bits)];
+        BloomFilter::from_parts(num_bits, num_hashes, words)
+    }
+
+    /// Creates an empty bloom filter sized to hold `expected_num_items`
+    /// items with (at most) the given false positive rate.
+    ///
+    /// # Arguments
+    ///
+    /// * `rate` - The target false positive rate (between 0 and 1).
+    /// * `expected_num_items` - The number of items expected to be inserted.
+    pub fn with_rate(rate: f32, expected_num_items: u32) -> Self {
+        let n = expected_num_items.max(1) as f64;
+        let p = (rate as f64).clamp(f64::MIN_POSITIVE, 0.5);
+        let ln2 = std::f64::consts::LN_2;
+        let num_bits = (-n * p.ln() / (ln2 * ln2)).ceil().max(1.0) as u64;
+        let num_hashes = ((num_bits as f64 / n) * ln2).round().max(1.0) as u32;
+        BloomFilter {
+            bits: vec![0; words_for(num_bits)],
+            num_bits,
+            num_hashes,
+        }
+    }
+
+    /// Creates a bloom filter from its raw parts.
+    ///
+    /// Returns an error if either count is zero, or if `bits` doesn't
+    /// hold exactly `num_bits` bits (with any padding bits unset).
+    pub fn from_parts(num_bits: u64, num_hashes: u32, bits: Vec<u64>) -> Result<Self> {
+        if num_bits == 0 {
+            return Err(anyhow!("Bloom filter must have at least one bit"));
+        }
+        if num_hashes == 0 {
+            return Err(anyhow!("Bloom filter must have at least one hash"));
+        }
+        if bits.len() != words_for(num_bits) {
+            return Err(anyhow!(
+                "Bloom filter with {} bits needs {} words, got {}",
+                num_bits,
+                words_for(num_bits),
+                bits.len()
+            ));
+        }
+        let padding = num_bits % 64;
+        if padding != 0 && bits[bits.len() - 1] >> padding != 0 {
+            return Err(anyhow!("Bloom filter has bits set past its size"));
+        }
+        Ok(BloomFilter {
+            bits,
+            num_bits,
+            num_hashes,
+        })
+    }
+
+    /// Returns the number of bits in the filter.
+    pub fn num_bits(&self) -> u64 {
+        self.num_bits
+    }
+
+    /// Returns the number of hash functions used by the filter.
+    pub fn num_hashes(&self) -> u32 {
+        self.num_hashes
+    }
+
+    /// Returns the filter's bits, packed into words.
+    pub fn bits(&self) -> &[u64] {
+        &self.bits
+    }
+
+    /// Returns the bit positions for an item.
+    fn positions<T: Hash + ?Sized>(&self, item: &T) -> impl Iterator<Item = u64> {
+        let h1 = hash_with(item, 0);
+        let h2 = hash_with(item, 1) | 1;
+        let num_bits = self.num_bits;
+        (0..self.num_hashes as u64).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % num_bits)
+    }
+
+    /// Adds an item to the filter.
+    ///
+    /// Returns `true` if the item wasn't (possibly) already present.
+    pub fn insert<T: Hash + ?Sized>(&mut self, item: &T) -> bool {
+        let mut added = false;
+        for pos in self.positions(item).collect::<Vec<_>>() {
+            let (word, mask) = ((pos / 64) as usize, 1 << (pos % 64));
+            added |= self.bits[word] & mask == 0;
+            self.bits[word] |= mask;
+        }
+        added
+    }
+
+    /// Checks if an item may be in the filter.
+    ///
+    /// False positives are possible but false negatives aren't.
+    pub fn contains<T: Hash + ?Sized>(&self, item: &T) -> bool {
+        self.positions(item)
+            .all(|pos| self.bits[(pos / 64) as usize] & (1 << (pos % 64)) != 0)
+    }
+}
+
+/// Returns the number of words needed to hold `num_bits` bits.
+fn words_for(num_bits: u64) -> usize {
+    num_bits.div_ceil(64) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bson::oid::ObjectId;
+
+    #[test]
+    fn deterministic_and_validated() -> Result<()> {
+        let keys: Vec<_> = (0..100).map(|_| ObjectId::new()).collect();
+
+        // Two filters built from the same keys are identical...
+        let mut a = BloomFilter::with_rate(0.01, 100);
+        let mut b = BloomFilter::with_rate(0.01, 100);
+        for key in keys.iter() {
+            a.insert(key);
+            b.insert(key);
+        }
+        assert_eq!(a, b);
+        assert!(keys.iter().all(|k| a.contains(k)));
+
+        // Bad parts are rejected...
+        assert!(BloomFilter::new(0, 1).is_err());
+        assert!(BloomFilter::new(64, 0).is_err());
+        assert!(BloomFilter::from_parts(65, 1, vec![0]).is_err());
+        assert!(BloomFilter::from_parts(8, 1, vec![1 << 8]).is_err());
+        Ok(())
+    }
+}
@@ -1,36 +1,123 @@
-/// The maximum number of tables per level in the LSM Tree.
+use crate::storage::bloom::BloomFilter;
+
+/// The default maximum number of tables per level in the LSM Tree.
 ///
-/// Note: This value is fixed temporarily, for simplicity.
-/// The goal is to remove this const and make it configurable.
+/// See also: [StorageConfig::max_tables_per_level]
 pub const MAX_TABLES_PER_LEVEL: usize = 10;
 
-/// The maximum number of records to store in the memtable
-/// before flushing to disk. This will also be the max size
-/// of a single SSTable in the first (on-disk) level of
-/// the LSM Tree.
+/// The default maximum number of records to store in the memtable
+/// before flushing to disk.
 ///
-/// Note: This value is fixed temporarily, for simplicity.
-/// The goal is to remove this const and make it configurable.
+/// See also: [StorageConfig::memtable_max_size]
 pub const MEMTABLE_MAX_SIZE: usize = 100;
 
-/// The fixed size of the bloom filter.
-///
-/// See also: [BLOOM_FILTER_ERROR_RATE]
+/// The default size of the level bloom filters.
 ///
-/// Note: This value is fixed temporarily, for simplicity.
-/// The goal is to remove this const and make it configurable.
+/// See also: [StorageConfig::bloom_filter_size]
 pub const BLOOM_FILTER_SIZE: u32 = 1000;
 
-/// The fixed error rate for level bloom filters.
+/// The default error rate for level bloom filters.
 ///
-/// See also: [BLOOM_FILTER_SIZE]
-///
-/// Note: This value is fixed temporarily, for simplicity.
-/// The goal is to remove this const and make it configurable.
+/// See also: [StorageConfig::bloom_filter_error_rate]
 pub const BLOOM_FILTER_ERROR_RATE: f32 = 0.001;
 
+/// The default number of records in each block of a compact SSTable.
+///
+/// See also: [StorageConfig::table_index_interval]
+pub const TABLE_INDEX_INTERVAL: usize = 64;
+
+/// The storage thresholds for an LSM Tree (and its memtable and levels).
+///
+/// The defaults are the consts above.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StorageConfig {
+    /// The maximum number of tables in a level before it's full.
+    pub max_tables_per_level: usize,
+
+    /// The maximum number of records to store in the memtable before
+    /// flushing to disk. This is also the max size of a single SSTable
+    /// in the first (on-disk) level, and the max size of tables in
+    /// level N is N times this.
+    pub memtable_max_size: usize,
+
+    /// The size of the level bloom filters.
+    pub bloom_filter_size: u32,
+
+    /// The error rate of the level bloom filters.
+    pub bloom_filter_error_rate: f32,
+
+    /// The number of records in each block of a compact SSTable, and
+    /// so between the entries of its sparse index (see
+    /// [crate::storage::sstable::SSTableMeta::index]). Zero turns the
+    /// index off.
+    pub table_index_interval: usize,
+}
+
+impl StorageConfig {
+    /// Creates a new, empty bloom filter for a level.
+    pub fn new_bloom_filter(&self) -> BloomFilter {
+        BloomFilter::with_rate(self.bloom_filter_error_rate, self.bloom_filter_size)
+    }
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            max_tables_per_level: MAX_TABLES_PER_LEVEL,
+            memtable_max_size: MEMTABLE_MAX_SIZE,
+            bloom_filter_size: BLOOM_FILTER_SIZE,
+            bloom_filter_error_rate: BLOOM_FILTER_ERROR_RATE,
+            table_index_interval: TABLE_INDEX_INTERVAL,
+        }
+    }
+}
+
 /// The name of the metadata file for a level.
 ///
 /// Note: This value is fixed for simplicity. This *may* change
 /// or become a configurable option in the future.
 pub const LEVEL_META_FILE: &str = "_meta.bson";
+
+/// The name of the backup copy of a level's metadata file.
+///
+/// This holds the previous version of [LEVEL_META_FILE] and is
+/// used if the primary copy can't be read.
+pub const LEVEL_META_BACKUP_FILE: &str = "_meta.bson.bak";
+
+/// The name of the file a level's bloom filter is persisted to.
+///
+/// This lets a level load its bloom filter without rebuilding it
+/// from its tables' records.
+pub const LEVEL_BLOOM_FILE: &str = "_bloom.bin";
+
+/// The version tag at the start of a [LEVEL_BLOOM_FILE].
+///
+/// This should be bumped whenever the filter's hashing or layout
+/// changes, so older files are rebuilt rather than trusted.
+pub const LEVEL_BLOOM_VERSION: &[u8; 8] = b"BDBLOOM1";
+
+/// The name of an LSM Tree's write-ahead log file.
+///
+/// Each LSM Tree (and so each collection) has its own WAL, stored
+/// in the tree's directory.
+pub const WAL_FILE: &str = "wal.log";
+
+/// The name of an LSM Tree's metadata file.
+///
+/// See also: [crate::storage::lsm::LSMTreeMeta]
+pub const TREE_META_FILE: &str = "_tree_meta.bson";
+
+/// The name of an LSM Tree's MANIFEST file, which holds a compaction
+/// that's been committed but not yet fully applied.
+///
+/// See also: [crate::storage::manifest]
+pub const MANIFEST_FILE: &str = "MANIFEST";
+
+/// The number of recent compactions each level keeps a record of.
+///
+/// See also: [crate::storage::level::CompactionEvent]
+pub const COMPACTION_HISTORY_SIZE: usize = 32;
+
+/// The default number of input tables a level reads ahead (in the
+/// background) while merging during compaction. Zero disables it.
+pub const COMPACTION_READ_AHEAD: usize = 0;
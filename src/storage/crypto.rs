@@ -0,0 +1,140 @@
+//! Encryption at rest for files written by the storage layer.
+//!
+//! Files are sealed with ChaCha20-Poly1305 (an AEAD cipher) using a
+//! fresh random nonce per file. The auth tag also catches corruption,
+//! since a file that's been modified (or truncated) won't decrypt.
+//!
+//! Note: Key management is out of scope -- the key is just passed in.
+
+use anyhow::{anyhow, Result};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use std::fmt;
+
+/// The length of an encryption key, in bytes.
+pub const KEY_LEN: usize = 32;
+
+/// The length of a file's nonce, in bytes.
+const NONCE_LEN: usize = 12;
+
+/// The magic bytes at the start of an encrypted file.
+const ENCRYPTED_MAGIC: &[u8; 4] = b"BKE1";
+
+/// A key for encrypting files at rest.
+#[derive(Clone, PartialEq, Eq)]
+pub struct EncryptionKey([u8; KEY_LEN]);
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Don't leak the key into logs...
+        write!(f, "EncryptionKey(..)")
+    }
+}
+
+impl EncryptionKey {
+    /// Creates a key from its raw bytes.
+    pub fn new(bytes: [u8; KEY_LEN]) -> Self {
+        EncryptionKey(bytes)
+    }
+
+    /// Creates a new random key.
+    pub fn generate() -> Self {
+        let mut bytes = [0u8; KEY_LEN];
+        bytes.copy_from_slice(&ChaCha20Poly1305::generate_key(&mut OsRng));
+        EncryptionKey(bytes)
+    }
+
+    fn cipher(&self) -> ChaCha20Poly1305 {
+        ChaCha20Poly1305::new(Key::from_slice(&self.0))
+    }
+
+    /// Encrypts a file's contents.
+    ///
+    /// The output is a magic number, the nonce, and then the ciphertext
+    /// (with its auth tag).
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher()
+            .encrypt(&nonce, plaintext)
+            .map_err(|_| anyhow!("Failed to encrypt data"))?;
+        let mut out = Vec::with_capacity(ENCRYPTED_MAGIC.len() + NONCE_LEN + ciphertext.len());
+        out.extend_from_slice(ENCRYPTED_MAGIC);
+        out.extend_from_slice(&nonce);
+        out.extend_from_slice(&ciphertext);
+        Ok(out)
+    }
+
+    /// Decrypts a file's contents (see [EncryptionKey::encrypt]).
+    ///
+    /// Returns an error if the data was encrypted with a different key
+    /// or has been corrupted.
+    pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let rest = data
+            .strip_prefix(ENCRYPTED_MAGIC)
+            .ok_or(anyhow!("Data isn't encrypted"))?;
+        if rest.len() < NONCE_LEN {
+            return Err(anyhow!("Encrypted data is too short"));
+        }
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+        self.cipher()
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow!("Failed to decrypt data (wrong key or corrupt data)"))
+    }
+}
+
+/// Checks if a file's contents are encrypted.
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(ENCRYPTED_MAGIC)
+}
+
+/// Seals a file's contents with the given key, if there is one.
+pub fn seal(data: Vec<u8>, key: Option<&EncryptionKey>) -> Result<Vec<u8>> {
+    match key {
+        Some(key) => key.encrypt(&data),
+        None => Ok(data),
+    }
+}
+
+/// Opens a file's contents sealed with [seal].
+///
+/// Unencrypted data is passed through as-is (so existing plaintext
+/// files can still be read once a key is configured), but encrypted
+/// data can't be read without the key.
+pub fn open(data: Vec<u8>, key: Option<&EncryptionKey>) -> Result<Vec<u8>> {
+    match (is_encrypted(&data), key) {
+        (false, _) => Ok(data),
+        (true, Some(key)) => key.decrypt(&data),
+        (true, None) => Err(anyhow!("Data is encrypted but no key was given")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip_and_tamper() -> Result<()> {
+        let key = EncryptionKey::generate();
+        let sealed = seal(b"hello, world".to_vec(), Some(&key))?;
+        assert!(is_encrypted(&sealed));
+        assert_eq!(open(sealed.clone(), Some(&key))?, b"hello, world");
+
+        // A fresh nonce is used each time...
+        assert_ne!(seal(b"hello, world".to_vec(), Some(&key))?, sealed);
+
+        // Any change is caught by the auth tag...
+        let mut tampered = sealed.clone();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        assert!(open(tampered, Some(&key)).is_err());
+
+        // As is the wrong key, or no key...
+        assert!(open(sealed.clone(), Some(&EncryptionKey::generate())).is_err());
+        assert!(open(sealed, None).is_err());
+
+        // Plaintext passes through...
+        assert_eq!(open(b"plain".to_vec(), Some(&key))?, b"plain");
+        Ok(())
+    }
+}
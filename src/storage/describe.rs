@@ -0,0 +1,90 @@
+//! Human-readable descriptions of an LSM Tree's structure, for debugging.
+//!
+//! See [crate::storage::lsm::LSMTree::describe].
+
+use serde::{Deserialize, Serialize};
+
+/// A description of an LSM Tree's structure.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TreeDescription {
+    /// The tree's name.
+    pub name: String,
+
+    /// The path to the tree's directory.
+    pub path: String,
+
+    /// The number of records in the memtable.
+    pub memtable_records: usize,
+
+    /// The approximate number of bytes in the memtable.
+    pub memtable_bytes: usize,
+
+    /// Whether there's a frozen memtable being flushed.
+    pub has_frozen_memtable: bool,
+
+    /// The tree's on-disk levels, in order.
+    pub levels: Vec<LevelDescription>,
+}
+
+/// A description of a single level.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LevelDescription {
+    /// The level's id (as hex).
+    pub id: String,
+
+    /// The level number (1 is the first on-disk level).
+    pub level: usize,
+
+    /// The level's tables, newest first.
+    pub tables: Vec<TableDescription>,
+}
+
+/// A description of a single SSTable.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TableDescription {
+    /// The table's id (as hex).
+    pub id: String,
+
+    /// When the table was created, in milliseconds since the epoch.
+    pub created_at: i64,
+
+    /// The minimum key in the table (as hex).
+    pub min_key: String,
+
+    /// The maximum key in the table (as hex).
+    pub max_key: String,
+
+    /// The number of records in the table.
+    pub num_records: usize,
+
+    /// The number of those records that are tombstones.
+    pub num_tombstones: usize,
+
+    /// Whether the table is active (considered for reads).
+    pub active: bool,
+}
+
+/// The size of a single level (see
+/// [crate::storage::lsm::LSMTree::level_stats]).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LevelStat {
+    /// The level number (1 is the first on-disk level).
+    pub level: usize,
+
+    /// The number of tables in the level.
+    pub tables: usize,
+
+    /// The number of records in the level's tables (including
+    /// tombstones and overwritten values).
+    pub records: usize,
+
+    /// The size of the level's directory on disk, in bytes.
+    pub bytes: u64,
+}
+
+impl TreeDescription {
+    /// Formats the description as pretty-printed JSON.
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string_pretty(self)
+    }
+}
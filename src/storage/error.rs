@@ -0,0 +1,75 @@
+//! Errors returned by the storage layer.
+
+use std::fmt;
+
+/// An error from the storage layer.
+///
+/// The variants are coarse on purpose, so that callers (like the gRPC
+/// server) can decide how to handle an error without knowing where
+/// it came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StorageError {
+    /// The requested data (e.g. a collection, level, or table) doesn't exist.
+    NotFound(String),
+
+    /// Data on disk failed a check (e.g. a checksum) and can't be trusted.
+    Corruption(String),
+
+    /// A limit was reached (e.g. a full memtable or disk).
+    Full(String),
+
+    /// Reading from or writing to disk failed.
+    Io(String),
+
+    /// Data couldn't be serialized or deserialized.
+    Serialization(String),
+}
+
+impl StorageError {
+    /// Returns the error's message.
+    pub fn message(&self) -> &str {
+        match self {
+            StorageError::NotFound(msg)
+            | StorageError::Corruption(msg)
+            | StorageError::Full(msg)
+            | StorageError::Io(msg)
+            | StorageError::Serialization(msg) => msg,
+        }
+    }
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self {
+            StorageError::NotFound(_) => "not found",
+            StorageError::Corruption(_) => "corruption",
+            StorageError::Full(_) => "full",
+            StorageError::Io(_) => "io error",
+            StorageError::Serialization(_) => "serialization error",
+        };
+        write!(f, "{}: {}", kind, self.message())
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<std::io::Error> for StorageError {
+    fn from(err: std::io::Error) -> Self {
+        match err.kind() {
+            std::io::ErrorKind::NotFound => StorageError::NotFound(err.to_string()),
+            _ => StorageError::Io(err.to_string()),
+        }
+    }
+}
+
+impl From<bson::ser::Error> for StorageError {
+    fn from(err: bson::ser::Error) -> Self {
+        StorageError::Serialization(err.to_string())
+    }
+}
+
+impl From<bson::de::Error> for StorageError {
+    fn from(err: bson::de::Error) -> Self {
+        StorageError::Serialization(err.to_string())
+    }
+}
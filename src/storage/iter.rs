@@ -0,0 +1,242 @@
+//! Iterating over the live records of an LSM Tree, in key order.
+//!
+//! See [crate::storage::lsm::LSMTree::iter] and
+//! [crate::storage::lsm::LSMTree::iter_range].
+
+use anyhow::Result;
+use bson::oid::ObjectId;
+use bson::Document;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+use crate::storage::level::newest_first;
+use crate::storage::record::*;
+use crate::storage::snapshot::Snapshot;
+use crate::storage::sstable::*;
+
+/// Somewhere records are merged from: a memtable or an SSTable.
+enum Source {
+    /// A copy of a memtable's records.
+    Memory(std::vec::IntoIter<Record>),
+
+    /// An open SSTable (see [SSTableHandle::stream_records]).
+    Table(RecordStream),
+
+    /// A table that hasn't been opened yet, or any source that has run
+    /// out of records.
+    Done,
+}
+
+/// An iterator over the live records of an LSM Tree (or rather, a
+/// snapshot of one), in key order.
+///
+/// The memtables and each of the tables are merged as they're read (a
+/// k-way merge), so when a key is in more than one of them the newest
+/// value wins. Deleted keys are skipped.
+///
+/// Tables are only opened once the merge reaches their smallest key,
+/// and are closed once they've been read, so (for keys written in
+/// roughly increasing order, like `ObjectId`s) only a few are open at
+/// once. Tables in the compact format are read a block at a time;
+/// others are read in whole when they're opened.
+///
+/// When iterating over a key range (see [TreeIter::range]), tables
+/// outside the range are never opened, indexed tables skip ahead to
+/// the block holding the range's start, and each source stops at the
+/// first record past its end. So reading the first few records of a
+/// range only reads a few blocks, however large the range is.
+pub struct TreeIter {
+    /// The snapshot being read, which keeps compaction from deleting
+    /// its tables until the iterator is dropped.
+    _snap: Snapshot,
+
+    /// The first key in the range being read.
+    start: ObjectId,
+
+    /// The last key in the range being read.
+    end: ObjectId,
+
+    /// The number of records read from the sources so far (including
+    /// shadowed and deleted ones).
+    records_read: usize,
+
+    /// The sources, from newest to oldest.
+    sources: Vec<Source>,
+
+    /// The next record from each source, if it's been read.
+    heads: Vec<Option<Record>>,
+
+    /// The tables that haven't been opened yet, with the first key they
+    /// could hold in the range and their source index, sorted so the
+    /// smallest key is last.
+    unopened: Vec<(ObjectId, usize, SSTableHandle)>,
+
+    /// The key and source index of each head. The smallest key comes
+    /// first and, for equal keys, the newest source.
+    heap: BinaryHeap<Reverse<(ObjectId, usize)>>,
+}
+
+impl TreeIter {
+    /// Creates an iterator over the records in the snapshot.
+    pub async fn new(snap: Snapshot) -> Result<Self> {
+        let start = ObjectId::from_bytes([0; 12]);
+        let end = ObjectId::from_bytes([0xff; 12]);
+        TreeIter::range(snap, &start, &end).await
+    }
+
+    /// Creates an iterator over the records in the snapshot with keys
+    /// in the given range (inclusive).
+    pub async fn range(snap: Snapshot, start: &ObjectId, end: &ObjectId) -> Result<Self> {
+        let mut sources = vec![];
+        let mut unopened = vec![];
+
+        // Copy the memtables' records in the range (newest first)...
+        for mt in std::iter::once(&snap.memtable).chain(snap.frozen_memtable.iter()) {
+            let records: Vec<_> = mt
+                .range(start, end)
+                .map(|(key, value)| Record {
+                    key: *key,
+                    value: value.clone(),
+                })
+                .collect();
+            sources.push(Source::Memory(records.into_iter()));
+        }
+        let num_memtables = sources.len();
+
+        // Then queue up the tables in the range, level by level (newest
+        // first)...
+        for tables in snap.levels.iter() {
+            for th in newest_first(tables) {
+                if !th.meta.overlaps(start, end) {
+                    continue;
+                }
+                let first = th.meta.min_key.max(*start);
+                unopened.push((first, sources.len(), th.clone()));
+                sources.push(Source::Done);
+            }
+        }
+        unopened.sort_by_key(|(min_key, i, _)| Reverse((*min_key, *i)));
+
+        // Read the memtables' first records...
+        let mut iter = TreeIter {
+            _snap: snap,
+            start: *start,
+            end: *end,
+            records_read: 0,
+            heads: (0..sources.len()).map(|_| None).collect(),
+            sources,
+            unopened,
+            heap: BinaryHeap::new(),
+        };
+        for i in 0..num_memtables {
+            iter.advance(i).await?;
+        }
+        Ok(iter)
+    }
+
+    /// Returns the next live record, or `None` once all of the records
+    /// have been read.
+    ///
+    /// After an error, the iterator ends.
+    pub async fn next(&mut self) -> Option<Result<(ObjectId, Document)>> {
+        match self.next_live().await {
+            Ok(res) => res.map(Ok),
+            Err(err) => {
+                self.heap.clear();
+                self.unopened.clear();
+                Some(Err(err))
+            }
+        }
+    }
+
+    /// Returns the number of records read from the memtables and tables
+    /// so far, including shadowed and deleted ones.
+    pub fn records_read(&self) -> usize {
+        self.records_read
+    }
+
+    /// Reads the rest of the records.
+    pub async fn collect(mut self) -> Result<Vec<(ObjectId, Document)>> {
+        let mut res = vec![];
+        while let Some(rec) = self.next().await {
+            res.push(rec?);
+        }
+        Ok(res)
+    }
+
+    /// Merges the sources up to the next live record.
+    async fn next_live(&mut self) -> Result<Option<(ObjectId, Document)>> {
+        loop {
+            // Open any tables that could hold the next key...
+            self.open_ready().await?;
+
+            // Take the next key's newest record...
+            let Some(Reverse((key, i))) = self.heap.pop() else {
+                return Ok(None);
+            };
+            let rec = self.heads[i].take();
+            self.advance(i).await?;
+
+            // Skip its older records...
+            while let Some(&Reverse((k, j))) = self.heap.peek() {
+                if k != key {
+                    break;
+                }
+                self.heap.pop();
+                self.heads[j] = None;
+                self.advance(j).await?;
+            }
+
+            // Return it, unless it was deleted...
+            if let Some(Record {
+                value: Value::Data(doc),
+                ..
+            }) = rec
+            {
+                return Ok(Some((key, doc)));
+            }
+        }
+    }
+
+    /// Opens the unopened tables whose smallest key isn't after the
+    /// smallest key queued up.
+    async fn open_ready(&mut self) -> Result<()> {
+        while let Some((min_key, _, _)) = self.unopened.last() {
+            if self.heap.peek().is_some_and(|Reverse((k, _))| k < min_key) {
+                break;
+            }
+            if let Some((first, i, th)) = self.unopened.pop() {
+                self.sources[i] = Source::Table(th.stream_records_from(&first).await?);
+                self.advance(i).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the next record in the range from source `i` into its
+    /// head, closing the source if it's run out (or passed the range).
+    async fn advance(&mut self, i: usize) -> Result<()> {
+        loop {
+            let next = match &mut self.sources[i] {
+                Source::Memory(records) => records.next().map(Ok),
+                Source::Table(stream) => stream.next().await,
+                Source::Done => None,
+            };
+            let Some(rec) = next.transpose()? else {
+                self.sources[i] = Source::Done;
+                return Ok(());
+            };
+            self.records_read += 1;
+            if rec.key < self.start {
+                continue;
+            }
+            if rec.key > self.end {
+                self.sources[i] = Source::Done;
+                return Ok(());
+            }
+            self.heap.push(Reverse((rec.key, i)));
+            self.heads[i] = Some(rec);
+            return Ok(());
+        }
+    }
+}
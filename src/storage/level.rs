@@ -1,13 +1,20 @@
 use anyhow::{anyhow, Result};
-use bloom::{BloomFilter, ASMS};
 use bson::oid::ObjectId;
-use bson::DateTime;
+use bson::{DateTime, Document};
+use core::cmp::Reverse;
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use std::collections::{BTreeMap, BinaryHeap, HashSet, VecDeque};
 use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
 use tokio::fs;
+use tokio::task::JoinHandle;
 
+use crate::storage::bloom::BloomFilter;
 use crate::storage::conf::*;
+use crate::storage::crypto::{self, EncryptionKey};
+use crate::storage::metrics::{LevelSize, Metrics};
 use crate::storage::record::*;
 use crate::storage::sstable::*;
 use crate::storage::util::*;
@@ -23,9 +30,26 @@ pub struct Level {
     /// A Bloom filter for this level.
     pub bloom_filter: BloomFilter,
 
+    /// If `true`, reads don't trust the bloom filter once it's found to
+    /// be inconsistent with the level's tables. They scan the tables
+    /// directly instead (while the filter is rebuilt in the background),
+    /// rather than risk wrongly reporting a key as missing.
+    pub bloom_fallback: bool,
+
+    /// A background rebuild of the bloom filter, if one is running.
+    ///
+    /// See also: [Level::finish_bloom_rebuild]
+    bloom_rebuild: Mutex<Option<JoinHandle<Result<BloomFilter>>>>,
+
     /// The path to this level's directory on disk.
     pub path: String,
 
+    /// The storage thresholds this level was created with.
+    ///
+    /// `max_tables`, `records_per_table`, and the bloom filter's size
+    /// start out from these.
+    pub config: StorageConfig,
+
     /// The maximum number of tables allowed in this level
     /// for it to be considered full.
     pub max_tables: usize,
@@ -35,6 +59,46 @@ pub struct Level {
     /// Due to compaction, there may be fewer records in a
     /// given table in this level.
     pub records_per_table: usize,
+
+    /// The strategy used to pick which tables get compacted.
+    pub compaction_strategy: CompactionStrategy,
+
+    /// The on-disk format new tables in this level are written in.
+    ///
+    /// Existing tables are read in whichever format they were written.
+    pub table_format: TableFormat,
+
+    /// The key this level's tables are encrypted with, if any.
+    pub encryption: Option<EncryptionKey>,
+
+    /// How old a table must be (since it was created) before it can
+    /// be compacted. Recently flushed tables are likely to be overwritten
+    /// soon, so holding off on them saves rewriting data.
+    pub min_table_age: Duration,
+
+    /// The number of input tables to read ahead (in the background)
+    /// while merging during compaction. Zero disables read-ahead.
+    pub read_ahead: usize,
+
+    /// The most recent compactions of this level, oldest first.
+    ///
+    /// This is kept in memory and holds at most
+    /// [COMPACTION_HISTORY_SIZE] events.
+    pub compaction_history: VecDeque<CompactionEvent>,
+
+    /// The table most recently read by [Level::get] or [Level::get_range],
+    /// kept so that repeated reads landing in the same table don't have
+    /// to read it from disk again.
+    last_table: Mutex<Option<Arc<SSTable>>>,
+
+    /// The number of tables read from disk by [Level::get] and
+    /// [Level::get_range].
+    table_reads: AtomicUsize,
+
+    /// The metrics reads from (and writes to) this level are counted in.
+    ///
+    /// This is shared with the level's tree (see [crate::storage::lsm::LSMTree::metrics]).
+    pub metrics: Arc<Metrics>,
 }
 
 impl Level {
@@ -46,6 +110,7 @@ impl Level {
     /// * `level_number` - The level number (1 is the first on-disk level).
     /// * `tables` - The SSTables in this level.
     /// * `to_disk` - Whether to create the directory for this level.
+    /// * `config` - The storage thresholds for this level.
     ///
     /// # Returns
     ///
@@ -55,6 +120,7 @@ impl Level {
         level_number: usize,
         tables: Vec<SSTableHandle>,
         to_disk: bool,
+        config: &StorageConfig,
     ) -> Result<Self> {
         // Create the metadata...
         let meta = LevelMeta::new(
@@ -72,16 +138,28 @@ impl Level {
             .to_string();
 
         // Create the bloom filter...
-        let bloom_filter = BloomFilter::with_rate(BLOOM_FILTER_ERROR_RATE, BLOOM_FILTER_SIZE);
+        let bloom_filter = config.new_bloom_filter();
 
         // Create the level...
         let level = Level {
             meta,
             tables,
             bloom_filter,
+            bloom_fallback: true,
+            bloom_rebuild: Mutex::new(None),
             path: path.clone(),
-            max_tables: MAX_TABLES_PER_LEVEL,
-            records_per_table: MEMTABLE_MAX_SIZE * level_number,
+            config: *config,
+            max_tables: config.max_tables_per_level,
+            records_per_table: config.memtable_max_size * level_number,
+            compaction_strategy: CompactionStrategy::default(),
+            table_format: TableFormat::default(),
+            encryption: None,
+            min_table_age: Duration::ZERO,
+            read_ahead: COMPACTION_READ_AHEAD,
+            compaction_history: VecDeque::new(),
+            last_table: Mutex::new(None),
+            table_reads: AtomicUsize::new(0),
+            metrics: Arc::default(),
         };
 
         if to_disk {
@@ -97,6 +175,17 @@ impl Level {
     }
 
     pub async fn load_from_file(parent_path: &str, id: &ObjectId) -> Result<Self> {
+        Self::load_from_file_with(parent_path, id, None, &StorageConfig::default()).await
+    }
+
+    /// Loads a level from disk, whose tables are encrypted with `encryption`
+    /// (if it's set), with the given storage thresholds.
+    pub async fn load_from_file_with(
+        parent_path: &str,
+        id: &ObjectId,
+        encryption: Option<EncryptionKey>,
+        config: &StorageConfig,
+    ) -> Result<Self> {
         // Get the level's path...
         let path = Path::new(parent_path);
         let path = path.join(id.to_string());
@@ -115,32 +204,51 @@ impl Level {
         }
 
         // Load the metadata...
-        let meta_path = path.join(LEVEL_META_FILE);
-        let meta = {
-            let bytes = read_bson(meta_path).await?;
-            let meta: LevelMeta = bson::from_slice(&bytes)?;
-            meta
-        };
+        let meta = read_meta(path).await?;
         let level_num = meta.level;
 
         // Create the level...
         let mut level = Level {
             meta,
             tables: vec![],
-            bloom_filter: BloomFilter::with_rate(BLOOM_FILTER_ERROR_RATE, BLOOM_FILTER_SIZE),
+            bloom_filter: config.new_bloom_filter(),
+            bloom_fallback: true,
+            bloom_rebuild: Mutex::new(None),
             path: path
                 .to_str()
                 .ok_or(anyhow!("Couldn't format level path"))?
                 .to_string(),
-            max_tables: MAX_TABLES_PER_LEVEL,
-            records_per_table: MEMTABLE_MAX_SIZE * level_num,
+            config: *config,
+            max_tables: config.max_tables_per_level,
+            records_per_table: config.memtable_max_size * level_num,
+            compaction_strategy: CompactionStrategy::default(),
+            table_format: TableFormat::default(),
+            encryption,
+            min_table_age: Duration::ZERO,
+            read_ahead: COMPACTION_READ_AHEAD,
+            compaction_history: VecDeque::new(),
+            last_table: Mutex::new(None),
+            table_reads: AtomicUsize::new(0),
+            metrics: Arc::default(),
         };
 
-        // Load the tables...
-        level.reload_handles().await?;
+        // Load the bloom filter that was persisted with the level...
+        let persisted = level.read_bloom().await?;
+        let rebuild = persisted.is_none();
+        if let Some(bf) = persisted {
+            level.bloom_filter = bf;
+        }
 
-        // Load the bloom filter...
-        level.bloom_filter = level.get_bloom_filter().await?;
+        // Load the tables (only rebuilding the filter if needed)...
+        level.load_handles(rebuild).await?;
+
+        // Rebuild it anyway if it doesn't match the tables...
+        if !level.bloom_is_consistent() {
+            level.bloom_filter = level.get_bloom_filter().await?;
+            level.write_bloom().await?;
+        } else if rebuild {
+            level.write_bloom().await?;
+        }
 
         Ok(level)
     }
@@ -149,23 +257,110 @@ impl Level {
     ///
     /// Note this *doesn't* change the `self.bloom_filter`.
     pub async fn get_bloom_filter(&self) -> Result<BloomFilter> {
-        // Create a new, empty bloom filter...
-        let mut bloom_filter = BloomFilter::with_rate(BLOOM_FILTER_ERROR_RATE, BLOOM_FILTER_SIZE);
-
-        // Iterate over the table handles (in reverse order)...
-        for table in self.tables.iter().rev() {
-            // Read in the table...
-            let sstable = table.read().await?;
-
-            // Iterate over the table's records...
-            for record in sstable.records.iter() {
-                // Insert the record's key into the bloom filter...
-                bloom_filter.insert(&record.key);
-            }
+        build_bloom_filter(&self.tables, &self.config).await
+    }
+
+    /// Checks that the bloom filter is consistent with the level's tables.
+    ///
+    /// Bloom filters never give false negatives, so if any table's min
+    /// or max key isn't in the filter, the filter is wrong (e.g. it's
+    /// stale or corrupt) and can't be trusted to rule keys out.
+    pub fn bloom_is_consistent(&self) -> bool {
+        newest_first(&self.tables).into_iter().all(|t| {
+            self.bloom_filter.contains(&t.meta.min_key)
+                && self.bloom_filter.contains(&t.meta.max_key)
+        })
+    }
+
+    fn lock_bloom_rebuild(&self) -> MutexGuard<'_, Option<JoinHandle<Result<BloomFilter>>>> {
+        match self.bloom_rebuild.lock() {
+            Ok(r) => r,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    fn lock_last_table(&self) -> MutexGuard<'_, Option<Arc<SSTable>>> {
+        match self.last_table.lock() {
+            Ok(t) => t,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    /// Reads a table for [Level::get] or [Level::get_range], reusing the
+    /// last table read if it's the same one.
+    async fn read_table(&self, th: &SSTableHandle) -> Result<Arc<SSTable>> {
+        // Check the last table read...
+        let last = self.lock_last_table().clone();
+        if let Some(table) = last.filter(|t| t.meta.table_id == th.meta.table_id) {
+            Metrics::add(&self.metrics.cache_hits, 1);
+            return Ok(table);
+        }
+
+        // Otherwise, read it from disk and keep it for next time...
+        let table = Arc::new(th.read().await?);
+        self.table_reads.fetch_add(1, Ordering::Relaxed);
+        Metrics::add(&self.metrics.cache_misses, 1);
+        Metrics::add(&self.metrics.bytes_read, th.size().await?);
+        *self.lock_last_table() = Some(table.clone());
+        Ok(table)
+    }
+
+    /// Returns the number of tables and records in the level.
+    ///
+    /// This only reads metadata, so it doesn't touch disk.
+    pub fn size(&self) -> LevelSize {
+        LevelSize {
+            tables: self.tables.len() as u64,
+            records: self.tables.iter().map(|t| t.meta.num_records as u64).sum(),
         }
+    }
 
-        // Return it!
-        Ok(bloom_filter)
+    /// Returns the number of tables read from disk by [Level::get] and
+    /// [Level::get_range] (not counting reads served from the last
+    /// table read).
+    pub fn table_reads(&self) -> usize {
+        self.table_reads.load(Ordering::Relaxed)
+    }
+
+    /// Starts rebuilding the bloom filter in a background task, if a
+    /// rebuild isn't already running.
+    pub fn start_bloom_rebuild(&self) {
+        let mut rebuild = self.lock_bloom_rebuild();
+        if rebuild.is_none() {
+            let tables = self.tables.clone();
+            let config = self.config;
+            let task = async move { build_bloom_filter(&tables, &config).await };
+            *rebuild = Some(tokio::spawn(task));
+        }
+    }
+
+    /// Swaps in the rebuilt bloom filter, if a background rebuild has
+    /// finished.
+    ///
+    /// # Returns
+    ///
+    /// Returns `true` if a new bloom filter was installed.
+    pub async fn finish_bloom_rebuild(&mut self) -> Result<bool> {
+        let handle = {
+            let mut rebuild = self.lock_bloom_rebuild();
+            match rebuild.as_ref() {
+                Some(h) if h.is_finished() => rebuild.take(),
+                _ => None,
+            }
+        };
+        let handle = match handle {
+            Some(h) => h,
+            None => return Ok(false),
+        };
+        let bloom_filter = handle.await??;
+
+        // Keep the current filter if it's been rebuilt meanwhile (e.g.
+        // when tables were added), since it'll be more up to date...
+        if !self.bloom_is_consistent() {
+            self.bloom_filter = bloom_filter;
+            self.write_bloom().await?;
+        }
+        Ok(true)
     }
 
     /// Checks if the level *doesn't* contain the given key.
@@ -184,27 +379,77 @@ impl Level {
     }
 
     fn format_table_path(&self, id: &ObjectId) -> Option<String> {
+        self.format_table_path_as(id, self.table_format)
+    }
+
+    fn format_table_path_as(&self, id: &ObjectId, format: TableFormat) -> Option<String> {
         Path::new(&self.path)
-            .join(format!("{}.bson", id))
+            .join(format!("{}.{}", id, format.extension()))
             .to_str()
             .map(|s| s.to_string())
     }
 
+    /// Finds the path to an existing table's file, in whichever
+    /// format it was written.
+    fn find_table_path(&self, id: &ObjectId) -> Option<String> {
+        TableFormat::ALL
+            .iter()
+            .filter_map(|f| self.format_table_path_as(id, *f))
+            .find(|p| Path::new(p).exists())
+    }
+
     /// Adds an SSTable to this level.
     pub async fn add_sstable(&mut self, table: &SSTable) -> Result<()> {
-        // Get the path to the table...
+        let handle = self.write_sstable(table).await?;
+        self.attach(vec![handle]).await
+    }
+
+    /// Writes an SSTable to this level's directory *without* adding
+    /// it to the level.
+    ///
+    /// See also: [Level::attach]
+    pub async fn write_sstable(&self, table: &SSTable) -> Result<SSTableHandle> {
+        // Write the table to disk...
+        let mut handle = self.new_handle(table)?;
+        handle.write(table).await?;
+        Metrics::add(&self.metrics.bytes_written, handle.size().await?);
+        Ok(handle)
+    }
+
+    /// Creates a handle for an SSTable in this level's directory, in
+    /// the level's format, *without* writing it.
+    ///
+    /// This lets the table be written somewhere the level isn't
+    /// available (e.g. on a background task).
+    pub fn new_handle(&self, table: &SSTable) -> Result<SSTableHandle> {
         let table_path = self
             .format_table_path(&table.meta.table_id)
             .ok_or(anyhow!("Couldn't format table path"))?;
+        let mut handle = SSTableHandle::new(table.meta.clone(), table_path.as_str());
+        handle.encryption = self.encryption.clone();
+        handle.index_interval = self.config.table_index_interval;
+        Ok(handle)
+    }
 
-        // Create the handle...
-        let handle = SSTableHandle::new(table.meta.clone(), table_path.as_str());
-
-        // Write the table to disk...
-        handle.write(table).await?;
+    /// Opens a table that's already on disk in this level's directory
+    /// (e.g. one written by [Level::write_sstable]) *without* adding it
+    /// to the level.
+    pub async fn open_sstable(&self, id: &ObjectId) -> Result<SSTableHandle> {
+        let table_path = self
+            .find_table_path(id)
+            .ok_or(anyhow!("Couldn't find table {}", id))?;
+        let table = read_sstable(&table_path, self.encryption.as_ref()).await?;
+        let mut handle = SSTableHandle::new(table.meta, &table_path);
+        handle.encryption = self.encryption.clone();
+        handle.index_interval = self.config.table_index_interval;
+        Ok(handle)
+    }
 
-        // Add the handle...
-        self.tables.push(handle);
+    /// Adds tables that have already been written to disk to this level,
+    /// and updates this level's metadata.
+    pub async fn attach(&mut self, handles: Vec<SSTableHandle>) -> Result<()> {
+        // Add the handles...
+        self.tables.extend(handles);
 
         // Update the metadata...
         self.update_table_ids().await?;
@@ -213,13 +458,8 @@ impl Level {
 
     /// Reads the metadata for this level from disk.
     pub async fn load_meta(&mut self) -> Result<()> {
-        // Get the path to the meta file...
-        let path =
-            format_meta_path(self.path.as_str()).ok_or(anyhow!("Couldn't format meta path"))?;
-
-        // Read in the data and deserialize from BSON...
-        let buff = read_bson(path).await?;
-        let meta: LevelMeta = bson::from_slice(&buff)?;
+        // Read in the metadata (falling back to the backup)...
+        let meta = read_meta(Path::new(&self.path)).await?;
 
         // Set the metadata...
         self.meta = meta;
@@ -227,20 +467,87 @@ impl Level {
     }
 
     /// Writes the metadata for this level to disk.
+    ///
+    /// The new metadata is written atomically and the previous
+    /// version is kept as a backup (see [LEVEL_META_BACKUP_FILE]).
     pub async fn write_meta(&self) -> Result<()> {
-        // Get the path to the meta file...
+        // Get the paths to the meta file and its backup...
         let path = format_meta_path(&self.path).ok_or(anyhow!("Couldn't format meta path"))?;
+        let bak_path = Path::new(&self.path).join(LEVEL_META_BACKUP_FILE);
 
         // Convert the metadata to a BSON document...
         let doc = bson::to_document(&self.meta)?;
 
+        // Keep the current version as the backup...
+        if Path::new(&path).exists() {
+            copy_atomic(&path, &bak_path).await?;
+        }
+
         // Write the data...
-        write_bson(path, &doc).await?;
+        write_bson_atomic(path, &doc).await?;
 
         // Success!
         Ok(())
     }
 
+    /// Writes the level's bloom filter to disk (see [LEVEL_BLOOM_FILE]).
+    ///
+    /// Like the metadata, the file is written atomically.
+    pub async fn write_bloom(&self) -> Result<()> {
+        let path = Path::new(&self.path).join(LEVEL_BLOOM_FILE);
+        let tmp_path = Path::new(&self.path).join(format!("{}.tmp", LEVEL_BLOOM_FILE));
+
+        // Tag the serialized filter with its version...
+        let mut buffer = LEVEL_BLOOM_VERSION.to_vec();
+        buffer.extend(serialize_bloom(&self.bloom_filter));
+
+        // Write it to a temp file and move it into place...
+        write_bytes(&tmp_path, buffer, self.encryption.as_ref()).await?;
+        fs::rename(&tmp_path, &path).await?;
+        Ok(())
+    }
+
+    /// Reads the level's persisted bloom filter.
+    ///
+    /// # Returns
+    ///
+    /// Returns `None` if the file is missing, has a different version
+    /// tag, can't be parsed, or doesn't match the configured filter
+    /// size -- in which case the filter should be rebuilt from the
+    /// level's tables.
+    pub async fn read_bloom(&self) -> Result<Option<BloomFilter>> {
+        // Read the file, if it's there...
+        let path = Path::new(&self.path).join(LEVEL_BLOOM_FILE);
+        let data = match fs::read(&path).await {
+            Ok(data) => data,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let data = match crypto::open(data, self.encryption.as_ref()) {
+            Ok(data) => data,
+            Err(_) => return Ok(None),
+        };
+
+        // Check the version tag...
+        let Some(rest) = data.strip_prefix(LEVEL_BLOOM_VERSION.as_slice()) else {
+            return Ok(None);
+        };
+
+        // Parse the filter and check it's the expected size...
+        let expected = self.config.new_bloom_filter();
+        Ok(deserialize_bloom(rest).ok().filter(|bf| {
+            bf.num_bits() == expected.num_bits() && bf.num_hashes() == expected.num_hashes()
+        }))
+    }
+
+    /// Checks if any of this level's (active) tables overlap the
+    /// key range from `min_key` to `max_key` (inclusive).
+    pub fn overlaps(&self, min_key: &ObjectId, max_key: &ObjectId) -> bool {
+        self.tables
+            .iter()
+            .any(|t| t.active && t.meta.overlaps(min_key, max_key))
+    }
+
     /// Checks if this level is full based on the number of tables.
     pub fn is_full(&self) -> bool {
         self.tables.len() >= self.max_tables
@@ -261,28 +568,37 @@ impl Level {
     pub async fn get(&self, key: &ObjectId) -> Result<Option<Record>> {
         // Check the bloom filter first...
         if self.doesnt_contain(key) {
-            return Ok(None);
-        }
-
-        // Then iterate through the SSTables...
-        for th in &self.tables {
-            // Check if the table is active...
-            if !th.active {
-                // The table isn't active, skip it...
-                continue;
+            if !self.bloom_fallback || self.bloom_is_consistent() {
+                Metrics::add(&self.metrics.bloom_hits, 1);
+                return Ok(None);
             }
 
+            // The filter is missing keys the level has, so it can't rule
+            // this one out. Scan the tables instead and fix the filter...
+            self.start_bloom_rebuild();
+        }
+        Metrics::add(&self.metrics.bloom_misses, 1);
+
+        // Then iterate through the active SSTables, newest first, so
+        // the latest version of the key wins...
+        for th in newest_first(&self.tables) {
             // Check if the key is in range...
             if !th.meta.key_in_range(key) {
                 // The key isn't in range, skip this table...
                 continue;
             }
 
-            // Read in the table...
-            let sstable = th.read().await?;
+            // Read the block that could hold the key (if the table is
+            // indexed), or else the whole table...
+            let record = if th.is_indexed() {
+                Metrics::add(&self.metrics.cache_misses, 1);
+                th.get(key).await?
+            } else {
+                self.read_table(th).await?.get(key)
+            };
 
             // Check if the table contains the key...
-            if let Some(record) = sstable.get(key) {
+            if let Some(record) = record {
                 // Return the record if it exists...
                 return Ok(Some(record));
             }
@@ -292,43 +608,247 @@ impl Level {
         Ok(None)
     }
 
-    /// Compacts the tables in this level into a single SSTable.
+    /// Gets all of the records in this level with keys in the given
+    /// range (inclusive), sorted by key.
+    ///
+    /// If tables in the level overlap, the newest version of each key
+    /// wins. Tombstones are included, so callers merging across levels
+    /// can tell that a key was deleted.
+    pub async fn get_range(&self, min_key: &ObjectId, max_key: &ObjectId) -> Result<Vec<Record>> {
+        // Skip the level if none of its tables overlap the range...
+        if !self.overlaps(min_key, max_key) {
+            return Ok(vec![]);
+        }
+
+        // Merge from oldest to newest, so newer values overwrite older ones...
+        let mut merged: BTreeMap<ObjectId, Value<Document>> = BTreeMap::new();
+        for th in newest_first(&self.tables).into_iter().rev() {
+            if !th.meta.overlaps(min_key, max_key) {
+                continue;
+            }
+            let sstable = self.read_table(th).await?;
+            for rec in sstable.get_range(min_key, max_key) {
+                merged.insert(rec.key, rec.value);
+            }
+        }
+        Ok(merged
+            .into_iter()
+            .map(|(key, value)| Record { key, value })
+            .collect())
+    }
+
+    /// Records a compaction of this level in its history, dropping
+    /// the oldest event if the history is full.
+    pub fn record_compaction(&mut self, event: CompactionEvent) {
+        if self.compaction_history.len() >= COMPACTION_HISTORY_SIZE {
+            self.compaction_history.pop_front();
+        }
+        self.compaction_history.push_back(event);
+    }
+
+    /// Compacts this level's tables using the level's [CompactionStrategy].
+    ///
+    /// # Arguments
+    ///
+    /// * `is_last_level` - If `true`, the merged table is going to the
+    ///   tree's deepest level, where tombstones have nothing left to
+    ///   shadow, so they're dropped (see [merge_handles]).
     ///
     /// # Returns
     ///
-    /// Returns a reference the new SSTable.
-    pub async fn compact_tables(&self) -> Result<CompactResult> {
-        // Create a place to store the merged SSTable...
-        let mut res: Option<SSTable> = None;
+    /// Returns the new SSTable and the ids of the tables it replaces.
+    pub async fn compact(&self, is_last_level: bool) -> Result<CompactResult> {
+        self.compact_among(newest_first(&self.tables), is_last_level)
+            .await
+    }
 
-        // Create a vector to store the old table ids...
-        let mut old_table_ids = vec![];
+    /// Compacts this level's tables as if the current time were `now`.
+    ///
+    /// Unlike [Level::compact], only tables at least `min_table_age` old
+    /// are compacted (see [Level::eligible_tables]). Younger tables are
+    /// left in the level, which is safe since they're newer than anything
+    /// moved down.
+    pub async fn compact_at(&self, now: DateTime, is_last_level: bool) -> Result<CompactResult> {
+        self.compact_among(self.eligible_tables(now), is_last_level)
+            .await
+    }
 
-        // Iterate through the level's sstables...
-        for table in self.tables.iter() {
-            // Add the table id to the old table ids...
-            old_table_ids.push(table.meta.table_id);
-
-            // Read in the table...
-            let sstable = table.read().await?;
-            if let Some(prev) = res {
-                // Merge the table with the accumulated SSTable...
-                let m = prev.merge(&sstable)?;
-                res = Some(m);
+    /// Compacts the given tables using the level's [CompactionStrategy].
+    async fn compact_among(
+        &self,
+        tables: Vec<&SSTableHandle>,
+        is_last_level: bool,
+    ) -> Result<CompactResult> {
+        match self.compaction_strategy {
+            CompactionStrategy::Full => {
+                merge_handles(&tables, self.read_ahead, is_last_level).await
+            }
+            CompactionStrategy::Hotspot => self.merge_hotspot(tables, is_last_level).await,
+        }
+    }
+
+    /// Compacts exactly the tables with the given ids.
+    ///
+    /// Returns an error if any of the tables isn't in this level, or if
+    /// merging them would change which version of a key wins against
+    /// an overlapping table that's left out:
+    ///
+    /// * If the output is moving to the next level (`in_place` is
+    ///   `false`), any table left behind must be newer than the inputs.
+    /// * If the output stays in this level, any table left out must be
+    ///   older than all of the inputs or newer than all of them. The
+    ///   output takes the newest input's creation time, so it stays
+    ///   ordered between them.
+    pub async fn compact_ids(&self, ids: &[ObjectId], in_place: bool) -> Result<CompactResult> {
+        // Find the tables...
+        let active = newest_first(&self.tables);
+        let mut tables = vec![];
+        for id in ids {
+            let table = active
+                .iter()
+                .find(|t| t.meta.table_id == *id)
+                .ok_or(anyhow!("Table {} isn't in level {}", id, self.meta.level))?;
+            if !tables.contains(table) {
+                tables.push(*table);
+            }
+        }
+
+        // Get the inputs' key range and ages...
+        let (min_key, max_key, oldest, newest) = match (
+            tables.iter().map(|t| t.meta.min_key).min(),
+            tables.iter().map(|t| t.meta.max_key).max(),
+            tables.iter().map(|t| age(t)).min(),
+            tables.iter().map(|t| age(t)).max(),
+        ) {
+            (Some(min), Some(max), Some(oldest), Some(newest)) => (min, max, oldest, newest),
+            _ => return Err(anyhow!("No tables to compact")),
+        };
+
+        // Check the overlapping tables that are left out...
+        for t in active.iter() {
+            if ids.contains(&t.meta.table_id) || !t.meta.overlaps(&min_key, &max_key) {
+                continue;
+            }
+            let ok = if in_place {
+                age(t) < oldest || t.meta.created_at > newest.0
             } else {
-                // There is no accumulated SSTable, so just use this one...
-                res = Some(sstable);
+                age(t) > newest
+            };
+            if !ok {
+                return Err(anyhow!(
+                    "Table {} overlaps the tables being compacted and would be reordered",
+                    t.meta.table_id
+                ));
             }
         }
 
-        // Return the merged SSTable.
-        match res {
-            Some(new_table) => Ok(CompactResult {
-                new_table,
-                old_table_ids,
-            }),
-            None => Err(anyhow!("No SSTable found")),
+        // Merge them...
+        let mut res = merge_handles(&tables, self.read_ahead, false).await?;
+        if in_place {
+            res.new_table.meta.created_at = newest.0;
+        }
+        Ok(res)
+    }
+
+    /// Returns the active tables that are old enough to be compacted
+    /// at time `now`, newest first.
+    ///
+    /// With no minimum age set, every active table is eligible.
+    pub fn eligible_tables(&self, now: DateTime) -> Vec<&SSTableHandle> {
+        let tables = newest_first(&self.tables);
+        if self.min_table_age.is_zero() {
+            return tables;
         }
+        let min_age = self.min_table_age.as_millis() as i64;
+        tables
+            .into_iter()
+            .filter(|t| now.timestamp_millis() - t.meta.created_at.timestamp_millis() >= min_age)
+            .collect()
+    }
+
+    /// Compacts the tables in this level into a single SSTable.
+    ///
+    /// # Returns
+    ///
+    /// Returns a reference the new SSTable.
+    ///
+    /// If `is_last_level` is set, tombstones are dropped from the new
+    /// SSTable (see [Level::compact]).
+    #[tracing::instrument(
+        skip(self),
+        fields(
+            level = self.meta.level,
+            tables = self.tables.len(),
+            records_in = self.tables.iter().map(|t| t.meta.num_records).sum::<usize>(),
+            records_out = tracing::field::Empty,
+        )
+    )]
+    pub async fn compact_tables(&self, is_last_level: bool) -> Result<CompactResult> {
+        let tables: Vec<_> = self.tables.iter().collect();
+        let res = merge_handles(&tables, self.read_ahead, is_last_level).await?;
+        tracing::Span::current().record("records_out", res.new_table.records.len());
+        Ok(res)
+    }
+
+    /// Compacts only the tables overlapping this level's hotspot.
+    ///
+    /// If no key range is covered by more than one table, there's
+    /// no hotspot and all of the level's tables are compacted instead.
+    ///
+    /// Any older tables overlapping the compacted tables' key range are
+    /// compacted with them. Otherwise, moving the newer versions of a
+    /// key down a level would leave an older version in this level to
+    /// shadow them.
+    ///
+    /// See also: [Level::find_hotspot]
+    pub async fn compact_hotspot(&self) -> Result<CompactResult> {
+        self.merge_hotspot(newest_first(&self.tables), false).await
+    }
+
+    /// Merges the hotspot among the `candidates` tables (along with any
+    /// older tables overlapping it). See [Level::compact_hotspot].
+    async fn merge_hotspot(
+        &self,
+        candidates: Vec<&SSTableHandle>,
+        drop_tombstones: bool,
+    ) -> Result<CompactResult> {
+        let mut tables = hotspot(&candidates);
+        if tables.len() < 2 {
+            return merge_handles(&candidates, self.read_ahead, drop_tombstones).await;
+        }
+
+        // Pull in older overlapping tables until there are none left...
+        loop {
+            let min_key = tables.iter().map(|t| t.meta.min_key).min();
+            let max_key = tables.iter().map(|t| t.meta.max_key).max();
+            let newest = tables.iter().map(|t| age(t)).max();
+            let (min_key, max_key, newest) = match (min_key, max_key, newest) {
+                (Some(min), Some(max), Some(newest)) => (min, max, newest),
+                _ => break,
+            };
+            let older: Vec<_> = candidates
+                .iter()
+                .filter(|t| !tables.iter().any(|s| s.meta.table_id == t.meta.table_id))
+                .filter(|t| t.meta.overlaps(&min_key, &max_key) && age(t) < newest)
+                .copied()
+                .collect();
+            if older.is_empty() {
+                break;
+            }
+            tables.extend(older);
+        }
+        merge_handles(&tables, self.read_ahead, drop_tombstones).await
+    }
+
+    /// Finds the key range covered by the most (active) tables in
+    /// this level and returns the tables overlapping it.
+    ///
+    /// This does a sweep over the tables' min/max keys, tracking how
+    /// many table ranges cover each point. Ranges are inclusive, so a
+    /// table starting at the same key another ends on overlaps it.
+    pub fn find_hotspot(&self) -> Vec<&SSTableHandle> {
+        let active: Vec<_> = self.tables.iter().filter(|t| t.active).collect();
+        hotspot(&active)
     }
 
     /// Clears the given tables from this level.
@@ -345,8 +865,32 @@ impl Level {
     /// Returns a `Result` containing either `()` if successful or
     /// an `Error` if not.
     pub async fn clear(&mut self, ids: &[ObjectId]) -> Result<()> {
-        // Create a vector to store the remaining tables...
+        // Remove the tables from the level...
+        let removed = self.detach(ids).await?;
+
+        // Then delete them from disk...
+        for table in removed.iter() {
+            table.delete().await?;
+        }
+
+        // Success!
+        Ok(())
+    }
+
+    /// Removes the given tables from this level *without* deleting
+    /// them from disk, and updates this level's metadata.
+    ///
+    /// # Arguments
+    ///
+    /// * `ids` - The ids of the tables to be removed.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `Result` containing the handles for the removed tables.
+    pub async fn detach(&mut self, ids: &[ObjectId]) -> Result<Vec<SSTableHandle>> {
+        // Create vectors to store the remaining and removed tables...
         let mut remaining = vec![];
+        let mut removed = vec![];
 
         // Convert the ids to a set...
         let ids: HashSet<_> = ids.iter().collect();
@@ -355,10 +899,8 @@ impl Level {
         for table in self.tables.iter() {
             // Check if the table is in the ids...
             if ids.contains(&table.meta.table_id) {
-                // The table is in the ids, so delete it...
-                table.delete().await?;
+                removed.push(table.clone());
             } else {
-                // The table isn't in the ids, so keep it...
                 remaining.push(table.clone());
             }
         }
@@ -369,8 +911,8 @@ impl Level {
         // Update the metadata...
         self.update_table_ids().await?;
 
-        // Success!
-        Ok(())
+        // Return the removed tables...
+        Ok(removed)
     }
 
     /// Removes all tables from this level.
@@ -388,6 +930,7 @@ impl Level {
 
         // Set the tables to an empty vector...
         self.tables = vec![];
+        *self.lock_last_table() = None;
 
         // Iterate through deleting the old tables...
         for table in tables {
@@ -399,16 +942,21 @@ impl Level {
 
     /// Updates the table ids in the level's metadata.
     pub async fn update_table_ids(&mut self) -> Result<()> {
+        // The level's tables changed, so drop the last table read...
+        *self.lock_last_table() = None;
+
         self.meta.table_ids = self.tables.iter().map(|t| t.meta.table_id).collect();
 
         // Update the number of tables...
         self.meta.num_tables = self.tables.len();
+        self.metrics.set_level(self.meta.level, self.size());
 
         // Update the bloom filter...
         self.bloom_filter = self.get_bloom_filter().await?;
 
         // Update the metadata file on disk...
         self.write_meta().await?;
+        self.write_bloom().await?;
 
         // Success!
         Ok(())
@@ -419,37 +967,50 @@ impl Level {
     /// Uses the table ids in the level's metadata to reload
     /// the table handles from disk.
     pub async fn reload_handles(&mut self) -> Result<()> {
-        // Create a vector to store the handles...
-        let mut handles = vec![];
-
-        // Create a bloom filter for the level...
-        let mut bf = BloomFilter::with_rate(BLOOM_FILTER_ERROR_RATE, BLOOM_FILTER_SIZE);
+        self.load_handles(true).await
+    }
 
-        // Iterate through the table ids...
-        // TODO - Make this parallel?
+    /// Reloads the table handles from disk, rebuilding the bloom filter
+    /// from the tables' records if `build_bloom` is set.
+    async fn load_handles(&mut self, build_bloom: bool) -> Result<()> {
+        // Read the tables in concurrently...
+        let mut reads = vec![];
         for id in self.meta.table_ids.iter() {
-            // Get the path to the table...
+            // Find the table's file...
             let table_path = self
-                .format_table_path(id)
-                .ok_or(anyhow!("Couldn't format table path"))?;
-
-            // Read in the table...
-            let table = {
-                let bytes = read_bson(table_path.clone()).await?;
-                let table: SSTable = bson::from_slice(&bytes)?;
-                table
-            };
+                .find_table_path(id)
+                .ok_or(anyhow!("Couldn't find table {}", id))?;
+
+            // Read it in on its own task...
+            let encryption = self.encryption.clone();
+            reads.push(tokio::spawn(async move {
+                let table = read_sstable(&table_path, encryption.as_ref()).await?;
+                Ok::<_, anyhow::Error>((table, table_path))
+            }));
+        }
+
+        // Create a bloom filter for the level...
+        let mut bf = self.config.new_bloom_filter();
+
+        // Collect the tables, in the order of their ids...
+        let mut handles = vec![];
+        for read in reads {
+            let (table, table_path) = read.await??;
 
             // Create the handle...
             let handle = SSTableHandle {
                 active: true,
                 meta: table.meta,
                 path: table_path,
+                encryption: self.encryption.clone(),
+                index_interval: self.config.table_index_interval,
             };
 
             // Add the table's records to the bloom filter...
-            for record in table.records.iter() {
-                bf.insert(&record.key);
+            if build_bloom {
+                for record in table.records.iter() {
+                    bf.insert(&record.key);
+                }
             }
 
             // Add the handle to the vector...
@@ -461,15 +1022,271 @@ impl Level {
 
         // Set the handles...
         self.tables = handles;
+        *self.lock_last_table() = None;
 
         // Set the bloom filter...
-        self.bloom_filter = bf;
+        if build_bloom {
+            self.bloom_filter = bf;
+        }
 
         // Success!
         Ok(())
     }
 }
 
+/// Finds the key range covered by the most of the given tables and
+/// returns the tables overlapping it. See [Level::find_hotspot].
+fn hotspot<'a>(tables: &[&'a SSTableHandle]) -> Vec<&'a SSTableHandle> {
+    // Create the start (0) and end (1) events for each table...
+    let mut events = vec![];
+    for t in tables.iter() {
+        events.push((t.meta.min_key, 0));
+        events.push((t.meta.max_key, 1));
+    }
+
+    // Sort the events so starts come before ends on the same key...
+    events.sort();
+
+    // Sweep through, finding the point with the most overlap...
+    let mut depth = 0;
+    let mut best: Option<(usize, ObjectId)> = None;
+    for (key, kind) in events {
+        if kind == 0 {
+            depth += 1;
+            let deeper = match best {
+                Some((d, _)) => depth > d,
+                None => true,
+            };
+            if deeper {
+                best = Some((depth, key));
+            }
+        } else {
+            depth -= 1;
+        }
+    }
+
+    // Return the tables covering that point...
+    match best {
+        Some((_, key)) => tables
+            .iter()
+            .filter(|t| t.meta.key_in_range(&key))
+            .copied()
+            .collect(),
+        None => vec![],
+    }
+}
+
+/// Returns a sort key for a table's age, where greater is newer.
+///
+/// Tables are ordered by when they were created, with ties broken by
+/// their (also time-ordered) ids.
+fn age(table: &SSTableHandle) -> (DateTime, ObjectId) {
+    (table.meta.created_at, table.meta.table_id)
+}
+
+/// Returns the active tables, sorted from newest to oldest.
+pub fn newest_first(tables: &[SSTableHandle]) -> Vec<&SSTableHandle> {
+    let mut tables: Vec<_> = tables.iter().filter(|t| t.active).collect();
+    tables.sort_by_key(|t| std::cmp::Reverse(age(t)));
+    tables
+}
+
+/// Reads in the given tables and merges them into a single SSTable.
+///
+/// The tables are merged in a single pass (see [SSTable::merge_many]),
+/// so when several tables share a key the newest table's record wins,
+/// regardless of the order the tables are given in.
+///
+/// If `read_ahead` is non-zero, the tables are read in whole, with up
+/// to that many of the upcoming tables read in the background while
+/// the current one is being read. Otherwise, their records are
+/// streamed (see [SSTableHandle::stream_records]), so only a block of
+/// each table is held in memory at once.
+///
+/// If `drop_tombstones` is set, tombstones are left out of the merged
+/// table -- unless that would leave it empty.
+async fn merge_handles(
+    tables: &[&SSTableHandle],
+    read_ahead: usize,
+    drop_tombstones: bool,
+) -> Result<CompactResult> {
+    if tables.is_empty() {
+        return Err(anyhow!("No SSTable found"));
+    }
+
+    // Sort the tables from oldest to newest...
+    let mut tables = tables.to_vec();
+    tables.sort_by_key(|t| age(t));
+    let old_table_ids = tables.iter().map(|t| t.meta.table_id).collect();
+
+    // Merge the records, keeping the newest for each key...
+    let mut records = if read_ahead > 0 {
+        read_and_merge(&tables, read_ahead).await?
+    } else {
+        stream_and_merge(&tables).await?
+    };
+
+    // Drop the tombstones, if there's nothing left for them to shadow...
+    if drop_tombstones && records.iter().any(|r| matches!(r.value, Value::Data(_))) {
+        records.retain(|r| matches!(r.value, Value::Data(_)));
+    }
+
+    // Return the merged SSTable.
+    Ok(CompactResult {
+        new_table: SSTable::new(records)?,
+        old_table_ids,
+    })
+}
+
+/// Merges the records of the given tables (sorted from oldest to
+/// newest) by streaming them, keeping the newest record for each key.
+async fn stream_and_merge(tables: &[&SSTableHandle]) -> Result<Vec<Record>> {
+    // Open a stream for each table and queue up its first record. The
+    // heap pops the smallest key first and, for equal keys, the newest
+    // table...
+    let mut streams = vec![];
+    let mut heads = vec![];
+    let mut heap = BinaryHeap::new();
+    for (i, table) in tables.iter().enumerate() {
+        let mut stream = table.stream_records().await?;
+        let head = stream.next().await.transpose()?;
+        if let Some(rec) = &head {
+            heap.push(Reverse((rec.key, Reverse(i))));
+        }
+        streams.push(stream);
+        heads.push(head);
+    }
+
+    let mut records: Vec<Record> = vec![];
+    while let Some(Reverse((key, Reverse(i)))) = heap.pop() {
+        // The newest record for a key comes first, so skip the rest...
+        let rec = heads[i].take().ok_or(anyhow!("Missing merge head"))?;
+        if records.last().is_none_or(|r| r.key != key) {
+            records.push(rec);
+        }
+
+        // Queue up the table's next record...
+        heads[i] = streams[i].next().await.transpose()?;
+        if let Some(rec) = &heads[i] {
+            heap.push(Reverse((rec.key, Reverse(i))));
+        }
+    }
+    Ok(records)
+}
+
+/// Reads in the given tables (sorted from oldest to newest), reading up
+/// to `read_ahead` of them in the background, and merges their records.
+async fn read_and_merge(tables: &[&SSTableHandle], read_ahead: usize) -> Result<Vec<Record>> {
+    // Create a vector to store the tables read...
+    let mut sstables = vec![];
+
+    // Start reading the first tables in the background...
+    let mut upcoming = tables.iter();
+    let mut pending: VecDeque<_> = upcoming
+        .by_ref()
+        .take(read_ahead)
+        .map(|t| spawn_read(t))
+        .collect();
+
+    // Iterate through the sstables...
+    for table in tables.iter() {
+        // Read in the table (or wait for its read-ahead to finish)...
+        let sstable = match pending.pop_front() {
+            Some(read) => read.await??,
+            None => table.read().await?,
+        };
+
+        // Keep the read-ahead going...
+        if read_ahead > 0 {
+            if let Some(next) = upcoming.next() {
+                pending.push_back(spawn_read(next));
+            }
+        }
+        sstables.push(sstable);
+    }
+
+    // Merge the records, keeping the newest for each key...
+    let merged = SSTable::merge_many(&sstables.iter().collect::<Vec<_>>())?;
+    Ok(merged.records)
+}
+
+/// Builds a bloom filter containing the keys of all of the given tables.
+async fn build_bloom_filter(
+    tables: &[SSTableHandle],
+    config: &StorageConfig,
+) -> Result<BloomFilter> {
+    // Create a new, empty bloom filter...
+    let mut bloom_filter = config.new_bloom_filter();
+
+    // Iterate over the table handles (in reverse order)...
+    for table in tables.iter().rev() {
+        // Read in the table...
+        let sstable = table.read().await?;
+
+        // Iterate over the table's records...
+        for record in sstable.records.iter() {
+            // Insert the record's key into the bloom filter...
+            bloom_filter.insert(&record.key);
+        }
+    }
+
+    // Return it!
+    Ok(bloom_filter)
+}
+
+/// Reads an SSTable in a background task.
+fn spawn_read(table: &SSTableHandle) -> JoinHandle<Result<SSTable>> {
+    let table = table.clone();
+    tokio::spawn(async move { table.read().await })
+}
+
+/// The strategy a level uses to pick which tables get compacted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CompactionStrategy {
+    /// Merge all of the level's tables together.
+    #[default]
+    Full,
+
+    /// Merge only the tables overlapping the key range covered
+    /// by the most tables, to cut read amplification there.
+    Hotspot,
+}
+
+/// A record of a single compaction of a level, for tuning.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompactionEvent {
+    /// When the compaction started.
+    pub started_at: DateTime,
+
+    /// How long the compaction took.
+    pub duration: Duration,
+
+    /// The number of tables that were merged.
+    pub input_tables: usize,
+
+    /// The total size of the merged tables, in bytes.
+    pub input_bytes: u64,
+
+    /// The size of the new table, in bytes.
+    pub output_bytes: u64,
+
+    /// The number of tombstones that didn't make it into the new table.
+    pub tombstones_dropped: usize,
+}
+
+impl CompactionEvent {
+    /// Returns the number of bytes written per byte compacted.
+    ///
+    /// Values near 1.0 mean compaction is rewriting data without
+    /// reclaiming much space.
+    pub fn write_amplification(&self) -> f64 {
+        if self.input_bytes == 0 {
+            return 0.0;
+        }
+        self.output_bytes as f64 / self.input_bytes as f64
+    }
+}
+
 pub struct CompactResult {
     pub new_table: SSTable,
     pub old_table_ids: Vec<ObjectId>,
@@ -517,6 +1334,31 @@ impl LevelMeta {
     }
 }
 
+/// Reads a level's metadata from the level directory at `path`.
+///
+/// If the primary metadata file is missing or can't be deserialized,
+/// the backup copy is tried instead.
+async fn read_meta(path: &Path) -> Result<LevelMeta> {
+    // Try the primary copy first...
+    let primary = async {
+        let bytes = read_bson(path.join(LEVEL_META_FILE)).await?;
+        let meta: LevelMeta = bson::from_slice(&bytes)?;
+        Ok::<_, anyhow::Error>(meta)
+    }
+    .await;
+    let err = match primary {
+        Ok(meta) => return Ok(meta),
+        Err(err) => err,
+    };
+
+    // Then fall back to the backup...
+    let bytes = read_bson(path.join(LEVEL_META_BACKUP_FILE))
+        .await
+        .map_err(|_| err.context("Couldn't read level metadata or its backup"))?;
+    let meta: LevelMeta = bson::from_slice(&bytes)?;
+    Ok(meta)
+}
+
 fn format_meta_path(path: &str) -> Option<String> {
     Path::new(path)
         .join(LEVEL_META_FILE)
@@ -533,7 +1375,7 @@ mod test {
     #[tokio::test]
     async fn create_level() -> Result<()> {
         // Create a new level with no tables...
-        let level = Level::new("/tmp", 1, vec![], true).await?;
+        let level = Level::new("/tmp", 1, vec![], true, &StorageConfig::default()).await?;
 
         println!("Created level: {:?}", level.meta.id);
 
@@ -569,7 +1411,7 @@ mod test {
     #[tokio::test]
     async fn get_bloom_filter() -> Result<()> {
         // Create a new level with no tables...
-        let mut level = Level::new("/tmp", 1, vec![], true).await?;
+        let mut level = Level::new("/tmp", 1, vec![], true, &StorageConfig::default()).await?;
 
         // Create an ID to check for...
         let id = ObjectId::new();
@@ -607,7 +1449,7 @@ mod test {
     #[tokio::test]
     async fn doesnt_contain() -> Result<()> {
         // Create a new level with no tables...
-        let mut level = Level::new("/tmp", 1, vec![], true).await?;
+        let mut level = Level::new("/tmp", 1, vec![], true, &StorageConfig::default()).await?;
         println!("level_id = {}", level.meta.id);
 
         // Create a new key...
@@ -663,10 +1505,191 @@ mod test {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn bad_bloom_filter_falls_back() -> Result<()> {
+        // Create a level with a table holding a key...
+        let mut level = Level::new("/tmp", 1, vec![], true, &StorageConfig::default()).await?;
+        let key = ObjectId::new();
+        let table = SSTable::new(vec![Record {
+            key,
+            value: Value::Data(doc! { "msg": "world" }),
+        }])?;
+        level.add_sstable(&table).await?;
+        assert!(level.bloom_is_consistent());
+
+        // Swap in a bad (empty) bloom filter...
+        level.bloom_filter = level.config.new_bloom_filter();
+        assert!(level.doesnt_contain(&key));
+        assert!(!level.bloom_is_consistent());
+
+        // Without the fallback, the key is wrongly reported missing...
+        level.bloom_fallback = false;
+        assert_eq!(level.get(&key).await?, None);
+
+        // With it, the tables are scanned and the key is found...
+        level.bloom_fallback = true;
+        let val = level.get(&key).await?.ok_or(anyhow!("No value found"))?;
+        assert_eq!(val.value, Value::Data(doc! { "msg": "world" }));
+
+        // And the filter gets rebuilt in the background...
+        while !level.finish_bloom_rebuild().await? {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(level.bloom_is_consistent());
+        assert!(!level.doesnt_contain(&key));
+
+        // Clean up...
+        fs::remove_dir_all(Path::new("/tmp").join(level.meta.id.to_string())).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_range_merges_tables() -> Result<()> {
+        // Create some keys (which will be created in sorted order)...
+        let k: Vec<_> = (0..6).map(|_| ObjectId::new()).collect();
+        let rec = |i: usize, value| Record { key: k[i], value };
+        let data = |n: i32| Value::Data(doc! { "n": n });
+
+        // Add two overlapping tables, the newer one updating and
+        // deleting some of the older one's keys...
+        let mut level = Level::new("/tmp", 1, vec![], true, &StorageConfig::default()).await?;
+        let old = SSTable::new(vec![
+            rec(0, data(0)),
+            rec(2, data(2)),
+            rec(3, data(3)),
+            rec(5, data(5)),
+        ])?;
+        level.add_sstable(&old).await?;
+        let mut new = SSTable::new(vec![
+            rec(1, data(10)),
+            rec(2, data(20)),
+            rec(3, Value::Tombstone),
+        ])?;
+        new.meta.created_at = DateTime::from_millis(old.meta.created_at.timestamp_millis() + 1);
+        level.add_sstable(&new).await?;
+
+        // The range should be merged, newest-wins, and sorted...
+        let res = level.get_range(&k[1], &k[4]).await?;
+        let exp = vec![rec(1, data(10)), rec(2, data(20)), rec(3, Value::Tombstone)];
+        assert_eq!(res, exp);
+
+        // And a range outside of the level's tables should be empty...
+        let before = ObjectId::from_bytes([0; 12]);
+        assert!(level.get_range(&before, &before).await?.is_empty());
+
+        // Clean up...
+        fs::remove_dir_all(Path::new("/tmp").join(level.meta.id.to_string())).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn repeated_reads_reuse_last_table() -> Result<()> {
+        // Add two tables to a level...
+        let k: Vec<_> = (0..4).map(|_| ObjectId::new()).collect();
+        let rec = |i: usize| Record {
+            key: k[i],
+            value: Value::Data(doc! { "n": i as i32 }),
+        };
+        let mut level = Level::new("/tmp", 1, vec![], true, &StorageConfig::default()).await?;
+        level
+            .add_sstable(&SSTable::new(vec![rec(0), rec(1)])?)
+            .await?;
+        level
+            .add_sstable(&SSTable::new(vec![rec(2), rec(3)])?)
+            .await?;
+
+        // Consecutive reads from the same table only read it once...
+        assert_eq!(level.get(&k[2]).await?, Some(rec(2)));
+        assert_eq!(level.get(&k[3]).await?, Some(rec(3)));
+        assert_eq!(level.get_range(&k[2], &k[3]).await?, vec![rec(2), rec(3)]);
+        assert_eq!(level.table_reads(), 1);
+
+        // Reading from the other table replaces it...
+        assert_eq!(level.get(&k[0]).await?, Some(rec(0)));
+        assert_eq!(level.get(&k[1]).await?, Some(rec(1)));
+        assert_eq!(level.table_reads(), 2);
+
+        // Changing the level's tables invalidates it...
+        level.add_sstable(&SSTable::new(vec![rec(3)])?).await?;
+        assert_eq!(level.get(&k[0]).await?, Some(rec(0)));
+        assert_eq!(level.table_reads(), 3);
+
+        // Clean up...
+        fs::remove_dir_all(Path::new("/tmp").join(level.meta.id.to_string())).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reload_handles_reads_all_tables() -> Result<()> {
+        let mut level = Level::new("/tmp", 1, vec![], true, &StorageConfig::default()).await?;
+
+        // Add several tables...
+        let mut bf = level.config.new_bloom_filter();
+        for i in 0..6 {
+            let records: Vec<_> = (0..10)
+                .map(|j| Record::new_data(doc! { "i": i, "j": j }))
+                .collect();
+            for r in records.iter() {
+                bf.insert(&r.key);
+            }
+            level.add_sstable(&SSTable::new(records)?).await?;
+        }
+        let mut expected: Vec<_> = level.tables.iter().map(|t| t.meta.clone()).collect();
+        expected.sort_by_key(|m| Reverse(m.created_at));
+
+        // Reloading them should give the same handles, newest first, and
+        // the same bloom filter...
+        level.tables.clear();
+        level.reload_handles().await?;
+        let metas: Vec<_> = level.tables.iter().map(|t| t.meta.clone()).collect();
+        assert_eq!(metas, expected);
+        assert_eq!(level.bloom_filter, bf);
+
+        // (Clean up) Remove the directory...
+        fs::remove_dir_all(Path::new("/tmp").join(level.meta.id.to_string())).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn bloom_filter_persisted_with_level() -> Result<()> {
+        let config = StorageConfig::default();
+        let mut level = Level::new("/tmp", 1, vec![], true, &config).await?;
+        for i in 0..3 {
+            let records: Vec<_> = (0..10)
+                .map(|j| Record::new_data(doc! { "i": i, "j": j }))
+                .collect();
+            level.add_sstable(&SSTable::new(records)?).await?;
+        }
+        let bloom_path = Path::new(&level.path).join(LEVEL_BLOOM_FILE);
+        assert!(bloom_path.exists());
+
+        // Loading the level should use the persisted filter...
+        let loaded = Level::load_from_file_with("/tmp", &level.meta.id, None, &config).await?;
+        assert_eq!(loaded.bloom_filter, level.bloom_filter);
+
+        // ...and rebuild it if the version tag doesn't match...
+        let mut data = fs::read(&bloom_path).await?;
+        data[..LEVEL_BLOOM_VERSION.len()].copy_from_slice(b"BDBLOOM0");
+        fs::write(&bloom_path, data).await?;
+        let loaded = Level::load_from_file_with("/tmp", &level.meta.id, None, &config).await?;
+        assert_eq!(loaded.bloom_filter, level.bloom_filter);
+        assert_eq!(loaded.read_bloom().await?, Some(level.bloom_filter.clone()));
+
+        // ...or if the file is missing...
+        fs::remove_file(&bloom_path).await?;
+        let loaded = Level::load_from_file_with("/tmp", &level.meta.id, None, &config).await?;
+        assert_eq!(loaded.bloom_filter, level.bloom_filter);
+        assert!(bloom_path.exists());
+
+        // (Clean up) Remove the directory...
+        fs::remove_dir_all(&level.path).await?;
+        Ok(())
+    }
+
     #[tokio::test]
     async fn add_sstable() -> Result<()> {
         // Create a new level with no tables...
-        let mut level = Level::new("/tmp", 1, vec![], true).await?;
+        let mut level = Level::new("/tmp", 1, vec![], true, &StorageConfig::default()).await?;
 
         // Create a new SSTable...
         let table = SSTable::new(vec![
@@ -686,8 +1709,8 @@ mod test {
             .format_table_path(&table.meta.table_id)
             .ok_or(anyhow!("Couldn't format table path"))?;
 
-        // Read in the bytes...
-        let bytes = std::fs::read(table_path)?;
+        // Read in the bytes (and decompress them)...
+        let bytes = decompress_table(std::fs::read(table_path)?)?;
 
         // Deserialize the table as an SSTable...
         let table: SSTable = bson::from_slice(&bytes)?;
@@ -700,6 +1723,31 @@ mod test {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn load_meta_from_backup() -> Result<()> {
+        // Create a new level and add a table...
+        let mut level = Level::new("/tmp", 1, vec![], true, &StorageConfig::default()).await?;
+        let table = SSTable::new(vec![Record::new_data(doc! { "name": "John" })])?;
+        level.add_sstable(&table).await?;
+
+        // Re-write the meta so the backup matches...
+        level.write_meta().await?;
+        let path = Path::new("/tmp").join(level.meta.id.to_string());
+        assert!(path.join(LEVEL_META_BACKUP_FILE).exists());
+
+        // Corrupt the primary meta file...
+        fs::write(path.join(LEVEL_META_FILE), b"not bson").await?;
+
+        // Load the level back in and check it used the backup...
+        let loaded = Level::load_from_file("/tmp", &level.meta.id).await?;
+        assert_eq!(loaded.meta, level.meta);
+        assert_eq!(loaded.tables.len(), 1);
+
+        // (Clean up) Remove the directory...
+        fs::remove_dir_all(path).await?;
+        Ok(())
+    }
+
     // #[test]
     // fn load_meta() -> Result<()> {
     //     todo!();
@@ -718,7 +1766,7 @@ mod test {
     #[tokio::test]
     async fn is_full() -> Result<()> {
         // Create a new level with no tables...
-        let mut level = Level::new("/tmp", 1, vec![], true).await?;
+        let mut level = Level::new("/tmp", 1, vec![], true, &StorageConfig::default()).await?;
 
         // Iterate through the max number of tables, adding handles to the level,
         // checking if the level is full after each iteration. It should only be
@@ -745,6 +1793,118 @@ mod test {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn compact_hotspot() -> Result<()> {
+        // Create a new level using the hotspot strategy...
+        let mut level = Level::new("/tmp", 1, vec![], true, &StorageConfig::default()).await?;
+        level.compaction_strategy = CompactionStrategy::Hotspot;
+
+        // Create some ordered keys...
+        let keys: Vec<_> = (0..10).map(|_| ObjectId::new()).collect();
+        let rec = |i: usize| Record {
+            key: keys[i],
+            value: Value::Data(doc! { "n": i as i32 }),
+        };
+
+        // Three tables overlap around keys 3-4 and one is off on its own...
+        let a = SSTable::new(vec![rec(0), rec(4)])?;
+        let b = SSTable::new(vec![rec(2), rec(3)])?;
+        let c = SSTable::new(vec![rec(3), rec(5)])?;
+        let d = SSTable::new(vec![rec(8), rec(9)])?;
+        for t in [&a, &b, &c, &d] {
+            level.add_sstable(t).await?;
+        }
+
+        // Check the hotspot analysis finds the overlapping tables...
+        let mut hot: Vec<_> = level
+            .find_hotspot()
+            .iter()
+            .map(|t| t.meta.table_id)
+            .collect();
+        hot.sort();
+        let mut exp = vec![a.meta.table_id, b.meta.table_id, c.meta.table_id];
+        exp.sort();
+        assert_eq!(hot, exp, "Expected only the overlapping tables");
+
+        // Compact and check that only the hotspot tables were touched...
+        let CompactResult {
+            new_table,
+            mut old_table_ids,
+        } = level.compact(false).await?;
+        old_table_ids.sort();
+        assert_eq!(old_table_ids, exp, "Expected only hotspot tables compacted");
+        assert!(
+            new_table.get(&keys[8]).is_none(),
+            "Expected the outlying table's keys to be left alone"
+        );
+
+        // (Clean up) Remove the directory...
+        fs::remove_dir_all(Path::new("/tmp").join(level.meta.id.to_string())).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn compact_keeps_newest_duplicate() -> Result<()> {
+        let mut level = Level::new("/tmp", 1, vec![], true, &StorageConfig::default()).await?;
+        let key = ObjectId::new();
+
+        // Three tables, created at distinct times, all with the same key...
+        let table = |v: i32, millis: i64| -> Result<SSTable> {
+            let mut t = SSTable::new(vec![Record {
+                key,
+                value: Value::Data(doc! { "v": v }),
+            }])?;
+            t.meta.created_at = DateTime::from_millis(millis);
+            Ok(t)
+        };
+        let oldest = table(1, 1_000)?;
+        let middle = table(2, 2_000)?;
+        let newest = table(3, 3_000)?;
+
+        // Add them out of order...
+        for t in [&middle, &newest, &oldest] {
+            level.add_sstable(t).await?;
+        }
+
+        // The newest value should survive compaction...
+        let CompactResult { new_table, .. } = level.compact_tables(false).await?;
+        assert_eq!(new_table.records.len(), 1);
+        assert_eq!(new_table.records[0].value, Value::Data(doc! { "v": 3 }));
+
+        // (Clean up) Remove the directory...
+        fs::remove_dir_all(Path::new("/tmp").join(level.meta.id.to_string())).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn compact_with_read_ahead() -> Result<()> {
+        // Create a level with a bunch of large-ish tables...
+        let mut level = Level::new("/tmp", 1, vec![], true, &StorageConfig::default()).await?;
+        for _ in 0..MAX_TABLES_PER_LEVEL {
+            let records: Vec<_> = (0..500)
+                .map(|i| Record::new_data(doc! { "i": i, "data": "x".repeat(100) }))
+                .collect();
+            level.add_sstable(&SSTable::new(records)?).await?;
+        }
+
+        // Compact without read-ahead...
+        level.read_ahead = 0;
+        let without = level.compact(false).await?;
+
+        // And then with it...
+        level.read_ahead = 4;
+        let with = level.compact(false).await?;
+
+        // The output should be the same either way...
+        assert_eq!(with.old_table_ids, without.old_table_ids);
+        assert_eq!(with.new_table.records, without.new_table.records);
+        assert_eq!(with.new_table.records.len(), 500 * MAX_TABLES_PER_LEVEL);
+
+        // (Clean up) Remove the directory...
+        fs::remove_dir_all(Path::new("/tmp").join(level.meta.id.to_string())).await?;
+        Ok(())
+    }
+
     // #[test]
     // fn get() -> Result<()> {
     //     todo!();
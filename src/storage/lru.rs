@@ -0,0 +1,128 @@
+//! Least-recently-used tracking for memtable entries.
+
+use bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Mutex, MutexGuard};
+
+/// A cap on the size of a memtable, past which the least recently
+/// used keys are evicted.
+///
+/// This is only meant for in-memory trees (see
+/// [crate::storage::lsm::Durability::InMemory]), where the memtable
+/// is never flushed to disk and would otherwise grow without bound.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EvictionCap {
+    /// Keep at most this many entries.
+    Entries(usize),
+
+    /// Keep at most (approximately) this many bytes.
+    ///
+    /// See also: [crate::storage::memtable::MemTable::bytes]
+    Bytes(usize),
+}
+
+#[derive(Debug, Default, Clone)]
+struct LruState {
+    /// A counter incremented on each access.
+    tick: u64,
+
+    /// The last access tick for each key.
+    by_key: HashMap<ObjectId, u64>,
+
+    /// The keys, ordered by their last access tick.
+    by_tick: BTreeMap<u64, ObjectId>,
+}
+
+/// Tracks the order keys were last accessed in.
+///
+/// Accesses and removals are `O(log n)`. Reads only have a shared
+/// reference to the memtable, so the state is behind a lock.
+#[derive(Debug, Default)]
+pub struct LruTracker {
+    state: Mutex<LruState>,
+}
+
+impl LruTracker {
+    /// Locks the state, ignoring poisoning.
+    fn lock(&self) -> MutexGuard<'_, LruState> {
+        match self.state.lock() {
+            Ok(s) => s,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    /// Marks the key as the most recently used.
+    pub fn touch(&self, key: &ObjectId) {
+        let mut state = self.lock();
+        state.tick += 1;
+        let tick = state.tick;
+        if let Some(prev) = state.by_key.insert(*key, tick) {
+            state.by_tick.remove(&prev);
+        }
+        state.by_tick.insert(tick, *key);
+    }
+
+    /// Stops tracking the key.
+    pub fn remove(&self, key: &ObjectId) {
+        let mut state = self.lock();
+        if let Some(prev) = state.by_key.remove(key) {
+            state.by_tick.remove(&prev);
+        }
+    }
+
+    /// Removes and returns the least recently used key.
+    pub fn pop_oldest(&self) -> Option<ObjectId> {
+        let mut state = self.lock();
+        let (_, key) = state.by_tick.pop_first()?;
+        state.by_key.remove(&key);
+        Some(key)
+    }
+
+    /// Returns (up to) the `n` most recently used keys, most recent first.
+    pub fn most_recent(&self, n: usize) -> Vec<ObjectId> {
+        self.lock()
+            .by_tick
+            .values()
+            .rev()
+            .take(n)
+            .copied()
+            .collect()
+    }
+
+    /// Stops tracking all keys.
+    pub fn clear(&self) {
+        *self.lock() = LruState::default();
+    }
+}
+
+impl Clone for LruTracker {
+    fn clone(&self) -> Self {
+        LruTracker {
+            state: Mutex::new(self.lock().clone()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pops_least_recent() {
+        let keys: Vec<_> = (0..3).map(|_| ObjectId::new()).collect();
+        let lru = LruTracker::default();
+        for k in keys.iter() {
+            lru.touch(k);
+        }
+
+        // Touching the first key makes the second the oldest...
+        lru.touch(&keys[0]);
+        assert_eq!(lru.pop_oldest(), Some(keys[1]));
+
+        // Removed keys aren't returned...
+        lru.remove(&keys[2]);
+        assert_eq!(lru.pop_oldest(), Some(keys[0]));
+        assert_eq!(lru.pop_oldest(), None);
+    }
+}
@@ -1,10 +1,28 @@
 use anyhow::{anyhow, Result};
 use bson::oid::ObjectId;
-use bson::Document;
+use bson::{DateTime, Document};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
 
+use crate::storage::conf::*;
+use crate::storage::crypto::EncryptionKey;
+use crate::storage::describe::*;
+use crate::storage::error::StorageError;
+use crate::storage::iter::TreeIter;
 use crate::storage::level::*;
+use crate::storage::manifest::*;
 use crate::storage::memtable::*;
+use crate::storage::metrics::{Metrics, MetricsSnapshot};
 use crate::storage::record::*;
+use crate::storage::schedule::*;
+use crate::storage::snapshot::*;
+use crate::storage::sstable::*;
+use crate::storage::util::{dir_size, read_bson, write_bson_atomic};
+use crate::storage::wal::WAL;
 
 /// A struct representing an LSM Tree managing both in-memory
 /// and on-disk data.
@@ -30,43 +48,501 @@ pub struct LSMTree {
     /// The on-disk levels for this LSM Tree.
     pub levels: Vec<Level>,
 
+    /// This LSM Tree's write-ahead log.
+    pub wal: WAL,
+
     /// The path to the directory where this LSM Tree's data is stored.
     pub path: String,
+
+    /// The storage thresholds for this LSM Tree's memtable and levels.
+    pub config: StorageConfig,
+
+    /// Counters tracking the tree's reads, writes, and compactions
+    /// (shared with its levels).
+    ///
+    /// See also: [LSMTree::stats]
+    pub metrics: Arc<Metrics>,
+
+    /// When full (level) compaction is allowed to run.
+    pub compaction_schedule: CompactionSchedule,
+
+    /// The SSTables pinned by live snapshots.
+    pub table_pins: TablePins,
+
+    /// If `true`, compacting level N can skip straight to level N+2
+    /// when none of level N+1's tables overlap the compacted range,
+    /// saving a rewrite.
+    pub skip_nonoverlapping_levels: bool,
+
+    /// How [LSMTree::estimate_reclaimable] trades precision for cost.
+    pub reclaim_estimate: ReclaimEstimate,
+
+    /// Whether the tree's data is persisted to disk or only kept in memory.
+    pub durability: Durability,
+
+    /// If `true`, each memtable flush is read back from disk and checked
+    /// before the memtable is released.
+    pub verify_flushes: bool,
+
+    /// If `true`, batches of records (e.g. from replaying the WAL) are
+    /// loaded into an empty memtable in bulk rather than one at a time.
+    ///
+    /// See also: [MemTable::from_records]
+    pub bulk_replay: bool,
+
+    /// If `true`, each compaction is committed to the tree's MANIFEST
+    /// before the levels are updated, so a crash part way through can
+    /// be recovered (see [LSMTree::recover]).
+    pub checkpoint_compactions: bool,
+
+    /// If `true`, levels left empty by a major compaction are removed
+    /// (along with their directories) and the rest are renumbered, so
+    /// reads don't have to check them (see [LSMTree::major_compact]).
+    pub prune_empty_levels: bool,
+
+    /// The most time (going by the timestamps in their keys) a compacted
+    /// table's keys may span, if any. Wider tables are split, so a range
+    /// scan doesn't have to read much outside its range.
+    ///
+    /// This is ignored unless [LSMTree::timestamped_keys] is set.
+    pub max_table_span: Option<Duration>,
+
+    /// Whether the tree's keys start with the time they were created,
+    /// as generated `ObjectId`s do (the default).
+    ///
+    /// Keys encoded from other values (e.g. strings, see
+    /// [crate::db::key::KeyKind]) only *look* like they have a timestamp,
+    /// so tables aren't split by [LSMTree::max_table_span] for them.
+    pub timestamped_keys: bool,
+
+    /// Tables with fewer records than this are considered tiny, if set.
+    ///
+    /// Each compaction cycle first coalesces runs of a level's tiny
+    /// tables into medium ones (see [LSMTree::minor_compact]), so many
+    /// small flushes don't fill the level with tables that each have to
+    /// be checked on reads.
+    pub tiny_table_records: Option<usize>,
+
+    /// The most tables the first level may hold before writes stall, if
+    /// set.
+    ///
+    /// Once the first level reaches this many tables, memtable flushes
+    /// are paused until level compaction catches up, and writes to a
+    /// full memtable are rejected with [StorageError::Full]. This trades
+    /// write throughput for bounded read latency, since every read may
+    /// have to check each of the first level's tables.
+    pub l0_stall_tables: Option<usize>,
+
+    /// How many of the memtable's most recently accessed records are
+    /// kept in memory when it's flushed (see [LSMTree::set_hot_keys]).
+    hot_keys: usize,
+
+    /// The key the tree's tables and WAL are encrypted with, if any
+    /// (see [LSMTree::set_encryption]).
+    encryption: Option<EncryptionKey>,
+
+    /// The memtable flush running in the background, if any.
+    ///
+    /// See also: [LSMTree::start_flush]
+    flush: Option<BackgroundFlush>,
+
+    /// The hottest records from the last memtable flush.
+    ///
+    /// Writes go to the memtable, which is checked first, so these are
+    /// never stale.
+    hot_cache: HashMap<ObjectId, Value<Document>>,
+
+    /// (Testing only) Corrupts the next flushed SSTable after it's written.
+    #[cfg(test)]
+    corrupt_next_flush: bool,
+
+    /// (Testing only) Fails the next compaction after the merged table is
+    /// added to its level but before the inputs are removed.
+    #[cfg(test)]
+    crash_next_compaction: bool,
 }
 
 impl LSMTree {
-    /// Creates a new LSM Tree with the given name.
-    pub fn new(name: &str, path: &str) -> Self {
+    /// Creates a new LSM Tree with the given name and storage thresholds.
+    pub fn new(name: &str, path: &str, config: StorageConfig) -> Self {
+        let wal_path = Path::new(path).join(WAL_FILE);
         LSMTree {
             id: ObjectId::new(),
             name: name.to_string(),
-            memtable: MemTable::new(),
+            memtable: MemTable::new(&config),
             frozen_memtable: None,
             levels: vec![],
+            wal: WAL::new(&wal_path.to_string_lossy()),
             path: path.to_string(),
+            config,
+            metrics: Arc::default(),
+            compaction_schedule: CompactionSchedule::default(),
+            table_pins: TablePins::default(),
+            skip_nonoverlapping_levels: false,
+            reclaim_estimate: ReclaimEstimate::default(),
+            durability: Durability::default(),
+            verify_flushes: true,
+            bulk_replay: true,
+            checkpoint_compactions: true,
+            prune_empty_levels: false,
+            max_table_span: None,
+            timestamped_keys: true,
+            tiny_table_records: None,
+            l0_stall_tables: None,
+            hot_keys: 0,
+            encryption: None,
+            flush: None,
+            hot_cache: HashMap::new(),
+            #[cfg(test)]
+            corrupt_next_flush: false,
+            #[cfg(test)]
+            crash_next_compaction: false,
         }
     }
 
     /// Load an existing LSM Tree from disk.
-    pub fn load() -> Result<Self> {
-        todo!();
+    ///
+    /// The tree's id and name are read from its metadata file (see
+    /// [TREE_META_FILE]) and its levels are loaded from the level
+    /// directories in `path`. Then any compaction interrupted by a crash
+    /// is finished (see [LSMTree::recover]) and the writes in the WAL
+    /// that never made it to an SSTable are replayed into the memtable.
+    ///
+    /// If there's no tree at `path`, this is the same as [LSMTree::new].
+    ///
+    /// Note: The replayed memtable may be over its size limit, so callers
+    /// should run a compaction cycle afterwards.
+    pub async fn load(name: &str, path: &str, config: StorageConfig) -> Result<Self> {
+        LSMTree::load_with(name, path, config, None).await
+    }
+
+    /// Loads an existing LSM Tree from disk (see [LSMTree::load]), whose
+    /// tables and WAL are encrypted with `encryption` (if it's set).
+    ///
+    /// The key is kept for the tree's new tables and WAL entries (see
+    /// [LSMTree::set_encryption]).
+    pub async fn load_with(
+        name: &str,
+        path: &str,
+        config: StorageConfig,
+        encryption: Option<EncryptionKey>,
+    ) -> Result<Self> {
+        let mut tree = LSMTree::new(name, path, config);
+        tree.set_encryption(encryption);
+
+        // Read the tree's metadata, if it has any...
+        if let Some(meta) = LSMTree::load_meta(path).await? {
+            tree.id = meta.id;
+            tree.name = meta.name;
+        }
+
+        // Load the levels (whose directories are named with their ids)...
+        let mut entries = match tokio::fs::read_dir(path).await {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(tree),
+            Err(err) => return Err(err.into()),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().to_string();
+            match ObjectId::parse_str(&name) {
+                Ok(id) if entry.metadata().await?.is_dir() => {
+                    let mut level = Level::load_from_file_with(
+                        path,
+                        &id,
+                        tree.encryption.clone(),
+                        &tree.config,
+                    )
+                    .await?;
+                    level.metrics = tree.metrics.clone();
+                    tree.levels.push(level);
+                }
+                _ => continue,
+            }
+        }
+        tree.levels.sort_by_key(|l| l.meta.level);
+        tree.sync_level_metrics();
+
+        // Finish any interrupted compaction...
+        tree.recover().await?;
+
+        // Replay the writes that weren't flushed...
+        let records = tree.wal.read().await?;
+        tree.replay(records);
+        Ok(tree)
+    }
+
+    /// Writes the tree's metadata (see [LSMTreeMeta]) to its directory.
+    ///
+    /// This is done when the tree's directory is created (along with its
+    /// first on-disk level), since [LSMTree::new] doesn't touch the disk.
+    pub async fn write_meta(&self) -> Result<()> {
+        let meta = LSMTreeMeta {
+            id: self.id,
+            name: self.name.clone(),
+            path: self.path.clone(),
+        };
+        let path = Path::new(&self.path).join(TREE_META_FILE);
+        write_bson_atomic(path, &bson::to_document(&meta)?).await
+    }
+
+    /// Reads the metadata for the tree stored at `path`.
+    ///
+    /// # Returns
+    ///
+    /// The tree's metadata, or `None` if it hasn't been written.
+    pub async fn load_meta(path: &str) -> Result<Option<LSMTreeMeta>> {
+        let path = Path::new(path).join(TREE_META_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let bytes = read_bson(&path).await?;
+        Ok(Some(bson::from_slice(&bytes)?))
+    }
+
+    /// Sets how many of the memtable's most recently accessed records
+    /// are kept in memory (in a small read cache) when it's flushed, so
+    /// reads of hot keys stay fast right after a flush.
+    ///
+    /// Zero (the default) disables the cache.
+    pub fn set_hot_keys(&mut self, n: usize) {
+        self.hot_keys = n;
+        self.memtable.track_recency = n > 0;
+        if n == 0 {
+            self.hot_cache.clear();
+        }
+    }
+
+    /// Sets the key the tree's new tables and WAL entries are encrypted
+    /// with, if any.
+    ///
+    /// Existing plaintext tables can still be read, but encrypted data
+    /// can't be read without the key.
+    ///
+    /// See also: [crate::storage::crypto]
+    pub fn set_encryption(&mut self, key: Option<EncryptionKey>) {
+        for level in self.levels.iter_mut() {
+            level.encryption = key.clone();
+        }
+        self.wal.encryption = key.clone();
+        self.encryption = key;
     }
 
     /// Set a key to a value in the LSM Tree.
-    pub fn set(&mut self, key: &ObjectId, doc: Document) {
-        self.memtable.set(key, doc);
+    ///
+    /// The write is appended to the WAL (and synced) before the memtable
+    /// is updated, so an error means the write wasn't applied.
+    pub async fn set(&mut self, key: &ObjectId, doc: Document) -> Result<()> {
+        self.write(Record {
+            key: *key,
+            value: Value::Data(doc),
+        })
+        .await
+    }
+
+    /// Writes a batch of records to the LSM Tree, in order.
+    ///
+    /// The records are appended to the WAL as a single entry (see
+    /// [WAL::write_atomic]) with a single sync and then loaded into the
+    /// memtable together (see [LSMTree::replay]), so the batch is applied
+    /// all-or-nothing, even if there's a crash part way through.
+    pub async fn write_batch(&mut self, records: Vec<Record>) -> Result<()> {
+        self.check_flush()?;
+        self.check_stall()?;
+        if self.durability == Durability::Persistent {
+            self.wal.write_atomic(&records).await?;
+            self.wal.sync().await?;
+        }
+        self.replay(records);
+        Ok(())
+    }
+
+    /// Appends a record to the WAL and then applies it to the memtable.
+    ///
+    /// In-memory trees (see [Durability::InMemory]) skip the WAL.
+    pub async fn write(&mut self, record: Record) -> Result<()> {
+        self.check_flush()?;
+        self.check_stall()?;
+        if self.durability == Durability::Persistent {
+            self.wal.write(&record).await?;
+            self.wal.sync().await?;
+        }
+        self.memtable.insert(&record.key, record.value);
+        Ok(())
+    }
+
+    /// Replays a batch of records (e.g. read back from the WAL) into
+    /// the memtable, in order.
+    ///
+    /// The records *aren't* written to the WAL.
+    ///
+    /// This doesn't flush the memtable, even if it fills up, so callers
+    /// should run a compaction cycle afterwards.
+    pub fn replay(&mut self, records: Vec<Record>) {
+        if self.bulk_replay {
+            self.memtable.extend(records);
+            return;
+        }
+        for r in records {
+            self.memtable.insert(&r.key, r.value);
+        }
+    }
+
+    /// Loads a large batch of records straight into SSTables spread
+    /// across the levels, rather than filling the memtable and then
+    /// compacting everything down.
+    ///
+    /// The records are sorted (if a key appears more than once, the
+    /// later record wins) and split across as many levels as it takes
+    /// to hold them, in proportion to each level's capacity. Levels are
+    /// filled to at most one table short of full, so the load doesn't
+    /// immediately trigger compaction.
+    ///
+    /// The tree must be empty.
+    pub async fn bulk_load_presplit(&mut self, records: Vec<Record>) -> Result<()> {
+        if !self.is_empty() {
+            return Err(anyhow!("Pre-split bulk loads need an empty tree"));
+        }
+
+        // In-memory trees never flush, so everything stays in the memtable...
+        if self.durability == Durability::InMemory {
+            self.replay(records);
+            return Ok(());
+        }
+
+        // Sort the records, keeping the latest value for each key...
+        let records: BTreeMap<_, _> = records.into_iter().map(|r| (r.key, r.value)).collect();
+        let n = records.len();
+        let mut records = records
+            .into_iter()
+            .map(|(key, value)| Record { key, value });
+
+        // Add levels until there's room for all of the records...
+        let mut capacities = vec![];
+        let mut total = 0;
+        while total < n {
+            let i = capacities.len();
+            if i == self.levels.len() {
+                self.add_level(true).await?;
+            }
+            let level = &self.levels[i];
+            let cap = level.max_tables.saturating_sub(1).max(1) * level.records_per_table.max(1);
+            capacities.push(cap);
+            total += cap;
+        }
+
+        // Split the records across the levels, by capacity...
+        let mut remaining = n;
+        for (i, cap) in capacities.into_iter().enumerate() {
+            let share = (n * cap).div_ceil(total).min(remaining);
+            remaining -= share;
+            let level_records: Vec<_> = records.by_ref().take(share).collect();
+
+            // Write the level's tables and add them all at once...
+            let mut handles = vec![];
+            for chunk in level_records.chunks(self.levels[i].records_per_table.max(1)) {
+                let table = SSTable::new(chunk.to_vec())?;
+                handles.push(self.levels[i].write_sstable(&table).await?);
+            }
+            self.levels[i].attach(handles).await?;
+        }
+        Ok(())
+    }
+
+    /// Sets a key to a value only if its current value matches `expected`.
+    ///
+    /// If `expected` is `None`, the key must be absent (or deleted).
+    ///
+    /// # Returns
+    ///
+    /// `true` if the value was swapped, `false` if the current value
+    /// didn't match.
+    pub async fn compare_and_set(
+        &mut self,
+        key: &ObjectId,
+        expected: Option<&Document>,
+        doc: Document,
+    ) -> Result<bool> {
+        if self.get(key).await?.as_ref() != expected {
+            return Ok(false);
+        }
+        self.set(key, doc).await?;
+        Ok(true)
     }
 
     /// Delete a key from the LSM Tree.
-    pub fn del(&mut self, key: &ObjectId) {
-        self.memtable.del(key);
+    ///
+    /// Like [LSMTree::set], the delete is appended to the WAL first.
+    pub async fn del(&mut self, key: &ObjectId) -> Result<()> {
+        self.write(Record {
+            key: *key,
+            value: Value::Tombstone,
+        })
+        .await
+    }
+
+    /// Checks if the LSM Tree has no records, in memory or on disk.
+    ///
+    /// Note that a tree holding only tombstones isn't considered empty.
+    pub fn is_empty(&self) -> bool {
+        self.memtable.size() == 0
+            && self.frozen_memtable.is_none()
+            && self.levels.iter().all(|l| l.tables.is_empty())
+    }
+
+    /// Get a value from the LSM Tree's on-disk levels, skipping the
+    /// memtable (and frozen memtable).
+    ///
+    /// This is mostly useful for checking that a write is durable.
+    pub async fn get_from_disk_only(&self, key: &ObjectId) -> Result<Option<Document>> {
+        match self.get_from_disk(key).await? {
+            Some(rec) => match rec.value {
+                Value::Data(doc) => Ok(Some(doc)),
+                Value::Tombstone => Ok(None),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Get all of the values with keys in the given range (inclusive),
+    /// sorted by key.
+    ///
+    /// Records are collected from the memtable, the frozen memtable,
+    /// and then each level, newest first -- so the first version of a
+    /// key found wins and deleted keys are left out.
+    pub async fn get_range(&self, min_key: &ObjectId, max_key: &ObjectId) -> Result<Vec<Document>> {
+        let mut merged: BTreeMap<ObjectId, Value<Document>> = BTreeMap::new();
+
+        // Check the memtables first...
+        let memtables = std::iter::once(&self.memtable).chain(self.frozen_memtable.as_ref());
+        for mt in memtables {
+            for (key, value) in mt.range(min_key, max_key) {
+                merged.entry(*key).or_insert_with(|| value.clone());
+            }
+        }
+
+        // Then the levels...
+        for level in self.levels.iter() {
+            for rec in level.get_range(min_key, max_key).await? {
+                merged.entry(rec.key).or_insert(rec.value);
+            }
+        }
+
+        // Drop the tombstones...
+        Ok(merged
+            .into_values()
+            .filter_map(|value| match value {
+                Value::Data(doc) => Some(doc),
+                Value::Tombstone => None,
+            })
+            .collect())
     }
 
     /// Get a value from the LSM Tree's on-disk levels.
     async fn get_from_disk(&self, key: &ObjectId) -> Result<Option<Record>> {
         // Iterate through the levels...
-        for level in self.levels.iter() {
+        for (i, level) in self.levels.iter().enumerate() {
             if let Some(val) = level.get(key).await? {
+                self.metrics.add_level_hit(i + 1);
                 return Ok(Some(val));
             }
         }
@@ -76,9 +552,13 @@ impl LSMTree {
     /// Get a value from the LSM Tree.
     ///
     /// This will first check the in-memory buffer, then the on-disk levels.
+    #[tracing::instrument(level = "trace", skip_all, fields(tree = %self.name, %key))]
     pub async fn get(&self, key: &ObjectId) -> Result<Option<Document>> {
+        Metrics::add(&self.metrics.gets, 1);
+
         // First try to get it from the memtable...
         if let Some(value) = self.memtable.get(key) {
+            Metrics::add(&self.metrics.memtable_hits, 1);
             return match value {
                 Value::Data(doc) => Ok(Some(doc)),
                 Value::Tombstone => Ok(None),
@@ -88,6 +568,7 @@ impl LSMTree {
         // Next try to get it from the frozen memtable...
         if let Some(frozen) = &self.frozen_memtable {
             if let Some(value) = frozen.get(key) {
+                Metrics::add(&self.metrics.memtable_hits, 1);
                 return match value {
                     Value::Data(doc) => Ok(Some(doc)),
                     Value::Tombstone => Ok(None),
@@ -95,6 +576,15 @@ impl LSMTree {
             }
         }
 
+        // Then try the records kept from the last flush...
+        if let Some(value) = self.hot_cache.get(key) {
+            Metrics::add(&self.metrics.cache_hits, 1);
+            return match value {
+                Value::Data(doc) => Ok(Some(doc.clone())),
+                Value::Tombstone => Ok(None),
+            };
+        }
+
         // Otherwise try to get it from disk...
         match self.get_from_disk(key).await? {
             Some(rec) => match rec.value {
@@ -108,13 +598,53 @@ impl LSMTree {
     /// Move through the levels of the LSM Tree (including the memtable)
     /// and compact them, if necessary.
     pub async fn compaction_cycle(&mut self) -> Result<()> {
-        // Compact the memtable...
-        self.compact_memtable(false).await?;
+        self.compaction_cycle_at(DateTime::now()).await
+    }
+
+    /// Runs a compaction cycle as if the current time were `now`.
+    ///
+    /// The memtable is always flushed if it's full but, if `now` is
+    /// outside of the tree's [CompactionSchedule], level compaction is
+    /// deferred until the next cycle inside a maintenance window.
+    #[tracing::instrument(
+        skip_all,
+        fields(
+            tree = %self.name,
+            memtable_records = self.memtable.size(),
+            levels = self.levels.len(),
+        )
+    )]
+    pub async fn compaction_cycle_at(&mut self, now: DateTime) -> Result<()> {
+        // Swap in any bloom filters rebuilt in the background...
+        for level in self.levels.iter_mut() {
+            level.finish_bloom_rebuild().await?;
+        }
+
+        // Add the last memtable flush's table, if it's done...
+        self.finish_flush(false).await?;
+
+        // If the first level has stalled writes, catch it up first...
+        if self.l0_stalled() && self.compaction_schedule.allows(now) {
+            self.compact_level_at(1, true, now).await?;
+        }
+
+        // Start flushing the memtable, if it's full...
+        self.start_flush(false).await?;
+
+        // Is level compaction allowed right now?
+        if !self.compaction_schedule.allows(now) {
+            return Ok(());
+        }
 
         // Iterate through the levels...
         // Using a while loop as number of levels may change during compaction...
         let mut i = 0;
         while i < self.levels.len() {
+            // Coalesce the level's tiny tables first...
+            if self.tiny_table_records.is_some() {
+                self.minor_compact(i + 1).await?;
+            }
+
             // Get a mutable reference to the level...
             if let Some(level) = self.levels.get_mut(i) {
                 // Is the level full?
@@ -125,62 +655,311 @@ impl LSMTree {
 
                 // Compact the level...
                 let n = i + 1; // The level number is 1-indexed...
-                self.compact_level(n, false).await?;
+                self.compact_level_at(n, false, now).await?;
             }
             i += 1;
         }
         Ok(())
     }
 
-    /// Compacts the memtable into an SSTable and adds it to the first level.
+    /// Compacts the memtable into an SSTable and adds it to the first level,
+    /// waiting for the flush to finish.
     ///
     /// # Arguments
     ///
     /// * `force` - If `true`, the memtable will be compacted even if it isn't full.
-    async fn compact_memtable(&mut self, force: bool) -> Result<()> {
-        // Is the memtable full?
-        if !force || !self.memtable.is_full() {
+    ///
+    /// This does nothing for an in-memory tree (see [Durability::InMemory]).
+    #[tracing::instrument(
+        skip(self),
+        fields(tree = %self.name, records = self.memtable.size())
+    )]
+    pub(crate) async fn compact_memtable(&mut self, force: bool) -> Result<()> {
+        self.start_flush(force).await?;
+        self.finish_flush(true).await
+    }
+
+    /// Flushes the memtable to a new SSTable in the first level, even if
+    /// it isn't full, and waits for the write to finish.
+    ///
+    /// Call this before shutting down, so the tree can be loaded again
+    /// without replaying its WAL. If the memtable is empty there's
+    /// nothing to write, so this only waits for any flush that's
+    /// already running.
+    ///
+    /// This does nothing for an in-memory tree (see [Durability::InMemory]).
+    pub async fn flush(&mut self) -> Result<()> {
+        self.compact_memtable(true).await
+    }
+
+    /// Freezes the memtable and starts flushing it to an SSTable on a
+    /// background task, so writes can continue against a fresh memtable.
+    ///
+    /// Reads are served from the frozen memtable until the flushed table
+    /// is added to the first level (see [LSMTree::finish_flush]). If a
+    /// flush is already running, this waits for it first, so two flushes
+    /// can't race.
+    ///
+    /// # Arguments
+    ///
+    /// * `force` - If `true`, the memtable will be flushed even if it isn't full.
+    async fn start_flush(&mut self, force: bool) -> Result<()> {
+        // In-memory trees never flush...
+        if self.durability == Durability::InMemory {
+            return Ok(());
+        }
+
+        // Is the memtable full (or is this forced)?
+        if !(force || self.memtable.is_full()) {
             // Not full, stop here...
             return Ok(());
         }
 
+        // Hold off until the first level is compacted, if it's stalled...
+        if !force && self.l0_stalled() {
+            return Ok(());
+        }
+
+        // Wait for the last flush to finish...
+        self.finish_flush(true).await?;
+
         // Ensure there isn't already a frozen memtable...
         if self.frozen_memtable.is_some() {
             return Err(anyhow!("Memtable already frozen!"));
         }
 
         // Freeze the memtable...
-        self.frozen_memtable = Some(self.memtable.clone()); // TODO - Get rid of clone
-        self.memtable = MemTable::new();
-
-        // Flush the frozen memtable to an SSTable...
-        let sstable = self
-            .frozen_memtable
-            .as_ref()
-            .ok_or(anyhow!("Failed to get frozen memtable"))?
-            .flush()?;
+        let mut fresh = MemTable::new(&self.config);
+        fresh.track_recency = self.hot_keys > 0;
+        let frozen = std::mem::replace(&mut self.memtable, fresh);
+        let sstable = match frozen.flush() {
+            Ok(Some(sstable)) => sstable,
+            Ok(None) => {
+                // Nothing to write...
+                self.memtable = frozen;
+                return Ok(());
+            }
+            Err(err) => {
+                self.memtable = frozen;
+                return Err(err);
+            }
+        };
+        self.frozen_memtable = Some(frozen);
 
         // Does a new level need to be created before adding the sstable?
-        if self.levels.len() == 0 {
+        if self.levels.is_empty() {
             self.add_level(true).await?;
         }
 
-        // Add the ss-table to the first level...
+        // Write the table in the background...
         // (There should now be at least one level)
-        self.levels[0].add_sstable(&sstable).await?;
+        let level = &self.levels[0];
+        let mut handle = level.new_handle(&sstable)?;
+        let level_id = level.meta.id;
+        let metrics = self.metrics.clone();
+        let verify = self.verify_flushes;
+        #[cfg(test)]
+        let corrupt = std::mem::take(&mut self.corrupt_next_flush);
+        let result: Arc<Mutex<Option<Result<SSTableHandle>>>> = Arc::default();
+        let slot = result.clone();
+        let task = tokio::spawn(async move {
+            let res = async {
+                handle.write(&sstable).await?;
+                Metrics::add(&metrics.bytes_written, handle.size().await?);
+
+                #[cfg(test)]
+                if corrupt {
+                    tokio::fs::write(&handle.path, b"corrupt").await?;
+                }
+
+                // Check that the table made it to disk intact...
+                if verify {
+                    if let Err(err) = verify_flush(&handle, &sstable).await {
+                        handle.delete().await?;
+                        return Err(err.context("Memtable flush failed verification"));
+                    }
+                }
+                Ok(handle)
+            }
+            .await;
+            *lock_flush_result(&slot) = Some(res);
+        });
+        self.flush = Some(BackgroundFlush {
+            task,
+            level_id,
+            result,
+        });
+        Ok(())
+    }
+
+    /// Finishes the background memtable flush, if there is one, adding
+    /// the flushed table to the first level and releasing the frozen
+    /// memtable.
+    ///
+    /// If the flush failed, the frozen memtable's records are put back
+    /// in the memtable (under any newer writes) and the error is returned.
+    ///
+    /// # Arguments
+    ///
+    /// * `wait` - If `true`, waits for a running flush to finish. Otherwise
+    ///   a running flush is left alone.
+    async fn finish_flush(&mut self, wait: bool) -> Result<()> {
+        // Is there a flush (that's done, unless waiting)?
+        let flush = match self.flush.take() {
+            Some(flush) if wait || flush.task.is_finished() => flush,
+            flush => {
+                self.flush = flush;
+                return Ok(());
+            }
+        };
+        flush.task.await?;
+        let res = lock_flush_result(&flush.result)
+            .take()
+            .ok_or(anyhow!("Memtable flush finished without a result"))?;
+        let handle = match res {
+            Ok(handle) => handle,
+            Err(err) => {
+                self.restore_frozen_memtable();
+                return Err(err);
+            }
+        };
+
+        // Add the table to the first level...
+        let level = self
+            .levels
+            .iter_mut()
+            .find(|l| l.meta.id == flush.level_id)
+            .ok_or(anyhow!("Level {} not found", flush.level_id))?;
+        level.attach(vec![handle]).await?;
+
+        // The flushed records are on disk now, so the WAL only needs
+        // the writes that arrived since the memtable was frozen...
+        let newer: Vec<_> = self
+            .memtable
+            .iter()
+            .map(|(key, value)| Record {
+                key: *key,
+                value: value.clone(),
+            })
+            .collect();
+        self.wal.rewrite(&newer).await?;
+        Metrics::add(&self.metrics.flushes, 1);
+
+        // Remove the frozen memtable, keeping its hottest records...
+        if let Some(frozen) = self.frozen_memtable.take() {
+            if self.hot_keys > 0 {
+                self.hot_cache = frozen
+                    .hottest(self.hot_keys)
+                    .into_iter()
+                    .map(|r| (r.key, r.value))
+                    .collect();
+            }
+        }
+        Ok(())
+    }
 
-        // Remove the frozen memtable...
-        self.frozen_memtable = None;
+    /// Checks if the first level has reached [LSMTree::l0_stall_tables]
+    /// tables (counting one being flushed).
+    pub fn l0_stalled(&self) -> bool {
+        let threshold = match self.l0_stall_tables {
+            Some(threshold) => threshold,
+            None => return false,
+        };
+        let tables = self.levels.first().map_or(0, |l| l.tables.len());
+        tables + usize::from(self.flush.is_some()) >= threshold
+    }
+
+    /// Rejects writes while the memtable is full and can't be flushed,
+    /// because the first level is stalled (see [LSMTree::l0_stall_tables]).
+    fn check_stall(&self) -> Result<()> {
+        if self.memtable.is_full() && self.l0_stalled() {
+            return Err(StorageError::Full(format!(
+                "Writes are stalled until level 1 is compacted (it has {} tables)",
+                self.levels.first().map_or(0, |l| l.tables.len())
+            ))
+            .into());
+        }
         Ok(())
     }
 
+    /// Waits for the memtable flush running in the background (if any)
+    /// and adds its table to the first level.
+    ///
+    /// Returns the flush's error, if it failed.
+    pub async fn wait_for_flush(&mut self) -> Result<()> {
+        self.finish_flush(true).await
+    }
+
+    /// Returns the error from the background memtable flush, if it
+    /// failed, putting the frozen memtable's records back in the memtable.
+    ///
+    /// This is checked on each write, so a failed flush is surfaced even
+    /// if nothing is waiting on it.
+    fn check_flush(&mut self) -> Result<()> {
+        let failed = self
+            .flush
+            .as_ref()
+            .is_some_and(|flush| matches!(lock_flush_result(&flush.result).as_ref(), Some(Err(_))));
+        if !failed {
+            return Ok(());
+        }
+        let flush = self
+            .flush
+            .take()
+            .ok_or(anyhow!("Failed to get memtable flush"))?;
+        self.restore_frozen_memtable();
+        let res = lock_flush_result(&flush.result).take();
+        match res {
+            Some(Err(err)) => Err(err.context("Background memtable flush failed")),
+            _ => Err(anyhow!("Background memtable flush failed")),
+        }
+    }
+
+    /// Moves the frozen memtable's records back into the memtable, after
+    /// its flush failed. Records written since it was frozen win.
+    ///
+    /// The WAL still has all of the records, since it's only rewritten
+    /// once a flush succeeds.
+    fn restore_frozen_memtable(&mut self) {
+        if let Some(mut frozen) = self.frozen_memtable.take() {
+            let newer = self
+                .memtable
+                .iter()
+                .map(|(key, value)| Record {
+                    key: *key,
+                    value: value.clone(),
+                })
+                .collect();
+            frozen.extend(newer);
+            frozen.track_recency = self.hot_keys > 0;
+            self.memtable = frozen;
+        }
+    }
+
     /// Compacts the given level into the next level.
     ///
     /// # Arguments
     ///
     /// * `n` - The level number (1-indexed).
     /// * `force` - If `true`, the memtable will be compacted even if it isn't full.
+    #[cfg(test)]
     async fn compact_level(&mut self, n: usize, force: bool) -> Result<()> {
+        self.compact_level_at(n, force, DateTime::now()).await
+    }
+
+    /// Compacts the given level into the next level, as if the current
+    /// time were `now`.
+    ///
+    /// Unless forced, only the level's tables old enough to be compacted
+    /// at `now` are moved down (see [Level::eligible_tables]). If none
+    /// are, this does nothing.
+    ///
+    /// # Arguments
+    ///
+    /// * `n` - The level number (1-indexed).
+    /// * `force` - If `true`, the level will be compacted even if it isn't full.
+    /// * `now` - The time to measure table ages against.
+    async fn compact_level_at(&mut self, n: usize, force: bool, now: DateTime) -> Result<()> {
         // Validate the level number...
         if n == 0 {
             return Err(anyhow!("Level number must be greater than 0"));
@@ -192,6 +971,9 @@ impl LSMTree {
 
         // Get the n-th level...
         let i = n - 1; // The level number is 1-indexed...
+        let started_at = DateTime::now();
+        let start = Instant::now();
+        let into_last = self.compacts_into_last_level(i);
 
         // Get the sstable...
         // Wrapped in a scope to ensure the mutable borrow of self.levels is dropped
@@ -204,14 +986,21 @@ impl LSMTree {
                 .get_mut(i)
                 .ok_or(anyhow!("Level {} not found", n))?;
 
-            // Is the level full?
-            if !force || !level.is_full() {
+            // Is the level full (or is this forced)?
+            if !(force || level.is_full()) {
                 // Not full, stop here...
                 return Ok(());
             }
 
             // Compact the level...
-            level.compact_tables().await?
+            if force {
+                level.compact(into_last).await?
+            } else if level.eligible_tables(now).is_empty() {
+                // Nothing is old enough yet, try again next cycle...
+                return Ok(());
+            } else {
+                level.compact_at(now, into_last).await?
+            }
         };
 
         // Does a new level need to be created before adding the sstable?
@@ -219,33 +1008,686 @@ impl LSMTree {
             self.add_level(true).await?;
         }
 
-        // Add the ss-table to the next level...
-        // (There should now be at least n levels)
-        self.levels[i + 1].add_sstable(&new_table).await?;
+        // Pick the level to add it to. If allowed, skip over the next
+        // level when none of its tables overlap the new table...
+        // (There should now be at least n + 1 levels)
+        let mut target = i + 1;
+        if self.skip_nonoverlapping_levels
+            && target + 1 < self.levels.len()
+            && !self.levels[target].overlaps(&new_table.meta.min_key, &new_table.meta.max_key)
+        {
+            target += 1;
+        }
+
+        // Move the tables from the old level to the target level...
+        let new_tables = self.split_table(new_table)?;
+        let old_tables = self
+            .commit_compaction(i, target, &new_tables, &old_table_ids)
+            .await?;
+
+        // Delete the old tables...
+        // (Tables pinned by a snapshot are deleted once they're released)
+        let mut input_bytes = 0;
+        let mut input_tombstones = 0;
+        for table in old_tables.iter() {
+            input_bytes += table.size().await?;
+            input_tombstones += table.meta.num_tombstones;
+            if !self.table_pins.orphan(table) {
+                table.delete().await?;
+            }
+        }
 
-        // Clear the old level...
-        self.levels[i].clear(&old_table_ids).await?;
+        // Record the compaction in the level's history...
+        let mut output_bytes = 0;
+        let mut output_tombstones = 0;
+        for new_table in new_tables.iter() {
+            output_tombstones += new_table.meta.num_tombstones;
+            if let Some(t) = self.levels[target]
+                .tables
+                .iter()
+                .find(|t| t.meta.table_id == new_table.meta.table_id)
+            {
+                output_bytes += t.size().await?;
+            }
+        }
+        self.levels[i].record_compaction(CompactionEvent {
+            started_at,
+            duration: start.elapsed(),
+            input_tables: old_tables.len(),
+            input_bytes,
+            output_bytes,
+            tombstones_dropped: input_tombstones.saturating_sub(output_tombstones),
+        });
+        Metrics::add(&self.metrics.compactions, 1);
         Ok(())
     }
 
-    /// Adds a new level to the LSM Tree.
+    /// Checks if compacting level `i` (0-indexed) moves its records into
+    /// the tree's deepest level, with nothing older below them.
     ///
-    /// # Arguments
+    /// That's the case if the next level is the last one (or doesn't
+    /// exist yet) and none of its tables overlap level `i`'s keys. Then
+    /// level `i`'s tombstones have nothing left to shadow and can be
+    /// dropped.
+    fn compacts_into_last_level(&self, i: usize) -> bool {
+        let tables = match self.levels.get(i) {
+            Some(level) => &level.tables,
+            None => return false,
+        };
+        let min_key = tables.iter().map(|t| t.meta.min_key).min();
+        let max_key = tables.iter().map(|t| t.meta.max_key).max();
+        let (min_key, max_key) = match (min_key, max_key) {
+            (Some(min), Some(max)) => (min, max),
+            _ => return false,
+        };
+        match self.levels.get(i + 1) {
+            Some(next) => i + 2 == self.levels.len() && !next.overlaps(&min_key, &max_key),
+            None => true,
+        }
+    }
+
+    /// Splits a compaction's merged table, if its keys span more than
+    /// the tree's [LSMTree::max_table_span].
+    fn split_table(&self, table: SSTable) -> Result<Vec<SSTable>> {
+        match self.max_table_span {
+            Some(span) if self.timestamped_keys => table.split_by_span(span),
+            _ => Ok(vec![table]),
+        }
+    }
+
+    /// Replaces the given tables of level `from` (0-indexed) with
+    /// `new_tables` in level `to`, returning the removed tables.
     ///
-    /// * `to_disk` - If `true`, the level will be stored on disk.
+    /// If [LSMTree::checkpoint_compactions] is set, the change is
+    /// committed to the MANIFEST first (see [crate::storage::manifest]).
+    /// The removed tables' files *aren't* deleted.
+    async fn commit_compaction(
+        &mut self,
+        from: usize,
+        to: usize,
+        new_tables: &[SSTable],
+        old_table_ids: &[ObjectId],
+    ) -> Result<Vec<SSTableHandle>> {
+        // Write the new tables (without adding them to their level yet)...
+        let mut handles = vec![];
+        for table in new_tables {
+            handles.push(self.levels[to].write_sstable(table).await?);
+        }
+        if !self.checkpoint_compactions {
+            self.levels[to].attach(handles).await?;
+            return self.levels[from].detach(old_table_ids).await;
+        }
+
+        // Commit the change...
+        let edit = ManifestEdit {
+            from_level: self.levels[from].meta.id,
+            to_level: self.levels[to].meta.id,
+            new_tables: new_tables.iter().map(|t| t.meta.table_id).collect(),
+            old_tables: old_table_ids.to_vec(),
+        };
+        write_manifest(&self.path, &edit).await?;
+
+        // Apply it to the levels...
+        self.levels[to].attach(handles).await?;
+        #[cfg(test)]
+        if std::mem::take(&mut self.crash_next_compaction) {
+            return Err(anyhow!("Simulated crash during compaction"));
+        }
+        let removed = self.levels[from].detach(old_table_ids).await?;
+
+        // Done!
+        clear_manifest(&self.path).await?;
+        Ok(removed)
+    }
+
+    /// Finishes a compaction that was interrupted by a crash, if the
+    /// MANIFEST has one pending.
+    ///
+    /// This should be called once the tree's levels have been loaded,
+    /// and before [LSMTree::gc_orphans]. The compaction is rolled
+    /// forward -- the merged table is added to its level and the inputs
+    /// are removed from theirs. The inputs' files are left behind for
+    /// [LSMTree::gc_orphans] to remove.
     ///
     /// # Returns
     ///
-    /// A `Result` containing `Ok(())` if the level was added successfully.
+    /// `true` if a compaction was recovered.
+    pub async fn recover(&mut self) -> Result<bool> {
+        // Is there a pending compaction?
+        let edit = match read_manifest(&self.path).await? {
+            Some(edit) => edit,
+            None => return Ok(false),
+        };
+
+        // Find its levels...
+        let find = |id: &ObjectId| {
+            self.levels
+                .iter()
+                .position(|l| l.meta.id == *id)
+                .ok_or(anyhow!("Level {} not found", id))
+        };
+        let from = find(&edit.from_level)?;
+        let to = find(&edit.to_level)?;
+
+        // Re-apply the edit (skipping any parts that were already applied)...
+        let mut handles = vec![];
+        for id in edit.new_tables.iter() {
+            if !self.levels[to].meta.table_ids.contains(id) {
+                handles.push(self.levels[to].open_sstable(id).await?);
+            }
+        }
+        self.levels[to].attach(handles).await?;
+        self.levels[from].detach(&edit.old_tables).await?;
+
+        // Done!
+        clear_manifest(&self.path).await?;
+        Ok(true)
+    }
+
+    /// Compacts exactly the given tables of a level into one table.
+    ///
+    /// The merged table replaces the originals, either in the same
+    /// level or (if `into_next` is set) in the next one. Returns an
+    /// error if any of the tables isn't in the level, or if merging them
+    /// would reorder them relative to an overlapping table that's left
+    /// out (see [Level::compact_ids]).
+    ///
+    /// # Arguments
+    ///
+    /// * `n` - The level number (1-indexed).
+    /// * `ids` - The ids of the tables to compact.
+    /// * `into_next` - If `true`, the merged table is moved to the next level.
+    pub async fn compact_tables(
+        &mut self,
+        n: usize,
+        ids: &[ObjectId],
+        into_next: bool,
+    ) -> Result<()> {
+        // Validate the level number...
+        let i = match n.checked_sub(1) {
+            Some(i) if i < self.levels.len() => i,
+            _ => return Err(anyhow!("Level {} not found", n)),
+        };
+
+        // Merge the tables...
+        let CompactResult {
+            new_table,
+            old_table_ids,
+        } = self.levels[i].compact_ids(ids, !into_next).await?;
+
+        // Add the merged table to its level...
+        let target = if into_next { i + 1 } else { i };
+        if target == self.levels.len() {
+            self.add_level(true).await?;
+        }
+        let new_tables = self.split_table(new_table)?;
+        let old_tables = self
+            .commit_compaction(i, target, &new_tables, &old_table_ids)
+            .await?;
+
+        // Delete the originals...
+        // (Tables pinned by a snapshot are deleted once they're released)
+        for table in old_tables {
+            if !self.table_pins.orphan(&table) {
+                table.delete().await?;
+            }
+        }
+        Metrics::add(&self.metrics.compactions, 1);
+        Ok(())
+    }
+
+    /// Coalesces runs of tiny tables in a level into medium tables,
+    /// keeping them in the same level.
+    ///
+    /// Tables are tiny if they have fewer than [LSMTree::tiny_table_records]
+    /// records. Runs of tiny tables that are adjacent by age are merged
+    /// in place, with each merged table holding at most the level's
+    /// `records_per_table` records. Since the inputs are adjacent, no
+    /// other table's version of a key can end up reordered against them.
+    ///
+    /// Unlike level compaction, no data is moved to a deeper level.
+    ///
+    /// # Arguments
+    ///
+    /// * `n` - The level number (1-indexed).
+    ///
+    /// # Returns
+    ///
+    /// The number of tables removed from the level.
+    pub async fn minor_compact(&mut self, n: usize) -> Result<usize> {
+        let threshold = match self.tiny_table_records {
+            Some(threshold) => threshold,
+            None => return Ok(0),
+        };
+        let level = match n.checked_sub(1).and_then(|i| self.levels.get(i)) {
+            Some(level) => level,
+            None => return Err(anyhow!("Level {} not found", n)),
+        };
+
+        // Group the tiny tables, from oldest to newest...
+        let mut runs = vec![];
+        let mut run: Vec<ObjectId> = vec![];
+        let mut run_records = 0;
+        for t in newest_first(&level.tables).into_iter().rev() {
+            let records = t.meta.num_records;
+            let tiny = records < threshold;
+            if !tiny || run_records + records > level.records_per_table {
+                runs.push(std::mem::take(&mut run));
+                run_records = 0;
+            }
+            if tiny {
+                run.push(t.meta.table_id);
+                run_records += records;
+            }
+        }
+        runs.push(run);
+
+        // Merge each run (of more than one table)...
+        let mut removed = 0;
+        for run in runs.into_iter().filter(|r| r.len() > 1) {
+            let before = self.levels[n - 1].tables.len();
+            self.compact_tables(n, &run, false).await?;
+            removed += before.saturating_sub(self.levels[n - 1].tables.len());
+        }
+        Ok(removed)
+    }
+
+    /// Merges all of the tree's data (including the memtable) into a
+    /// single table in the last level.
+    ///
+    /// Since nothing older is left for them to hide, tombstones are
+    /// dropped along with any shadowed records.
+    ///
+    /// If [LSMTree::prune_empty_levels] is set, the (now empty) upper
+    /// levels are removed afterwards.
+    pub async fn major_compact(&mut self) -> Result<()> {
+        // Flush the memtable...
+        self.finish_flush(true).await?;
+        if self.memtable.size() > 0 {
+            self.compact_memtable(true).await?;
+        }
+
+        // Merge from oldest to newest, so newer values overwrite older ones...
+        let mut merged: BTreeMap<ObjectId, Value<Document>> = BTreeMap::new();
+        let mut old_table_ids = vec![];
+        for level in self.levels.iter().rev() {
+            for th in newest_first(&level.tables).into_iter().rev() {
+                for rec in th.read().await?.records {
+                    merged.insert(rec.key, rec.value);
+                }
+            }
+            old_table_ids.push(
+                level
+                    .tables
+                    .iter()
+                    .map(|t| t.meta.table_id)
+                    .collect::<Vec<_>>(),
+            );
+        }
+        old_table_ids.reverse();
+
+        // Drop the tombstones...
+        let records: Vec<_> = merged
+            .into_iter()
+            .filter(|(_, value)| matches!(value, Value::Data(_)))
+            .map(|(key, value)| Record { key, value })
+            .collect();
+
+        // Add the new table to the last level...
+        if !records.is_empty() {
+            let table = SSTable::new(records)?;
+            self.levels
+                .last_mut()
+                .ok_or(anyhow!("No levels to compact into"))?
+                .add_sstable(&table)
+                .await?;
+        }
+
+        // Remove the old tables...
+        // (Tables pinned by a snapshot are deleted once they're released)
+        for (level, ids) in self.levels.iter_mut().zip(old_table_ids) {
+            for table in level.detach(&ids).await? {
+                if !self.table_pins.orphan(&table) {
+                    table.delete().await?;
+                }
+            }
+        }
+
+        Metrics::add(&self.metrics.compactions, 1);
+
+        // Remove the levels that are left empty...
+        if self.prune_empty_levels {
+            self.remove_empty_levels().await?;
+        }
+        Ok(())
+    }
+
+    /// Removes the tree's empty levels (and their directories), then
+    /// renumbers the remaining levels so they're numbered from 1 again.
+    async fn remove_empty_levels(&mut self) -> Result<()> {
+        let (levels, empty): (Vec<_>, Vec<_>) = std::mem::take(&mut self.levels)
+            .into_iter()
+            .partition(|level| !level.tables.is_empty());
+        self.levels = levels;
+
+        // Delete the empty levels' directories...
+        for level in empty {
+            match tokio::fs::remove_dir_all(&level.path).await {
+                Ok(()) => {}
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        // Renumber the levels that are left...
+        for (i, level) in self.levels.iter_mut().enumerate() {
+            if level.meta.level != i + 1 {
+                level.meta.level = i + 1;
+                level.write_meta().await?;
+            }
+        }
+        self.sync_level_metrics();
+        Ok(())
+    }
+
+    /// Returns a snapshot of the tree's metrics (see [LSMTree::metrics]).
+    ///
+    /// This only reads counters, so it's cheap and doesn't touch disk.
+    pub fn stats(&self) -> MetricsSnapshot {
+        self.metrics.snapshot()
+    }
+
+    /// Resets the level sizes in the tree's metrics from its levels,
+    /// after levels are added, removed, or renumbered.
+    fn sync_level_metrics(&self) {
+        self.metrics
+            .set_levels(self.levels.iter().map(|l| l.size()).collect());
+    }
+
+    /// Removes files in the tree's directory that no longer belong to it.
+    ///
+    /// This covers level directories for levels the tree no longer has,
+    /// table files that aren't in their level (and aren't pinned by a
+    /// snapshot), and leftover temp files from interrupted writes.
+    ///
+    /// Returns an error if a compaction is pending (see [LSMTree::recover]).
+    ///
+    /// # Returns
+    ///
+    /// The number of files and directories removed.
+    pub async fn gc_orphans(&self) -> Result<usize> {
+        // A pending compaction's merged table isn't in its level yet...
+        if has_pending_edit(&self.path) {
+            return Err(anyhow!("A compaction is pending, recover it first"));
+        }
+
+        // Neither is a table being flushed in the background...
+        if self.flush.is_some() {
+            return Err(anyhow!("A memtable flush is running, wait for it first"));
+        }
+
+        let mut removed = 0;
+        let mut entries = match tokio::fs::read_dir(&self.path).await {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(err) => return Err(err.into()),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            // Level directories are named with an ObjectId...
+            let name = entry.file_name().to_string_lossy().to_string();
+            let id = match ObjectId::parse_str(&name) {
+                Ok(id) if entry.metadata().await?.is_dir() => id,
+                _ => continue,
+            };
+
+            // Remove the whole directory if it isn't one of the tree's levels...
+            let level = match self.levels.iter().find(|l| l.meta.id == id) {
+                Some(level) => level,
+                None => {
+                    tokio::fs::remove_dir_all(entry.path()).await?;
+                    removed += 1;
+                    continue;
+                }
+            };
+
+            // Otherwise, remove any files that aren't the level's...
+            let mut files = tokio::fs::read_dir(entry.path()).await?;
+            while let Some(file) = files.next_entry().await? {
+                let name = file.file_name().to_string_lossy().to_string();
+                if [LEVEL_META_FILE, LEVEL_META_BACKUP_FILE, LEVEL_BLOOM_FILE]
+                    .contains(&name.as_str())
+                {
+                    continue;
+                }
+                let keep = match TableFormat::table_id(&name) {
+                    Some(tid) => {
+                        level.meta.table_ids.contains(&tid) || self.table_pins.is_pinned(&tid)
+                    }
+                    None => false,
+                };
+                if !keep {
+                    tokio::fs::remove_file(file.path()).await?;
+                    removed += 1;
+                }
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Adds a new level to the LSM Tree.
+    ///
+    /// # Arguments
+    ///
+    /// * `to_disk` - If `true`, the level will be stored on disk.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing `Ok(())` if the level was added successfully.
     pub async fn add_level(&mut self, to_disk: bool) -> Result<()> {
         // Create a new level...
-        let level = Level::new(self.path.as_str(), self.levels.len() + 1, vec![], to_disk).await?;
+        let mut level = Level::new(
+            self.path.as_str(),
+            self.levels.len() + 1,
+            vec![],
+            to_disk,
+            &self.config,
+        )
+        .await?;
+        level.encryption = self.encryption.clone();
+        level.metrics = self.metrics.clone();
 
         // Add the level to the LSM Tree...
         self.levels.push(level);
+        self.sync_level_metrics();
+
+        // Persist the tree's metadata along with its first on-disk level...
+        if to_disk && !Path::new(&self.path).join(TREE_META_FILE).exists() {
+            self.write_meta().await?;
+        }
         Ok(())
     }
 
+    /// Returns the recent compactions of the given level (1-indexed),
+    /// oldest first.
+    pub fn compaction_history(&self, n: usize) -> Result<&VecDeque<CompactionEvent>> {
+        match n.checked_sub(1).and_then(|i| self.levels.get(i)) {
+            Some(level) => Ok(&level.compaction_history),
+            None => Err(anyhow!("Level {} not found", n)),
+        }
+    }
+
+    /// Describes the tree's structure (its memtable, levels, and tables).
+    ///
+    /// This only reads metadata, so it's cheap and doesn't touch disk.
+    pub fn describe(&self) -> TreeDescription {
+        TreeDescription {
+            name: self.name.clone(),
+            path: self.path.clone(),
+            memtable_records: self.memtable.size(),
+            memtable_bytes: self.memtable.bytes(),
+            has_frozen_memtable: self.frozen_memtable.is_some(),
+            levels: self
+                .levels
+                .iter()
+                .map(|level| LevelDescription {
+                    id: level.meta.id.to_hex(),
+                    level: level.meta.level,
+                    tables: newest_first(&level.tables)
+                        .into_iter()
+                        .map(|t| TableDescription {
+                            id: t.meta.table_id.to_hex(),
+                            created_at: t.meta.created_at.timestamp_millis(),
+                            min_key: t.meta.min_key.to_hex(),
+                            max_key: t.meta.max_key.to_hex(),
+                            num_records: t.meta.num_records,
+                            num_tombstones: t.meta.num_tombstones,
+                            active: t.active,
+                        })
+                        .collect(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Returns the approximate number of records in the tree.
+    ///
+    /// This sums the records in the memtables and the level metadata,
+    /// so it doesn't touch disk. It's approximate because a key written
+    /// more than once (or deleted, leaving a tombstone) is counted once
+    /// for each table (or memtable) it's in.
+    pub fn approx_count(&self) -> usize {
+        let frozen = self.frozen_memtable.as_ref().map_or(0, |m| m.size());
+        let on_disk: usize = self
+            .levels
+            .iter()
+            .flat_map(|l| l.tables.iter())
+            .map(|t| t.meta.num_records)
+            .sum();
+        self.memtable.size() + frozen + on_disk
+    }
+
+    /// Returns the size of each of the tree's levels, in order.
+    ///
+    /// The table and record counts come from the levels' metadata, but
+    /// each level's directory is walked to find its size on disk.
+    pub async fn level_stats(&self) -> Result<Vec<LevelStat>> {
+        let mut stats = Vec::with_capacity(self.levels.len());
+        for level in self.levels.iter() {
+            stats.push(LevelStat {
+                level: level.meta.level,
+                tables: level.tables.len(),
+                records: level.tables.iter().map(|t| t.meta.num_records).sum(),
+                bytes: dir_size(&level.path).await?,
+            });
+        }
+        Ok(stats)
+    }
+
+    /// Takes a point-in-time snapshot of the LSM Tree.
+    ///
+    /// Reads through the snapshot (see [Snapshot::get] and
+    /// [Snapshot::get_range]) see the tree as it was when the snapshot
+    /// was taken, even after later writes and compactions.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot::new(
+            self.memtable.clone(),
+            self.frozen_memtable.clone(),
+            self.levels.iter().map(|l| l.tables.clone()).collect(),
+            self.table_pins.clone(),
+        )
+    }
+
+    /// Get a value from the LSM Tree as of the given snapshot (see
+    /// [Snapshot::get]).
+    pub async fn snapshot_get(&self, snap: &Snapshot, key: &ObjectId) -> Result<Option<Document>> {
+        snap.get(key).await
+    }
+
+    /// Get all of the values with keys in the given range (inclusive)
+    /// as of the given snapshot, sorted by key (see [Snapshot::get_range]).
+    pub async fn snapshot_range(
+        &self,
+        snap: &Snapshot,
+        start: &ObjectId,
+        end: &ObjectId,
+    ) -> Result<Vec<(ObjectId, Document)>> {
+        snap.get_range(start, end).await
+    }
+
+    /// Iterates over every live record in the tree, in key order.
+    ///
+    /// The iterator reads from a snapshot (see [LSMTree::snapshot]), so
+    /// it isn't affected by later writes or compactions, and merges the
+    /// memtables and tables as it goes rather than reading them all in
+    /// up front (see [TreeIter]).
+    pub async fn iter(&self) -> Result<TreeIter> {
+        TreeIter::new(self.snapshot()).await
+    }
+
+    /// Like [LSMTree::iter], but only over the records with keys in the
+    /// given range (inclusive).
+    ///
+    /// Tables outside the range aren't read, and reading stops at the
+    /// end of the range, so the work done is bounded by how many records
+    /// are read rather than by the size of the range (see
+    /// [TreeIter::range]).
+    pub async fn iter_range(&self, start: &ObjectId, end: &ObjectId) -> Result<TreeIter> {
+        TreeIter::range(self.snapshot(), start, end).await
+    }
+
+    /// Estimates the number of bytes a full compaction would free by
+    /// dropping tombstones and shadowed (overwritten or deleted) records,
+    /// without compacting.
+    ///
+    /// How precise (and expensive) the estimate is depends on the tree's
+    /// [ReclaimEstimate] setting.
+    ///
+    /// Tombstones are counted as reclaimable, since a full compaction
+    /// (see [LSMTree::major_compact]) drops them.
+    pub async fn estimate_reclaimable(&self) -> Result<u64> {
+        let max_tables = match self.reclaim_estimate {
+            ReclaimEstimate::Metadata => 0,
+            ReclaimEstimate::Sampled { max_tables } => max_tables,
+        };
+
+        // Track the keys that have a newer value, which shadows
+        // any older records with the same key...
+        let mut newer: HashSet<ObjectId> = self.memtable.iter().map(|(k, _)| *k).collect();
+        if let Some(frozen) = &self.frozen_memtable {
+            newer.extend(frozen.iter().map(|(k, _)| *k));
+        }
+
+        // Iterate through the tables from newest to oldest...
+        let mut total = 0;
+        let mut sampled = 0;
+        for level in self.levels.iter() {
+            for th in newest_first(&level.tables) {
+                if sampled < max_tables {
+                    // Read the table and count its reclaimable records...
+                    sampled += 1;
+                    let (mut reclaimable, mut raw) = (0, 0);
+                    for rec in th.read().await?.records {
+                        let len = bson::to_vec(&rec)?.len() as u64;
+                        raw += len;
+                        let shadowed = !newer.insert(rec.key);
+                        if shadowed || matches!(rec.value, Value::Tombstone) {
+                            reclaimable += len;
+                        }
+                    }
+
+                    // Then scale it to the (compressed) size on disk...
+                    let size = th.size().await?;
+                    total += (size * reclaimable).checked_div(raw).unwrap_or(0);
+                } else if th.meta.num_records > 0 {
+                    // Otherwise, use the table's share of tombstones...
+                    let size = th.size().await?;
+                    total += size * th.meta.num_tombstones as u64 / th.meta.num_records as u64;
+                }
+            }
+        }
+        Ok(total)
+    }
+
     pub async fn scan(
         &self,
         _start: Option<&ObjectId>,
@@ -265,7 +1707,82 @@ impl LSMTree {
     }
 }
 
+/// A memtable flush running on a background task.
+///
+/// The task writes the frozen memtable's table to disk and leaves the
+/// result in the shared `result` slot, where the tree picks it up (see
+/// [LSMTree::finish_flush]).
+struct BackgroundFlush {
+    /// The task writing the table.
+    task: JoinHandle<()>,
+
+    /// The id of the level the table belongs in.
+    level_id: ObjectId,
+
+    /// The written table's handle, or the error the flush failed with,
+    /// once the task is done.
+    result: Arc<Mutex<Option<Result<SSTableHandle>>>>,
+}
+
+/// Locks a background flush's result slot, ignoring poisoning.
+fn lock_flush_result(
+    result: &Mutex<Option<Result<SSTableHandle>>>,
+) -> MutexGuard<'_, Option<Result<SSTableHandle>>> {
+    match result.lock() {
+        Ok(r) => r,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+/// Reads a newly flushed SSTable back from disk and checks that it
+/// matches what was written.
+async fn verify_flush(handle: &SSTableHandle, expected: &SSTable) -> Result<()> {
+    let table = handle.read().await?;
+    if table.records.len() != expected.records.len()
+        || table.meta.num_records != expected.meta.num_records
+    {
+        return Err(anyhow!(
+            "Flushed table {} has {} records, expected {}",
+            expected.meta.table_id,
+            table.records.len(),
+            expected.records.len()
+        ));
+    }
+    Ok(())
+}
+
+/// Whether an LSM Tree's data is persisted to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Durability {
+    /// Data is flushed to SSTables on disk (the default).
+    #[default]
+    Persistent,
+
+    /// Data is only ever kept in the memtable, which is never flushed.
+    ///
+    /// This is useful for ephemeral data (like sessions or caches) but
+    /// **all of the data is lost when the process exits.**
+    InMemory,
+}
+
+/// How [LSMTree::estimate_reclaimable] trades precision for cost.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReclaimEstimate {
+    /// Only use the tables' metadata and file sizes.
+    ///
+    /// This is cheap but only counts tombstones, not the older
+    /// records they (or newer writes) shadow.
+    #[default]
+    Metadata,
+
+    /// Read up to `max_tables` tables (newest first) and count their
+    /// tombstones and shadowed records. Any remaining tables fall back
+    /// to the metadata-only estimate.
+    Sampled { max_tables: usize },
+}
+
 /// A struct representing the metadata for an LSM Tree.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct LSMTreeMeta {
     /// The unique identifier for this LSM Tree.
     pub id: ObjectId,
@@ -278,4 +1795,1422 @@ pub struct LSMTreeMeta {
 }
 
 #[cfg(test)]
-mod test {}
+mod test {
+    use super::*;
+    use bson::doc;
+
+    #[test]
+    fn wal_per_tree() {
+        // Create two trees, as two collections would...
+        let a = LSMTree::new("a", "/tmp/db/a", StorageConfig::default());
+        let b = LSMTree::new("b", "/tmp/db/b", StorageConfig::default());
+
+        // Each should have its own log in its own directory...
+        assert_eq!(
+            Path::new(&a.wal.path),
+            Path::new("/tmp/db/a").join(WAL_FILE)
+        );
+        assert_eq!(
+            Path::new(&b.wal.path),
+            Path::new("/tmp/db/b").join(WAL_FILE)
+        );
+        assert_ne!(a.wal.path, b.wal.path);
+    }
+
+    #[tokio::test]
+    async fn writes_are_logged_until_flushed() -> Result<()> {
+        let path = format!("/tmp/{}", ObjectId::new());
+        let mut tree = LSMTree::new("test", &path, StorageConfig::default());
+        let keys: Vec<_> = (0..3).map(|_| ObjectId::new()).collect();
+
+        // Writes go to the WAL, in order...
+        tree.set(&keys[0], doc! { "v": 0 }).await?;
+        tree.set(&keys[1], doc! { "v": 1 }).await?;
+        tree.del(&keys[0]).await?;
+        let logged: Vec<_> = tree.wal.read().await?.into_iter().map(|r| r.key).collect();
+        assert_eq!(logged, vec![keys[0], keys[1], keys[0]]);
+        assert_eq!(tree.get(&keys[1]).await?, Some(doc! { "v": 1 }));
+
+        // Flushing the memtable truncates the WAL...
+        tree.compact_memtable(true).await?;
+        assert!(tree.wal.read().await?.is_empty());
+        tree.set(&keys[2], doc! { "v": 2 }).await?;
+        assert_eq!(tree.wal.read().await?.len(), 1);
+
+        // In-memory trees don't log anything...
+        let mem_path = format!("/tmp/{}", ObjectId::new());
+        let mut mem = LSMTree::new("test", &mem_path, StorageConfig::default());
+        mem.durability = Durability::InMemory;
+        mem.set(&keys[0], doc! { "v": 0 }).await?;
+        assert!(!Path::new(&mem.wal.path).exists());
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn presplit_bulk_load_fills_levels_by_capacity() -> Result<()> {
+        let path = format!("/tmp/{}", ObjectId::new());
+        let mut tree = LSMTree::new("test", &path, StorageConfig::default());
+
+        // Bulk load more records than the first two levels can hold...
+        let docs: Vec<_> = (0..5000)
+            .map(|i| (ObjectId::new(), doc! { "i": i }))
+            .collect();
+        let records: Vec<_> = docs
+            .iter()
+            .map(|(key, doc)| Record {
+                key: *key,
+                value: Value::Data(doc.clone()),
+            })
+            .collect();
+        tree.bulk_load_presplit(records.clone()).await?;
+        assert_eq!(tree.levels.len(), 3);
+
+        // Each level's share should match its share of the capacity...
+        let capacity = |l: &Level| (l.max_tables - 1) * l.records_per_table;
+        let total: usize = tree.levels.iter().map(capacity).sum();
+        for level in tree.levels.iter() {
+            let n: usize = level.tables.iter().map(|t| t.meta.num_records).sum();
+            let expected = 5000 * capacity(level) / total;
+            assert!(
+                n.abs_diff(expected) <= 1,
+                "{} records, expected {}",
+                n,
+                expected
+            );
+            assert!(!level.is_full());
+        }
+
+        // Nothing needs compacting...
+        tree.compaction_cycle().await?;
+        assert_eq!(tree.levels.len(), 3);
+        assert_eq!(tree.memtable.size(), 0);
+
+        // And all of the data is readable...
+        for (key, doc) in docs {
+            assert_eq!(tree.get(&key).await?, Some(doc));
+        }
+
+        // Loading into a tree with data is an error...
+        assert!(tree.bulk_load_presplit(records).await.is_err());
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn meta_round_trip() -> Result<()> {
+        let path = format!("/tmp/{}", ObjectId::new());
+        assert_eq!(LSMTree::load_meta(&path).await?, None);
+
+        // Creating the tree's first level should write its metadata...
+        let mut tree = LSMTree::new("test", &path, StorageConfig::default());
+        tree.add_level(true).await?;
+        let meta = LSMTree::load_meta(&path).await?.unwrap();
+        assert_eq!(meta.id, tree.id);
+        assert_eq!(meta.name, "test");
+
+        // ...and rewriting it should pick up changes...
+        tree.name = "renamed".to_string();
+        tree.write_meta().await?;
+        let meta = LSMTree::load_meta(&path).await?.unwrap();
+        assert_eq!(meta.id, tree.id);
+        assert_eq!(meta.name, "renamed");
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn load_recovers_levels_and_wal() -> Result<()> {
+        let path = format!("/tmp/{}", ObjectId::new());
+
+        // Loading a tree that doesn't exist gives a new, empty tree...
+        let tree = LSMTree::load("test", &path, StorageConfig::default()).await?;
+        assert!(tree.is_empty());
+        assert!(tree.levels.is_empty());
+
+        // Write enough to fill a few levels, with some writes left unflushed...
+        let mut tree = LSMTree::new("test", &path, StorageConfig::default());
+        let keys: Vec<_> = (0..1250).map(|_| ObjectId::new()).collect();
+        for (i, k) in keys.iter().enumerate() {
+            tree.set(k, doc! { "i": i as i32 }).await?;
+            tree.compaction_cycle().await?;
+        }
+        tree.wait_for_flush().await?;
+        tree.del(&keys[0]).await?;
+        assert!(tree.levels.len() > 1);
+        assert!(tree.memtable.size() > 0);
+
+        // Load it back in (as after a crash)...
+        let loaded = LSMTree::load("other", &path, StorageConfig::default()).await?;
+        assert_eq!(loaded.id, tree.id);
+        assert_eq!(loaded.name, "test");
+        let level_ids = |t: &LSMTree| -> Vec<_> { t.levels.iter().map(|l| l.meta.id).collect() };
+        assert_eq!(level_ids(&loaded), level_ids(&tree));
+        assert_eq!(loaded.memtable.size(), tree.memtable.size());
+
+        // All of the writes should be there...
+        assert_eq!(loaded.get(&keys[0]).await?, None);
+        for (i, k) in keys.iter().enumerate().skip(1) {
+            assert_eq!(loaded.get(k).await?, Some(doc! { "i": i as i32 }));
+        }
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn load_encrypted_tree() -> Result<()> {
+        let path = format!("/tmp/{}", ObjectId::new());
+        let key = EncryptionKey::generate();
+
+        // Write an encrypted tree, with some writes left in the WAL...
+        let mut tree = LSMTree::new("test", &path, StorageConfig::default());
+        tree.set_encryption(Some(key.clone()));
+        let keys: Vec<_> = (0..250).map(|_| ObjectId::new()).collect();
+        for (i, k) in keys.iter().enumerate() {
+            tree.set(k, doc! { "i": i as i32 }).await?;
+            tree.compaction_cycle().await?;
+        }
+        tree.wait_for_flush().await?;
+        assert!(!tree.levels.is_empty());
+        assert!(tree.memtable.size() > 0);
+
+        // It can't be loaded without the key...
+        assert!(LSMTree::load("test", &path, StorageConfig::default())
+            .await
+            .is_err());
+
+        // But it can with it...
+        let loaded = LSMTree::load_with("test", &path, StorageConfig::default(), Some(key)).await?;
+        assert_eq!(loaded.memtable.size(), tree.memtable.size());
+        for (i, k) in keys.iter().enumerate() {
+            assert_eq!(loaded.get(k).await?, Some(doc! { "i": i as i32 }));
+        }
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn compare_and_set() -> Result<()> {
+        let path = format!("/tmp/{}", ObjectId::new());
+        let mut tree = LSMTree::new("test", &path, StorageConfig::default());
+        let key = ObjectId::new();
+
+        // Expecting it to be absent...
+        assert!(tree.compare_and_set(&key, None, doc! { "v": 1 }).await?);
+        assert!(!tree.compare_and_set(&key, None, doc! { "v": 2 }).await?);
+
+        // Expecting a value...
+        let v1 = doc! { "v": 1 };
+        assert!(
+            !tree
+                .compare_and_set(&key, Some(&doc! { "v": 0 }), doc! { "v": 2 })
+                .await?
+        );
+        assert!(
+            tree.compare_and_set(&key, Some(&v1), doc! { "v": 2 })
+                .await?
+        );
+        assert_eq!(tree.get(&key).await?, Some(doc! { "v": 2 }));
+
+        // A deleted key counts as absent...
+        tree.del(&key).await?;
+        assert!(tree.compare_and_set(&key, None, doc! { "v": 3 }).await?);
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn corrupt_flush_is_caught() -> Result<()> {
+        let path = format!("/tmp/{}", ObjectId::new());
+        let mut tree = LSMTree::new("test", &path, StorageConfig::default());
+        let key = ObjectId::new();
+        tree.set(&key, doc! { "v": 1 }).await?;
+
+        // A corrupted flush should fail...
+        tree.corrupt_next_flush = true;
+        assert!(tree.compact_memtable(true).await.is_err());
+
+        // ...and leave the data in the memtable, not on disk...
+        assert!(tree.frozen_memtable.is_none());
+        assert_eq!(tree.memtable.get(&key), Some(Value::Data(doc! { "v": 1 })));
+        assert!(tree.levels[0].tables.is_empty());
+        assert_eq!(tree.get(&key).await?, Some(doc! { "v": 1 }));
+
+        // The next (uncorrupted) flush should succeed...
+        tree.compact_memtable(true).await?;
+        assert_eq!(tree.get_from_disk_only(&key).await?, Some(doc! { "v": 1 }));
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reads_see_latest_write_during_compaction() -> Result<()> {
+        let path = format!("/tmp/{}", ObjectId::new());
+        let tree = std::sync::Arc::new(tokio::sync::Mutex::new(LSMTree::new(
+            "test",
+            &path,
+            StorageConfig::default(),
+        )));
+        let key = ObjectId::new();
+
+        // Repeatedly write the key, sometimes flushing it to disk...
+        let writer = {
+            let tree = tree.clone();
+            tokio::spawn(async move {
+                for i in 0..60 {
+                    {
+                        let mut t = tree.lock().await;
+                        t.set(&key, doc! { "v": i }).await?;
+                        t.set(&ObjectId::new(), doc! { "other": i }).await?;
+                        if i % 2 == 0 {
+                            t.compact_memtable(true).await?;
+                        }
+                    }
+                    tokio::task::yield_now().await;
+
+                    // The read should always see the latest write...
+                    let got = tree.lock().await.get(&key).await?;
+                    assert_eq!(got, Some(doc! { "v": i }), "Stale read after write {}", i);
+                }
+                Ok::<_, anyhow::Error>(())
+            })
+        };
+
+        // Meanwhile, keep moving the older versions down the levels...
+        let compactor = {
+            let tree = tree.clone();
+            tokio::spawn(async move {
+                for _ in 0..60 {
+                    {
+                        let mut t = tree.lock().await;
+                        for n in 1..=t.levels.len() {
+                            if t.levels[n - 1].tables.len() >= 2 {
+                                t.compact_level(n, true).await?;
+                            }
+                        }
+                    }
+                    tokio::task::yield_now().await;
+                }
+                Ok::<_, anyhow::Error>(())
+            })
+        };
+        writer.await??;
+        compactor.await??;
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn describe_lists_levels_and_tables() -> Result<()> {
+        let path = format!("/tmp/{}", ObjectId::new());
+        let mut tree = LSMTree::new("test", &path, StorageConfig::default());
+
+        // Flush a few tables and compact some of them down a level...
+        let keys: Vec<_> = (0..9).map(|_| ObjectId::new()).collect();
+        for chunk in keys.chunks(3) {
+            for k in chunk {
+                tree.set(k, doc! {}).await?;
+            }
+            tree.compact_memtable(true).await?;
+        }
+        tree.compact_level(1, true).await?;
+        tree.set(&keys[0], doc! {}).await?;
+        tree.del(&keys[1]).await?;
+        tree.compact_memtable(true).await?;
+        tree.set(&ObjectId::new(), doc! {}).await?;
+
+        // Check the description...
+        let desc = tree.describe();
+        assert_eq!(desc.memtable_records, 1);
+        assert!(!desc.has_frozen_memtable);
+        assert_eq!(desc.levels.len(), 2);
+        assert_eq!(desc.levels[0].level, 1);
+        assert_eq!(desc.levels[0].tables.len(), 1);
+        assert_eq!(desc.levels[0].tables[0].num_records, 2);
+        assert_eq!(desc.levels[0].tables[0].num_tombstones, 1);
+        assert_eq!(desc.levels[1].tables.len(), 1);
+        let t = &desc.levels[1].tables[0];
+        assert_eq!(t.num_records, 9);
+        assert_eq!(t.min_key, keys[0].to_hex());
+        assert_eq!(t.max_key, keys[8].to_hex());
+
+        // And that it can be dumped as JSON...
+        let json = desc.to_json()?;
+        assert!(json.contains(&keys[8].to_hex()));
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn compaction_respects_schedule() -> Result<()> {
+        // Create a tree that can only compact from 1-5am...
+        let path = format!("/tmp/{}", ObjectId::new());
+        let mut tree = LSMTree::new("test", &path, StorageConfig::default());
+        tree.compaction_schedule =
+            CompactionSchedule::new(vec![MaintenanceWindow::from_hours(1, 5)]);
+
+        // Fill up the first level...
+        tree.add_level(true).await?;
+        for _ in 0..MAX_TABLES_PER_LEVEL {
+            let table = SSTable::new(vec![Record::new_data(doc! { "n": 1 })])?;
+            tree.levels[0].add_sstable(&table).await?;
+        }
+        assert!(tree.levels[0].is_full());
+
+        // Outside of the window, level compaction should be deferred...
+        let noon = DateTime::from_millis(12 * 60 * 60 * 1000);
+        tree.compaction_cycle_at(noon).await?;
+        assert_eq!(tree.levels.len(), 1, "Expected compaction to be deferred");
+        assert!(tree.levels[0].is_full());
+
+        // Inside of the window, it should proceed...
+        let three_am = DateTime::from_millis(3 * 60 * 60 * 1000);
+        tree.compaction_cycle_at(three_am).await?;
+        assert_eq!(tree.levels.len(), 2, "Expected a new level");
+        assert_eq!(tree.levels[0].tables.len(), 0);
+        assert_eq!(tree.levels[1].tables.len(), 1);
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn compaction_waits_for_min_table_age() -> Result<()> {
+        // Create a tree whose first level only compacts hour-old tables...
+        let path = format!("/tmp/{}", ObjectId::new());
+        let mut tree = LSMTree::new("test", &path, StorageConfig::default());
+        tree.add_level(true).await?;
+        tree.levels[0].min_table_age = Duration::from_secs(60 * 60);
+
+        // Fill up the first level with new tables...
+        for _ in 0..MAX_TABLES_PER_LEVEL {
+            let table = SSTable::new(vec![Record::new_data(doc! { "n": 1 })])?;
+            tree.levels[0].add_sstable(&table).await?;
+        }
+        assert!(tree.levels[0].is_full());
+
+        // While they're young, compaction should be deferred...
+        let now = DateTime::now();
+        tree.compaction_cycle_at(now).await?;
+        assert_eq!(tree.levels.len(), 1, "Expected compaction to be deferred");
+        assert!(tree.levels[0].is_full());
+
+        // Once they've aged past the threshold, it should proceed...
+        let later = DateTime::from_millis(now.timestamp_millis() + 2 * 60 * 60 * 1000);
+        tree.compaction_cycle_at(later).await?;
+        assert_eq!(tree.levels.len(), 2, "Expected a new level");
+        assert_eq!(tree.levels[0].tables.len(), 0);
+        assert_eq!(tree.levels[1].tables.len(), 1);
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn compact_specific_tables() -> Result<()> {
+        let path = format!("/tmp/{}", ObjectId::new());
+        let mut tree = LSMTree::new("test", &path, StorageConfig::default());
+
+        // Flush four tables, the last one updating a key from the first...
+        let keys: Vec<_> = (0..6).map(|_| ObjectId::new()).collect();
+        for (i, chunk) in keys.chunks(2).enumerate() {
+            for k in chunk {
+                tree.set(k, doc! { "v": i as i32 }).await?;
+            }
+            tree.compact_memtable(true).await?;
+        }
+        tree.set(&keys[0], doc! { "v": 10 }).await?;
+        tree.compact_memtable(true).await?;
+        let ids: Vec<_> = newest_first(&tree.levels[0].tables)
+            .into_iter()
+            .rev()
+            .map(|t| t.meta.table_id)
+            .collect();
+        assert_eq!(ids.len(), 4);
+
+        // Moving the update down would leave the older value above it...
+        assert!(tree
+            .compact_tables(1, &[ids[1], ids[3]], true)
+            .await
+            .is_err());
+
+        // But moving the first two tables down is fine...
+        tree.compact_tables(1, &[ids[0], ids[1]], true).await?;
+        assert_eq!(tree.levels.len(), 2);
+
+        // Only those two tables should have been merged and removed...
+        let remaining: Vec<_> = tree.levels[0]
+            .tables
+            .iter()
+            .map(|t| t.meta.table_id)
+            .collect();
+        assert_eq!(remaining.len(), 2);
+        assert!(remaining.contains(&ids[2]) && remaining.contains(&ids[3]));
+        assert_eq!(tree.levels[1].tables.len(), 1);
+        assert_eq!(tree.levels[1].tables[0].meta.num_records, 4);
+
+        // The update (still in level 1) should still win...
+        assert_eq!(tree.get(&keys[0]).await?, Some(doc! { "v": 10 }));
+        assert_eq!(tree.get(&keys[3]).await?, Some(doc! { "v": 1 }));
+
+        // Tables that aren't in the level are an error...
+        assert!(tree.compact_tables(1, &[ids[0]], false).await.is_err());
+        assert!(tree.compact_tables(3, &[ids[2]], false).await.is_err());
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn recover_interrupted_compaction() -> Result<()> {
+        let path = format!("/tmp/{}", ObjectId::new());
+        let mut tree = LSMTree::new("test", &path, StorageConfig::default());
+
+        // Flush a few tables...
+        let keys: Vec<_> = (0..6).map(|_| ObjectId::new()).collect();
+        for (i, chunk) in keys.chunks(2).enumerate() {
+            for k in chunk {
+                tree.set(k, doc! { "v": i as i32 }).await?;
+            }
+            tree.compact_memtable(true).await?;
+        }
+
+        // Crash after the merged table is added to level 2 but before
+        // the inputs are removed from level 1...
+        tree.crash_next_compaction = true;
+        assert!(tree.compact_level(1, true).await.is_err());
+
+        // Load the levels back in from disk (as after a restart)...
+        let count_records = |tree: &LSMTree| -> usize {
+            tree.levels
+                .iter()
+                .flat_map(|l| l.tables.iter())
+                .map(|t| t.meta.num_records)
+                .sum()
+        };
+        let mut loaded = LSMTree::new("test", &path, StorageConfig::default());
+        for level in tree.levels.iter() {
+            loaded
+                .levels
+                .push(Level::load_from_file(&path, &level.meta.id).await?);
+        }
+
+        // The records are in both levels until it's recovered...
+        assert_eq!(count_records(&loaded), 12);
+        assert!(loaded.gc_orphans().await.is_err());
+        assert!(loaded.recover().await?);
+        assert!(!loaded.recover().await?);
+        assert_eq!(count_records(&loaded), 6);
+        assert!(loaded.levels[0].tables.is_empty());
+        assert_eq!(loaded.levels[1].tables.len(), 1);
+
+        // The recovery should have been persisted...
+        let level = Level::load_from_file(&path, &loaded.levels[0].meta.id).await?;
+        assert!(level.tables.is_empty());
+
+        // The old tables' files are cleaned up and the data is intact...
+        assert_eq!(loaded.gc_orphans().await?, 3);
+        for (i, k) in keys.iter().enumerate() {
+            assert_eq!(loaded.get(k).await?, Some(doc! { "v": (i / 2) as i32 }));
+        }
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn hot_keys_survive_flush() -> Result<()> {
+        let path = format!("/tmp/{}", ObjectId::new());
+        let mut tree = LSMTree::new("test", &path, StorageConfig::default());
+        tree.set_hot_keys(2);
+
+        // Write some keys, then read two of them...
+        let keys: Vec<_> = (0..10).map(|_| ObjectId::new()).collect();
+        for (i, k) in keys.iter().enumerate() {
+            tree.set(k, doc! { "v": i as i32 }).await?;
+        }
+        tree.get(&keys[3]).await?;
+        tree.get(&keys[7]).await?;
+
+        // Flush the memtable...
+        tree.compact_memtable(true).await?;
+        assert_eq!(tree.memtable.size(), 0);
+
+        // The hot keys are served without reading the table...
+        assert_eq!(tree.get(&keys[3]).await?, Some(doc! { "v": 3 }));
+        assert_eq!(tree.get(&keys[7]).await?, Some(doc! { "v": 7 }));
+        assert_eq!(tree.levels[0].table_reads(), 0);
+
+        // But the rest are read from disk...
+        assert_eq!(tree.get(&keys[0]).await?, Some(doc! { "v": 0 }));
+        assert_eq!(tree.levels[0].table_reads(), 1);
+
+        // Newer writes still win...
+        tree.set(&keys[3], doc! { "v": 30 }).await?;
+        assert_eq!(tree.get(&keys[3]).await?, Some(doc! { "v": 30 }));
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn gets_are_counted_by_source() -> Result<()> {
+        let path = format!("/tmp/{}", ObjectId::new());
+        let mut tree = LSMTree::new("test", &path, StorageConfig::default());
+
+        // Write some keys and flush them to the first level...
+        let old_keys: Vec<_> = (0..5).map(|_| ObjectId::new()).collect();
+        for k in old_keys.iter() {
+            tree.set(k, doc! { "v": 1 }).await?;
+        }
+        tree.compact_memtable(true).await?;
+
+        // Then write some more, which stay in the memtable...
+        let new_keys: Vec<_> = (0..3).map(|_| ObjectId::new()).collect();
+        for k in new_keys.iter() {
+            tree.set(k, doc! { "v": 2 }).await?;
+        }
+
+        // Read them all back, plus a missing key...
+        for k in old_keys.iter().chain(new_keys.iter()) {
+            assert!(tree.get(k).await?.is_some());
+        }
+        assert!(tree.get(&ObjectId::new()).await?.is_none());
+
+        // Each get should be counted where its key was found...
+        let stats = tree.stats();
+        assert_eq!(stats.gets, 9);
+        assert_eq!(stats.memtable_hits, 3);
+        assert_eq!(stats.level_hits, vec![5]);
+        assert_eq!(stats.flushes, 1);
+        assert!(stats.bytes_written > 0);
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn flush_persists_the_memtable() -> Result<()> {
+        let path = format!("/tmp/{}", ObjectId::new());
+        let mut tree = LSMTree::new("test", &path, StorageConfig::default());
+
+        // Flushing an empty tree does nothing...
+        tree.flush().await?;
+        assert!(tree.levels.is_empty());
+
+        // Write a few records (not enough to fill the memtable) and flush...
+        let keys: Vec<_> = (0..5).map(|_| ObjectId::new()).collect();
+        for (i, k) in keys.iter().enumerate() {
+            tree.set(k, doc! { "i": i as i32 }).await?;
+        }
+        tree.flush().await?;
+        assert_eq!(tree.memtable.size(), 0);
+        assert_eq!(tree.levels[0].tables.len(), 1);
+
+        // Flushing again (with nothing new) doesn't add a table...
+        tree.flush().await?;
+        assert_eq!(tree.levels[0].tables.len(), 1);
+
+        // A freshly loaded tree reads the records from disk...
+        let loaded = LSMTree::load("test", &path, StorageConfig::default()).await?;
+        assert_eq!(loaded.memtable.size(), 0);
+        for (i, k) in keys.iter().enumerate() {
+            assert_eq!(loaded.get(k).await?, Some(doc! { "i": i as i32 }));
+        }
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn iter_merges_memtable_and_levels() -> Result<()> {
+        let path = format!("/tmp/{}", ObjectId::new());
+        let mut tree = LSMTree::new("test", &path, StorageConfig::default());
+        let mut keys: Vec<_> = (0..35).map(|_| ObjectId::new()).collect();
+        keys.sort();
+
+        // Write the oldest values and move them down to the second level...
+        for k in keys[..30].iter() {
+            tree.set(k, doc! { "v": 1 }).await?;
+        }
+        tree.compact_memtable(true).await?;
+        tree.compact_level(1, true).await?;
+
+        // Overwrite (and delete) some of them in the first level...
+        for k in keys[..10].iter() {
+            tree.set(k, doc! { "v": 2 }).await?;
+        }
+        for k in keys[10..15].iter() {
+            tree.del(k).await?;
+        }
+        tree.compact_memtable(true).await?;
+        assert!(!tree.levels[0].tables.is_empty());
+        assert!(!tree.levels[1].tables.is_empty());
+
+        // Then again in the memtable, along with some new keys...
+        for k in keys[..5].iter() {
+            tree.set(k, doc! { "v": 3 }).await?;
+        }
+        for k in keys[15..20].iter() {
+            tree.del(k).await?;
+        }
+        for k in keys[30..].iter() {
+            tree.set(k, doc! { "v": 4 }).await?;
+        }
+
+        // The newest live value for each key should come back, in order...
+        let got = tree.iter().await?.collect().await?;
+        let want: Vec<_> = keys
+            .iter()
+            .enumerate()
+            .filter_map(|(i, k)| {
+                let v = match i {
+                    0..=4 => 3,
+                    5..=9 => 2,
+                    10..=19 => return None,
+                    20..=29 => 1,
+                    _ => 4,
+                };
+                Some((*k, doc! { "v": v }))
+            })
+            .collect();
+        assert_eq!(got, want);
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn iter_range_reads_a_bounded_number_of_records() -> Result<()> {
+        let path = format!("/tmp/{}", ObjectId::new());
+        let config = StorageConfig {
+            max_tables_per_level: 100,
+            table_index_interval: 8,
+            ..StorageConfig::default()
+        };
+        let mut tree = LSMTree::new("test", &path, config);
+        tree.add_level(true).await?;
+        tree.levels[0].table_format = TableFormat::Compact;
+
+        // Write ten (non-overlapping) tables...
+        let mut keys: Vec<_> = (0..1000).map(|_| ObjectId::new()).collect();
+        keys.sort();
+        for chunk in keys.chunks(100) {
+            for (i, k) in chunk.iter().enumerate() {
+                tree.set(k, doc! { "i": i as i32 }).await?;
+            }
+            tree.compact_memtable(true).await?;
+        }
+        assert_eq!(tree.levels[0].tables.len(), 10);
+
+        // Reading the first few records of a large range should only
+        // read the blocks around them...
+        let mut iter = tree.iter_range(&keys[450], &keys[999]).await?;
+        let mut got = vec![];
+        for _ in 0..10 {
+            got.push(iter.next().await.unwrap()?.0);
+        }
+        assert_eq!(got, keys[450..460]);
+        assert!(
+            iter.records_read() <= 10 + 2 * 8,
+            "read {} records",
+            iter.records_read()
+        );
+
+        // ...and the range should still end in the right place
+        let rest = iter.collect().await?;
+        assert_eq!(rest.len(), 540);
+        let rest = tree
+            .iter_range(&keys[10], &keys[19])
+            .await?
+            .collect()
+            .await?;
+        let rest: Vec<_> = rest.into_iter().map(|(k, _)| k).collect();
+        assert_eq!(rest, keys[10..20]);
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn approx_count_and_level_stats() -> Result<()> {
+        let path = format!("/tmp/{}", ObjectId::new());
+        let mut tree = LSMTree::new("test", &path, StorageConfig::default());
+
+        // Flush some records to disk, then overwrite some of them...
+        let keys: Vec<_> = (0..50).map(|_| ObjectId::new()).collect();
+        for k in keys.iter() {
+            tree.set(k, doc! { "v": 1 }).await?;
+        }
+        tree.compact_memtable(true).await?;
+        for k in keys.iter().take(10) {
+            tree.set(k, doc! { "v": 2 }).await?;
+        }
+
+        // The overwritten keys are counted twice...
+        assert_eq!(tree.approx_count(), 60);
+
+        // The level should report its table, records, and bytes...
+        let stats = tree.level_stats().await?;
+        assert_eq!(stats.len(), tree.levels.len());
+        assert_eq!(stats[0].level, 1);
+        assert_eq!(stats[0].tables, 1);
+        assert_eq!(stats[0].records, 50);
+        assert!(stats[0].bytes > 0);
+        assert!(stats[1..].iter().all(|s| s.records == 0));
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn compaction_skips_nonoverlapping_level() -> Result<()> {
+        // Create a tree with three levels...
+        let path = format!("/tmp/{}", ObjectId::new());
+        let mut tree = LSMTree::new("test", &path, StorageConfig::default());
+        tree.skip_nonoverlapping_levels = true;
+        for _ in 0..3 {
+            tree.add_level(true).await?;
+        }
+
+        // Create some ordered keys...
+        let keys: Vec<_> = (0..30).map(|_| ObjectId::new()).collect();
+        let table = |ks: &[ObjectId]| {
+            SSTable::new(
+                ks.iter()
+                    .map(|k| Record {
+                        key: *k,
+                        value: Value::Data(doc! { "k": k.to_hex() }),
+                    })
+                    .collect(),
+            )
+        };
+
+        // Level 2 only has keys outside of level 1's range...
+        let l2_table = table(&keys[0..3])?;
+        tree.levels[1].add_sstable(&l2_table).await?;
+        tree.levels[0].add_sstable(&table(&keys[10..15])?).await?;
+        tree.levels[0].add_sstable(&table(&keys[15..20])?).await?;
+
+        // Compact level 1, which should skip level 2...
+        tree.compact_level(1, true).await?;
+        assert_eq!(tree.levels[0].tables.len(), 0);
+        assert_eq!(
+            tree.levels[1].meta.table_ids,
+            vec![l2_table.meta.table_id],
+            "Expected level 2 to be left alone"
+        );
+        assert_eq!(tree.levels[2].tables.len(), 1);
+        assert!(tree.get(&keys[12]).await?.is_some());
+
+        // Now add an overlapping table, which shouldn't skip...
+        tree.levels[0].add_sstable(&table(&keys[1..5])?).await?;
+        tree.compact_level(1, true).await?;
+        assert_eq!(tree.levels[1].tables.len(), 2);
+        assert_eq!(tree.levels[2].tables.len(), 1);
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    /// Returns the total size of the tree's table files.
+    async fn disk_size(tree: &LSMTree) -> Result<u64> {
+        let mut size = 0;
+        for level in tree.levels.iter() {
+            for th in level.tables.iter() {
+                size += th.size().await?;
+            }
+        }
+        Ok(size)
+    }
+
+    #[tokio::test]
+    async fn estimate_reclaimable_tracks_compaction() -> Result<()> {
+        // Create a tree and write some (largish) documents to disk...
+        let path = format!("/tmp/{}", ObjectId::new());
+        let mut tree = LSMTree::new("test", &path, StorageConfig::default());
+        let keys: Vec<_> = (0..50).map(|_| ObjectId::new()).collect();
+        for k in keys.iter() {
+            tree.set(k, doc! { "data": "x".repeat(200) }).await?;
+        }
+        tree.compact_memtable(true).await?;
+
+        // Nothing is reclaimable yet...
+        assert_eq!(tree.estimate_reclaimable().await?, 0);
+
+        // Delete most of them...
+        for k in keys.iter().take(40) {
+            tree.del(k).await?;
+        }
+        tree.compact_memtable(true).await?;
+
+        // The metadata-only estimate sees the tombstones...
+        let meta_estimate = tree.estimate_reclaimable().await?;
+        assert!(meta_estimate > 0, "Expected a positive estimate");
+
+        // Sampling also sees the shadowed documents...
+        tree.reclaim_estimate = ReclaimEstimate::Sampled { max_tables: 10 };
+        let estimate = tree.estimate_reclaimable().await?;
+        assert!(estimate > meta_estimate);
+
+        // Compact the level and compare with the space actually freed...
+        let before = disk_size(&tree).await?;
+        tree.compact_level(1, true).await?;
+        let freed = before - disk_size(&tree).await?;
+        assert!(
+            freed / 2 < estimate && estimate < freed * 2,
+            "Expected the estimate ({}) to roughly track the freed space ({})",
+            estimate,
+            freed
+        );
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn compaction_history_records_events() -> Result<()> {
+        let path = format!("/tmp/{}", ObjectId::new());
+        let mut tree = LSMTree::new("test", &path, StorageConfig::default());
+
+        // Flush and compact a few times...
+        for round in 0..3 {
+            for _ in 0..3 {
+                tree.set(&ObjectId::new(), doc! { "round": round }).await?;
+                tree.del(&ObjectId::new()).await?;
+                tree.compact_memtable(true).await?;
+            }
+            tree.compact_level(1, true).await?;
+        }
+
+        // Each compaction should have been recorded...
+        let history = tree.compaction_history(1)?;
+        assert_eq!(history.len(), 3);
+        for event in history.iter() {
+            assert_eq!(event.input_tables, 3);
+            assert!(event.input_bytes > 0);
+            assert!(event.output_bytes > 0);
+            assert!(event.output_bytes <= event.input_bytes);
+        }
+        assert!(tree.compaction_history(2)?.is_empty());
+        assert!(tree.compaction_history(5).is_err());
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn compaction_cycle_flushes_and_compacts() -> Result<()> {
+        let path = format!("/tmp/{}", ObjectId::new());
+        let mut tree = LSMTree::new("test", &path, StorageConfig::default());
+
+        // A memtable that isn't full is left alone...
+        tree.set(&ObjectId::new(), doc! { "v": 0 }).await?;
+        tree.compaction_cycle().await?;
+        assert!(tree.levels.is_empty());
+
+        // Filling it up flushes it to level 1...
+        for _ in 1..MEMTABLE_MAX_SIZE {
+            tree.set(&ObjectId::new(), doc! { "v": 0 }).await?;
+        }
+        tree.compaction_cycle().await?;
+        tree.wait_for_flush().await?;
+        assert_eq!(tree.memtable.size(), 0);
+        assert_eq!(tree.levels[0].tables.len(), 1);
+        assert_eq!(tree.levels[0].tables[0].meta.num_records, MEMTABLE_MAX_SIZE);
+
+        // And filling level 1 compacts it into level 2...
+        for _ in 1..MAX_TABLES_PER_LEVEL {
+            for _ in 0..MEMTABLE_MAX_SIZE {
+                tree.set(&ObjectId::new(), doc! { "v": 0 }).await?;
+            }
+            tree.compaction_cycle().await?;
+        }
+        tree.wait_for_flush().await?;
+        tree.compaction_cycle().await?;
+        assert_eq!(tree.levels.len(), 2);
+        assert!(!tree.levels[0].is_full());
+        let n: usize = tree.levels[1]
+            .tables
+            .iter()
+            .map(|t| t.meta.num_records)
+            .sum();
+        assert_eq!(n, MEMTABLE_MAX_SIZE * MAX_TABLES_PER_LEVEL);
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn storage_config_sets_thresholds() -> Result<()> {
+        let path = format!("/tmp/{}", ObjectId::new());
+        let config = StorageConfig {
+            max_tables_per_level: 2,
+            memtable_max_size: 10,
+            bloom_filter_size: 100,
+            bloom_filter_error_rate: 0.01,
+            ..StorageConfig::default()
+        };
+        let mut tree = LSMTree::new("test", &path, config);
+
+        // The memtable flushes after 10 records...
+        for i in 0..10 {
+            tree.set(&ObjectId::new(), doc! { "i": i }).await?;
+        }
+        tree.compaction_cycle().await?;
+        tree.wait_for_flush().await?;
+        assert_eq!(tree.memtable.size(), 0);
+        assert_eq!(tree.levels[0].tables[0].meta.num_records, 10);
+
+        // ...and level 1 compacts after 2 tables...
+        for i in 0..10 {
+            tree.set(&ObjectId::new(), doc! { "i": i }).await?;
+        }
+        tree.compaction_cycle().await?;
+        tree.wait_for_flush().await?;
+        tree.compaction_cycle().await?;
+        assert_eq!(tree.levels.len(), 2);
+        assert_eq!(tree.levels[1].max_tables, 2);
+        assert_eq!(tree.levels[1].records_per_table, 20);
+        assert_eq!(
+            tree.levels[1].bloom_filter.num_bits(),
+            config.new_bloom_filter().num_bits()
+        );
+
+        // The config carries over when the tree is loaded...
+        let loaded = LSMTree::load("test", &path, config).await?;
+        assert_eq!(loaded.memtable.max_records, 10);
+        assert_eq!(loaded.levels[1].records_per_table, 20);
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn compaction_splits_wide_tables() -> Result<()> {
+        let path = format!("/tmp/{}", ObjectId::new());
+        let mut tree = LSMTree::new("test", &path, StorageConfig::default());
+        tree.max_table_span = Some(Duration::from_secs(60 * 60));
+
+        // Write keys spread over a day, in a few flushes...
+        let key_at = |secs: u32| {
+            let mut bytes = ObjectId::new().bytes();
+            bytes[..4].copy_from_slice(&secs.to_be_bytes());
+            ObjectId::from_bytes(bytes)
+        };
+        let mut keys = vec![];
+        for i in 0..4 {
+            for j in 0..24 {
+                let key = key_at(1_700_000_000 + (j * 4 + i) * 15 * 60);
+                tree.set(&key, doc! { "j": j }).await?;
+                keys.push(key);
+            }
+            tree.compact_memtable(true).await?;
+        }
+
+        // Compacting level 1 splits its output by the max span...
+        tree.compact_level(1, true).await?;
+        let tables = &tree.levels[1].tables;
+        assert_eq!(tables.len(), 20);
+        for t in tables.iter() {
+            let span = t.meta.max_key.timestamp().timestamp_millis()
+                - t.meta.min_key.timestamp().timestamp_millis();
+            assert!(span <= 60 * 60 * 1000, "Table spans {}ms", span);
+        }
+        let n: usize = tables.iter().map(|t| t.meta.num_records).sum();
+        assert_eq!(n, keys.len());
+
+        // A narrow range scan only touches the tables it overlaps...
+        let overlapping = tables
+            .iter()
+            .filter(|t| t.meta.overlaps(&keys[0], &keys[0]))
+            .count();
+        assert_eq!(overlapping, 1);
+        for key in keys.iter() {
+            assert!(tree.get(key).await?.is_some());
+        }
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_range_merges_sources() -> Result<()> {
+        let path = format!("/tmp/{}", ObjectId::new());
+        let mut tree = LSMTree::new("test", &path, StorageConfig::default());
+
+        // Write some keys to disk...
+        let mut keys: Vec<_> = (0..10).map(|_| ObjectId::new()).collect();
+        keys.sort();
+        for (i, k) in keys.iter().enumerate() {
+            tree.set(k, doc! { "i": i as i32, "v": 1 }).await?;
+        }
+        tree.compact_memtable(true).await?;
+        tree.compact_level(1, true).await?;
+        for k in keys.iter().skip(5) {
+            tree.set(k, doc! { "v": 2 }).await?;
+        }
+        tree.compact_memtable(true).await?;
+
+        // Then delete one and update another in the memtable...
+        tree.del(&keys[2]).await?;
+        tree.set(&keys[3], doc! { "v": 3 }).await?;
+
+        // The newest version of each key wins and deleted keys are skipped...
+        let docs = tree.get_range(&keys[1], &keys[6]).await?;
+        assert_eq!(
+            docs,
+            vec![
+                doc! { "i": 1, "v": 1 },
+                doc! { "v": 3 },
+                doc! { "i": 4, "v": 1 },
+                doc! { "v": 2 },
+                doc! { "v": 2 },
+            ]
+        );
+        assert!(tree.get_range(&keys[2], &keys[2]).await?.is_empty());
+        assert!(tree.get_range(&keys[6], &keys[1]).await?.is_empty());
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn major_compact_drops_tombstones() -> Result<()> {
+        let path = format!("/tmp/{}", ObjectId::new());
+        let mut tree = LSMTree::new("test", &path, StorageConfig::default());
+
+        // Write, overwrite, and delete across a couple of levels...
+        let keys: Vec<_> = (0..20).map(|_| ObjectId::new()).collect();
+        for k in keys.iter() {
+            tree.set(k, doc! { "v": 1 }).await?;
+        }
+        tree.compact_memtable(true).await?;
+        tree.compact_level(1, true).await?;
+        for k in keys.iter().take(10) {
+            tree.del(k).await?;
+        }
+        tree.set(&keys[15], doc! { "v": 2 }).await?;
+        tree.compact_memtable(true).await?;
+
+        // Leave a stray file behind, too...
+        let stray = Path::new(&tree.levels[0].path).join(ObjectId::new().to_hex());
+        tokio::fs::write(&stray, b"stray").await?;
+
+        // Major compact, leaving just the live records in the last level...
+        tree.major_compact().await?;
+        assert!(tree.levels[0].tables.is_empty());
+        let table = tree.levels[1].tables[0].read().await?;
+        assert_eq!(table.records.len(), 10);
+        assert_eq!(table.meta.num_tombstones, 0);
+        assert_eq!(tree.get(&keys[0]).await?, None);
+        assert_eq!(tree.get(&keys[15]).await?, Some(doc! { "v": 2 }));
+
+        // And clean up the stray file...
+        assert_eq!(tree.gc_orphans().await?, 1);
+        assert!(!stray.exists());
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn major_compact_prunes_empty_levels() -> Result<()> {
+        let path = format!("/tmp/{}", ObjectId::new());
+        let mut tree = LSMTree::new("test", &path, StorageConfig::default());
+        tree.prune_empty_levels = true;
+
+        // Spread some records across a few levels...
+        let keys: Vec<_> = (0..30).map(|_| ObjectId::new()).collect();
+        for chunk in keys.chunks(10) {
+            for k in chunk {
+                tree.set(k, doc! { "v": 1 }).await?;
+            }
+            tree.compact_memtable(true).await?;
+            tree.compact_level(1, true).await?;
+        }
+        tree.compact_level(2, true).await?;
+        assert_eq!(tree.levels.len(), 3);
+        let old_paths: Vec<_> = tree.levels.iter().map(|l| l.path.clone()).collect();
+
+        // Major compact, leaving just the (renumbered) last level...
+        tree.major_compact().await?;
+        assert_eq!(tree.levels.len(), 1);
+        assert_eq!(tree.levels[0].meta.level, 1);
+        assert_eq!(tree.levels[0].path, old_paths[2]);
+        assert!(!Path::new(&old_paths[0]).exists());
+        assert!(!Path::new(&old_paths[1]).exists());
+
+        // Reads go straight to the populated level...
+        let reads = tree.levels[0].table_reads();
+        for k in keys.iter() {
+            assert_eq!(tree.get(k).await?, Some(doc! { "v": 1 }));
+        }
+        assert!(tree.levels[0].table_reads() > reads);
+
+        // And the renumbering survives a reload...
+        let loaded = LSMTree::load("test", &path, StorageConfig::default()).await?;
+        assert_eq!(loaded.levels.len(), 1);
+        assert_eq!(loaded.levels[0].meta.level, 1);
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn snapshot_survives_compaction() -> Result<()> {
+        // Create a tree and write a value to disk...
+        let path = format!("/tmp/{}", ObjectId::new());
+        let mut tree = LSMTree::new("test", &path, StorageConfig::default());
+        let key = ObjectId::new();
+        tree.set(&key, doc! { "v": 1 }).await?;
+        tree.compact_memtable(true).await?;
+        let old_table = tree.levels[0].tables[0].clone();
+
+        // Take a snapshot...
+        let snap = tree.snapshot();
+
+        // Overwrite the value and fully compact the tree...
+        tree.set(&key, doc! { "v": 2 }).await?;
+        tree.compact_memtable(true).await?;
+        tree.compact_level(1, true).await?;
+        assert_eq!(tree.levels[0].tables.len(), 0);
+        assert_eq!(tree.get(&key).await?, Some(doc! { "v": 2 }));
+
+        // The snapshot should still see the original data...
+        assert_eq!(tree.snapshot_get(&snap, &key).await?, Some(doc! { "v": 1 }));
+        let range = tree.snapshot_range(&snap, &key, &key).await?;
+        assert_eq!(range, vec![(key, doc! { "v": 1 })]);
+        assert_eq!(snap.get(&key).await?, Some(doc! { "v": 1 }));
+        assert_eq!(snap.get_range(&key, &key).await?, range);
+
+        // Once the snapshot is dropped, the old table's file is deleted...
+        assert!(Path::new(&old_table.path).exists());
+        drop(snap);
+        assert!(!Path::new(&old_table.path).exists());
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn minor_compaction_coalesces_tiny_tables() -> Result<()> {
+        let path = format!("/tmp/{}", ObjectId::new());
+        let mut tree = LSMTree::new("test", &path, StorageConfig::default());
+        tree.tiny_table_records = Some(5);
+
+        // Fill the first level with tiny tables, and one bigger one...
+        let mut keys = vec![];
+        for size in [2, 2, 2, 2, 6, 2, 2, 2, 2, 2] {
+            for _ in 0..size {
+                let key = ObjectId::new();
+                tree.set(&key, doc! { "n": keys.len() as i32 }).await?;
+                keys.push(key);
+            }
+            tree.compact_memtable(true).await?;
+        }
+        assert_eq!(tree.levels[0].tables.len(), 10);
+        assert!(tree.levels[0].is_full());
+
+        // The cycle should coalesce the tiny tables on either side of
+        // the bigger one, leaving the level no longer full...
+        tree.compaction_cycle().await?;
+        assert_eq!(tree.levels.len(), 1);
+        let mut sizes: Vec<_> = newest_first(&tree.levels[0].tables)
+            .into_iter()
+            .map(|t| t.meta.num_records)
+            .collect();
+        sizes.reverse();
+        assert_eq!(sizes, vec![8, 6, 10]);
+
+        // All of the data should still be there...
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(tree.get(key).await?, Some(doc! { "n": i as i32 }));
+        }
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn memtable_flushes_in_background() -> Result<()> {
+        let path = format!("/tmp/{}", ObjectId::new());
+        let mut tree = LSMTree::new("test", &path, StorageConfig::default());
+
+        // Filling the memtable starts a flush, freezing it...
+        let keys: Vec<_> = (0..MEMTABLE_MAX_SIZE * 2)
+            .map(|_| ObjectId::new())
+            .collect();
+        let (first, second) = keys.split_at(MEMTABLE_MAX_SIZE);
+        for k in first {
+            tree.set(k, doc! { "v": 1 }).await?;
+        }
+        tree.compaction_cycle().await?;
+        assert!(tree.frozen_memtable.is_some());
+        assert_eq!(tree.memtable.size(), 0);
+
+        // Writes carry on against the new memtable, and reads see both...
+        for k in second {
+            tree.set(k, doc! { "v": 2 }).await?;
+        }
+        assert_eq!(tree.get(&first[0]).await?, Some(doc! { "v": 1 }));
+        assert_eq!(tree.get(&second[0]).await?, Some(doc! { "v": 2 }));
+
+        // A second flush waits for the first, rather than racing it...
+        tree.start_flush(true).await?;
+        tree.wait_for_flush().await?;
+        assert!(tree.frozen_memtable.is_none());
+        assert_eq!(tree.levels[0].tables.len(), 2);
+        for k in keys.iter() {
+            assert!(tree.get_from_disk_only(k).await?.is_some());
+        }
+        assert!(tree.wal.read().await?.is_empty());
+
+        // A failed flush is surfaced by the next write...
+        let key = ObjectId::new();
+        tree.set(&key, doc! { "v": 3 }).await?;
+        tree.corrupt_next_flush = true;
+        tree.start_flush(true).await?;
+        while !tree.flush.as_ref().is_some_and(|f| f.task.is_finished()) {
+            tokio::task::yield_now().await;
+        }
+        assert!(tree.set(&ObjectId::new(), doc! { "v": 4 }).await.is_err());
+
+        // ...with the data put back in the memtable...
+        assert!(tree.frozen_memtable.is_none());
+        assert_eq!(tree.memtable.get(&key), Some(Value::Data(doc! { "v": 3 })));
+        assert_eq!(tree.levels[0].tables.len(), 2);
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tombstones_dropped_at_last_level() -> Result<()> {
+        let path = format!("/tmp/{}", ObjectId::new());
+        let mut tree = LSMTree::new("test", &path, StorageConfig::default());
+        let tombstones =
+            |l: &Level| -> usize { l.tables.iter().map(|t| t.meta.num_tombstones).sum() };
+
+        // Write some keys and move them down to level 2, with an empty
+        // level 3 below it...
+        let keys: Vec<_> = (0..4).map(|_| ObjectId::new()).collect();
+        for k in keys.iter() {
+            tree.set(k, doc! { "v": 1 }).await?;
+        }
+        tree.compact_memtable(true).await?;
+        tree.compact_level(1, true).await?;
+        tree.add_level(true).await?;
+
+        // Delete one and compact it into level 2. The tombstone has to
+        // stay, since it shadows the older value...
+        tree.del(&keys[1]).await?;
+        tree.compact_memtable(true).await?;
+        tree.compact_level(1, true).await?;
+        assert_eq!(tombstones(&tree.levels[1]), 1);
+        assert_eq!(tree.get(&keys[1]).await?, None);
+
+        // Compacting into the last level drops it...
+        tree.compact_level(2, true).await?;
+        assert_eq!(tombstones(&tree.levels[2]), 0);
+        let n: usize = tree.levels[2]
+            .tables
+            .iter()
+            .map(|t| t.meta.num_records)
+            .sum();
+        assert_eq!(n, 3);
+        assert_eq!(tree.get(&keys[1]).await?, None);
+        assert_eq!(tree.get(&keys[2]).await?, Some(doc! { "v": 1 }));
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn writes_stall_at_l0_threshold() -> Result<()> {
+        // Create a tree that can only compact from 1-5am...
+        let path = format!("/tmp/{}", ObjectId::new());
+        let config = StorageConfig {
+            memtable_max_size: 5,
+            ..StorageConfig::default()
+        };
+        let mut tree = LSMTree::new("test", &path, config);
+        tree.l0_stall_tables = Some(3);
+        tree.compaction_schedule =
+            CompactionSchedule::new(vec![MaintenanceWindow::from_hours(1, 5)]);
+        let noon = DateTime::from_millis(12 * 60 * 60 * 1000);
+        let three_am = DateTime::from_millis(3 * 60 * 60 * 1000);
+
+        // Keep filling and flushing the memtable while compaction is deferred...
+        let mut stalled = None;
+        for i in 0..50 {
+            if let Err(err) = tree.set(&ObjectId::new(), doc! { "i": i }).await {
+                stalled = Some(err);
+                break;
+            }
+            tree.compaction_cycle_at(noon).await?;
+            tree.wait_for_flush().await?;
+        }
+
+        // Writes should stall once the first level hits the threshold...
+        let err = stalled.expect("Expected writes to stall");
+        assert!(matches!(
+            err.downcast_ref::<StorageError>(),
+            Some(StorageError::Full(_))
+        ));
+        assert_eq!(tree.levels[0].tables.len(), 3);
+        assert!(tree.memtable.is_full());
+
+        // Once compaction catches up, writes can continue...
+        tree.compaction_cycle_at(three_am).await?;
+        tree.wait_for_flush().await?;
+        assert!(!tree.l0_stalled());
+        assert_eq!(tree.levels[0].tables.len(), 1);
+        tree.set(&ObjectId::new(), doc! { "i": 50 }).await?;
+
+        // (Clean up) Remove the directory...
+        tokio::fs::remove_dir_all(&path).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,79 @@
+//! The MANIFEST, which makes compactions atomic.
+//!
+//! A compaction changes two levels' metadata (adding the merged table
+//! to one and removing the inputs from the other), and each level's
+//! metadata is its own file. To keep a crash between those writes
+//! from leaving the data duplicated (or lost), the change is first
+//! recorded as a single [ManifestEdit] in the tree's MANIFEST. Once
+//! both levels are updated the edit is cleared. If the tree finds an
+//! edit on load, the compaction is rolled forward.
+
+use anyhow::Result;
+use bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+use crate::storage::conf::*;
+use crate::storage::util::*;
+
+/// A compaction that's been committed but may not have been fully
+/// applied to the levels' metadata yet.
+///
+/// Usually a compaction has a single merged table, but it may be split
+/// (see [crate::storage::lsm::LSMTree::max_table_span]).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ManifestEdit {
+    /// The id of the level the input tables are removed from.
+    pub from_level: ObjectId,
+
+    /// The id of the level the merged table is added to.
+    ///
+    /// This is the same as `from_level` for in-place compactions.
+    pub to_level: ObjectId,
+
+    /// The ids of the merged tables.
+    ///
+    /// Their files are written before the edit is committed.
+    pub new_tables: Vec<ObjectId>,
+
+    /// The ids of the input tables.
+    pub old_tables: Vec<ObjectId>,
+}
+
+/// Returns the path to the MANIFEST for the tree at `tree_path`.
+fn manifest_path(tree_path: &str) -> PathBuf {
+    Path::new(tree_path).join(MANIFEST_FILE)
+}
+
+/// Commits an edit to the tree's MANIFEST.
+///
+/// The write is atomic, so after a crash the MANIFEST holds either
+/// the whole edit or nothing.
+pub async fn write_manifest(tree_path: &str, edit: &ManifestEdit) -> Result<()> {
+    let doc = bson::to_document(edit)?;
+    write_bson_atomic(manifest_path(tree_path), &doc).await
+}
+
+/// Reads the pending edit from the tree's MANIFEST, if there is one.
+pub async fn read_manifest(tree_path: &str) -> Result<Option<ManifestEdit>> {
+    let path = manifest_path(tree_path);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let bytes = read_bson(path).await?;
+    Ok(Some(bson::from_slice(&bytes)?))
+}
+
+/// Clears the tree's MANIFEST, once its edit has been applied.
+pub async fn clear_manifest(tree_path: &str) -> Result<()> {
+    match tokio::fs::remove_file(manifest_path(tree_path)).await {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Checks if the tree at `tree_path` has an edit waiting to be applied.
+pub fn has_pending_edit(tree_path: &str) -> bool {
+    manifest_path(tree_path).exists()
+}
@@ -1,14 +1,13 @@
-use anyhow::{anyhow, Result};
+use anyhow::Result;
 use bson::oid::ObjectId;
 use bson::{DateTime, Document};
 use std::collections::BTreeMap;
 
 use crate::storage::conf::*;
+use crate::storage::lru::*;
 use crate::storage::record::*;
 use crate::storage::sstable::*;
 
-use super::wal::WAL;
-
 /// The in-memory buffer for an LSM Tree.
 ///
 /// This buffer is comprised of a red-black tree of records, sorted by key.
@@ -22,21 +21,130 @@ pub struct MemTable {
     /// The maximum number of records allowed in the MemTable.
     pub max_records: usize,
 
-    pub wal: WAL,
+    /// The approximate number of bytes held by the records.
+    ///
+    /// See also: [MemTable::bytes]
+    bytes: usize,
+
+    /// If set, the least recently used records are evicted (dropped,
+    /// *not* flushed) once the MemTable grows past this cap.
+    ///
+    /// Note: This should only be used by in-memory trees.
+    pub eviction: Option<EvictionCap>,
+
+    /// If `true`, the access order of the records is tracked even
+    /// without an eviction cap (see [MemTable::hottest]).
+    pub track_recency: bool,
+
+    /// The access order of the records, used for eviction.
+    lru: LruTracker,
 }
 
 impl MemTable {
-    /// Creates a new MemTable.
-    pub fn new() -> Self {
+    /// Creates a new MemTable, which holds up to the config's
+    /// `memtable_max_size` records.
+    pub fn new(config: &StorageConfig) -> Self {
         Self {
-            max_records: MEMTABLE_MAX_SIZE,
+            max_records: config.memtable_max_size,
             ..Default::default()
         }
     }
 
+    /// Builds a MemTable from a batch of records in one go, rather
+    /// than inserting them one at a time.
+    ///
+    /// The records don't need to be sorted and, if a key appears more
+    /// than once, the later record wins (as if they were inserted in
+    /// order). The `BTreeMap` is bulk-constructed from the sorted
+    /// records, which avoids rebalancing it as it grows and is faster
+    /// for large loads like replaying a WAL.
+    pub fn from_records(records: Vec<Record>) -> Self {
+        let records: BTreeMap<_, _> = records.into_iter().map(|r| (r.key, r.value)).collect();
+        let bytes = records.values().map(entry_size).sum();
+        Self {
+            records,
+            bytes,
+            ..Self::new(&StorageConfig::default())
+        }
+    }
+
+    /// Inserts a batch of records into the MemTable.
+    ///
+    /// If the MemTable is empty (and isn't evicting), it's rebuilt in
+    /// bulk with [MemTable::from_records]. Otherwise, the records are
+    /// inserted one at a time.
+    pub fn extend(&mut self, records: Vec<Record>) {
+        if self.records.is_empty() && self.eviction.is_none() {
+            let max_records = self.max_records;
+            let track_recency = self.track_recency;
+            *self = Self::from_records(records);
+            self.max_records = max_records;
+            self.track_recency = track_recency;
+            return;
+        }
+        for r in records {
+            self.insert(&r.key, r.value);
+        }
+    }
+
     /// Inserts a record into the MemTable.
     pub fn insert(&mut self, key: &ObjectId, value: Value<Document>) {
-        self.records.insert(*key, value);
+        // Account for the new value, less any value it replaces...
+        self.bytes += entry_size(&value);
+        if let Some(prev) = self.records.insert(*key, value) {
+            self.bytes = self.bytes.saturating_sub(entry_size(&prev));
+        }
+
+        // Track the access and evict old records, if there's a cap...
+        if self.tracks_recency() {
+            self.lru.touch(key);
+        }
+        if self.eviction.is_some() {
+            self.evict();
+        }
+    }
+
+    /// Checks if the access order of the records is being tracked.
+    fn tracks_recency(&self) -> bool {
+        self.track_recency || self.eviction.is_some()
+    }
+
+    /// Returns (up to) the `n` most recently accessed records, most
+    /// recent first.
+    ///
+    /// Access order is only tracked if [MemTable::track_recency] is set
+    /// (or there's an eviction cap), otherwise this is empty.
+    pub fn hottest(&self, n: usize) -> Vec<Record> {
+        self.lru
+            .most_recent(n)
+            .into_iter()
+            .filter_map(|key| {
+                let value = self.records.get(&key)?.clone();
+                Some(Record { key, value })
+            })
+            .collect()
+    }
+
+    /// Evicts the least recently used records until the MemTable is
+    /// back within its eviction cap (if it has one).
+    fn evict(&mut self) {
+        loop {
+            let over = match self.eviction {
+                Some(EvictionCap::Entries(n)) => self.records.len() > n,
+                Some(EvictionCap::Bytes(n)) => self.bytes > n,
+                None => false,
+            };
+            if !over {
+                return;
+            }
+            let key = match self.lru.pop_oldest() {
+                Some(key) => key,
+                None => return,
+            };
+            if let Some(prev) = self.records.remove(&key) {
+                self.bytes = self.bytes.saturating_sub(entry_size(&prev));
+            }
+        }
     }
 
     /// Sets a value in the MemTable.
@@ -54,11 +162,43 @@ impl MemTable {
 
     /// Gets a value from the MemTable.
     pub fn get(&self, key: &ObjectId) -> Option<Value<Document>> {
-        self.records.get(key).cloned()
+        let value = self.records.get(key).cloned();
+        if value.is_some() && self.tracks_recency() {
+            self.lru.touch(key);
+        }
+        value
+    }
+
+    /// Returns an iterator over the MemTable's entries, in sorted key order.
+    ///
+    /// Tombstones are included, so callers merging across levels can
+    /// tell that a key was deleted.
+    pub fn iter(&self) -> impl Iterator<Item = (&ObjectId, &Value<Document>)> {
+        self.records.iter()
+    }
+
+    /// Returns an iterator over the MemTable's entries with keys in the
+    /// given range (inclusive), in sorted key order.
+    ///
+    /// Like [MemTable::iter], tombstones are included.
+    pub fn range(
+        &self,
+        min_key: &ObjectId,
+        max_key: &ObjectId,
+    ) -> impl Iterator<Item = (&ObjectId, &Value<Document>)> {
+        let max_key = *max_key;
+        self.records
+            .range(*min_key..)
+            .take_while(move |(key, _)| **key <= max_key)
     }
 
     /// Flushes the contents of the MemTable to an SSTable.
-    pub fn flush(&self) -> Result<SSTable> {
+    ///
+    /// # Returns
+    ///
+    /// The SSTable, or `None` if the MemTable is empty (so there's
+    /// nothing to write).
+    pub fn flush(&self) -> Result<Option<SSTable>> {
         // Create a vector of records from the BTreeMap...
         let records: Vec<_> = self
             .records
@@ -70,23 +210,38 @@ impl MemTable {
             .collect();
 
         // Get the min/max keys and count from the records...
-        let min_key = records.first().ok_or(anyhow!("records vec was empty"))?.key;
-        let max_key = records.last().ok_or(anyhow!("records vec was empty"))?.key;
+        let (min_key, max_key) = match (records.first(), records.last()) {
+            (Some(first), Some(last)) => (first.key, last.key),
+            _ => return Ok(None),
+        };
         let num_records = records.len();
+        let num_tombstones = count_tombstones(&records);
         let meta = SSTableMeta {
             table_id: ObjectId::new(),
             created_at: DateTime::now(),
             min_key,
             max_key,
             num_records,
+            num_tombstones,
+            index: vec![],
         };
 
         // Create and return!
-        Ok(SSTable { meta, records })
+        Ok(Some(SSTable { meta, records }))
     }
 
     pub fn clear(&mut self) {
         self.records.clear();
+        self.bytes = 0;
+        self.lru.clear();
+    }
+
+    /// Returns the approximate number of bytes held by the MemTable's
+    /// current records (their keys plus BSON-encoded values).
+    ///
+    /// Overwritten and deleted values don't count towards the total.
+    pub fn bytes(&self) -> usize {
+        self.bytes
     }
 
     /// Check the size of the MemTable.
@@ -100,6 +255,15 @@ impl MemTable {
     }
 }
 
+/// Returns the approximate size of a MemTable entry, in bytes.
+fn entry_size(value: &Value<Document>) -> usize {
+    let value_size = match value {
+        Value::Data(doc) => bson::to_vec(doc).map(|b| b.len()).unwrap_or(0),
+        Value::Tombstone => 0,
+    };
+    std::mem::size_of::<ObjectId>() + value_size
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -117,7 +281,7 @@ mod test {
         let exp = Some(Value::Data(v.clone()));
 
         // Create an empty memtable...
-        let mut mt = MemTable::new();
+        let mut mt = MemTable::new(&StorageConfig::default());
 
         // Add it to the memtable...
         mt.set(&k, v);
@@ -146,7 +310,7 @@ mod test {
         };
 
         // Create an empty memtable...
-        let mut mt = MemTable::new();
+        let mut mt = MemTable::new(&StorageConfig::default());
 
         // Add it to the memtable...
         mt.set(&k, v);
@@ -171,4 +335,149 @@ mod test {
         let exp = Some(Value::<Document>::Tombstone);
         assert_eq!(res, exp, "Expecting a present tombstone");
     }
+
+    #[test]
+    fn iter_sorted() {
+        // Create some keys (which will be created in sorted order)...
+        let k1 = ObjectId::new();
+        let k2 = ObjectId::new();
+        let k3 = ObjectId::new();
+        assert!(k1 < k2 && k2 < k3, "Expected object ids to be ordered");
+
+        // Add them to the memtable out of order, deleting one...
+        let mut mt = MemTable::new(&StorageConfig::default());
+        mt.set(&k3, doc! { "n": 3 });
+        mt.set(&k1, doc! { "n": 1 });
+        mt.set(&k2, doc! { "n": 2 });
+        mt.del(&k2);
+
+        // Iterate and check the order (including the tombstone)...
+        let res: Vec<_> = mt.iter().map(|(k, v)| (*k, v.clone())).collect();
+        let exp = vec![
+            (k1, Value::Data(doc! { "n": 1 })),
+            (k2, Value::Tombstone),
+            (k3, Value::Data(doc! { "n": 3 })),
+        ];
+        assert_eq!(res, exp, "Expected records in sorted key order");
+    }
+
+    #[test]
+    fn bytes_track_overwrites() {
+        let k = ObjectId::new();
+        let mut mt = MemTable::new(&StorageConfig::default());
+        assert_eq!(mt.bytes(), 0);
+
+        // Repeatedly overwrite the same key with different sizes...
+        for n in [10, 1000, 5, 200, 50] {
+            let doc = doc! { "data": "x".repeat(n) };
+            let exp = entry_size(&Value::Data(doc.clone()));
+            mt.set(&k, doc);
+            assert_eq!(mt.bytes(), exp, "Expected only the current value to count");
+        }
+
+        // Deleting it should shrink down to a tombstone...
+        mt.del(&k);
+        assert_eq!(mt.bytes(), entry_size(&Value::Tombstone));
+
+        // And other keys should add to it...
+        let k2 = ObjectId::new();
+        mt.set(&k2, doc! { "n": 1 });
+        let exp = entry_size(&Value::Tombstone) + entry_size(&Value::Data(doc! { "n": 1 }));
+        assert_eq!(mt.bytes(), exp);
+
+        // Clearing resets it...
+        mt.clear();
+        assert_eq!(mt.bytes(), 0);
+    }
+
+    #[test]
+    fn flush_empty_and_full() -> Result<()> {
+        // An empty memtable has nothing to flush...
+        let mut mt = MemTable::new(&StorageConfig::default());
+        assert!(mt.flush()?.is_none());
+
+        // Otherwise the table should hold its records, in order...
+        let mut keys: Vec<_> = (0..3).map(|_| ObjectId::new()).collect();
+        for k in keys.iter().rev() {
+            mt.set(k, doc! { "n": 1 });
+        }
+        mt.del(&keys[1]);
+        let sstable = mt.flush()?.expect("Expected a table");
+        keys.sort();
+        assert_eq!(sstable.meta.min_key, keys[0]);
+        assert_eq!(sstable.meta.max_key, keys[2]);
+        assert_eq!(sstable.meta.num_records, 3);
+        assert_eq!(sstable.meta.num_tombstones, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn bulk_build_matches_inserts() {
+        // Create a large "WAL" of writes, with overwrites and deletes...
+        let keys: Vec<_> = (0..1000).map(|_| ObjectId::new()).collect();
+        let mut log = vec![];
+        for round in 0..20 {
+            for (i, k) in keys.iter().enumerate().rev() {
+                let value = match (i + round) % 7 {
+                    0 => Value::Tombstone,
+                    n => Value::Data(doc! { "round": round as i32, "n": n as i32 }),
+                };
+                log.push(Record { key: *k, value });
+            }
+        }
+
+        // Replay it one record at a time...
+        let mut incremental = MemTable::new(&StorageConfig::default());
+        for r in log.iter() {
+            incremental.insert(&r.key, r.value.clone());
+        }
+
+        // And again, building it in bulk...
+        let bulk = MemTable::from_records(log.clone());
+
+        // The contents should be identical...
+        assert_eq!(bulk.records, incremental.records);
+        assert_eq!(bulk.bytes(), incremental.bytes());
+        assert_eq!(bulk.max_records, incremental.max_records);
+
+        // Extending an empty MemTable takes the bulk path, too...
+        let mut extended = MemTable::new(&StorageConfig::default());
+        extended.extend(log);
+        assert_eq!(extended.records, incremental.records);
+        assert_eq!(extended.bytes(), incremental.bytes());
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut mt = MemTable::new(&StorageConfig::default());
+        mt.eviction = Some(EvictionCap::Entries(3));
+        let keys: Vec<_> = (0..5).map(|_| ObjectId::new()).collect();
+
+        // Fill it up, then read the first key so it's recently used...
+        for k in keys.iter().take(3) {
+            mt.set(k, doc! { "n": 1 });
+        }
+        assert!(mt.get(&keys[0]).is_some());
+
+        // Adding more should evict the oldest (unread) keys...
+        mt.set(&keys[3], doc! { "n": 1 });
+        mt.set(&keys[4], doc! { "n": 1 });
+        assert_eq!(mt.size(), 3);
+        assert!(mt.get(&keys[1]).is_none());
+        assert!(mt.get(&keys[2]).is_none());
+        assert!(mt.get(&keys[0]).is_some());
+        assert!(mt.get(&keys[4]).is_some());
+
+        // A byte cap works the same way...
+        let mut mt = MemTable::new(&StorageConfig::default());
+        let doc = doc! { "data": "x".repeat(100) };
+        let size = entry_size(&Value::Data(doc.clone()));
+        mt.eviction = Some(EvictionCap::Bytes(size * 2));
+        for k in keys.iter() {
+            mt.set(k, doc.clone());
+        }
+        assert_eq!(mt.bytes(), size * 2);
+        assert!(mt.get(&keys[2]).is_none());
+        assert!(mt.get(&keys[3]).is_some());
+    }
 }
@@ -0,0 +1,160 @@
+//! Counters tracking how an LSM Tree is behaving.
+//!
+//! The counters are atomics, shared (via an `Arc`) between a tree and
+//! its levels, so they can be updated from the read path and read at
+//! any time without locking the tree.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+/// An LSM Tree's counters.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// The number of gets.
+    pub(crate) gets: AtomicU64,
+
+    /// The number of gets answered by the memtable (or the frozen
+    /// memtable being flushed).
+    pub(crate) memtable_hits: AtomicU64,
+
+    /// The number of memtables flushed to disk.
+    pub(crate) flushes: AtomicU64,
+
+    /// The number of compactions.
+    pub(crate) compactions: AtomicU64,
+
+    /// The number of bytes of tables read from disk.
+    pub(crate) bytes_read: AtomicU64,
+
+    /// The number of bytes of tables written to disk.
+    pub(crate) bytes_written: AtomicU64,
+
+    /// The number of times a level's bloom filter ruled a key out,
+    /// saving a read.
+    pub(crate) bloom_hits: AtomicU64,
+
+    /// The number of times a level's bloom filter couldn't rule a
+    /// key out, so its tables were checked.
+    pub(crate) bloom_misses: AtomicU64,
+
+    /// The number of reads served from memory (the hot key cache or a
+    /// level's last table read) rather than disk.
+    pub(crate) cache_hits: AtomicU64,
+
+    /// The number of tables read from disk.
+    pub(crate) cache_misses: AtomicU64,
+
+    /// The current size of each level, by level number (from 1).
+    levels: Mutex<Vec<LevelSize>>,
+
+    /// The number of gets answered by each level, by level number
+    /// (from 1).
+    level_hits: Mutex<Vec<u64>>,
+}
+
+/// The size of a level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LevelSize {
+    /// The number of tables in the level.
+    pub tables: u64,
+
+    /// The number of records in the level's tables.
+    pub records: u64,
+}
+
+/// A point-in-time copy of a tree's [Metrics].
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct MetricsSnapshot {
+    pub gets: u64,
+    pub memtable_hits: u64,
+    pub flushes: u64,
+    pub compactions: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub bloom_hits: u64,
+    pub bloom_misses: u64,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+
+    /// The size of each level, in level order.
+    pub levels: Vec<LevelSize>,
+
+    /// The number of gets answered by each level, in level order.
+    pub level_hits: Vec<u64>,
+}
+
+impl MetricsSnapshot {
+    /// Returns the fraction of reads served from memory, or zero if
+    /// there haven't been any.
+    pub fn cache_hit_rate(&self) -> f64 {
+        let total = self.cache_hits + self.cache_misses;
+        if total == 0 {
+            return 0.0;
+        }
+        self.cache_hits as f64 / total as f64
+    }
+}
+
+impl Metrics {
+    /// Adds `n` to a counter.
+    pub(crate) fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Counts a get answered by level `n` (1-indexed).
+    pub(crate) fn add_level_hit(&self, n: usize) {
+        let Some(i) = n.checked_sub(1) else {
+            return;
+        };
+        let mut hits = lock(&self.level_hits);
+        if hits.len() <= i {
+            hits.resize(i + 1, 0);
+        }
+        hits[i] += 1;
+    }
+
+    /// Sets the size of level `n` (1-indexed).
+    pub(crate) fn set_level(&self, n: usize, size: LevelSize) {
+        let Some(i) = n.checked_sub(1) else {
+            return;
+        };
+        let mut levels = lock(&self.levels);
+        if levels.len() <= i {
+            levels.resize(i + 1, LevelSize::default());
+        }
+        levels[i] = size;
+    }
+
+    /// Replaces the sizes of all of the levels.
+    pub(crate) fn set_levels(&self, sizes: Vec<LevelSize>) {
+        *lock(&self.levels) = sizes;
+    }
+
+    /// Returns the current values of the counters.
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let get = |c: &AtomicU64| c.load(Ordering::Relaxed);
+        MetricsSnapshot {
+            gets: get(&self.gets),
+            memtable_hits: get(&self.memtable_hits),
+            flushes: get(&self.flushes),
+            compactions: get(&self.compactions),
+            bytes_read: get(&self.bytes_read),
+            bytes_written: get(&self.bytes_written),
+            bloom_hits: get(&self.bloom_hits),
+            bloom_misses: get(&self.bloom_misses),
+            cache_hits: get(&self.cache_hits),
+            cache_misses: get(&self.cache_misses),
+            levels: lock(&self.levels).clone(),
+            level_hits: lock(&self.level_hits).clone(),
+        }
+    }
+}
+
+/// Locks one of the metrics' mutexes, ignoring poisoning (the values
+/// are just counters, so they're still usable).
+fn lock<T>(m: &Mutex<T>) -> MutexGuard<'_, T> {
+    match m.lock() {
+        Ok(l) => l,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
@@ -1,10 +1,20 @@
 //! This module handles database storage.
 
+pub mod bloom;
 pub mod conf;
+pub mod crypto;
+pub mod describe;
+pub mod error;
+pub mod iter;
 pub mod level;
+pub mod lru;
 pub mod lsm;
+pub mod manifest;
 pub mod memtable;
+pub mod metrics;
 pub mod record;
+pub mod schedule;
+pub mod snapshot;
 pub mod sstable;
 pub mod util;
 pub mod wal;
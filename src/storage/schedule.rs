@@ -0,0 +1,103 @@
+//! Scheduling for when heavy (level) compaction is allowed to run.
+
+use bson::DateTime;
+
+/// The number of milliseconds in a day.
+const MILLIS_PER_DAY: i64 = 24 * 60 * 60 * 1000;
+
+/// A daily window of time (in UTC) during which full compaction may run.
+///
+/// If `start` is after `end`, the window wraps around midnight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MaintenanceWindow {
+    /// The start of the window, in seconds since midnight (inclusive).
+    pub start: u32,
+
+    /// The end of the window, in seconds since midnight (exclusive).
+    pub end: u32,
+}
+
+impl MaintenanceWindow {
+    /// Creates a new window from `start` to `end` hours (UTC).
+    pub fn from_hours(start: u32, end: u32) -> Self {
+        MaintenanceWindow {
+            start: start * 60 * 60,
+            end: end * 60 * 60,
+        }
+    }
+
+    /// Checks if the given time of day (in seconds since midnight)
+    /// falls within this window.
+    pub fn contains(&self, secs: u32) -> bool {
+        if self.start <= self.end {
+            self.start <= secs && secs < self.end
+        } else {
+            secs >= self.start || secs < self.end
+        }
+    }
+}
+
+/// A schedule of the times when full compaction is allowed.
+///
+/// Outside of the schedule's windows, an LSM Tree will still flush its
+/// memtable (to bound memory use) but will defer level compaction until
+/// the next window.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompactionSchedule {
+    /// The allowed windows. If empty, compaction is always allowed.
+    pub windows: Vec<MaintenanceWindow>,
+}
+
+impl CompactionSchedule {
+    /// Creates a new schedule with the given windows.
+    pub fn new(windows: Vec<MaintenanceWindow>) -> Self {
+        CompactionSchedule { windows }
+    }
+
+    /// Checks if full compaction is allowed at the given time.
+    pub fn allows(&self, now: DateTime) -> bool {
+        if self.windows.is_empty() {
+            return true;
+        }
+        let secs = (now.timestamp_millis().rem_euclid(MILLIS_PER_DAY) / 1000) as u32;
+        self.windows.iter().any(|w| w.contains(secs))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates a `DateTime` at the given hour on an arbitrary day.
+    fn at_hour(h: i64) -> DateTime {
+        DateTime::from_millis(19_000 * MILLIS_PER_DAY + h * 60 * 60 * 1000)
+    }
+
+    #[test]
+    fn window_contains() {
+        let w = MaintenanceWindow::from_hours(1, 5);
+        assert!(w.contains(60 * 60));
+        assert!(w.contains(4 * 60 * 60));
+        assert!(!w.contains(5 * 60 * 60));
+        assert!(!w.contains(12 * 60 * 60));
+    }
+
+    #[test]
+    fn window_wraps_midnight() {
+        let w = MaintenanceWindow::from_hours(22, 2);
+        assert!(w.contains(23 * 60 * 60));
+        assert!(w.contains(60 * 60));
+        assert!(!w.contains(12 * 60 * 60));
+    }
+
+    #[test]
+    fn schedule_allows() {
+        // An empty schedule always allows compaction...
+        assert!(CompactionSchedule::default().allows(at_hour(12)));
+
+        // Otherwise only within the windows...
+        let s = CompactionSchedule::new(vec![MaintenanceWindow::from_hours(1, 5)]);
+        assert!(s.allows(at_hour(3)));
+        assert!(!s.allows(at_hour(12)));
+    }
+}
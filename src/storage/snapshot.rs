@@ -0,0 +1,216 @@
+//! Point-in-time snapshots of an LSM Tree.
+
+use anyhow::Result;
+use bson::oid::ObjectId;
+use bson::Document;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::storage::level::newest_first;
+use crate::storage::memtable::*;
+use crate::storage::record::Value;
+use crate::storage::sstable::*;
+
+/// The pin state for a single SSTable.
+#[derive(Debug, Default)]
+struct Pin {
+    /// The number of snapshots holding the table.
+    count: usize,
+
+    /// If set, the table was removed from its level while pinned and
+    /// its file (at this path) should be deleted once it's unpinned.
+    orphan_path: Option<String>,
+}
+
+/// A shared, reference-counted registry of the SSTables held by
+/// live snapshots.
+///
+/// While a table is pinned, compaction can remove it from its level
+/// but its file is kept on disk until the last snapshot holding it
+/// is dropped.
+#[derive(Debug, Clone, Default)]
+pub struct TablePins {
+    pins: Arc<Mutex<HashMap<ObjectId, Pin>>>,
+}
+
+impl TablePins {
+    /// Locks the registry, ignoring poisoning.
+    fn lock(&self) -> MutexGuard<'_, HashMap<ObjectId, Pin>> {
+        match self.pins.lock() {
+            Ok(g) => g,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    /// Adds a pin to the given table.
+    pub fn pin(&self, id: &ObjectId) {
+        self.lock().entry(*id).or_default().count += 1;
+    }
+
+    /// Removes a pin from the given table.
+    ///
+    /// If it was the last pin and the table has been orphaned, its
+    /// file is deleted.
+    pub fn unpin(&self, id: &ObjectId) {
+        let mut pins = self.lock();
+        let done = match pins.get_mut(id) {
+            Some(pin) => {
+                pin.count = pin.count.saturating_sub(1);
+                pin.count == 0
+            }
+            None => false,
+        };
+        if done {
+            if let Some(Pin {
+                orphan_path: Some(path),
+                ..
+            }) = pins.remove(id)
+            {
+                // Best effort -- there's nowhere to report an error from here...
+                let _ = std::fs::remove_file(path);
+            }
+        }
+    }
+
+    /// Checks if the given table is pinned by a snapshot.
+    pub fn is_pinned(&self, id: &ObjectId) -> bool {
+        self.lock().get(id).map(|p| p.count > 0).unwrap_or(false)
+    }
+
+    /// Marks a table that has been removed from its level for deletion.
+    ///
+    /// Returns `true` if the table is pinned, in which case deleting its
+    /// file is deferred until it's unpinned. Returns `false` if it isn't
+    /// pinned and the caller should delete it now.
+    pub fn orphan(&self, handle: &SSTableHandle) -> bool {
+        match self.lock().get_mut(&handle.meta.table_id) {
+            Some(pin) if pin.count > 0 => {
+                pin.orphan_path = Some(handle.path.clone());
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// A consistent, point-in-time view of an LSM Tree.
+///
+/// The snapshot holds a copy of the memtable state and pins the
+/// exact SSTables that were live when it was taken, so reads through
+/// it aren't affected by later writes or compactions.
+#[derive(Debug)]
+pub struct Snapshot {
+    /// A copy of the tree's memtable.
+    pub memtable: MemTable,
+
+    /// A copy of the tree's frozen memtable, if it had one.
+    pub frozen_memtable: Option<MemTable>,
+
+    /// The handles for each level's tables (in level order).
+    pub levels: Vec<Vec<SSTableHandle>>,
+
+    /// The registry holding this snapshot's pins.
+    pins: TablePins,
+}
+
+impl Snapshot {
+    /// Creates a new snapshot, pinning all of the given tables.
+    pub fn new(
+        memtable: MemTable,
+        frozen_memtable: Option<MemTable>,
+        levels: Vec<Vec<SSTableHandle>>,
+        pins: TablePins,
+    ) -> Self {
+        for t in levels.iter().flatten() {
+            pins.pin(&t.meta.table_id);
+        }
+        Snapshot {
+            memtable,
+            frozen_memtable,
+            levels,
+            pins,
+        }
+    }
+
+    /// Gets a value as of the snapshot.
+    pub async fn get(&self, key: &ObjectId) -> Result<Option<Document>> {
+        // Check the memtables first...
+        let memtables = std::iter::once(&self.memtable).chain(self.frozen_memtable.as_ref());
+        for mt in memtables {
+            if let Some(value) = mt.get(key) {
+                return match value {
+                    Value::Data(doc) => Ok(Some(doc)),
+                    Value::Tombstone => Ok(None),
+                };
+            }
+        }
+
+        // Then the levels' tables (newest first)...
+        for tables in self.levels.iter() {
+            for th in newest_first(tables) {
+                if !th.meta.key_in_range(key) {
+                    continue;
+                }
+                if let Some(rec) = th.read().await?.get(key) {
+                    return match rec.value {
+                        Value::Data(doc) => Ok(Some(doc)),
+                        Value::Tombstone => Ok(None),
+                    };
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Gets all of the values with keys in the given range (inclusive)
+    /// as of the snapshot, sorted by key.
+    pub async fn get_range(
+        &self,
+        start: &ObjectId,
+        end: &ObjectId,
+    ) -> Result<Vec<(ObjectId, Document)>> {
+        // Merge from oldest to newest, so newer values overwrite older ones...
+        let mut merged: BTreeMap<ObjectId, Value<Document>> = BTreeMap::new();
+        for tables in self.levels.iter().rev() {
+            for th in newest_first(tables).into_iter().rev() {
+                if th.meta.max_key < *start || th.meta.min_key > *end {
+                    continue;
+                }
+                let sstable = th.read().await?;
+                for rec in sstable.records {
+                    if *start <= rec.key && rec.key <= *end {
+                        merged.insert(rec.key, rec.value);
+                    }
+                }
+            }
+        }
+        let memtables = self
+            .frozen_memtable
+            .iter()
+            .chain(std::iter::once(&self.memtable));
+        for mt in memtables {
+            for (k, v) in mt.iter() {
+                if start <= k && k <= end {
+                    merged.insert(*k, v.clone());
+                }
+            }
+        }
+
+        // Drop the tombstones...
+        Ok(merged
+            .into_iter()
+            .filter_map(|(k, v)| match v {
+                Value::Data(doc) => Some((k, doc)),
+                Value::Tombstone => None,
+            })
+            .collect())
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        for t in self.levels.iter().flatten() {
+            self.pins.unpin(&t.meta.table_id);
+        }
+    }
+}
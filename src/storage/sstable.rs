@@ -1,12 +1,18 @@
 use anyhow::{anyhow, Result};
-use bloom::{BloomFilter, ASMS};
 use bson::oid::ObjectId;
-use bson::DateTime;
-use core::cmp::Ordering;
+use bson::{DateTime, Document};
+use core::cmp::{Ordering, Reverse};
 use serde::{Deserialize, Serialize};
+use std::collections::BinaryHeap;
+use std::io::SeekFrom;
 use std::path::Path;
+use std::time::Duration;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, BufReader};
 
+use crate::storage::bloom::BloomFilter;
 use crate::storage::conf::*;
+use crate::storage::crypto::EncryptionKey;
 use crate::storage::record::*;
 use crate::storage::util::*;
 
@@ -23,6 +29,19 @@ pub struct SSTableHandle {
     /// A flag indicating whether this SSTable is active
     /// and should be considered for reads.
     pub active: bool,
+
+    /// The key the SSTable's file is encrypted with, if any.
+    #[serde(skip)]
+    pub encryption: Option<EncryptionKey>,
+
+    /// The number of records in each block when the table is written
+    /// in the compact format (see [StorageConfig::table_index_interval]).
+    #[serde(skip, default = "default_index_interval")]
+    pub index_interval: usize,
+}
+
+fn default_index_interval() -> usize {
+    TABLE_INDEX_INTERVAL
 }
 
 impl SSTableHandle {
@@ -32,33 +51,75 @@ impl SSTableHandle {
             meta,
             path: path.to_string(),
             active: true,
+            encryption: None,
+            index_interval: TABLE_INDEX_INTERVAL,
         }
     }
 
+    /// Returns the format of the SSTable's file, based on its extension.
+    pub fn format(&self) -> TableFormat {
+        TableFormat::from_path(&self.path)
+    }
+
     /// Reads the SSTable from disk, from `self.path`.
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(table = %self.meta.table_id, records = tracing::field::Empty)
+    )]
     pub async fn read(&self) -> Result<SSTable> {
-        // Open the file and wrap it in a reader...
-        let buff = read_bson(self.path.as_str()).await?;
-
-        // Convert the buffer to a document and return...
-        let sstable: SSTable = bson::from_slice(&buff)?;
+        let sstable = read_sstable(&self.path, self.encryption.as_ref()).await?;
+        tracing::Span::current().record("records", sstable.records.len());
         Ok(sstable)
     }
 
     /// Writes the SSTable to disk.
     ///
-    /// The data is written to `self.path` as a BSON document.
-    pub async fn write(&self, sstable: &SSTable) -> Result<()> {
-        // Convert the table to a document...
-        let doc = bson::to_document(sstable)?;
+    /// The data is written to `self.path` in the format matching its
+    /// extension (see [TableFormat]), compressed with snappy, and
+    /// encrypted if the handle has a key.
+    ///
+    /// BSON tables are compressed as a whole (see [compress_table]).
+    /// Compact tables are compressed a block at a time, and the table's
+    /// sparse index is stored in the handle's metadata (see
+    /// [SSTable::to_compact_bytes]).
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(
+            table = %self.meta.table_id,
+            records = sstable.records.len(),
+            bytes = tracing::field::Empty,
+        )
+    )]
+    pub async fn write(&mut self, sstable: &SSTable) -> Result<()> {
+        let buf = match self.format() {
+            TableFormat::Bson => {
+                // Convert the table to a document and write it to a buffer...
+                let mut buf = vec![];
+                bson::to_document(sstable)?.to_writer(&mut buf)?;
+                compress_table(&buf)?
+            }
+            TableFormat::Compact => {
+                let (buf, index) = sstable.to_compact_bytes(self.index_interval)?;
+                self.meta.index = index;
+                buf
+            }
+        };
 
-        // Write the document to a vec buffer...
-        write_bson(self.path.as_str(), &doc).await?;
+        // Write the data to disk...
+        tracing::Span::current().record("bytes", buf.len());
+        write_bytes(self.path.as_str(), buf, self.encryption.as_ref()).await?;
 
         // Success!
         Ok(())
     }
 
+    /// Returns the size of the SSTable's file on disk, in bytes.
+    pub async fn size(&self) -> Result<u64> {
+        Ok(tokio::fs::metadata(&self.path).await?.len())
+    }
+
     /// Deletes the SSTable from disk (at `self.path`).
     pub async fn delete(&self) -> Result<()> {
         tokio::fs::remove_file(&self.path).await?;
@@ -69,6 +130,294 @@ impl SSTableHandle {
     pub async fn get_bloom_filter(&self) -> Result<BloomFilter> {
         self.read().await?.get_bloom_filter()
     }
+
+    /// Checks if the table can be read a block at a time, using its
+    /// sparse index (see [SSTableMeta::index]).
+    ///
+    /// Encrypted tables are sealed as a whole, so they can't be.
+    pub fn is_indexed(&self) -> bool {
+        self.format() == TableFormat::Compact
+            && self.encryption.is_none()
+            && !self.meta.index.is_empty()
+    }
+
+    /// Gets a record from the SSTable on disk, if it exists.
+    ///
+    /// If the table is indexed (see [SSTableHandle::is_indexed]), only
+    /// the block that could hold the key is read and decoded. Otherwise
+    /// the whole table is read.
+    pub async fn get(&self, key: &ObjectId) -> Result<Option<Record>> {
+        if !self.meta.key_in_range(key) {
+            return Ok(None);
+        }
+        if !self.is_indexed() {
+            return Ok(self.read().await?.get(key));
+        }
+
+        // Find the last block starting at or before the key...
+        let i = self.meta.index.partition_point(|(k, _)| k <= key);
+        let Some(&(_, offset)) = i.checked_sub(1).and_then(|i| self.meta.index.get(i)) else {
+            return Ok(None);
+        };
+
+        // Read it in and look for the key...
+        let block = self.read_block(offset).await?;
+        let mut rest = block.as_slice();
+        while !rest.is_empty() {
+            let record = decode_record(&mut rest)?;
+            match record.key.cmp(key) {
+                Ordering::Less => continue,
+                Ordering::Equal => return Ok(Some(record)),
+                Ordering::Greater => break,
+            }
+        }
+        Ok(None)
+    }
+
+    /// Opens a stream of the table's records, in key order.
+    ///
+    /// Tables in the compact format are read a block at a time, so only
+    /// one block of records is held in memory at once. Other tables
+    /// (BSON, encrypted, or written before records were stored in
+    /// blocks) can't be read in pieces, so they're read in whole.
+    pub async fn stream_records(&self) -> Result<RecordStream> {
+        self.stream_records_from(&self.meta.min_key).await
+    }
+
+    /// Like [SSTableHandle::stream_records], but skips ahead to the
+    /// block that could hold `start`, using the table's sparse index.
+    ///
+    /// Records before `start` can still be returned (from that block, or
+    /// from anywhere in a table that can't be read in pieces), so the
+    /// caller should skip them.
+    pub async fn stream_records_from(&self, start: &ObjectId) -> Result<RecordStream> {
+        if self.format() == TableFormat::Compact && self.encryption.is_none() {
+            // Check the file is stored in blocks...
+            let mut file = File::open(&self.path).await?;
+            let mut header = [0u8; 8];
+            let blocks =
+                file.read_exact(&mut header).await.is_ok() && &header[..4] == COMPACT_BLOCKS_MAGIC;
+
+            // If so, skip past the metadata to the last block starting
+            // at or before `start`...
+            if blocks {
+                let meta_len = u32::from_le_bytes(header[4..].try_into()?) as u64;
+                let i = self.meta.index.partition_point(|(k, _)| k <= start);
+                let offset = match i.checked_sub(1) {
+                    Some(i) => self.meta.index[i].1,
+                    None => 0,
+                };
+                let pos = COMPACT_BLOCKS_MAGIC.len() as u64 + meta_len + offset;
+                file.seek(SeekFrom::Start(pos)).await?;
+                return Ok(RecordStream {
+                    file: Some(BufReader::new(file)),
+                    block: vec![].into_iter(),
+                });
+            }
+        }
+
+        // Otherwise, read the whole table...
+        Ok(RecordStream {
+            file: None,
+            block: self.read().await?.records.into_iter(),
+        })
+    }
+
+    /// Reads and decompresses the block at `offset` (from the end of the
+    /// metadata) in the table's file.
+    async fn read_block(&self, offset: u64) -> Result<Vec<u8>> {
+        let mut file = File::open(&self.path).await?;
+
+        // Read the magic number and the metadata's length, to find
+        // where the blocks start...
+        let mut header = [0u8; 8];
+        file.read_exact(&mut header).await?;
+        if &header[..4] != COMPACT_BLOCKS_MAGIC {
+            return Err(anyhow!(
+                "SSTable {} isn't stored in blocks",
+                self.meta.table_id
+            ));
+        }
+        let meta_len = u32::from_le_bytes(header[4..].try_into()?) as u64;
+
+        // Then seek to the block and read it...
+        let start = COMPACT_BLOCKS_MAGIC.len() as u64 + meta_len + offset;
+        file.seek(SeekFrom::Start(start)).await?;
+        let mut len = [0u8; 4];
+        file.read_exact(&mut len).await?;
+        let mut block = vec![0u8; u32::from_le_bytes(len) as usize];
+        file.read_exact(&mut block).await?;
+        Ok(snap::raw::Decoder::new().decompress_vec(&block)?)
+    }
+}
+
+/// The records of an SSTable, read incrementally (see
+/// [SSTableHandle::stream_records]).
+pub struct RecordStream {
+    /// The table's file, positioned at the next block, if the table
+    /// is being read a block at a time.
+    file: Option<BufReader<File>>,
+
+    /// The remaining records from the current block (or the whole table).
+    block: std::vec::IntoIter<Record>,
+}
+
+impl RecordStream {
+    /// Returns the next record, or `None` once all of the records have
+    /// been read.
+    ///
+    /// After an error, the stream ends.
+    pub async fn next(&mut self) -> Option<Result<Record>> {
+        loop {
+            if let Some(record) = self.block.next() {
+                return Some(Ok(record));
+            }
+            match self.next_block().await {
+                Ok(true) => continue,
+                Ok(false) => return None,
+                Err(err) => {
+                    self.file = None;
+                    return Some(Err(err));
+                }
+            }
+        }
+    }
+
+    /// Reads the next block of records from the file.
+    ///
+    /// # Returns
+    ///
+    /// Returns `false` if there are no blocks left.
+    async fn next_block(&mut self) -> Result<bool> {
+        let Some(file) = self.file.as_mut() else {
+            return Ok(false);
+        };
+
+        // Read the block's length, stopping at the end of the file...
+        let mut len = [0u8; 4];
+        match file.read_exact(&mut len).await {
+            Ok(_) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+                self.file = None;
+                return Ok(false);
+            }
+            Err(err) => return Err(err.into()),
+        }
+
+        // Then read it in and decode its records...
+        let mut block = vec![0u8; u32::from_le_bytes(len) as usize];
+        file.read_exact(&mut block).await?;
+        let block = snap::raw::Decoder::new().decompress_vec(&block)?;
+        let mut rest = block.as_slice();
+        let mut records = vec![];
+        while !rest.is_empty() {
+            records.push(decode_record(&mut rest)?);
+        }
+        self.block = records.into_iter();
+        Ok(true)
+    }
+}
+
+/// Reads an SSTable from the file at `path`, in the format matching
+/// its extension (see [TableFormat]).
+///
+/// If the file is encrypted, `key` must be the key it was written with.
+pub async fn read_sstable(path: &str, key: Option<&EncryptionKey>) -> Result<SSTable> {
+    // Read in the file (and decompress it)...
+    let buff = read_bson_with(path, key).await?;
+    let buff = decompress_table(buff)?;
+
+    // Decode the table and return...
+    match TableFormat::from_path(path) {
+        TableFormat::Bson => Ok(bson::from_slice(&buff)?),
+        TableFormat::Compact => SSTable::from_compact_bytes(&buff),
+    }
+}
+
+/// The on-disk format of an SSTable's file, tagged by its extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TableFormat {
+    /// The whole table is stored as one BSON document (`.bson`).
+    #[default]
+    Bson,
+
+    /// The table's metadata followed by its records, in compressed
+    /// blocks, without wrapping them in a document (`.sst`).
+    ///
+    /// This saves the field names and array indexes BSON would store
+    /// for every record, so files are smaller and faster to parse.
+    ///
+    /// See also: [SSTable::to_compact_bytes]
+    Compact,
+}
+
+impl TableFormat {
+    /// All of the formats, for finding a table's file.
+    pub const ALL: [TableFormat; 2] = [TableFormat::Bson, TableFormat::Compact];
+
+    /// Returns the file extension for tables in this format.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            TableFormat::Bson => "bson",
+            TableFormat::Compact => "sst",
+        }
+    }
+
+    /// Returns the format of the table file at `path`, based on its
+    /// extension. Anything unrecognized is assumed to be BSON.
+    pub fn from_path(path: impl AsRef<Path>) -> Self {
+        let ext = path.as_ref().extension().and_then(|e| e.to_str());
+        match ext {
+            Some(ext) if ext == TableFormat::Compact.extension() => TableFormat::Compact,
+            _ => TableFormat::Bson,
+        }
+    }
+
+    /// Parses a table's id from its file name (e.g. `<id>.bson`).
+    ///
+    /// Returns `None` if the file name isn't a table's.
+    pub fn table_id(file_name: &str) -> Option<ObjectId> {
+        TableFormat::ALL.iter().find_map(|f| {
+            let stem = file_name.strip_suffix(f.extension())?.strip_suffix('.')?;
+            ObjectId::parse_str(stem).ok()
+        })
+    }
+}
+
+/// A sparse index over an SSTable's blocks (see [SSTableMeta::index]):
+/// the first key of each block and its offset in the table's file.
+pub type SparseIndex = Vec<(ObjectId, u64)>;
+
+/// The magic bytes at the start of an SSTable in the compact format,
+/// with its records stored one after the other.
+const COMPACT_MAGIC: &[u8; 4] = b"BKT1";
+
+/// The magic bytes at the start of an SSTable in the compact format,
+/// with its records stored in compressed blocks.
+const COMPACT_BLOCKS_MAGIC: &[u8; 4] = b"BKT2";
+
+/// The header at the start of a snappy-compressed SSTable file.
+///
+/// Tables written before compression was added don't have it (in
+/// either format), so they're read back as-is.
+const SNAPPY_HEADER: &[u8; 4] = b"BKZ1";
+
+/// Compresses an encoded SSTable with snappy, behind a [SNAPPY_HEADER].
+pub fn compress_table(data: &[u8]) -> Result<Vec<u8>> {
+    let mut buf = SNAPPY_HEADER.to_vec();
+    buf.extend(snap::raw::Encoder::new().compress_vec(data)?);
+    Ok(buf)
+}
+
+/// Decompresses an SSTable written by [compress_table].
+///
+/// Data without a [SNAPPY_HEADER] (i.e. an uncompressed table) is
+/// returned unchanged.
+pub fn decompress_table(data: Vec<u8>) -> Result<Vec<u8>> {
+    match data.strip_prefix(SNAPPY_HEADER.as_slice()) {
+        Some(payload) => Ok(snap::raw::Decoder::new().decompress_vec(payload)?),
+        None => Ok(data),
+    }
 }
 
 /// An SSTable read from disk.
@@ -93,6 +442,7 @@ impl SSTable {
         // Get the min/max keys and count from the records...
         let min_key = records.first().ok_or(anyhow!("records vec was empty"))?.key;
         let max_key = records.last().ok_or(anyhow!("records vec was empty"))?.key;
+        let num_tombstones = count_tombstones(&records);
 
         // Create the SSTable...
         Ok(SSTable {
@@ -102,6 +452,8 @@ impl SSTable {
                 min_key,
                 max_key,
                 num_records: records.len(),
+                num_tombstones,
+                index: vec![],
             },
             records,
         })
@@ -129,14 +481,15 @@ impl SSTable {
     }
 
     /// Get all records in the SSTable with keys in the given range (inclusive).
+    ///
+    /// The bounds don't need to be keys in the table.
     pub fn get_range(&self, min_key: &ObjectId, max_key: &ObjectId) -> Vec<Record> {
-        // Get the starting point...
-        let min_i = match self.get_index(min_key) {
-            Some(i) => i,
-            None => {
-                return vec![];
-            }
-        };
+        // Get the starting point (`min_key` or, if it isn't stored,
+        // where it would be inserted)...
+        let min_i = self
+            .records
+            .binary_search_by(|record| record.key.cmp(min_key))
+            .unwrap_or_else(|i| i);
 
         // Create a vector to store the records...
         let mut records = vec![];
@@ -155,9 +508,23 @@ impl SSTable {
     }
 
     /// Create a new SSTable by merging this SSTable with another SSTable.
+    ///
+    /// When both tables have a key, the record from the newer table (by
+    /// `created_at`) is kept, whichever of the two is `self`.
+    ///
+    /// If either table is empty, a copy of the other is returned. If
+    /// both are empty, a copy of `self` is returned.
     pub fn merge(&self, other: &SSTable) -> Result<SSTable> {
+        // Handle empty tables...
+        if other.records.is_empty() {
+            return Ok(self.clone());
+        }
+        if self.records.is_empty() {
+            return Ok(other.clone());
+        }
+
         // Create a vec to store the merged records...
-        let mut records = vec![];
+        let mut records = Vec::with_capacity(self.records.len() + other.records.len());
 
         // Check which SSTable is newer...
         let (newer, older) = if self.meta.created_at > other.meta.created_at {
@@ -173,7 +540,7 @@ impl SSTable {
         while i_newer < newer.records.len() && i_older < older.records.len() {
             // Get the records at the current indexes...
             let r_newer = &newer.records[i_newer];
-            let r_older = &other.records[i_older];
+            let r_older = &older.records[i_older];
 
             // Compare the keys...
             match r_newer.key.cmp(&r_older.key) {
@@ -213,6 +580,81 @@ impl SSTable {
         SSTable::new(records)
     }
 
+    /// Create a new SSTable by merging any number of SSTables in a
+    /// single pass (a k-way merge), rather than pairwise with
+    /// [SSTable::merge].
+    ///
+    /// Where tables share a key, the record from the newest table (by
+    /// creation time, then table id) is kept.
+    pub fn merge_many(tables: &[&SSTable]) -> Result<SSTable> {
+        // Start a cursor at the front of each table. The heap pops the
+        // smallest key first and, for equal keys, the newest table...
+        let mut cursors = vec![0; tables.len()];
+        let mut heap = BinaryHeap::new();
+        for (i, table) in tables.iter().enumerate() {
+            if let Some(rec) = table.records.first() {
+                let age = (table.meta.created_at, table.meta.table_id);
+                heap.push(Reverse((rec.key, Reverse(age), i)));
+            }
+        }
+
+        // Create a vec to store the merged records...
+        let mut records = Vec::with_capacity(tables.iter().map(|t| t.records.len()).sum());
+
+        while let Some(Reverse((key, age, i))) = heap.pop() {
+            // The newest record for a key comes first, so skip the rest...
+            if records.last().is_none_or(|r: &Record| r.key != key) {
+                records.push(tables[i].records[cursors[i]].clone());
+            }
+
+            // Move the table's cursor along...
+            cursors[i] += 1;
+            if let Some(rec) = tables[i].records.get(cursors[i]) {
+                heap.push(Reverse((rec.key, age, i)));
+            }
+        }
+
+        // Create the SSTable...
+        SSTable::new(records)
+    }
+
+    /// Splits the SSTable into tables whose keys each span at most
+    /// `max_span` of time (going by the timestamps in the keys).
+    ///
+    /// The new tables keep this table's creation time. If the table
+    /// already fits within `max_span`, it's returned as is.
+    pub fn split_by_span(self, max_span: Duration) -> Result<Vec<SSTable>> {
+        let max_span = i64::try_from(max_span.as_millis()).unwrap_or(i64::MAX);
+        let millis = |key: &ObjectId| key.timestamp().timestamp_millis();
+        if millis(&self.meta.max_key) - millis(&self.meta.min_key) <= max_span {
+            return Ok(vec![self]);
+        }
+
+        // Start a new table whenever a key is too far from the first...
+        let created_at = self.meta.created_at;
+        let mut chunks = vec![];
+        let mut chunk: Vec<Record> = vec![];
+        for record in self.records {
+            if let Some(first) = chunk.first() {
+                if millis(&record.key) - millis(&first.key) > max_span {
+                    chunks.push(std::mem::take(&mut chunk));
+                }
+            }
+            chunk.push(record);
+        }
+        chunks.push(chunk);
+
+        // Create the tables...
+        chunks
+            .into_iter()
+            .map(|records| {
+                let mut table = SSTable::new(records)?;
+                table.meta.created_at = created_at;
+                Ok(table)
+            })
+            .collect()
+    }
+
     /// Returns a handle for this SSTable.
     ///
     /// If `write` is true, the SSTable will be written to disk before
@@ -236,7 +678,7 @@ impl SSTable {
             .ok_or(anyhow!("Failed to create sstable path"))?;
 
         // Create the handle...
-        let handle = SSTableHandle::new(self.meta.clone(), path);
+        let mut handle = SSTableHandle::new(self.meta.clone(), path);
 
         // If we're writing, write the SSTable to disk...
         if write {
@@ -247,6 +689,106 @@ impl SSTable {
         Ok(handle)
     }
 
+    /// Encodes the SSTable in the compact format (see [TableFormat::Compact]).
+    ///
+    /// The layout is a magic number, then the metadata as a BSON document,
+    /// then the records in blocks of `index_interval`. Each block is its
+    /// length (as a little-endian `u32`) followed by its records,
+    /// compressed with snappy. Each record is its 12 key bytes, a tag byte
+    /// (`0` for a tombstone, `1` for data) and, for data, the BSON
+    /// document. BSON documents are prefixed with their length, so
+    /// records can be read back one after the other.
+    ///
+    /// The metadata holds the table's sparse index: the first key and
+    /// offset of each block (see [SSTableMeta::index]). If
+    /// `index_interval` is zero, the records are stored in a single block
+    /// and the index is left empty.
+    ///
+    /// # Returns
+    ///
+    /// The encoded table and its sparse index.
+    pub fn to_compact_bytes(&self, index_interval: usize) -> Result<(Vec<u8>, SparseIndex)> {
+        // Encode and compress the blocks, noting where each one starts...
+        let block_len = match index_interval {
+            0 => self.records.len().max(1),
+            n => n,
+        };
+        let mut blocks = vec![];
+        let mut index = vec![];
+        for chunk in self.records.chunks(block_len) {
+            index.push((chunk[0].key, blocks.len() as u64));
+            let mut raw = vec![];
+            for record in chunk.iter() {
+                encode_record(record, &mut raw)?;
+            }
+            let block = snap::raw::Encoder::new().compress_vec(&raw)?;
+            blocks.extend_from_slice(&(block.len() as u32).to_le_bytes());
+            blocks.extend(block);
+        }
+        if index_interval == 0 {
+            index.clear();
+        }
+
+        // Then write the metadata (with the index) and the blocks...
+        let mut meta = self.meta.clone();
+        meta.index = index;
+        let mut buf = COMPACT_BLOCKS_MAGIC.to_vec();
+        bson::to_document(&meta)?.to_writer(&mut buf)?;
+        buf.extend(blocks);
+        Ok((buf, meta.index))
+    }
+
+    /// Decodes an SSTable from the compact format.
+    ///
+    /// Tables written before records were stored in blocks can still
+    /// be read.
+    ///
+    /// See also: [SSTable::to_compact_bytes]
+    pub fn from_compact_bytes(bytes: &[u8]) -> Result<Self> {
+        // Read the records from their blocks...
+        if let Some(mut rest) = bytes.strip_prefix(COMPACT_BLOCKS_MAGIC) {
+            let meta: SSTableMeta = bson::from_document(Document::from_reader(&mut rest)?)?;
+            let mut records = Vec::with_capacity(meta.num_records);
+            while !rest.is_empty() {
+                if rest.len() < 4 {
+                    return Err(anyhow!("Compact SSTable has a truncated block"));
+                }
+                let len = u32::from_le_bytes(rest[..4].try_into()?) as usize;
+                let block = rest
+                    .get(4..4 + len)
+                    .ok_or(anyhow!("Compact SSTable has a truncated block"))?;
+                rest = &rest[4 + len..];
+                let block = snap::raw::Decoder::new().decompress_vec(block)?;
+                let mut block = block.as_slice();
+                while !block.is_empty() {
+                    records.push(decode_record(&mut block)?);
+                }
+            }
+            if records.len() != meta.num_records {
+                return Err(anyhow!("Compact SSTable ended early"));
+            }
+            return Ok(SSTable { meta, records });
+        }
+
+        // Otherwise, they're one after the other...
+        let mut rest = bytes
+            .strip_prefix(COMPACT_MAGIC)
+            .ok_or(anyhow!("Not a compact SSTable (bad magic number)"))?;
+
+        // Read the metadata...
+        let meta: SSTableMeta = bson::from_document(Document::from_reader(&mut rest)?)?;
+
+        // Then the records...
+        let mut records = Vec::with_capacity(meta.num_records);
+        for _ in 0..meta.num_records {
+            records.push(decode_record(&mut rest)?);
+        }
+        if !rest.is_empty() {
+            return Err(anyhow!("Compact SSTable has trailing data"));
+        }
+        Ok(SSTable { meta, records })
+    }
+
     /// Returns a bloom filter for this SSTable.
     pub fn get_bloom_filter(&self) -> Result<BloomFilter> {
         let mut bf = BloomFilter::with_rate(BLOOM_FILTER_ERROR_RATE, BLOOM_FILTER_SIZE);
@@ -257,6 +799,48 @@ impl SSTable {
     }
 }
 
+/// Encodes a record for the compact format (see [SSTable::to_compact_bytes]).
+fn encode_record(record: &Record, buf: &mut Vec<u8>) -> Result<()> {
+    buf.extend_from_slice(&record.key.bytes());
+    match &record.value {
+        Value::Tombstone => buf.push(0),
+        Value::Data(doc) => {
+            buf.push(1);
+            doc.to_writer(buf)?;
+        }
+    }
+    Ok(())
+}
+
+/// Decodes a record written by [encode_record] from the front of
+/// `rest`, moving `rest` past it.
+fn decode_record(rest: &mut &[u8]) -> Result<Record> {
+    if rest.len() < 13 {
+        return Err(anyhow!("Compact SSTable ended early"));
+    }
+    let mut key = [0u8; 12];
+    key.copy_from_slice(&rest[..12]);
+    let tag = rest[12];
+    *rest = &rest[13..];
+    let value = match tag {
+        0 => Value::Tombstone,
+        1 => Value::Data(Document::from_reader(&mut *rest)?),
+        _ => return Err(anyhow!("Unknown record tag {} in compact SSTable", tag)),
+    };
+    Ok(Record {
+        key: ObjectId::from_bytes(key),
+        value,
+    })
+}
+
+/// Returns the number of tombstones in the given records.
+pub fn count_tombstones(records: &[Record]) -> usize {
+    records
+        .iter()
+        .filter(|r| matches!(r.value, Value::Tombstone))
+        .count()
+}
+
 pub struct SSTableRecordIterator<'a> {
     sstable: &'a SSTable,
     index: usize,
@@ -301,6 +885,18 @@ pub struct SSTableMeta {
 
     /// The number of records in this SSTable.
     pub num_records: usize,
+
+    /// The number of those records that are tombstones.
+    #[serde(default)]
+    pub num_tombstones: usize,
+
+    /// The table's sparse index: the first key of each block of records
+    /// and the block's offset in the file (from the end of the metadata).
+    ///
+    /// Only tables in the compact format have one (see
+    /// [SSTable::to_compact_bytes]).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub index: SparseIndex,
 }
 
 impl SSTableMeta {
@@ -308,6 +904,12 @@ impl SSTableMeta {
     pub fn key_in_range(&self, key: &ObjectId) -> bool {
         self.min_key <= *key && *key <= self.max_key
     }
+
+    /// Returns true if this SSTable's key range overlaps the
+    /// range from `min_key` to `max_key` (inclusive).
+    pub fn overlaps(&self, min_key: &ObjectId, max_key: &ObjectId) -> bool {
+        self.min_key <= *max_key && *min_key <= self.max_key
+    }
 }
 
 #[cfg(test)]
@@ -317,6 +919,78 @@ mod test {
     use bson::doc;
     use bson::oid::ObjectId;
 
+    #[test]
+    fn split_by_span_bounds_tables() -> Result<()> {
+        // Create a table with keys spread over 100 seconds...
+        let key_at = |secs: u32| {
+            let mut bytes = ObjectId::new().bytes();
+            bytes[..4].copy_from_slice(&secs.to_be_bytes());
+            ObjectId::from_bytes(bytes)
+        };
+        let mut keys: Vec<_> = (0..100).map(|n| key_at(1_000_000 + n)).collect();
+        keys.sort();
+        let records = keys
+            .iter()
+            .map(|k| Record {
+                key: *k,
+                value: Value::Data(doc! {}),
+            })
+            .collect();
+        let sstable = SSTable::new(records)?;
+
+        // Split it into tables spanning at most 30 seconds...
+        let span = Duration::from_secs(30);
+        let tables = sstable.clone().split_by_span(span)?;
+        assert_eq!(tables.len(), 4);
+        for t in tables.iter() {
+            let secs = |k: &ObjectId| k.timestamp().timestamp_millis() / 1000;
+            assert!(secs(&t.meta.max_key) - secs(&t.meta.min_key) <= 30);
+            assert_eq!(t.meta.created_at, sstable.meta.created_at);
+        }
+        let records: Vec<_> = tables.into_iter().flat_map(|t| t.records).collect();
+        assert_eq!(records, sstable.records);
+
+        // A table within the span is left alone...
+        let tables = sstable.clone().split_by_span(Duration::from_secs(100))?;
+        assert_eq!(tables, vec![sstable]);
+        Ok(())
+    }
+
+    #[test]
+    fn get_range_between_keys() -> Result<()> {
+        // Create a table with the keys 10, 20, ..., 50...
+        let key = |n: u8| ObjectId::from_bytes([n; 12]);
+        let records = (1..=5)
+            .map(|n| Record {
+                key: key(n * 10),
+                value: Value::Data(doc! { "n": n as i32 * 10 }),
+            })
+            .collect();
+        let sstable = SSTable::new(records)?;
+        let range = |min: u8, max: u8| -> Vec<u8> {
+            sstable
+                .get_range(&key(min), &key(max))
+                .iter()
+                .map(|r| r.key.bytes()[0])
+                .collect()
+        };
+
+        // Bounds that aren't stored keys...
+        assert_eq!(range(15, 35), vec![20, 30]);
+        assert_eq!(range(20, 35), vec![20, 30]);
+        assert_eq!(range(15, 30), vec![20, 30]);
+
+        // A range starting before all keys and one ending after them...
+        assert_eq!(range(0, 25), vec![10, 20]);
+        assert_eq!(range(45, 255), vec![50]);
+        assert_eq!(range(0, 255), vec![10, 20, 30, 40, 50]);
+
+        // And ranges with nothing in them...
+        assert!(range(31, 39).is_empty());
+        assert!(range(51, 255).is_empty());
+        Ok(())
+    }
+
     #[tokio::test]
     async fn create_and_read_sstable() -> Result<()> {
         // Create an sstable...
@@ -347,7 +1021,7 @@ mod test {
         let parent_path = "/tmp";
 
         // Create a handle for the sstable...
-        let handle = sstable.get_handle(parent_path, false).await?;
+        let mut handle = sstable.get_handle(parent_path, false).await?;
 
         // Get the path...
         let path = Path::new(parent_path).join(handle.meta.table_id.to_string());
@@ -386,6 +1060,251 @@ mod test {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn encrypted_sstable_needs_key() -> Result<()> {
+        let sstable = SSTable::new(vec![
+            Record {
+                key: ObjectId::new(),
+                value: Value::Data(doc! { "secret": "hunter2" }),
+            },
+            Record {
+                key: ObjectId::new(),
+                value: Value::Tombstone,
+            },
+        ])?;
+
+        for format in TableFormat::ALL {
+            // Write it encrypted...
+            let path = format!("/tmp/{}.{}", sstable.meta.table_id, format.extension());
+            let mut handle = SSTableHandle::new(sstable.meta.clone(), &path);
+            handle.encryption = Some(EncryptionKey::generate());
+            handle.write(&sstable).await?;
+
+            // The plaintext shouldn't be visible on disk...
+            let raw = tokio::fs::read(&path).await?;
+            assert!(!raw.windows(7).any(|w| w == b"hunter2"));
+
+            // It reads back with the key...
+            let read = handle.read().await?;
+            assert_eq!(read.records, sstable.records);
+            assert_eq!(read.meta, handle.meta);
+
+            // But not without it, or with the wrong one...
+            assert!(read_sstable(&path, None).await.is_err());
+            let wrong = EncryptionKey::generate();
+            assert!(read_sstable(&path, Some(&wrong)).await.is_err());
+
+            // Clean up...
+            handle.delete().await?;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sstable_files_are_compressed() -> Result<()> {
+        let records: Vec<_> = (0..50)
+            .map(|n| Record {
+                key: ObjectId::new(),
+                value: Value::Data(doc! { "n": n, "msg": "hello hello hello" }),
+            })
+            .collect();
+        let sstable = SSTable::new(records)?;
+
+        for format in TableFormat::ALL {
+            // Write the table and read it back...
+            let path = format!("/tmp/{}.{}", sstable.meta.table_id, format.extension());
+            let mut handle = SSTableHandle::new(sstable.meta.clone(), &path);
+            handle.write(&sstable).await?;
+            assert_eq!(handle.read().await?.records, sstable.records);
+
+            // The file should be compressed (as a whole or in blocks)...
+            let raw = tokio::fs::read(&path).await?;
+            let plain = match format {
+                TableFormat::Bson => {
+                    assert!(raw.starts_with(SNAPPY_HEADER));
+                    decompress_table(raw)?
+                }
+                TableFormat::Compact => {
+                    assert!(raw.starts_with(COMPACT_BLOCKS_MAGIC));
+                    let mut plain = COMPACT_MAGIC.to_vec();
+                    bson::to_document(&sstable.meta)?.to_writer(&mut plain)?;
+                    for record in sstable.records.iter() {
+                        encode_record(record, &mut plain)?;
+                    }
+                    assert!(raw.len() < plain.len());
+                    plain
+                }
+            };
+
+            // An uncompressed (older) file should still be readable...
+            tokio::fs::write(&path, &plain).await?;
+            assert_eq!(handle.read().await?, sstable);
+
+            // Clean up...
+            handle.delete().await?;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sparse_index_reads_one_block() -> Result<()> {
+        let records: Vec<_> = (0..100)
+            .map(|n| Record {
+                key: ObjectId::new(),
+                value: match n % 7 {
+                    0 => Value::Tombstone,
+                    _ => Value::Data(doc! { "n": n }),
+                },
+            })
+            .collect();
+        let sstable = SSTable::new(records)?;
+
+        // Write it in blocks of 16 records...
+        let path = format!("/tmp/{}.sst", sstable.meta.table_id);
+        let mut handle = SSTableHandle::new(sstable.meta.clone(), &path);
+        handle.index_interval = 16;
+        handle.write(&sstable).await?;
+        assert!(handle.is_indexed());
+        assert_eq!(handle.meta.index.len(), 7);
+        assert_eq!(handle.read().await?.meta.index, handle.meta.index);
+
+        // Point lookups should match the in-memory table...
+        for rec in sstable.records.iter() {
+            assert_eq!(handle.get(&rec.key).await?, Some(rec.clone()));
+        }
+        assert_eq!(handle.get(&ObjectId::new()).await?, None);
+
+        // Corrupting the last block shouldn't affect lookups in the others...
+        let mut raw = tokio::fs::read(&path).await?;
+        let len = raw.len();
+        raw[len - 8..].copy_from_slice(&[0xff; 8]);
+        tokio::fs::write(&path, raw).await?;
+        assert!(handle.read().await.is_err());
+        let first = &sstable.records[0];
+        assert_eq!(handle.get(&first.key).await?, Some(first.clone()));
+
+        // Clean up...
+        handle.delete().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stream_records_matches_read() -> Result<()> {
+        let records: Vec<_> = (0..100)
+            .map(|n| Record {
+                key: ObjectId::new(),
+                value: match n % 9 {
+                    0 => Value::Tombstone,
+                    _ => Value::Data(doc! { "n": n }),
+                },
+            })
+            .collect();
+        let sstable = SSTable::new(records)?;
+
+        for format in TableFormat::ALL {
+            // Write the table (in several blocks, if it's compact)...
+            let path = format!("/tmp/{}.{}", sstable.meta.table_id, format.extension());
+            let mut handle = SSTableHandle::new(sstable.meta.clone(), &path);
+            handle.index_interval = 16;
+            handle.write(&sstable).await?;
+
+            // Streaming should give the same records, in the same order...
+            let mut stream = handle.stream_records().await?;
+            let mut streamed = vec![];
+            while let Some(rec) = stream.next().await {
+                streamed.push(rec?);
+            }
+            assert_eq!(streamed, handle.read().await?.records);
+
+            // Clean up...
+            handle.delete().await?;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stream_records_from_skips_blocks() -> Result<()> {
+        let records: Vec<_> = (0..100)
+            .map(|n| Record {
+                key: ObjectId::new(),
+                value: Value::Data(doc! { "n": n }),
+            })
+            .collect();
+        let sstable = SSTable::new(records)?;
+        let path = format!(
+            "/tmp/{}.{}",
+            sstable.meta.table_id,
+            TableFormat::Compact.extension()
+        );
+        let mut handle = SSTableHandle::new(sstable.meta.clone(), &path);
+        handle.index_interval = 16;
+        handle.write(&sstable).await?;
+
+        // Starting partway through should skip the earlier blocks, but
+        // still return the rest of the start key's block...
+        let start = sstable.records[40].key;
+        let mut stream = handle.stream_records_from(&start).await?;
+        let mut streamed = vec![];
+        while let Some(rec) = stream.next().await {
+            streamed.push(rec?);
+        }
+        assert_eq!(streamed, sstable.records[32..]);
+
+        // Clean up...
+        handle.delete().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn compact_format_is_smaller() -> Result<()> {
+        // Create an sstable with a mix of data and tombstones...
+        let records: Vec<_> = (0..100)
+            .map(|n| Record {
+                key: ObjectId::new(),
+                value: match n % 5 {
+                    0 => Value::Tombstone,
+                    _ => Value::Data(doc! { "n": n, "msg": "hello" }),
+                },
+            })
+            .collect();
+        let sstable = SSTable::new(records)?;
+
+        // Write it in each format...
+        let id = sstable.meta.table_id;
+        let bson_path = format!("/tmp/{}.{}", id, TableFormat::Bson.extension());
+        let compact_path = format!("/tmp/{}.{}", id, TableFormat::Compact.extension());
+        let mut bson_handle = SSTableHandle::new(sstable.meta.clone(), &bson_path);
+        let mut compact_handle = SSTableHandle::new(sstable.meta.clone(), &compact_path);
+        assert_eq!(bson_handle.format(), TableFormat::Bson);
+        assert_eq!(compact_handle.format(), TableFormat::Compact);
+        bson_handle.write(&sstable).await?;
+        compact_handle.write(&sstable).await?;
+
+        // The compact file should be smaller...
+        let bson_size = bson_handle.size().await?;
+        let compact_size = compact_handle.size().await?;
+        assert!(
+            compact_size < bson_size,
+            "Expected compact ({} bytes) to be smaller than BSON ({} bytes)",
+            compact_size,
+            bson_size
+        );
+
+        // But both should read back the same...
+        assert_eq!(compact_handle.read().await?.records, sstable.records);
+        assert_eq!(bson_handle.read().await?, sstable);
+
+        // And the table id can be parsed from either file name...
+        assert_eq!(TableFormat::table_id(&format!("{}.sst", id)), Some(id));
+        assert_eq!(TableFormat::table_id(&format!("{}.bson", id)), Some(id));
+        assert_eq!(TableFormat::table_id("_meta.bson"), None);
+
+        // Clean up...
+        bson_handle.delete().await?;
+        compact_handle.delete().await?;
+        Ok(())
+    }
+
     #[test]
     fn sstablemeta_key_in_range() {
         // Create three ObjectIds and ensure they're in order...
@@ -402,6 +1321,8 @@ mod test {
             min_key: oid1,
             max_key: oid3,
             num_records: 0,
+            num_tombstones: 0,
+            index: vec![],
         };
 
         // oid 1, 2, and 3 should be in range...
@@ -416,6 +1337,8 @@ mod test {
             min_key: oid1,
             max_key: oid2,
             num_records: 0,
+            num_tombstones: 0,
+            index: vec![],
         };
 
         // oid 1 and 2 should be in range, 3 should not...
@@ -433,6 +1356,8 @@ mod test {
             min_key: oid2,
             max_key: oid3,
             num_records: 0,
+            num_tombstones: 0,
+            index: vec![],
         };
 
         // oid 2 and 3 should be in range, 1 should not...
@@ -444,6 +1369,112 @@ mod test {
         assert!(meta.key_in_range(&oid3), "Expected oid3 to be in range");
     }
 
+    /// Creates an SSTable with a record for each of the given keys.
+    fn table_with_keys(keys: &[ObjectId], n: i32) -> Result<SSTable> {
+        SSTable::new(
+            keys.iter()
+                .map(|k| Record {
+                    key: *k,
+                    value: Value::Data(doc! { "n": n }),
+                })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn merge_single_record() -> Result<()> {
+        // Create some ordered keys...
+        let keys: Vec<_> = (0..5).map(|_| ObjectId::new()).collect();
+
+        // A single-record table in the middle of a multi-record table...
+        let multi = table_with_keys(&[keys[0], keys[1], keys[3], keys[4]], 1)?;
+        let single = table_with_keys(&[keys[2]], 2)?;
+
+        // Merge in both orders...
+        for merged in [multi.merge(&single)?, single.merge(&multi)?] {
+            let got: Vec<_> = merged.records.iter().map(|r| r.key).collect();
+            assert_eq!(got, keys, "Expected all keys in sorted order");
+            assert_eq!(merged.meta.num_records, 5);
+            assert_eq!(merged.meta.min_key, keys[0]);
+            assert_eq!(merged.meta.max_key, keys[4]);
+        }
+
+        // A single record with the same key as another should keep one...
+        let dup = table_with_keys(&[keys[3]], 3)?;
+        let merged = multi.merge(&dup)?;
+        assert_eq!(
+            merged.records.len(),
+            4,
+            "Expected the duplicate to be dropped"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn merge_keeps_newer_records_in_either_order() -> Result<()> {
+        let keys: Vec<_> = (0..4).map(|_| ObjectId::new()).collect();
+
+        // An older and a newer table, sharing some keys...
+        let mut older = table_with_keys(&[keys[0], keys[1], keys[2]], 1)?;
+        let mut newer = table_with_keys(&[keys[1], keys[2], keys[3]], 2)?;
+        older.meta.created_at = DateTime::from_millis(1_000);
+        newer.meta.created_at = DateTime::from_millis(2_000);
+
+        // The older table's values shouldn't shadow the newer ones, even
+        // when merging into the older table (which used to read the
+        // newer table's records in place of the older's)...
+        for merged in [older.merge(&newer)?, newer.merge(&older)?] {
+            let got: Vec<_> = merged
+                .records
+                .iter()
+                .map(|r| match &r.value {
+                    Value::Data(doc) => (r.key, doc.get_i32("n").unwrap()),
+                    Value::Tombstone => panic!("Unexpected tombstone"),
+                })
+                .collect();
+            let want = vec![(keys[0], 1), (keys[1], 2), (keys[2], 2), (keys[3], 2)];
+            assert_eq!(got, want);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn merge_many_matches_pairwise() -> Result<()> {
+        let keys: Vec<_> = (0..8).map(|_| ObjectId::new()).collect();
+
+        // Three tables with overlapping keys, from oldest to newest...
+        let mut tables = [
+            table_with_keys(&[keys[0], keys[2], keys[4], keys[6]], 1)?,
+            table_with_keys(&[keys[1], keys[2], keys[3], keys[6]], 2)?,
+            table_with_keys(&[keys[2], keys[5], keys[6], keys[7]], 3)?,
+        ];
+        for (i, table) in tables.iter_mut().enumerate() {
+            table.meta.created_at = DateTime::from_millis(1_000 * (i as i64 + 1));
+        }
+
+        // The k-way merge should match folding the tables pairwise (from
+        // newest to oldest, since each merged table is newer still)...
+        let pairwise = tables[2].merge(&tables[1])?.merge(&tables[0])?;
+        let merged = SSTable::merge_many(&[&tables[2], &tables[0], &tables[1]])?;
+        assert_eq!(merged.records, pairwise.records);
+        assert_eq!(merged.meta.num_records, keys.len());
+        assert_eq!(merged.get(&keys[2]), tables[2].get(&keys[2]));
+        Ok(())
+    }
+
+    #[test]
+    fn merge_empty_table() -> Result<()> {
+        let keys: Vec<_> = (0..3).map(|_| ObjectId::new()).collect();
+        let table = table_with_keys(&keys, 1)?;
+        let mut empty = table.clone();
+        empty.records = vec![];
+
+        // Merging with an empty table gives back the non-empty one...
+        assert_eq!(table.merge(&empty)?.records, table.records);
+        assert_eq!(empty.merge(&table)?.records, table.records);
+        Ok(())
+    }
+
     #[test]
     fn get_bloom_filter() -> Result<()> {
         // Create an ID that _will_ be in the table...
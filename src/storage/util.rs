@@ -1,11 +1,14 @@
 //! Utility functions for the storage module.
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use bson::Document;
 use std::path::Path;
 use tokio::fs::File;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
+use crate::storage::bloom::BloomFilter;
+use crate::storage::crypto::{self, EncryptionKey};
+
 /// Write a document to disk.
 ///
 /// If the file already exists, it will be overwritten.
@@ -22,6 +25,20 @@ use tokio::io::{AsyncReadExt, AsyncWriteExt};
 ///
 /// * `Result<()>` - A result indicating whether the operation was successful.
 pub async fn write_bson(path: impl AsRef<Path>, doc: &Document) -> Result<()> {
+    write_bson_with(path, doc, None).await
+}
+
+/// Write a document to disk, encrypting it if a key is given.
+///
+/// The document is prefixed with a checksum header (see
+/// [add_checksum]), which is verified when it's read back in.
+///
+/// See also: [write_bson]
+pub async fn write_bson_with(
+    path: impl AsRef<Path>,
+    doc: &Document,
+    key: Option<&EncryptionKey>,
+) -> Result<()> {
     // Write the document to a buffer...
     let mut buffer: Vec<u8> = vec![];
     doc.to_writer(&mut buffer)?;
@@ -30,6 +47,70 @@ pub async fn write_bson(path: impl AsRef<Path>, doc: &Document) -> Result<()> {
     // let mut encoder = snap::raw::Encoder::new();
     // let buffer = encoder.compress_vec(&buffer)?;
 
+    write_bytes(path, add_checksum(buffer), key).await
+}
+
+/// The magic number at the start of a checksummed BSON file.
+const CHECKSUM_MAGIC: u32 = u32::from_le_bytes(*b"BKC1");
+
+/// The length of a checksum header (the magic number and the CRC).
+const CHECKSUM_HEADER_LEN: usize = 8;
+
+/// Prefixes data with a header of [CHECKSUM_MAGIC] and the data's
+/// CRC32 (both as little-endian `u32`s).
+fn add_checksum(data: Vec<u8>) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(CHECKSUM_HEADER_LEN + data.len());
+    buf.extend_from_slice(&CHECKSUM_MAGIC.to_le_bytes());
+    buf.extend_from_slice(&crc32fast::hash(&data).to_le_bytes());
+    buf.extend(data);
+    buf
+}
+
+/// Verifies and strips the header added by [add_checksum].
+///
+/// Data without the header (written before checksums were added) is
+/// returned as-is.
+///
+/// # Arguments
+///
+/// * `path` - The path the data was read from, for error messages.
+/// * `data` - The data read from the file.
+///
+/// # Returns
+///
+/// * `Result<Vec<u8>>` - The data, or an error if the checksum doesn't match.
+fn verify_checksum(path: &Path, mut data: Vec<u8>) -> Result<Vec<u8>> {
+    if !data.starts_with(&CHECKSUM_MAGIC.to_le_bytes()) {
+        return Ok(data);
+    }
+    if data.len() < CHECKSUM_HEADER_LEN {
+        return Err(anyhow!("truncated checksum header for {}", path.display()));
+    }
+    let crc = u32::from_le_bytes(data[4..CHECKSUM_HEADER_LEN].try_into()?);
+    if crc32fast::hash(&data[CHECKSUM_HEADER_LEN..]) != crc {
+        return Err(anyhow!("checksum mismatch for {}", path.display()));
+    }
+    data.drain(..CHECKSUM_HEADER_LEN);
+    Ok(data)
+}
+
+/// Write raw bytes to disk, encrypting them if a key is given.
+///
+/// If the file already exists, it will be overwritten.
+///
+/// # Arguments
+///
+/// * `path` - The path to write the data to.
+/// * `buffer` - The data to be written.
+/// * `key` - The key to encrypt the data with, if any.
+pub async fn write_bytes(
+    path: impl AsRef<Path>,
+    buffer: Vec<u8>,
+    key: Option<&EncryptionKey>,
+) -> Result<()> {
+    // Encrypt the data (if there's a key)...
+    let buffer = crypto::seal(buffer, key)?;
+
     // Write to disk...
     let mut file = File::create(path).await?;
     file.write_all(&buffer).await?;
@@ -44,6 +125,72 @@ pub async fn write_bson(path: impl AsRef<Path>, doc: &Document) -> Result<()> {
     Ok(())
 }
 
+/// Atomically write a document to disk.
+///
+/// The document is written (and synced) to a temporary file next to
+/// `path` which is then renamed over `path`, and the directory is
+/// synced so the rename itself is durable. A crash part way through
+/// leaves either the old file or the new one, never a partial one.
+///
+/// # Arguments
+///
+/// * `path` - The path to write the document to.
+/// * `doc` - The document to be written.
+///
+/// # Returns
+///
+/// * `Result<()>` - A result indicating whether the operation was successful.
+pub async fn write_bson_atomic(path: impl AsRef<Path>, doc: &Document) -> Result<()> {
+    // Format the temp file's path...
+    let path = path.as_ref();
+    let tmp_path = tmp_path_for(path);
+
+    // Write to the temp file (which syncs it)...
+    write_bson(&tmp_path, doc).await?;
+
+    // Move it into place...
+    rename_durable(&tmp_path, path).await
+}
+
+/// Atomically copies the file at `from` to `to`.
+///
+/// Like [write_bson_atomic], the copy is written (and synced) to a
+/// temporary file which is then renamed over `to`.
+///
+/// # Arguments
+///
+/// * `from` - The path of the file to copy.
+/// * `to` - The path to copy it to.
+pub async fn copy_atomic(from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<()> {
+    // Copy to the temp file and sync it...
+    let to = to.as_ref();
+    let tmp_path = tmp_path_for(to);
+    tokio::fs::copy(from, &tmp_path).await?;
+    File::open(&tmp_path).await?.sync_all().await?;
+
+    // Move it into place...
+    rename_durable(&tmp_path, to).await
+}
+
+/// Formats the path of the temporary file used to atomically write `path`.
+fn tmp_path_for(path: &Path) -> std::path::PathBuf {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    tmp_path.into()
+}
+
+/// Renames `from` to `to` and then syncs their directory, so the
+/// rename survives a crash.
+async fn rename_durable(from: &Path, to: &Path) -> Result<()> {
+    tokio::fs::rename(from, to).await?;
+    let dir = match to.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir).await?.sync_all().await?;
+    Ok(())
+}
+
 /// Read bson data from disk.
 ///
 /// This expects the data to be compressed with snappy and will
@@ -57,7 +204,25 @@ pub async fn write_bson(path: impl AsRef<Path>, doc: &Document) -> Result<()> {
 ///
 /// * `Result<Vec<u8>>` - A result containing the document if the operation was successful.
 pub async fn read_bson(path: impl AsRef<Path>) -> Result<Vec<u8>> {
+    read_bson_with(path, None).await
+}
+
+/// Read bson data from disk, decrypting it if it was encrypted.
+///
+/// Returns an error if the data is encrypted and no key (or the
+/// wrong key) is given. Unencrypted data is returned as-is.
+///
+/// If the data has a checksum header (see [write_bson_with]), the
+/// checksum is verified and the header is stripped. Returns an error
+/// if it doesn't match.
+///
+/// See also: [read_bson]
+pub async fn read_bson_with(
+    path: impl AsRef<Path>,
+    key: Option<&EncryptionKey>,
+) -> Result<Vec<u8>> {
     // Get the file...
+    let path = path.as_ref();
     let mut file = File::open(path).await?;
 
     // Read the data in to a buffer...
@@ -68,8 +233,100 @@ pub async fn read_bson(path: impl AsRef<Path>) -> Result<Vec<u8>> {
     // let mut decoder = snap::raw::Decoder::new();
     // let buf = decoder.decompress_vec(&buf)?;
 
+    // Decrypt the data (if it's encrypted) and check it...
+    let buf = crypto::open(buf, key)?;
+    verify_checksum(path, buf)
+}
+
+/// The length of a serialized bloom filter's header (the bit count
+/// and the hash count), in bytes.
+const BLOOM_HEADER_LEN: usize = 12;
+
+/// Serializes a bloom filter to a portable format.
+///
+/// The output is the number of bits (as a little-endian `u64`), the
+/// number of hashes (as a little-endian `u32`), and then the filter's
+/// bits (as little-endian `u64` words).
+///
+/// # Arguments
+///
+/// * `bf` - The bloom filter to serialize.
+///
+/// # Returns
+///
+/// * `Vec<u8>` - The serialized bloom filter.
+pub fn serialize_bloom(bf: &BloomFilter) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(BLOOM_HEADER_LEN + bf.bits().len() * 8);
+    buf.extend_from_slice(&bf.num_bits().to_le_bytes());
+    buf.extend_from_slice(&bf.num_hashes().to_le_bytes());
+    for word in bf.bits() {
+        buf.extend_from_slice(&word.to_le_bytes());
+    }
+    buf
+}
+
+/// Deserializes a bloom filter written by [serialize_bloom].
+///
+/// Returns an error if the data is truncated, or if the size of the
+/// bit array doesn't match the bit count.
+///
+/// # Arguments
+///
+/// * `data` - The serialized bloom filter.
+///
+/// # Returns
+///
+/// * `Result<BloomFilter>` - A result containing the bloom filter if the data was valid.
+pub fn deserialize_bloom(data: &[u8]) -> Result<BloomFilter> {
+    if data.len() < BLOOM_HEADER_LEN {
+        return Err(anyhow!("Serialized bloom filter is too short"));
+    }
+    let (header, rest) = data.split_at(BLOOM_HEADER_LEN);
+    let num_bits = u64::from_le_bytes(header[..8].try_into()?);
+    let num_hashes = u32::from_le_bytes(header[8..].try_into()?);
+    if rest.len() % 8 != 0 {
+        return Err(anyhow!("Serialized bloom filter has a partial word"));
+    }
+    let bits = rest
+        .chunks_exact(8)
+        .map(|c| u64::from_le_bytes(c.try_into().expect("chunk is 8 bytes")))
+        .collect();
+    BloomFilter::from_parts(num_bits, num_hashes, bits)
+}
+
+/// Returns the total size of the files in a directory (recursively).
+///
+/// A missing directory has a size of zero.
+///
+/// # Arguments
+///
+/// * `path` - The path to the directory.
+///
+/// # Returns
+///
+/// * `Result<u64>` - A result containing the size in bytes.
+pub async fn dir_size(path: impl AsRef<Path>) -> Result<u64> {
+    // Walk the directory tree with a stack (to avoid async recursion)...
+    let mut size = 0;
+    let mut dirs = vec![path.as_ref().to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let mut entries = match tokio::fs::read_dir(&dir).await {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err.into()),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let meta = entry.metadata().await?;
+            if meta.is_dir() {
+                dirs.push(entry.path());
+            } else {
+                size += meta.len();
+            }
+        }
+    }
+
     // Done!
-    Ok(buf)
+    Ok(size)
 }
 
 #[cfg(test)]
@@ -100,6 +357,66 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_write_bson_atomic() -> Result<()> {
+        // Define setup params...
+        let path = "/tmp/test-write-atomic.bson";
+        let doc = doc! {
+            "name": "test",
+            "value": 1
+        };
+
+        // Write the document twice (so it overwrites)...
+        write_bson_atomic(path, &doc! { "value": 0 }).await?;
+        write_bson_atomic(path, &doc).await?;
+
+        // Check that the temp file was moved into place...
+        assert!(!std::path::Path::new("/tmp/test-write-atomic.bson.tmp").exists());
+        let doc2: Document = bson::from_slice(&read_bson(path).await?)?;
+        assert_eq!(doc, doc2);
+
+        // Copy it (atomically, too)...
+        let copy_path = "/tmp/test-write-atomic.bson.bak";
+        copy_atomic(path, copy_path).await?;
+        assert!(!std::path::Path::new("/tmp/test-write-atomic.bson.bak.tmp").exists());
+        let doc3: Document = bson::from_slice(&read_bson(copy_path).await?)?;
+        assert_eq!(doc, doc3);
+
+        // Clean up...
+        fs::remove_file(path).await?;
+        fs::remove_file(copy_path).await?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_bloom_round_trip() -> Result<()> {
+        // Build a bloom filter...
+        let keys: Vec<_> = (0..500).map(|_| bson::oid::ObjectId::new()).collect();
+        let mut bf = BloomFilter::with_rate(0.01, 250);
+        for key in keys.iter().take(250) {
+            bf.insert(key);
+        }
+
+        // Round-trip it...
+        let data = serialize_bloom(&bf);
+        let bf2 = deserialize_bloom(&data)?;
+        assert_eq!(bf, bf2);
+
+        // Check that membership is identical (including false positives)...
+        for key in keys.iter() {
+            assert_eq!(bf.contains(key), bf2.contains(key));
+        }
+
+        // Check that mismatched sizes are rejected...
+        assert!(deserialize_bloom(&data[..data.len() - 8]).is_err());
+        assert!(deserialize_bloom(&data[..data.len() - 1]).is_err());
+        assert!(deserialize_bloom(&data[..4]).is_err());
+        let mut bad = data.clone();
+        bad[..8].copy_from_slice(&(bf.num_bits() * 2).to_le_bytes());
+        assert!(deserialize_bloom(&bad).is_err());
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_read_and_write_bson() -> Result<()> {
         // Define setup params...
@@ -125,4 +442,34 @@ mod tests {
         fs::remove_file(path).await?;
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_bson_checksum() -> Result<()> {
+        let path = format!("/tmp/{}.bson", bson::oid::ObjectId::new());
+        let doc = doc! { "name": "test", "value": 1 };
+
+        // A good file reads back...
+        write_bson(&path, &doc).await?;
+        let doc2: Document = bson::from_slice(&read_bson(&path).await?)?;
+        assert_eq!(doc, doc2);
+
+        // A corrupted byte is caught...
+        let mut data = fs::read(&path).await?;
+        let last = data.len() - 2;
+        data[last] ^= 0xff;
+        fs::write(&path, &data).await?;
+        let err = read_bson(&path).await.unwrap_err();
+        assert!(err.to_string().contains("checksum mismatch"), "{}", err);
+
+        // A legacy file (without a checksum) still reads...
+        let mut legacy = vec![];
+        doc.to_writer(&mut legacy)?;
+        fs::write(&path, &legacy).await?;
+        let doc2: Document = bson::from_slice(&read_bson(&path).await?)?;
+        assert_eq!(doc, doc2);
+
+        // Clean up...
+        fs::remove_file(&path).await?;
+        Ok(())
+    }
 }
@@ -1,39 +1,866 @@
+use crate::storage::crypto::EncryptionKey;
 use crate::storage::record::*;
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+/// The length of a WAL entry's length prefix, in bytes.
+const LEN_PREFIX_LEN: usize = 4;
+
+/// The length of a WAL entry's CRC32 checksum, in bytes.
+const CRC_LEN: usize = 4;
+
+/// Set in an entry's length prefix if its payload isn't compressed.
+const FLAG_RAW: u32 = 1 << 31;
+
+/// Set in an entry's length prefix if its payload holds several
+/// records (one after the other) rather than one.
+const FLAG_BATCH: u32 = 1 << 30;
+
+/// Set in an entry's length prefix if its payload is encrypted.
+const FLAG_SEALED: u32 = 1 << 29;
+
+/// The bits of an entry's length prefix that hold the length.
+const LEN_MASK: u32 = !(FLAG_RAW | FLAG_BATCH | FLAG_SEALED);
+
+/// How a WAL's entries are compressed.
+///
+/// Each entry is flagged with how it was written, so a log written with
+/// a mix of settings still replays correctly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WalCompression {
+    /// Entries aren't compressed.
+    None,
+
+    /// Each record is compressed with snappy on its own (the default).
+    #[default]
+    PerRecord,
+
+    /// Records written together (see [WAL::write_batch]) are compressed
+    /// as a single entry, which compresses better than one at a time.
+    /// Records written on their own are compressed individually.
+    Batched,
+}
 
 /// A Write Ahead Log (WAL) that stores database writes
 /// to disk for durability.
 ///
 /// The WAL is a log of all database record modificiations. It's used
 /// in case of a crash to ensure that all changes are persisted.
+///
+/// Each LSM Tree has its own WAL, so collections can flush and
+/// checkpoint independently of each other.
+///
+/// Each entry in the log holds a record (or a batch of records), encoded
+/// as BSON and (usually) compressed with snappy, framed as:
+///
+/// * The length of the payload (a little-endian `u32`). The top three
+///   bits are flags, marking an uncompressed payload, a batch of records,
+///   and an encrypted payload.
+/// * The payload (encrypted after it's compressed, if the WAL has a key).
+/// * A CRC32 checksum of the payload (a little-endian `u32`).
+///
+/// See also: [WalCompression]
 #[derive(Default, Debug, Clone)]
-pub struct WAL;
+pub struct WAL {
+    /// The path to the WAL file on disk.
+    pub path: String,
+
+    /// How new entries are compressed.
+    pub compression: WalCompression,
+
+    /// How long a sync waits for other writers' entries before syncing
+    /// (see [GroupCommit::window]).
+    pub commit_window: Duration,
+
+    /// The key new entries are encrypted with, if any. Encrypted entries
+    /// can't be read without it.
+    ///
+    /// See also: [crate::storage::crypto]
+    pub encryption: Option<EncryptionKey>,
+
+    /// The open log file (and its group commits), once it's been opened.
+    file: Arc<Mutex<Option<Arc<GroupCommit<LogFile>>>>>,
+}
 
 impl WAL {
-    /// Creates a new instance of the `WAL` struct.
-    pub fn new() -> Self {
-        todo!();
+    /// Creates a new instance of the `WAL` struct for the log
+    /// file at `path`.
+    ///
+    /// The file is created (or opened for appending, if it already
+    /// exists) when the first record is written.
+    pub fn new(path: &str) -> Self {
+        WAL {
+            path: path.to_string(),
+            compression: WalCompression::default(),
+            commit_window: Duration::ZERO,
+            encryption: None,
+            file: Arc::default(),
+        }
+    }
+
+    /// Loads an existing WAL from disk, opening its log file for
+    /// appending.
+    ///
+    /// The log isn't truncated, so its records can still be read and
+    /// new records are added after them.
+    pub async fn load(path: &str) -> Result<Self> {
+        let wal = WAL::new(path);
+        let opened = wal.clone();
+        blocking(move || {
+            if !Path::new(&opened.path).is_file() {
+                return Err(anyhow!("WAL file {:?} doesn't exist", opened.path));
+            }
+            opened.log_file()?;
+            Ok(())
+        })
+        .await?;
+        Ok(wal)
+    }
+
+    /// Returns the open log file, opening it if it isn't already.
+    ///
+    /// This blocks, so it should only be called off the async runtime
+    /// (see [blocking]).
+    fn log_file(&self) -> Result<Arc<GroupCommit<LogFile>>> {
+        let mut file = match self.file.lock() {
+            Ok(f) => f,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if let Some(file) = file.as_ref() {
+            return Ok(file.clone());
+        }
+        if let Some(dir) = Path::new(&self.path).parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let opened = Arc::new(GroupCommit::new(
+            LogFile::open(&self.path)?,
+            self.commit_window,
+        ));
+        *file = Some(opened.clone());
+        Ok(opened)
     }
 
-    /// Loads a WAL connection from disk.
-    pub fn load() -> Self {
-        todo!();
+    /// Encodes records as WAL entries, one per record.
+    fn encode_each(&self, records: &[Record]) -> Result<Vec<u8>> {
+        let compress = self.compression != WalCompression::None;
+        let mut buf = vec![];
+        for record in records {
+            buf.extend(encode_entry(
+                std::slice::from_ref(record),
+                compress,
+                self.encryption.as_ref(),
+            )?);
+        }
+        Ok(buf)
+    }
+
+    /// Appends encoded entries to the log.
+    async fn append(&self, entries: Vec<u8>) -> Result<()> {
+        let wal = self.clone();
+        blocking(move || {
+            wal.log_file()?.append(&entries)?;
+            Ok(())
+        })
+        .await
     }
 
     /// Writes a record to the WAL.
-    pub fn write(&self, _record: &Record) -> Result<()> {
-        todo!();
+    ///
+    /// The record is appended to the log but isn't synced to disk
+    /// (see [WAL::sync]).
+    pub async fn write(&self, record: &Record) -> Result<()> {
+        self.append(self.encode_each(std::slice::from_ref(record))?)
+            .await
+    }
+
+    /// Writes a batch of records to the WAL.
+    ///
+    /// With [WalCompression::Batched] the records are written as a single
+    /// entry, otherwise they're written one at a time. Like [WAL::write],
+    /// they aren't synced.
+    pub async fn write_batch(&self, records: &[Record]) -> Result<()> {
+        let entries = match self.compression {
+            WalCompression::Batched if !records.is_empty() => {
+                encode_entry(records, true, self.encryption.as_ref())?
+            }
+            _ => self.encode_each(records)?,
+        };
+        self.append(entries).await
+    }
+
+    /// Writes a batch of records to the WAL as a single entry, so
+    /// replaying the log gives all of them or (if a crash tore the entry)
+    /// none of them.
+    ///
+    /// Unlike [WAL::write_batch], the entry is compressed as a whole
+    /// unless compression is off. Like [WAL::write], the records aren't
+    /// synced.
+    pub async fn write_atomic(&self, records: &[Record]) -> Result<()> {
+        if records.is_empty() {
+            return Ok(());
+        }
+        let compress = self.compression != WalCompression::None;
+        self.append(encode_entry(records, compress, self.encryption.as_ref())?)
+            .await
+    }
+
+    /// Syncs the records written so far to disk.
+    ///
+    /// Concurrent syncs (e.g. from clones of the WAL) are coalesced by
+    /// the log's [GroupCommit], so they share an fsync.
+    pub async fn sync(&self) -> Result<()> {
+        let wal = self.clone();
+        let file = blocking(move || wal.log_file()).await?;
+        file.sync().await
+    }
+
+    /// Truncates the log, once the records in it are safely on disk
+    /// elsewhere (e.g. after the memtable they were written to has been
+    /// flushed).
+    pub async fn truncate(&self) -> Result<()> {
+        let wal = self.clone();
+        blocking(move || {
+            if !Path::new(&wal.path).exists() {
+                return Ok(());
+            }
+            wal.log_file()?.file().truncate()?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Replaces the records in the log with `records` (e.g. the writes
+    /// that arrived while a memtable was being flushed).
+    ///
+    /// The new log is written to a temporary file and synced before it's
+    /// renamed over the old one, so a crash leaves one or the other.
+    pub async fn rewrite(&self, records: &[Record]) -> Result<()> {
+        if records.is_empty() {
+            return self.truncate().await;
+        }
+        let entries = self.encode_each(records)?;
+        let wal = self.clone();
+        blocking(move || {
+            let mut file = match wal.file.lock() {
+                Ok(f) => f,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+
+            // Write the new log...
+            let tmp_path = format!("{}.tmp", wal.path);
+            let mut tmp = File::create(&tmp_path)?;
+            tmp.write_all(&entries)?;
+            tmp.sync_all()?;
+
+            // Swap it in (the old file is re-opened on the next write)...
+            std::fs::rename(&tmp_path, &wal.path)?;
+            *file = None;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Reads all records from the WAL, in the order they were written.
+    ///
+    /// Reading stops at the first truncated or corrupt entry (e.g. one
+    /// that was being written during a crash), returning the records
+    /// before it. A missing log has no records.
+    ///
+    /// Returns an error if an entry is encrypted and the WAL doesn't have
+    /// the key it was encrypted with.
+    pub async fn read(&self) -> Result<Vec<Record>> {
+        let data = match tokio::fs::read(&self.path).await {
+            Ok(data) => data,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(err.into()),
+        };
+        let mut records = vec![];
+        let mut rest = data.as_slice();
+        while let Some((batch, next)) = decode_entry(rest, self.encryption.as_ref())? {
+            records.extend(batch);
+            rest = next;
+        }
+        Ok(records)
+    }
+
+    /// Deletes the WAL's log file, returning the records it held.
+    pub async fn delete(&self) -> Result<Vec<Record>> {
+        let records = self.read().await?;
+        match self.file.lock() {
+            Ok(mut f) => *f = None,
+            Err(poisoned) => *poisoned.into_inner() = None,
+        }
+        match tokio::fs::remove_file(&self.path).await {
+            Ok(()) => Ok(records),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(records),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+/// Runs blocking file I/O (and fsyncs) on tokio's blocking thread pool,
+/// so it doesn't stall the async worker threads.
+async fn blocking<T: Send + 'static>(f: impl FnOnce() -> Result<T> + Send + 'static) -> Result<T> {
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|err| anyhow!("WAL I/O task failed: {}", err))?
+}
+
+/// Encodes records as a single WAL entry (see [WAL]), compressing
+/// it if `compress` is set and encrypting it if there's a `key`.
+fn encode_entry(
+    records: &[Record],
+    compress: bool,
+    key: Option<&EncryptionKey>,
+) -> Result<Vec<u8>> {
+    // Encode the records...
+    let mut buf = vec![];
+    for record in records {
+        bson::to_document(record)?.to_writer(&mut buf)?;
+    }
+
+    // Compress them, if asked to...
+    let mut flags = 0;
+    if records.len() > 1 {
+        flags |= FLAG_BATCH;
+    }
+    let payload = if compress {
+        snap::raw::Encoder::new().compress_vec(&buf)?
+    } else {
+        flags |= FLAG_RAW;
+        buf
+    };
+
+    // Encrypt them, if there's a key...
+    let payload = match key {
+        Some(key) => {
+            flags |= FLAG_SEALED;
+            key.encrypt(&payload)?
+        }
+        None => payload,
+    };
+
+    // Frame it...
+    let len = u32::try_from(payload.len())
+        .ok()
+        .filter(|len| len & !LEN_MASK == 0)
+        .ok_or(anyhow!("WAL record is too large"))?;
+    let mut entry = Vec::with_capacity(LEN_PREFIX_LEN + payload.len() + CRC_LEN);
+    entry.extend_from_slice(&(len | flags).to_le_bytes());
+    entry.extend_from_slice(&payload);
+    entry.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    Ok(entry)
+}
+
+/// Decodes the WAL entry at the start of `data`, decrypting it with
+/// `key` if it's encrypted, and returns its records and the data after
+/// it.
+///
+/// Returns `None` if the entry is truncated or corrupt, and an error if
+/// it's encrypted and `key` is missing (or the wrong key).
+fn decode_entry<'a>(
+    data: &'a [u8],
+    key: Option<&EncryptionKey>,
+) -> Result<Option<(Vec<Record>, &'a [u8])>> {
+    // Read the length prefix (and its flags)...
+    let Some((prefix, rest)) = data.split_first_chunk::<LEN_PREFIX_LEN>() else {
+        return Ok(None);
+    };
+    let prefix = u32::from_le_bytes(*prefix);
+    let len = (prefix & LEN_MASK) as usize;
+
+    // Read the payload and check its checksum...
+    if rest.len() < len + CRC_LEN {
+        return Ok(None);
+    }
+    let (payload, rest) = rest.split_at(len);
+    let Some((crc, rest)) = rest.split_first_chunk::<CRC_LEN>() else {
+        return Ok(None);
+    };
+    if crc32fast::hash(payload) != u32::from_le_bytes(*crc) {
+        return Ok(None);
+    }
+
+    // Decrypt it, if it's encrypted (the checksum passed, so a failure
+    // here means the wrong key)...
+    let payload = if prefix & FLAG_SEALED != 0 {
+        key.ok_or(anyhow!("WAL entry is encrypted but no key was given"))?
+            .decrypt(payload)?
+    } else {
+        payload.to_vec()
+    };
+
+    // Decompress and decode the records...
+    Ok(decode_records(&payload, prefix).map(|records| (records, rest)))
+}
+
+/// Decompresses and decodes the records in an entry's (decrypted)
+/// payload, going by the flags in its length `prefix`.
+///
+/// Returns `None` if the payload is corrupt.
+fn decode_records(payload: &[u8], prefix: u32) -> Option<Vec<Record>> {
+    let buf = if prefix & FLAG_RAW == 0 {
+        snap::raw::Decoder::new().decompress_vec(payload).ok()?
+    } else {
+        payload.to_vec()
+    };
+    let mut records = vec![];
+    let mut reader = buf.as_slice();
+    while !reader.is_empty() {
+        let doc = bson::Document::from_reader(&mut reader).ok()?;
+        records.push(bson::from_document(doc).ok()?);
+        if prefix & FLAG_BATCH == 0 {
+            break;
+        }
+    }
+    Some(records)
+}
+
+/// A file that WAL frames are appended and synced to.
+///
+/// This is a trait so the sync behavior can be instrumented in tests.
+pub trait SyncFile: Send + Sync + 'static {
+    /// Appends a frame to the file (without syncing it).
+    fn append(&self, frame: &[u8]) -> io::Result<()>;
+
+    /// Syncs everything appended so far to disk.
+    fn sync(&self) -> io::Result<()>;
+}
+
+/// A log file on disk.
+#[derive(Debug)]
+pub struct LogFile {
+    file: Mutex<File>,
+}
+
+impl LogFile {
+    /// Opens the log file at `path` for appending, creating it if needed.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(LogFile {
+            file: Mutex::new(file),
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, File> {
+        match self.file.lock() {
+            Ok(f) => f,
+            Err(poisoned) => poisoned.into_inner(),
+        }
     }
 
-    /// Reads all records from the WAL.
-    pub fn read(&self) -> Result<Vec<Record>> {
-        todo!();
+    /// Truncates the file to zero length and syncs it.
+    ///
+    /// The file is opened for appending, so later appends start from
+    /// the beginning again.
+    pub fn truncate(&self) -> io::Result<()> {
+        let file = self.lock();
+        file.set_len(0)?;
+        file.sync_all()
+    }
+}
+
+impl SyncFile for LogFile {
+    fn append(&self, frame: &[u8]) -> io::Result<()> {
+        self.lock().write_all(frame)
     }
 
-    pub fn delete(&self) -> Result<Vec<Record>> {
-        todo!();
+    fn sync(&self) -> io::Result<()> {
+        self.lock().sync_all()
+    }
+}
+
+#[derive(Debug, Default)]
+struct CommitState {
+    /// The number of frames appended so far.
+    appended: u64,
+
+    /// The number of frames known to be synced to disk.
+    synced: u64,
+
+    /// Whether a writer is currently syncing on everyone's behalf.
+    syncing: bool,
+
+    /// The number of syncs done so far.
+    syncs: u64,
+}
+
+/// Coordinates group commits to a WAL file, so concurrent writers
+/// share fsyncs rather than each doing their own.
+///
+/// Each writer appends its frame and then waits for it to be synced.
+/// If nobody is syncing, the writer becomes the syncer: it waits out
+/// the batching `window` (letting other writers append), does a single
+/// sync covering every frame appended so far, and wakes the others.
+/// Writers that arrive during a sync wait for the next one.
+#[derive(Debug)]
+pub struct GroupCommit<F: SyncFile> {
+    /// How long the syncer waits for more frames before syncing.
+    ///
+    /// Zero still coalesces the frames appended during a sync.
+    pub window: Duration,
+
+    file: Arc<F>,
+    state: Mutex<CommitState>,
+    synced: Notify,
+}
+
+impl<F: SyncFile> GroupCommit<F> {
+    /// Creates a new group commit coordinator for the given file.
+    pub fn new(file: F, window: Duration) -> Self {
+        GroupCommit {
+            window,
+            file: Arc::new(file),
+            state: Mutex::new(CommitState::default()),
+            synced: Notify::new(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, CommitState> {
+        match self.state.lock() {
+            Ok(s) => s,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    /// Returns a reference to the underlying file.
+    pub fn file(&self) -> &F {
+        &self.file
+    }
+
+    /// Returns the number of syncs done so far.
+    pub fn syncs(&self) -> u64 {
+        self.lock().syncs
+    }
+
+    /// Appends a frame to the log (without syncing it), returning its
+    /// position in the log.
+    ///
+    /// This blocks on the write, so async callers should run it off the
+    /// async runtime.
+    pub fn append(&self, frame: &[u8]) -> Result<u64> {
+        let mut state = self.lock();
+        self.file.append(frame)?;
+        state.appended += 1;
+        Ok(state.appended)
+    }
+
+    /// Appends a frame to the log and waits until it's synced to disk.
+    pub async fn commit(&self, frame: &[u8]) -> Result<()> {
+        let seq = self.append(frame)?;
+        self.sync_to(seq).await
+    }
+
+    /// Waits until every frame appended so far is synced to disk.
+    pub async fn sync(&self) -> Result<()> {
+        let seq = self.lock().appended;
+        self.sync_to(seq).await
+    }
+
+    /// Waits until the frames up to (and including) position `seq` are
+    /// synced to disk, syncing them if nobody else is.
+    async fn sync_to(&self, seq: u64) -> Result<()> {
+        loop {
+            // Register for the wake-up *before* checking the state, so
+            // a sync finishing in between isn't missed...
+            let notified = self.synced.notified();
+            {
+                let mut state = self.lock();
+                if state.synced >= seq {
+                    return Ok(());
+                }
+                if !state.syncing {
+                    state.syncing = true;
+                    break;
+                }
+            }
+            notified.await;
+        }
+
+        // This writer is the syncer. Give others a chance to append...
+        if !self.window.is_zero() {
+            tokio::time::sleep(self.window).await;
+        }
+
+        // Sync everything appended so far...
+        let target = self.lock().appended;
+        let file = self.file.clone();
+        let res = tokio::task::spawn_blocking(move || file.sync()).await;
+
+        // Record the result and wake the waiting writers. (If the sync
+        // failed, one of them will take over and try again.)
+        {
+            let mut state = self.lock();
+            if let Ok(Ok(())) = res {
+                state.synced = state.synced.max(target);
+                state.syncs += 1;
+            }
+            state.syncing = false;
+        }
+        self.synced.notify_waiters();
+        match res {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(err)) => Err(err.into()),
+            Err(err) => Err(anyhow!("WAL sync task failed: {}", err)),
+        }
     }
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A file that counts its appends and (slow) syncs.
+    #[derive(Default)]
+    struct CountingFile {
+        appends: AtomicUsize,
+        syncs: AtomicUsize,
+    }
+
+    impl SyncFile for CountingFile {
+        fn append(&self, _frame: &[u8]) -> io::Result<()> {
+            self.appends.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+
+        fn sync(&self) -> io::Result<()> {
+            std::thread::sleep(Duration::from_millis(5));
+            self.syncs.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_commits_share_syncs() -> Result<()> {
+        let wal = Arc::new(GroupCommit::new(
+            CountingFile::default(),
+            Duration::from_millis(2),
+        ));
+
+        // Commit from many writers at once...
+        let k = 64;
+        let mut handles = vec![];
+        for i in 0..k {
+            let wal = wal.clone();
+            handles.push(tokio::spawn(async move {
+                wal.commit(format!("frame {}", i).as_bytes()).await
+            }));
+        }
+        for h in handles {
+            h.await??;
+        }
+
+        // Every frame was appended, but with far fewer syncs...
+        let appends = wal.file().appends.load(Ordering::SeqCst);
+        let syncs = wal.file().syncs.load(Ordering::SeqCst);
+        assert_eq!(appends, k);
+        assert!(syncs >= 1);
+        assert!(
+            syncs < k / 4,
+            "Expected far fewer than {} syncs, got {}",
+            k,
+            syncs
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn log_file_commits() -> Result<()> {
+        let path = format!("/tmp/{}.log", bson::oid::ObjectId::new());
+        let wal = GroupCommit::new(LogFile::open(&path)?, Duration::ZERO);
+        wal.commit(b"hello ").await?;
+        wal.commit(b"world").await?;
+        assert_eq!(tokio::fs::read(&path).await?, b"hello world");
+
+        // (Clean up) Remove the file...
+        tokio::fs::remove_file(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_wal_syncs_are_grouped() -> Result<()> {
+        let path = format!("/tmp/{}.log", bson::oid::ObjectId::new());
+        let mut wal = WAL::new(&path);
+        wal.commit_window = Duration::from_millis(2);
+
+        // Write and sync from many clones of the WAL at once...
+        let k = 64;
+        let mut handles = vec![];
+        for i in 0..k {
+            let wal = wal.clone();
+            handles.push(tokio::spawn(async move {
+                wal.write(&Record::new_data(bson::doc! { "i": i as i32 }))
+                    .await?;
+                wal.sync().await
+            }));
+        }
+        for h in handles {
+            h.await??;
+        }
+
+        // Every record made it, but with far fewer syncs...
+        assert_eq!(wal.read().await?.len(), k);
+        let syncs = wal.log_file()?.syncs() as usize;
+        assert!(syncs >= 1);
+        assert!(
+            syncs < k / 4,
+            "Expected far fewer than {} syncs, got {}",
+            k,
+            syncs
+        );
+
+        // (Clean up) Delete the log...
+        wal.delete().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn write_and_read_records() -> Result<()> {
+        let path = format!("/tmp/{}.log", bson::oid::ObjectId::new());
+        let records = vec![
+            Record::new_data(bson::doc! { "n": 1 }),
+            Record::new_tombstone(),
+            Record::new_data(bson::doc! { "n": 3 }),
+        ];
+
+        // A new WAL has no records...
+        let wal = WAL::new(&path);
+        assert!(wal.read().await?.is_empty());
+
+        // Write the first two records...
+        wal.write(&records[0]).await?;
+        wal.write(&records[1]).await?;
+        wal.sync().await?;
+        assert_eq!(wal.read().await?, records[..2]);
+
+        // Reopen it and append the last one...
+        let wal = WAL::load(&path).await?;
+        wal.write(&records[2]).await?;
+        wal.sync().await?;
+        assert_eq!(wal.read().await?, records);
+
+        // A partially-written entry is ignored...
+        let full = std::fs::read(&path)?;
+        let first_len = encode_entry(&records[..1], true, None)?.len();
+        std::fs::write(&path, &full[..full.len() - 3])?;
+        assert_eq!(wal.read().await?, records[..2]);
+
+        // As is everything after a corrupt entry...
+        let mut corrupt = full.clone();
+        corrupt[first_len + LEN_PREFIX_LEN] ^= 0xff;
+        std::fs::write(&path, &corrupt)?;
+        assert_eq!(wal.read().await?, records[..1]);
+
+        // (Clean up) Delete the log...
+        wal.delete().await?;
+        assert!(!Path::new(&path).exists());
+        assert!(WAL::load(&path).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn torn_atomic_batch_is_dropped() -> Result<()> {
+        let path = format!("/tmp/{}.log", bson::oid::ObjectId::new());
+        let records: Vec<_> = (0..5)
+            .map(|i| Record::new_data(bson::doc! { "i": i }))
+            .collect();
+
+        // Write a record on its own, then a batch...
+        let wal = WAL::new(&path);
+        wal.write(&records[0]).await?;
+        wal.write_atomic(&records[1..]).await?;
+        wal.sync().await?;
+        assert_eq!(wal.read().await?, records);
+
+        // If the batch is torn, none of it is read back...
+        let full = tokio::fs::read(&path).await?;
+        tokio::fs::write(&path, &full[..full.len() - 1]).await?;
+        assert_eq!(wal.read().await?, records[..1]);
+
+        // (Clean up) Delete the log...
+        wal.delete().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn encrypted_log_needs_key() -> Result<()> {
+        let path = format!("/tmp/{}.log", bson::oid::ObjectId::new());
+        let records = vec![
+            Record::new_data(bson::doc! { "secret": "hunter2" }),
+            Record::new_tombstone(),
+        ];
+
+        // Write some records with a key...
+        let key = EncryptionKey::generate();
+        let mut wal = WAL::new(&path);
+        wal.compression = WalCompression::None;
+        wal.encryption = Some(key.clone());
+        wal.write_batch(&records).await?;
+        wal.sync().await?;
+        assert_eq!(wal.read().await?, records);
+
+        // The plaintext isn't in the file...
+        let raw = tokio::fs::read(&path).await?;
+        assert!(!raw.windows(7).any(|w| w == b"hunter2"));
+
+        // And it can't be read without the key, or with the wrong one...
+        assert!(WAL::new(&path).read().await.is_err());
+        let mut other = WAL::new(&path);
+        other.encryption = Some(EncryptionKey::generate());
+        assert!(other.read().await.is_err());
+
+        // (Clean up) Delete the log...
+        wal.delete().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn batched_compression_shrinks_log() -> Result<()> {
+        let text = "all work and no play makes jack a dull boy ".repeat(20);
+        let records: Vec<_> = (0..20)
+            .map(|i| Record::new_data(bson::doc! { "i": i, "text": &text }))
+            .collect();
+
+        // Write the same records with each setting...
+        let mut sizes = vec![];
+        for compression in [
+            WalCompression::None,
+            WalCompression::PerRecord,
+            WalCompression::Batched,
+        ] {
+            let path = format!("/tmp/{}.log", bson::oid::ObjectId::new());
+            let mut wal = WAL::new(&path);
+            wal.compression = compression;
+            wal.write_batch(&records).await?;
+            wal.sync().await?;
+            assert_eq!(wal.read().await?, records);
+            sizes.push(std::fs::metadata(&path)?.len());
+            wal.delete().await?;
+        }
+        assert!(sizes[1] < sizes[0], "{:?}", sizes);
+        assert!(sizes[2] < sizes[1], "{:?}", sizes);
+
+        // A log with a mix of entries replays in order...
+        let path = format!("/tmp/{}.log", bson::oid::ObjectId::new());
+        let mut wal = WAL::new(&path);
+        wal.compression = WalCompression::None;
+        wal.write(&records[0]).await?;
+        wal.compression = WalCompression::Batched;
+        wal.write_batch(&records[1..10]).await?;
+        wal.write(&records[10]).await?;
+        wal.compression = WalCompression::PerRecord;
+        wal.write_batch(&records[11..]).await?;
+        wal.sync().await?;
+        assert_eq!(wal.read().await?, records);
+
+        // (Clean up) Delete the log...
+        wal.delete().await?;
+        Ok(())
+    }
+}